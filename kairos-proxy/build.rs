@@ -0,0 +1,84 @@
+//! Embeds the build's provenance for `GET /admin/buildinfo`: the git commit, the compiler and
+//! the build time, as `KAIROS_PROXY_GIT_SHA`, `KAIROS_PROXY_RUSTC_VERSION` and
+//! `KAIROS_PROXY_BUILD_TIMESTAMP`.
+//!
+//! Builds outside a git checkout (e.g. a Docker context without `.git`) can pass the commit in
+//! `GIT_SHA`; `SOURCE_DATE_EPOCH` pins the timestamp for reproducible builds.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let sha = std::env::var("GIT_SHA")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .or_else(|| output("git", &["rev-parse", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    let built_at = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+
+    println!("cargo:rustc-env=KAIROS_PROXY_GIT_SHA={}", sha);
+    println!(
+        "cargo:rustc-env=KAIROS_PROXY_RUSTC_VERSION={}",
+        rustc_version
+    );
+    println!(
+        "cargo:rustc-env=KAIROS_PROXY_BUILD_TIMESTAMP={}",
+        rfc3339(built_at)
+    );
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // A new commit moves HEAD (or the branch it points to)
+    if let Some(head) = output("git", &["rev-parse", "--git-path", "HEAD"]) {
+        println!("cargo:rerun-if-changed={}", head);
+    }
+    if let Some(refs) = output("git", &["rev-parse", "--symbolic-full-name", "HEAD"])
+        .and_then(|r| output("git", &["rev-parse", "--git-path", &r]))
+    {
+        println!("cargo:rerun-if-changed={}", refs);
+    }
+}
+
+/// Trimmed stdout of a command that succeeded.
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let out = Command::new(program).args(args).output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    let s = String::from_utf8(out.stdout).ok()?.trim().to_string();
+    (!s.is_empty()).then_some(s)
+}
+
+/// `secs` since the epoch as a UTC RFC 3339 timestamp.
+fn rfc3339(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
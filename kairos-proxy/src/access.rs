@@ -0,0 +1,155 @@
+//! Client address allow and deny lists.
+//!
+//! `[ip_access]` restricts the data endpoints and `[admin_ip_access]` the admin API, checked
+//! against the client address (see `forwarded`) before anything is routed. A client must match
+//! `allow`, when set, and must not match `deny`; anyone else gets `403 Forbidden`.
+//!
+//! Without an `admin_token`, admin requests that change anything (anything but `GET` and
+//! `HEAD`) are only accepted from loopback addresses.
+
+use crate::config::IpAccessConfig;
+use crate::forwarded::{self, ClientIp};
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::{Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::Body;
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    allow: Option<Vec<IpNet>>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn from_config(key: &str, cfg: &Option<IpAccessConfig>) -> anyhow::Result<Self> {
+        let Some(cfg) = cfg else {
+            return Ok(IpFilter::default());
+        };
+        Ok(IpFilter {
+            allow: cfg
+                .allow
+                .as_deref()
+                .map(|a| forwarded::parse_networks(&format!("{}.allow", key), a))
+                .transpose()?,
+            deny: forwarded::parse_networks(
+                &format!("{}.deny", key),
+                cfg.deny.as_deref().unwrap_or_default(),
+            )?,
+        })
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let ip = unmapped(ip);
+        self.allow
+            .as_ref()
+            .is_none_or(|allow| forwarded::contains(allow, ip))
+            && !forwarded::contains(&self.deny, ip)
+    }
+}
+
+// An IPv4 client on a dual-stack listener shows up as ::ffff:a.b.c.d
+fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+/// Whether an admin request with `method` from `ip` may proceed to the token check.
+fn admin_permits(token_configured: bool, method: &Method, ip: IpAddr) -> bool {
+    token_configured || matches!(*method, Method::GET | Method::HEAD) || unmapped(ip).is_loopback()
+}
+
+/// Refuses requests from clients the filter for their path does not permit.
+pub async fn layer(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    // Missing when the service is driven without a listener, as in tests
+    let Some(ClientIp(ip)) = req.extensions().get::<ClientIp>().copied() else {
+        return next.run(req).await;
+    };
+    let path = req.uri().path();
+    let (filter, scope) = if path == "/admin" || path.starts_with("/admin/") {
+        (&state.admin_ip_filter, "admin")
+    } else {
+        (&state.ip_filter, "data")
+    };
+    if scope == "admin" && !admin_permits(state.admin_token.is_some(), req.method(), ip) {
+        warn!(
+            "Refused admin {} from {}: without admin_token only local changes are accepted",
+            req.method(),
+            ip
+        );
+        state
+            .metrics
+            .inc_counter("kairos_proxy_ip_denied_total", &[("scope", scope)]);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    if !filter.permits(ip) {
+        debug!("Refused {} request from {}", scope, ip);
+        state
+            .metrics
+            .inc_counter("kairos_proxy_ip_denied_total", &[("scope", scope)]);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denies_win_over_allows() {
+        let cfg = IpAccessConfig {
+            allow: Some(vec!["10.0.0.0/8".to_string(), "2001:db8::/32".to_string()]),
+            deny: Some(vec!["10.6.6.0/24".to_string()]),
+        };
+        let filter = IpFilter::from_config("admin_ip_access", &Some(cfg)).expect("filter");
+        let permits = |s: &str| filter.permits(s.parse().unwrap());
+        assert!(permits("10.1.2.3"));
+        assert!(permits("::ffff:10.1.2.3"));
+        assert!(permits("2001:db8::1"));
+        assert!(!permits("10.6.6.6"));
+        assert!(!permits("192.0.2.1"));
+
+        let deny_only = IpAccessConfig {
+            allow: None,
+            deny: Some(vec!["192.0.2.1".to_string()]),
+        };
+        let filter = IpFilter::from_config("ip_access", &Some(deny_only)).expect("filter");
+        assert!(filter.permits("198.51.100.1".parse().unwrap()));
+        assert!(!filter.permits("192.0.2.1".parse().unwrap()));
+        assert!(IpFilter::default().permits("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn admin_changes_need_a_token_or_a_local_client() {
+        let remote: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(admin_permits(false, &Method::GET, remote));
+        assert!(!admin_permits(false, &Method::POST, remote));
+        assert!(!admin_permits(false, &Method::PUT, remote));
+        assert!(admin_permits(
+            false,
+            &Method::POST,
+            "127.0.0.1".parse().unwrap()
+        ));
+        assert!(admin_permits(
+            false,
+            &Method::POST,
+            "::ffff:127.0.0.1".parse().unwrap()
+        ));
+        assert!(admin_permits(false, &Method::POST, "::1".parse().unwrap()));
+        assert!(admin_permits(true, &Method::POST, remote));
+    }
+}
@@ -0,0 +1,582 @@
+//! Runtime administration API mounted under `/admin`.
+//!
+//! Every request must carry `Authorization: Bearer <admin_token>` when `admin_token` is
+//! configured. Without one, requests that change anything are only accepted from loopback
+//! addresses (see `access`). The exception is `/admin/ui`, a static status page holding no data of its own:
+//! it asks for the token and renders `/admin/status` in the browser.
+
+use crate::routes::RegexLimits;
+use crate::state::AppState;
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/config", get(config_handler))
+        .route("/config/reload", post(reload_handler))
+        .route("/config/rollback", post(rollback_handler))
+        .route(
+            "/loglevel",
+            get(log_level_handler).put(set_log_level_handler),
+        )
+        .route("/backends", get(list_backends_handler))
+        .route("/backends/:name/drain", post(drain_handler))
+        .route("/backends/:name/undrain", post(undrain_handler))
+        .route("/status", get(status_handler))
+        .route("/buildinfo", get(buildinfo_handler))
+        .route("/events", get(crate::events::handler))
+        .route("/usage", get(crate::quota::usage_handler))
+        .route("/cache/stats", get(cache_stats_handler))
+        .route("/cache/purge", post(cache_purge_handler))
+        .route("/ui", get(ui_handler))
+}
+
+pub(crate) fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(expected) = &state.admin_token else {
+        return Ok(());
+    };
+    let presented = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if presented.is_some_and(|presented| token_matches(presented, expected)) {
+        Ok(())
+    } else {
+        warn!("Rejected admin request with missing or invalid token");
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// Compares without leaking through timing how much of a guess matched: each token keys an
+/// HMAC of the same message and ring checks the tags in constant time.
+fn token_matches(presented: &str, expected: &str) -> bool {
+    use ring::hmac;
+    let tag = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, expected.as_bytes()),
+        b"admin",
+    );
+    let key = hmac::Key::new(hmac::HMAC_SHA256, presented.as_bytes());
+    hmac::verify(&key, b"admin", tag.as_ref()).is_ok()
+}
+
+fn describe(state: &AppState, i: usize) -> Value {
+    let b = &state.backends[i];
+    let routing = state.routing();
+    let patterns: Vec<&str> = routing
+        .routes
+        .iter()
+        .filter(|r| r.backend == i)
+        .filter_map(|r| r.metric.as_ref().map(|m| m.as_str()))
+        .collect();
+    let mut described = json!({
+        "name": b.name,
+        "patterns": patterns,
+        "url": b.url.as_str(),
+        "draining": b.is_draining(),
+        "latency_ms": b.estimates.latency().map(|l| l.as_secs_f64() * 1000.0),
+        "error_rate": b.estimates.error_rate(),
+    });
+    if let Some(endpoints) = &b.endpoints {
+        let instances: Vec<Value> = endpoints
+            .balancer
+            .instances()
+            .iter()
+            .map(|i| {
+                json!({
+                    "url": i.url.as_str(),
+                    "outstanding": i.outstanding(),
+                    "latency_ms": i.latency().as_secs_f64() * 1000.0,
+                    "error_rate": i.error_rate(),
+                })
+            })
+            .collect();
+        described["instances"] = instances.into();
+    }
+    described
+}
+
+/// `GET /admin/config`: the config the proxy is routing by, credentials redacted, its
+/// generation and the generations kept for rollback.
+async fn config_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    authorize(&state, &headers)?;
+    let current = state.routing();
+    Ok(Json(json!({
+        "generation": current.number,
+        "kept_generations": state.generations.kept(),
+        "config": current.config,
+    })))
+}
+
+fn generation_changed(result: Result<u64, String>) -> Response {
+    match result {
+        Ok(generation) => Json(json!({ "generation": generation })).into_response(),
+        Err(e) => (StatusCode::CONFLICT, Json(json!({ "error": e }))).into_response(),
+    }
+}
+
+/// `POST /admin/config/reload`: re-read the config file and switch to its routing table.
+async fn reload_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    authorize(&state, &headers)?;
+    Ok(generation_changed(
+        state.reload_from_file().map_err(|e| e.to_string()),
+    ))
+}
+
+#[derive(Deserialize)]
+struct RollbackParams {
+    generation: Option<u64>,
+}
+
+/// `POST /admin/config/rollback[?generation=N]`: route by generation N, or the one before the
+/// current generation, again.
+async fn rollback_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<RollbackParams>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    authorize(&state, &headers)?;
+    let result = state.generations.rollback(params.generation);
+    match &result {
+        Ok(generation) => {
+            warn!("Rolled routing back to config generation {}", generation);
+            state
+                .events
+                .publish("config_rolled_back", json!({ "generation": generation }));
+        }
+        Err(e) => warn!("Config rollback refused: {}", e),
+    }
+    Ok(generation_changed(result))
+}
+
+/// `GET /admin/loglevel`: the active log filter.
+async fn log_level_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    authorize(&state, &headers)?;
+    let control = state
+        .log_control
+        .as_ref()
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    Ok(Json(json!({ "filter": control.current() })))
+}
+
+#[derive(Deserialize)]
+struct LogLevelBody {
+    // EnvFilter directives, e.g. `debug` or `info,kairos_proxy::fanout=trace`
+    filter: String,
+    // Restore the previous filter after this many seconds
+    revert_after_secs: Option<u64>,
+}
+
+/// `PUT /admin/loglevel`: switch the log filter, optionally for a limited time.
+async fn set_log_level_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<LogLevelBody>,
+) -> Result<Response, StatusCode> {
+    authorize(&state, &headers)?;
+    let control = state
+        .log_control
+        .as_ref()
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let revert_after = body.revert_after_secs.map(Duration::from_secs);
+    Ok(match control.set(&body.filter, revert_after) {
+        Ok(previous) => Json(json!({
+            "filter": control.current(),
+            "previous": previous,
+            "revert_after_secs": body.revert_after_secs,
+        }))
+        .into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response(),
+    })
+}
+
+/// `GET /admin/backends`: every configured backend in routing order with its drain state.
+async fn list_backends_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    authorize(&state, &headers)?;
+    Ok(Json(Value::Array(
+        (0..state.backends.len())
+            .map(|i| describe(&state, i))
+            .collect(),
+    )))
+}
+
+/// Sets the drain flag on every backend named `name` (several routing rules may share a
+/// name) and returns the affected backends.
+fn set_draining(state: &AppState, name: &str, draining: bool) -> Result<Json<Value>, StatusCode> {
+    let matched: Vec<Value> = state
+        .backends
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| b.name == name)
+        .map(|(i, b)| {
+            b.set_draining(draining);
+            describe(state, i)
+        })
+        .collect();
+    if matched.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    info!(
+        "Backend '{}' {}",
+        name,
+        if draining { "drained" } else { "undrained" }
+    );
+    let kind = if draining {
+        "backend_drained"
+    } else {
+        "backend_undrained"
+    };
+    state.events.publish(kind, json!({ "backend": name }));
+    Ok(Json(Value::Array(matched)))
+}
+
+/// `POST /admin/backends/:name/drain`: stop sending new requests to the backend.
+async fn drain_handler(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    authorize(&state, &headers)?;
+    set_draining(&state, &name, true)
+}
+
+/// `POST /admin/backends/:name/undrain`: put a drained backend back into rotation.
+async fn undrain_handler(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    authorize(&state, &headers)?;
+    set_draining(&state, &name, false)
+}
+
+/// Lookups by result so far, as counted in `kairos_proxy_cache_requests_total`.
+fn cache_requests(state: &AppState) -> Map<String, Value> {
+    state
+        .metrics
+        .snapshot()
+        .into_iter()
+        .filter(|(name, _, _)| name == "kairos_proxy_cache_requests_total")
+        .filter_map(|(_, labels, value)| {
+            let (_, result) = labels.into_iter().find(|(k, _)| k == "result")?;
+            Some((result, json!(value as u64)))
+        })
+        .collect()
+}
+
+/// `GET /admin/cache/stats`: what the response cache holds and how lookups fared.
+async fn cache_stats_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    authorize(&state, &headers)?;
+    let Some(cache) = &state.cache else {
+        return Ok(Json(json!({ "enabled": false })));
+    };
+    let mut stats = cache.stats().await;
+    stats["enabled"] = json!(true);
+    stats["requests"] = Value::Object(cache_requests(&state));
+    Ok(Json(stats))
+}
+
+#[derive(Deserialize, Default)]
+struct PurgeBody {
+    // Only entries whose query names a metric matching this regex
+    pattern: Option<String>,
+}
+
+/// `POST /admin/cache/purge`: drop cached results, all of them without a body.
+async fn cache_purge_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Response, StatusCode> {
+    authorize(&state, &headers)?;
+    let Some(cache) = &state.cache else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let body: PurgeBody = if body.iter().all(u8::is_ascii_whitespace) {
+        PurgeBody::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(body) => body,
+            Err(e) => {
+                let error = format!("Invalid purge request: {}", e);
+                return Ok(
+                    (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response()
+                );
+            }
+        }
+    };
+    let pattern = match body
+        .pattern
+        .as_deref()
+        .map(|p| RegexLimits::default().build(p))
+        .transpose()
+    {
+        Ok(pattern) => pattern,
+        Err(e) => return Ok((StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response()),
+    };
+    let purged = cache.purge(pattern.as_ref()).await;
+    info!(
+        "Purged {} cached result(s){}",
+        purged,
+        body.pattern
+            .as_ref()
+            .map_or(String::new(), |p| format!(" for metrics matching '{}'", p))
+    );
+    state.events.publish(
+        "cache_purged",
+        json!({ "pattern": body.pattern, "purged": purged }),
+    );
+    Ok(Json(json!({ "purged": purged })).into_response())
+}
+
+/// Backends as in `/admin/backends` with their failures so far by reason, and the response
+/// cache's lookups by result. Counters are cumulative; the status page turns them into rates.
+fn status(state: &AppState) -> Value {
+    let mut failures: Map<String, Value> = Map::new();
+    let mut shed = 0.0;
+    for (name, labels, value) in state.metrics.snapshot() {
+        let label = |key: &str| {
+            labels
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+                .unwrap_or_default()
+        };
+        match name.as_str() {
+            "kairos_proxy_backend_failures_total" => {
+                let reasons = failures
+                    .entry(label("backend"))
+                    .or_insert_with(|| json!({}));
+                reasons[label("reason")] = json!(value as u64);
+            }
+            "kairos_proxy_shed_requests_total" => shed += value,
+            _ => {}
+        }
+    }
+    let backends: Vec<Value> = (0..state.backends.len())
+        .map(|i| {
+            let mut described = describe(state, i);
+            described["failures"] = failures
+                .get(&state.backends[i].name)
+                .cloned()
+                .unwrap_or_else(|| json!({}));
+            described
+        })
+        .collect();
+    json!({
+        "mode": format!("{:?}", state.mode),
+        "generation": state.routing().number,
+        "ready": state.is_ready(),
+        "backends": backends,
+        "cache": { "enabled": state.cache.is_some(), "requests": cache_requests(state) },
+        "shed_requests": shed as u64,
+    })
+}
+
+/// `GET /admin/status`: what the status page shows.
+async fn status_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    authorize(&state, &headers)?;
+    Ok(Json(status(&state)))
+}
+
+/// The running build, as embedded by `build.rs`.
+pub fn build_info() -> Value {
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_sha": env!("KAIROS_PROXY_GIT_SHA"),
+        "rustc_version": env!("KAIROS_PROXY_RUSTC_VERSION"),
+        "build_timestamp": env!("KAIROS_PROXY_BUILD_TIMESTAMP"),
+    })
+}
+
+/// `GET /admin/buildinfo`: which build this replica runs.
+async fn buildinfo_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    authorize(&state, &headers)?;
+    Ok(Json(build_info()))
+}
+
+/// `GET /admin/ui`: the status page.
+async fn ui_handler() -> Html<&'static str> {
+    Html(include_str!("admin_ui.html"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, Config};
+
+    fn state(admin_token: Option<&str>) -> Arc<AppState> {
+        let cfg = Config {
+            backends: vec![
+                Backend {
+                    name: Some("primary".to_string()),
+                    pattern: "^cpu\\..*".to_string(),
+                    url: "http://127.0.0.1:9001".to_string(),
+                    ..Default::default()
+                },
+                Backend {
+                    name: Some("fallback".to_string()),
+                    pattern: ".*".to_string(),
+                    url: "http://127.0.0.1:9002".to_string(),
+                    ..Default::default()
+                },
+            ],
+            admin_token: admin_token.map(str::to_string),
+            ..Default::default()
+        };
+        Arc::new(AppState::from_config(&cfg).expect("state"))
+    }
+
+    #[test]
+    fn draining_routes_to_fallback_then_errors() {
+        let state = state(None);
+        assert!(set_draining(&state, "primary", true).is_ok());
+        let (_, target) = state.backend_for("cpu.load").expect("fallback");
+        assert_eq!(target.name, "fallback");
+
+        assert!(set_draining(&state, "fallback", true).is_ok());
+        assert!(state.backend_for("cpu.load").is_none());
+        assert_eq!(
+            state.unroutable_status("cpu.load"),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        assert!(set_draining(&state, "primary", false).is_ok());
+        let (_, target) = state.backend_for("cpu.load").expect("primary");
+        assert_eq!(target.name, "primary");
+        assert_eq!(
+            set_draining(&state, "missing", true).unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn reload_and_rollback_swap_the_routing_table() {
+        let state = state(None);
+        let reloaded = Config {
+            backends: vec![
+                Backend {
+                    name: Some("primary".to_string()),
+                    pattern: "^mem\\..*".to_string(),
+                    url: "http://127.0.0.1:9001".to_string(),
+                    ..Default::default()
+                },
+                Backend {
+                    name: Some("fallback".to_string()),
+                    pattern: ".*".to_string(),
+                    url: "http://127.0.0.1:9002".to_string(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(state.reload(&reloaded).unwrap(), 2);
+        assert_eq!(state.backend_for("cpu.load").unwrap().1.name, "fallback");
+
+        assert_eq!(state.generations.rollback(None), Ok(1));
+        assert_eq!(state.backend_for("cpu.load").unwrap().1.name, "primary");
+
+        // Backends themselves only change on restart
+        let renamed = Config {
+            backends: vec![Backend {
+                name: Some("other".to_string()),
+                pattern: ".*".to_string(),
+                url: "http://127.0.0.1:9003".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(state.reload(&renamed).is_err());
+        assert_eq!(state.routing().number, 1);
+    }
+
+    #[test]
+    fn status_counts_failures_per_backend() {
+        use crate::upstream::{BackendError, Failure};
+        let state = state(None);
+        BackendError::new(&state, "primary", Failure::Timeout);
+        BackendError::new(&state, "primary", Failure::Timeout);
+        BackendError::new(&state, "primary", Failure::Unreachable);
+        state
+            .metrics
+            .inc_counter("kairos_proxy_cache_requests_total", &[("result", "hit")]);
+
+        let status = status(&state);
+        assert_eq!(status["ready"], true);
+        assert_eq!(status["backends"][0]["name"], "primary");
+        assert_eq!(
+            status["backends"][0]["failures"],
+            json!({ "timeout": 2, "unreachable": 1 })
+        );
+        assert_eq!(status["backends"][1]["failures"], json!({}));
+        assert_eq!(
+            status["cache"],
+            json!({ "enabled": false, "requests": { "hit": 1 } })
+        );
+    }
+
+    #[test]
+    fn admin_token_is_enforced_when_configured() {
+        let state = state(Some("s3cret"));
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            authorize(&state, &headers).unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+        headers.insert(
+            axum::http::header::AUTHORIZATION,
+            "Bearer s3cret".parse().unwrap(),
+        );
+        assert!(authorize(&state, &headers).is_ok());
+        for wrong in ["Bearer s3cre", "Bearer s3cretx", "Bearer S3cret", "s3cret"] {
+            headers.insert(axum::http::header::AUTHORIZATION, wrong.parse().unwrap());
+            assert_eq!(
+                authorize(&state, &headers).unwrap_err(),
+                StatusCode::UNAUTHORIZED
+            );
+        }
+    }
+
+    #[test]
+    fn build_info_names_the_build() {
+        let info = build_info();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert!(info["rustc_version"]
+            .as_str()
+            .unwrap()
+            .starts_with("rustc "));
+        assert!(!info["git_sha"].as_str().unwrap().is_empty());
+        let built = info["build_timestamp"].as_str().unwrap();
+        assert!(crate::grafana::rfc3339_to_millis(built).is_some_and(|ms| ms > 0));
+    }
+}
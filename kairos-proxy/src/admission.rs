@@ -0,0 +1,378 @@
+//! Admission of outbound requests with priority classes.
+//!
+//! Outbound requests share `max_outbound_concurrency` permits. Requests marked
+//! `X-Query-Priority: batch` queue behind every waiting interactive request and can be capped
+//! to `batch_concurrency` permits, so bulk exports never hold up dashboards when the proxy is
+//! saturated.
+//!
+//! With `[adaptive_concurrency]` the number of permits is not fixed: it shrinks by 10% (at most
+//! once per target latency) while backend latencies exceed the target and grows back by about
+//! one permit per round trip below it. Once too many requests queue, new queries are shed with
+//! `503 Service Unavailable` and `Retry-After` instead of waiting until they time out. The wait
+//! asked for is how long the queue should take to drain at the current limit and average
+//! latency, between `retry_after_secs` and a minute.
+
+use crate::config::AdaptiveConcurrency;
+use hyper::HeaderMap;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::debug;
+
+// Factor the limit shrinks by when latency exceeds the target
+const DECREASE: f64 = 0.9;
+// Weight of the newest latency in the average
+const LATENCY_WEIGHT: f64 = 0.2;
+// Longest Retry-After sent with shed queries
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+pub(crate) const PRIORITY_HEADER: &str = "x-query-priority";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    Interactive,
+    Batch,
+}
+
+impl Priority {
+    /// The class named by `X-Query-Priority`. Anything but `batch` is interactive.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        match headers.get(PRIORITY_HEADER).and_then(|v| v.to_str().ok()) {
+            Some(v) if v.trim().eq_ignore_ascii_case("batch") => Priority::Batch,
+            _ => Priority::Interactive,
+        }
+    }
+}
+
+/// Settings of the latency-driven limit.
+struct Adaptive {
+    target: Duration,
+    min: f64,
+    max_queue: usize,
+    retry_after: Duration,
+}
+
+struct Slots {
+    in_flight: usize,
+    // Current number of permits; fixed at `max` unless adaptive
+    limit: f64,
+    max: usize,
+    next_decrease: Instant,
+    // Moving average of backend latency; zero until observed
+    latency: Duration,
+    batch_in_flight: usize,
+    batch_limit: usize,
+    interactive: VecDeque<oneshot::Sender<Permit>>,
+    batch: VecDeque<oneshot::Sender<Permit>>,
+}
+
+impl Slots {
+    fn can_grant(&self, priority: Priority) -> bool {
+        (self.in_flight as f64) < self.limit.floor()
+            && match priority {
+                Priority::Interactive => true,
+                Priority::Batch => self.batch_in_flight < self.batch_limit,
+            }
+    }
+
+    fn grant(&mut self, priority: Priority) {
+        self.in_flight += 1;
+        if priority == Priority::Batch {
+            self.batch_in_flight += 1;
+        }
+    }
+
+    /// Takes a permit for the next live waiter, interactive ones first.
+    fn next_waiter(&mut self) -> Option<(oneshot::Sender<Permit>, Priority)> {
+        self.interactive.retain(|tx| !tx.is_closed());
+        self.batch.retain(|tx| !tx.is_closed());
+        let priority = if !self.interactive.is_empty() {
+            Priority::Interactive
+        } else if !self.batch.is_empty() {
+            Priority::Batch
+        } else {
+            return None;
+        };
+        if !self.can_grant(priority) {
+            return None;
+        }
+        self.grant(priority);
+        let queue = match priority {
+            Priority::Interactive => &mut self.interactive,
+            Priority::Batch => &mut self.batch,
+        };
+        queue.pop_front().map(|tx| (tx, priority))
+    }
+}
+
+pub struct Admission {
+    slots: Mutex<Slots>,
+    adaptive: Option<Adaptive>,
+}
+
+/// Permission for one outbound request; returned to the next waiter when dropped.
+pub struct Permit {
+    admission: Arc<Admission>,
+    priority: Priority,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.admission.release(self.priority);
+    }
+}
+
+impl Admission {
+    /// `permits` shared by all requests, of which batch requests may hold `batch_limit`. With
+    /// `adaptive`, the number of permits moves between `min_concurrency` and `permits`.
+    pub fn new(
+        permits: usize,
+        batch_limit: usize,
+        adaptive: Option<&AdaptiveConcurrency>,
+    ) -> Arc<Self> {
+        let permits = permits.max(1);
+        let adaptive = adaptive.map(|a| Adaptive {
+            target: Duration::from_millis(a.target_latency_ms),
+            min: a.min_concurrency.unwrap_or(1).clamp(1, permits) as f64,
+            max_queue: a.max_queue.unwrap_or(permits),
+            retry_after: Duration::from_secs(a.retry_after_secs.unwrap_or(1)),
+        });
+        Arc::new(Admission {
+            slots: Mutex::new(Slots {
+                in_flight: 0,
+                limit: permits as f64,
+                max: permits,
+                next_decrease: Instant::now(),
+                latency: Duration::ZERO,
+                batch_in_flight: 0,
+                batch_limit: batch_limit.min(permits),
+                interactive: VecDeque::new(),
+                batch: VecDeque::new(),
+            }),
+            adaptive,
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Slots> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn release(self: &Arc<Self>, priority: Priority) {
+        let mut slots = self.lock();
+        slots.in_flight -= 1;
+        if priority == Priority::Batch {
+            slots.batch_in_flight -= 1;
+        }
+        self.wake(slots);
+    }
+
+    /// Hands permits to waiters for as long as the limit allows.
+    fn wake(self: &Arc<Self>, mut slots: std::sync::MutexGuard<'_, Slots>) {
+        let mut next = Vec::new();
+        while let Some(waiter) = slots.next_waiter() {
+            next.push(waiter);
+        }
+        drop(slots);
+        for (tx, priority) in next {
+            // A waiter that gave up in the meantime drops the permit, releasing it again
+            let _ = tx.send(Permit {
+                admission: self.clone(),
+                priority,
+            });
+        }
+    }
+
+    /// Feeds the latency of a finished backend request to the adaptive limit.
+    pub fn observe(self: &Arc<Self>, elapsed: Duration) {
+        let Some(adaptive) = &self.adaptive else {
+            return;
+        };
+        let mut slots = self.lock();
+        slots.latency = if slots.latency.is_zero() {
+            elapsed
+        } else {
+            slots.latency.mul_f64(1.0 - LATENCY_WEIGHT) + elapsed.mul_f64(LATENCY_WEIGHT)
+        };
+        let before = slots.limit.floor();
+        if elapsed > adaptive.target {
+            let now = Instant::now();
+            if now < slots.next_decrease {
+                return;
+            }
+            slots.limit = (slots.limit * DECREASE).max(adaptive.min);
+            slots.next_decrease = now + adaptive.target;
+        } else {
+            slots.limit = (slots.limit + 1.0 / slots.limit).min(slots.max as f64);
+        }
+        if slots.limit.floor() != before {
+            debug!(
+                "Outbound concurrency limit now {} (latency {:?})",
+                slots.limit.floor(),
+                elapsed
+            );
+        }
+        self.wake(slots);
+    }
+
+    /// When adaptive and too many requests are queued: how long clients should wait before
+    /// retrying instead of queueing too.
+    pub fn shed(&self) -> Option<Duration> {
+        let adaptive = self.adaptive.as_ref()?;
+        let slots = self.lock();
+        let queued = slots.interactive.len() + slots.batch.len();
+        if queued < adaptive.max_queue {
+            return None;
+        }
+        let drain = slots
+            .latency
+            .mul_f64(queued as f64 / slots.limit.floor().max(1.0));
+        Some(drain.min(MAX_RETRY_AFTER).max(adaptive.retry_after))
+    }
+
+    /// Takes a permit if one is free and no request of equal or higher priority is waiting.
+    pub fn try_acquire(self: &Arc<Self>, priority: Priority) -> Option<Permit> {
+        let mut slots = self.lock();
+        let queued = match priority {
+            Priority::Interactive => !slots.interactive.is_empty(),
+            Priority::Batch => !slots.interactive.is_empty() || !slots.batch.is_empty(),
+        };
+        if queued || !slots.can_grant(priority) {
+            return None;
+        }
+        slots.grant(priority);
+        Some(Permit {
+            admission: self.clone(),
+            priority,
+        })
+    }
+
+    /// Waits for a permit. Interactive requests are served before any waiting batch request.
+    pub async fn acquire(self: &Arc<Self>, priority: Priority) -> Permit {
+        if let Some(permit) = self.try_acquire(priority) {
+            return permit;
+        }
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut slots = self.lock();
+            match priority {
+                Priority::Interactive => slots.interactive.push_back(tx),
+                Priority::Batch => slots.batch.push_back(tx),
+            }
+        }
+        // The sender is only dropped after handing over a permit
+        rx.await.expect("admission waiter dropped")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn interactive_requests_overtake_queued_batch_requests() {
+        let admission = Admission::new(1, 1, None);
+        let held = admission.acquire(Priority::Batch).await;
+        assert!(admission.try_acquire(Priority::Interactive).is_none());
+
+        let (order_tx, mut order) = tokio::sync::mpsc::unbounded_channel();
+        for priority in [Priority::Batch, Priority::Interactive] {
+            let admission = admission.clone();
+            let order_tx = order_tx.clone();
+            tokio::spawn(async move {
+                let _permit = admission.acquire(priority).await;
+                order_tx.send(priority).unwrap();
+            });
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        drop(held);
+        assert_eq!(order.recv().await, Some(Priority::Interactive));
+        assert_eq!(order.recv().await, Some(Priority::Batch));
+
+        // Batch requests never hold more than their share
+        let admission = Admission::new(2, 1, None);
+        let _batch = admission.acquire(Priority::Batch).await;
+        assert!(admission.try_acquire(Priority::Batch).is_none());
+        assert!(admission.try_acquire(Priority::Interactive).is_some());
+    }
+
+    fn limit(admission: &Admission) -> f64 {
+        admission.lock().limit.floor()
+    }
+
+    #[tokio::test]
+    async fn adaptive_limit_follows_latency_and_sheds_when_queue_is_full() {
+        let cfg = AdaptiveConcurrency {
+            target_latency_ms: 100,
+            min_concurrency: Some(2),
+            max_queue: Some(1),
+            ..Default::default()
+        };
+        let admission = Admission::new(10, 10, Some(&cfg));
+        admission.observe(Duration::from_millis(500));
+        assert_eq!(limit(&admission), 9.0);
+        // Decreases at most once per target latency
+        admission.observe(Duration::from_millis(500));
+        assert_eq!(limit(&admission), 9.0);
+        for _ in 0..10 {
+            admission.observe(Duration::from_millis(10));
+        }
+        assert_eq!(limit(&admission), 10.0);
+
+        let _held: Vec<_> = (0..10)
+            .map(|_| admission.try_acquire(Priority::Interactive).unwrap())
+            .collect();
+        assert_eq!(admission.shed(), None);
+        let waiter = admission.clone();
+        let queued = tokio::spawn(async move { waiter.acquire(Priority::Interactive).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(admission.shed(), Some(Duration::from_secs(1)));
+        queued.abort();
+    }
+
+    #[tokio::test]
+    async fn sheds_with_the_time_the_queue_takes_to_drain() {
+        let cfg = AdaptiveConcurrency {
+            // Never exceeded, so the limit stays put
+            target_latency_ms: 3_600_000,
+            max_queue: Some(2),
+            retry_after_secs: Some(2),
+            ..Default::default()
+        };
+        // What is asked of clients while 4 permits are held and 2 requests queue for them,
+        // backends having answered in `latency`
+        let shed = |latency: Duration| {
+            let admission = Admission::new(4, 4, Some(&cfg));
+            admission.observe(latency);
+            async move {
+                let _held: Vec<_> = (0..4)
+                    .map(|_| admission.try_acquire(Priority::Interactive).unwrap())
+                    .collect();
+                let queued: Vec<_> = (0..2)
+                    .map(|_| {
+                        let waiter = admission.clone();
+                        tokio::spawn(async move { waiter.acquire(Priority::Interactive).await })
+                    })
+                    .collect();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                let shed = admission.shed();
+                queued.iter().for_each(|q| q.abort());
+                shed
+            }
+        };
+
+        // Fast backends: the configured least wait
+        assert_eq!(
+            shed(Duration::from_millis(100)).await,
+            Some(Duration::from_secs(2))
+        );
+        // 2 queued for 4 permits each held 10s: half a round
+        assert_eq!(
+            shed(Duration::from_secs(10)).await,
+            Some(Duration::from_secs(5))
+        );
+        // Never more than a minute
+        assert_eq!(shed(Duration::from_secs(1000)).await, Some(MAX_RETRY_AFTER));
+    }
+}
@@ -0,0 +1,190 @@
+//! API keys identifying the clients of the data endpoints.
+//!
+//! Keys come from `[[api_keys]]` in the config and from `api_keys_file`, a TOML file of
+//! `[[api_keys]]` tables that can be mounted from a secret store apart from the config. Once
+//! any key is known, every endpoint but `/health`, `/metrics` and `/admin` needs one, sent as
+//! `X-Api-Key` or `Authorization: Bearer`; anything else gets `401`. The key is removed before
+//! the request goes further, and the [`Principal`] it belongs to (its `name`, `team` and
+//! free-form `attributes`) is added to the request's extensions. Quotas and tag scopes (see
+//! `quota`) are looked up by its name, and the request's log span records its name and team.
+
+use crate::config::{ApiKeyConfig, Config};
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::Body;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tracing::debug;
+
+/// Who a request's API key belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub name: String,
+    pub team: Option<String>,
+    pub attributes: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct KeysFile {
+    #[serde(default)]
+    api_keys: Vec<ApiKeyConfig>,
+}
+
+/// The keys of the config followed by those of its `api_keys_file`.
+pub fn key_configs(cfg: &Config) -> anyhow::Result<Vec<ApiKeyConfig>> {
+    let mut keys = cfg.api_keys.clone();
+    if let Some(path) = &cfg.api_keys_file {
+        let s = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Cannot read api_keys_file '{}': {}", path, e))?;
+        let file: KeysFile = toml::from_str(&s)
+            .map_err(|e| anyhow::anyhow!("Invalid api_keys_file '{}': {}", path, e))?;
+        keys.extend(file.api_keys);
+    }
+    Ok(keys)
+}
+
+pub struct ApiKeys {
+    // By secret
+    keys: HashMap<String, Arc<Principal>>,
+}
+
+impl ApiKeys {
+    /// `None` unless any keys are configured.
+    pub fn from_config(keys: &[ApiKeyConfig]) -> anyhow::Result<Option<Self>> {
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        let mut by_secret = HashMap::new();
+        for k in keys {
+            if k.key.is_empty() {
+                anyhow::bail!("API key '{}' has an empty key", k.name);
+            }
+            let principal = Principal {
+                name: k.name.clone(),
+                team: k.team.clone(),
+                attributes: k.attributes.clone().unwrap_or_default(),
+            };
+            if by_secret
+                .insert(k.key.clone(), Arc::new(principal))
+                .is_some()
+            {
+                anyhow::bail!("API key '{}' reuses another key's secret", k.name);
+            }
+        }
+        Ok(Some(ApiKeys { keys: by_secret }))
+    }
+
+    /// Whom the key a request presents belongs to. The key is removed so it does not reach
+    /// the backends.
+    pub fn authenticate(&self, headers: &mut HeaderMap) -> Option<Arc<Principal>> {
+        if let Some(secret) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+            let principal = self.keys.get(secret).cloned();
+            headers.remove("x-api-key");
+            return principal;
+        }
+        let secret = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))?;
+        let principal = self.keys.get(secret).cloned();
+        if principal.is_some() {
+            headers.remove(header::AUTHORIZATION);
+        }
+        principal
+    }
+}
+
+/// Refuses data requests without a valid API key and tags the others with its [`Principal`].
+pub async fn layer(
+    State(state): State<Arc<AppState>>,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(keys) = &state.api_keys else {
+        return next.run(req).await;
+    };
+    let path = req.uri().path();
+    // Capability probes and CORS preflights carry no credentials
+    if req.method() == axum::http::Method::OPTIONS
+        || path == "/health"
+        || path == "/metrics"
+        || path == "/admin"
+        || path.starts_with("/admin/")
+    {
+        return next.run(req).await;
+    }
+    let Some(principal) = keys.authenticate(req.headers_mut()) else {
+        debug!("Refused request without a valid API key");
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "a valid API key is required" })),
+        )
+            .into_response();
+    };
+    req.extensions_mut().insert(principal);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binds_keys_from_config_and_file_to_principals() {
+        let path =
+            std::env::temp_dir().join(format!("kairos-proxy-keys-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+[[api_keys]]
+name = "etl"
+key = "k2"
+team = "data"
+attributes = { tier = "batch" }
+"#,
+        )
+        .unwrap();
+        let cfg = Config {
+            api_keys: vec![ApiKeyConfig {
+                name: "dashboards".to_string(),
+                key: "k1".to_string(),
+                ..Default::default()
+            }],
+            api_keys_file: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let configs = key_configs(&cfg).expect("keys");
+        std::fs::remove_file(&path).unwrap();
+        let keys = ApiKeys::from_config(&configs).unwrap().expect("keys");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer k2".parse().unwrap());
+        let etl = keys.authenticate(&mut headers).expect("etl");
+        assert_eq!(etl.team.as_deref(), Some("data"));
+        assert_eq!(etl.attributes["tier"], "batch");
+        assert!(headers.get(header::AUTHORIZATION).is_none());
+
+        headers.insert("x-api-key", "k1".parse().unwrap());
+        assert_eq!(
+            keys.authenticate(&mut headers).expect("k1").name,
+            "dashboards"
+        );
+        assert!(headers.get("x-api-key").is_none());
+        headers.insert(header::AUTHORIZATION, "Bearer nope".parse().unwrap());
+        assert!(keys.authenticate(&mut headers).is_none());
+        // Not ours to strip
+        assert!(headers.get(header::AUTHORIZATION).is_some());
+
+        let reused = [configs[0].clone(), configs[0].clone()];
+        assert!(ApiKeys::from_config(&reused).is_err());
+        assert!(ApiKeys::from_config(&[]).unwrap().is_none());
+    }
+}
@@ -0,0 +1,200 @@
+//! Load balancing across the instances of one backend.
+//!
+//! Each instance keeps its number of outstanding requests and moving averages of its latency
+//! and error rate, which the `least_outstanding` and `power_of_two_choices` strategies choose
+//! by.
+
+use crate::config::LoadBalancing;
+use crate::estimate::Estimates;
+use reqwest::Url;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// How much more an instance failing every request costs than a healthy one
+const ERROR_PENALTY: f64 = 4.0;
+
+pub struct Instance {
+    pub url: Url,
+    outstanding: AtomicUsize,
+    estimates: Estimates,
+}
+
+impl Instance {
+    fn new(url: Url) -> Self {
+        Instance {
+            url,
+            outstanding: AtomicUsize::new(0),
+            estimates: Estimates::default(),
+        }
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    /// Average latency; zero until the first response.
+    pub fn latency(&self) -> Duration {
+        self.estimates.latency().unwrap_or_default()
+    }
+
+    /// Share of recent requests that failed.
+    pub fn error_rate(&self) -> f64 {
+        self.estimates.error_rate()
+    }
+
+    /// Expected cost of one more request: latency scaled by queue depth and recent errors.
+    /// Instances without a latency sample yet cost nothing, so they are tried early.
+    fn cost(&self) -> f64 {
+        self.latency().as_secs_f64()
+            * (self.outstanding() as f64 + 1.0)
+            * (1.0 + ERROR_PENALTY * self.error_rate())
+    }
+}
+
+/// A request in flight to an instance. Counts as outstanding until dropped.
+pub struct InstanceLoad(Arc<Instance>);
+
+impl InstanceLoad {
+    fn start(instance: &Arc<Instance>) -> Self {
+        instance.outstanding.fetch_add(1, Ordering::Relaxed);
+        InstanceLoad(instance.clone())
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.0.url
+    }
+
+    /// Folds the outcome of the finished request into the instance's averages.
+    pub fn observe(&self, success: bool, elapsed: Duration) {
+        self.0.estimates.observe(success, elapsed);
+    }
+}
+
+impl Drop for InstanceLoad {
+    fn drop(&mut self) {
+        self.0.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Balancer {
+    strategy: LoadBalancing,
+    instances: RwLock<Vec<Arc<Instance>>>,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub fn new(strategy: LoadBalancing) -> Self {
+        Balancer {
+            strategy,
+            instances: RwLock::new(Vec::new()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Current instances with their stats.
+    pub fn instances(&self) -> Vec<Arc<Instance>> {
+        self.instances
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Replaces the instance set, keeping the stats of instances that remain. Returns whether
+    /// the set changed.
+    pub fn set(&self, urls: Vec<Url>) -> bool {
+        let mut instances = self.instances.write().unwrap_or_else(|e| e.into_inner());
+        if instances.iter().map(|i| &i.url).eq(urls.iter()) {
+            return false;
+        }
+        *instances = urls
+            .into_iter()
+            .map(|url| {
+                instances
+                    .iter()
+                    .find(|i| i.url == url)
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(Instance::new(url)))
+            })
+            .collect();
+        true
+    }
+
+    /// Chooses an instance for one request. `None` when there are none.
+    pub fn pick(&self) -> Option<InstanceLoad> {
+        let instances = self.instances.read().unwrap_or_else(|e| e.into_inner());
+        let n = instances.len();
+        if n == 0 {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let chosen = match self.strategy {
+            LoadBalancing::RoundRobin => &instances[start % n],
+            // Scan from a rotating start so ties are spread out
+            LoadBalancing::LeastOutstanding => (0..n)
+                .map(|k| &instances[(start + k) % n])
+                .min_by_key(|i| i.outstanding())?,
+            LoadBalancing::PowerOfTwoChoices => {
+                let a = fastrand::usize(..n);
+                let b = (a + 1 + fastrand::usize(..n.max(2) - 1)) % n;
+                let (a, b) = (&instances[a], &instances[b]);
+                if b.cost() < a.cost() {
+                    b
+                } else {
+                    a
+                }
+            }
+        };
+        Some(InstanceLoad::start(chosen))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer(strategy: LoadBalancing) -> Balancer {
+        let b = Balancer::new(strategy);
+        b.set(
+            ["http://10.0.0.1:8080", "http://10.0.0.2:8080"]
+                .iter()
+                .map(|u| Url::parse(u).unwrap())
+                .collect(),
+        );
+        b
+    }
+
+    #[test]
+    fn strategies_prefer_idle_and_fast_instances() {
+        let rr = balancer(LoadBalancing::RoundRobin);
+        let first = rr.pick().unwrap().url().clone();
+        assert_ne!(rr.pick().unwrap().url(), &first);
+
+        let least = balancer(LoadBalancing::LeastOutstanding);
+        let busy = least.pick().unwrap();
+        for _ in 0..4 {
+            assert_ne!(least.pick().unwrap().url(), busy.url());
+        }
+        drop(busy);
+        assert!(least.instances().iter().all(|i| i.outstanding() == 0));
+
+        let p2c = balancer(LoadBalancing::PowerOfTwoChoices);
+        let slow = p2c.pick().unwrap();
+        slow.observe(true, Duration::from_millis(500));
+        let slow_url = slow.url().clone();
+        drop(slow);
+        let fast = p2c.pick().unwrap();
+        assert_ne!(fast.url(), &slow_url);
+        fast.observe(true, Duration::from_millis(5));
+        drop(fast);
+        for _ in 0..10 {
+            assert_ne!(p2c.pick().unwrap().url(), &slow_url);
+        }
+
+        // Stats survive an unchanged instance staying in the set
+        let urls = vec![slow_url.clone()];
+        assert!(p2c.set(urls.clone()));
+        assert!(!p2c.set(urls));
+        assert_eq!(p2c.instances()[0].latency(), Duration::from_millis(500));
+    }
+}
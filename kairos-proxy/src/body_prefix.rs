@@ -0,0 +1,316 @@
+//! Simple-mode routing from the start of a query body.
+//!
+//! With `stream_simple_queries = true`, a Simple-mode query is routed as soon as the leading
+//! bytes of its body name the first metric (or straight away with `X-METRICNAME`), and the body
+//! is then streamed to the backend as it arrives instead of being buffered whole. Anything that
+//! needs the whole body first turns this off for the request, which is then buffered as usual:
+//! a compressed body, query checks, API-key tag scopes or metric budgets, the recorder, dry
+//! runs, routing rules on tags or time tiers, and a backend that signs its requests or has a
+//! mirror. A body whose first `MAX_PREFIX_BYTES` do not name a metric is buffered too. The JSON
+//! limits do not apply to streamed bodies, which the proxy never parses;
+//! `max_request_body_bytes` still does, and a body exceeding it is cut off, failing the backend
+//! request.
+
+use crate::proxy::{forward_to_backend_simple, SimpleBody};
+use crate::routes::Subject;
+use crate::state::AppState;
+use crate::timerange::now_ms;
+use axum::{
+    body::{Body, HttpBody},
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt};
+
+// Leading bytes read in search of the first metric's name before buffering the body instead
+const MAX_PREFIX_BYTES: usize = 64 * 1024;
+// Nesting the scanner follows before giving up on the body
+const MAX_DEPTH: usize = 64;
+
+/// What the start of a body says about the first metric's name.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Scan {
+    Found(String),
+    /// The name may follow in bytes not read yet.
+    More,
+    /// The body is not a query naming a first metric; the full parse will say why.
+    Absent,
+}
+
+enum Stop {
+    More,
+    Absent,
+}
+
+struct Scanner<'a> {
+    buf: &'a [u8],
+    at: usize,
+}
+
+impl Scanner<'_> {
+    /// The next byte that is not whitespace, without consuming it.
+    fn peek(&mut self) -> Result<u8, Stop> {
+        while let Some(&b) = self.buf.get(self.at) {
+            if !b.is_ascii_whitespace() {
+                return Ok(b);
+            }
+            self.at += 1;
+        }
+        Err(Stop::More)
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), Stop> {
+        if self.peek()? != b {
+            return Err(Stop::Absent);
+        }
+        self.at += 1;
+        Ok(())
+    }
+
+    /// The string at the cursor, unescaped.
+    fn string(&mut self) -> Result<String, Stop> {
+        self.expect(b'"')?;
+        let start = self.at - 1;
+        let mut escaped = false;
+        while let Some(&b) = self.buf.get(self.at) {
+            self.at += 1;
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => {
+                    return serde_json::from_slice(&self.buf[start..self.at])
+                        .map_err(|_| Stop::Absent)
+                }
+                _ => {}
+            }
+        }
+        Err(Stop::More)
+    }
+
+    /// Consumes the value at the cursor.
+    fn skip(&mut self, depth: usize) -> Result<(), Stop> {
+        if depth > MAX_DEPTH {
+            return Err(Stop::Absent);
+        }
+        match self.peek()? {
+            b'"' => self.string().map(drop),
+            b'{' => {
+                self.at += 1;
+                self.members(|s, _| s.skip(depth + 1))
+            }
+            b'[' => {
+                self.at += 1;
+                if self.peek()? == b']' {
+                    self.at += 1;
+                    return Ok(());
+                }
+                loop {
+                    self.skip(depth + 1)?;
+                    match self.peek()? {
+                        b',' => self.at += 1,
+                        b']' => {
+                            self.at += 1;
+                            return Ok(());
+                        }
+                        _ => return Err(Stop::Absent),
+                    }
+                }
+            }
+            _ => {
+                // A number or literal ends at the next delimiter, which may not have arrived
+                while let Some(&b) = self.buf.get(self.at) {
+                    if b.is_ascii_whitespace() || matches!(b, b',' | b']' | b'}') {
+                        return Ok(());
+                    }
+                    self.at += 1;
+                }
+                Err(Stop::More)
+            }
+        }
+    }
+
+    /// Goes through the members of an object whose `{` was consumed, passing each key to
+    /// `value` to consume its value.
+    fn members(
+        &mut self,
+        mut value: impl FnMut(&mut Self, &str) -> Result<(), Stop>,
+    ) -> Result<(), Stop> {
+        if self.peek()? == b'}' {
+            self.at += 1;
+            return Ok(());
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(b':')?;
+            value(self, &key)?;
+            match self.peek()? {
+                b',' => self.at += 1,
+                b'}' => {
+                    self.at += 1;
+                    return Ok(());
+                }
+                _ => return Err(Stop::Absent),
+            }
+        }
+    }
+
+    fn first_metric_name(&mut self) -> Result<String, Stop> {
+        self.expect(b'{')?;
+        let mut found = None;
+        let scanned = self.members(|s, key| {
+            if key != "metrics" {
+                return s.skip(1);
+            }
+            s.expect(b'[')?;
+            s.expect(b'{')?;
+            s.members(|s, key| match key {
+                "name" => {
+                    found = Some(s.string()?);
+                    // Nothing after the name matters; stop the scan here
+                    Err(Stop::More)
+                }
+                _ => s.skip(3),
+            })?;
+            // The first metric has no name
+            Err(Stop::Absent)
+        });
+        match (found, scanned) {
+            (Some(name), _) => Ok(name),
+            (None, Err(stop)) => Err(stop),
+            (None, Ok(())) => Err(Stop::Absent),
+        }
+    }
+}
+
+/// Looks for `metrics[0].name` in the leading bytes of a query body.
+pub fn scan(buf: &[u8]) -> Scan {
+    let mut scanner = Scanner { buf, at: 0 };
+    match scanner.first_metric_name() {
+        Ok(name) => Scan::Found(name),
+        Err(Stop::More) => Scan::More,
+        Err(Stop::Absent) => Scan::Absent,
+    }
+}
+
+/// Whether the request can be routed and streamed without its whole body.
+fn streamable(state: &AppState, req: &Request<Body>) -> bool {
+    state.stream_simple_queries
+        && !req.headers().contains_key(header::CONTENT_ENCODING)
+        && !state.query_checks.enabled()
+        && !crate::quota::tag_scoped()
+        && !crate::quota::metric_budgeted()
+        && state.recorder.is_none()
+        && !crate::dry_run::requested(req.headers())
+        && state.routing().routes.iter().all(|r| r.by_name_only())
+}
+
+/// Reads the body up to the first metric's name (or `MAX_PREFIX_BYTES`), then puts what was
+/// read back in front of the rest.
+async fn read_prefix(req: &mut Request<Body>) -> Result<Scan, StatusCode> {
+    let mut body = std::mem::replace(req.body_mut(), Body::empty());
+    let mut prefix = BytesMut::new();
+    let mut scanned = Scan::More;
+    while scanned == Scan::More && prefix.len() < MAX_PREFIX_BYTES {
+        let Some(chunk) = body.data().await else {
+            break;
+        };
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        crate::memory::charge(chunk.len()).map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+        prefix.extend_from_slice(&chunk);
+        scanned = scan(&prefix);
+    }
+    let prefix = stream::once(async move { Ok::<_, hyper::Error>(prefix.freeze()) });
+    *req.body_mut() = Body::wrap_stream(prefix.chain(body));
+    Ok(scanned)
+}
+
+/// `body`, failing once it grows past `max` bytes.
+fn limited(body: Body, max: usize) -> Body {
+    let mut total = 0usize;
+    Body::wrap_stream(body.map(move |chunk| {
+        let chunk: Bytes = chunk.map_err(std::io::Error::other)?;
+        total = total.saturating_add(chunk.len());
+        if total > max {
+            return Err(std::io::Error::other("request body too large"));
+        }
+        Ok(chunk)
+    }))
+}
+
+/// Routes a Simple-mode request from the start of its body and streams it to the backend.
+/// Returns `None`, with the request as it was, when it needs buffering instead.
+pub async fn forward(
+    state: &AppState,
+    req: &mut Request<Body>,
+    endpoint: &str,
+) -> Result<Option<Response>, StatusCode> {
+    if !streamable(state, req) {
+        return Ok(None);
+    }
+    let from_header = req
+        .headers()
+        .get("x-metricname")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let name = match from_header {
+        Some(name) => name,
+        None => match read_prefix(req).await? {
+            Scan::Found(name) => name,
+            Scan::More | Scan::Absent => return Ok(None),
+        },
+    };
+    let subject = Subject {
+        metric: &name,
+        tags: None,
+        headers: Some(req.headers()),
+    };
+    let Some((_, target)) = state.select(&subject, now_ms()) else {
+        return Ok(Some(state.unroutable(&name).into_response()));
+    };
+    if target.signer.is_some() || target.mirror.is_some() {
+        return Ok(None);
+    }
+    let (max_body_bytes, _) = state.body_limits(req.uri().path());
+    let body = limited(
+        std::mem::replace(req.body_mut(), Body::empty()),
+        max_body_bytes,
+    );
+    // A streamed body is never counted, hence not for keys with metric budgets; it is one query
+    let response = forward_to_backend_simple(
+        state,
+        target,
+        SimpleBody::Streamed(body),
+        1,
+        req.headers(),
+        endpoint,
+    )
+    .await?;
+    Ok(Some(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_first_name_before_the_body_ends() {
+        let body = br#"{ "start_relative": {"value": 1, "unit": "hours"}, "cache_time": 0,
+            "metrics": [ { "tags": {"host": ["a", "b\"]"]}, "name": "cpu.load",
+            "aggregators": [] }, { "name": "mem" } ] }"#;
+        // Up to the quote closing the name, the name may still be coming
+        let end = body.windows(9).position(|w| w == b"cpu.load\"").unwrap() + 9;
+        for cut in 0..end {
+            assert_eq!(scan(&body[..cut]), Scan::More, "cut at {}", cut);
+        }
+        let found = Scan::Found("cpu.load".to_string());
+        assert_eq!(scan(&body[..end]), found);
+        assert_eq!(scan(body), found);
+
+        assert_eq!(scan(br#"{"metrics": [{"tags": {}}]}"#), Scan::Absent);
+        assert_eq!(scan(br#"{"metrics": []}"#), Scan::Absent);
+        assert_eq!(scan(br#"{"start_absolute": 1}"#), Scan::Absent);
+        assert_eq!(scan(b"[1, 2]"), Scan::Absent);
+        assert_eq!(scan(br#"{"metrics": [{"name": 7}]}"#), Scan::Absent);
+    }
+}
@@ -0,0 +1,499 @@
+//! Response cache for merged Multi-mode query results.
+//!
+//! Entries are fresh for `ttl`, then stale for a further `stale_ttl`: a stale entry is still
+//! served immediately, and the first caller to see it triggers a background refresh. Past
+//! both windows an entry is a miss.
+//!
+//! Storage sits behind [`CacheStore`]: an in-process LRU by default, or Redis so that several
+//! proxy replicas share one cache and it survives restarts.
+//!
+//! `GET /admin/cache/stats` shows the entries held, and `POST /admin/cache/purge` drops them
+//! all or, with `{"pattern": "<regex>"}`, those whose query names a matching metric, e.g.
+//! after backfilling data the cached results predate.
+
+use crate::config::CacheConfig;
+use crate::redis_conn::RedisConnection;
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// Outcome of a cache lookup.
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    Fresh(serde_json::Value),
+    /// Past its TTL but within the stale window. `refresh` is set for exactly one caller
+    /// (per proxy instance) until the entry is replaced or the refresh is abandoned.
+    Stale {
+        value: serde_json::Value,
+        refresh: bool,
+    },
+    Miss,
+}
+
+/// A cached result and when it was stored. Wall-clock time so ages stay meaningful across
+/// restarts and replicas.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CachedValue {
+    pub value: serde_json::Value,
+    pub stored_ms: u64,
+}
+
+/// Which keys to act on.
+pub type KeyFilter<'a> = dyn Fn(&str) -> bool + Send + Sync + 'a;
+
+/// Where cached results live. Stores only keep and expire entries; freshness is decided by
+/// [`ResponseCache`].
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    async fn get(&self, key: &str) -> Option<CachedValue>;
+    /// Stores `entry`, which may be dropped once `retain` has passed.
+    async fn put(&self, key: &str, entry: CachedValue, retain: Duration);
+    /// Entries held, expired ones included where the store cannot tell.
+    async fn len(&self) -> usize;
+    /// Drops the entries whose key `matches`; returns how many.
+    async fn purge(&self, matches: &KeyFilter<'_>) -> usize;
+}
+
+/// Metric names of the query a cache key was built from. Keys end with the query's JSON,
+/// after the endpoint and routing headers.
+fn metric_names(key: &str) -> Vec<String> {
+    let Some(query) = key
+        .match_indices('{')
+        .find_map(|(at, _)| serde_json::from_str::<serde_json::Value>(&key[at..]).ok())
+    else {
+        return Vec::new();
+    };
+    query["metrics"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|m| m["name"].as_str().map(str::to_string))
+        .collect()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+struct Entry {
+    cached: CachedValue,
+    expires_ms: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    // last_used tick -> key, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.order.remove(&entry.last_used);
+            entry.last_used = self.tick;
+            self.order.insert(self.tick, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.last_used);
+        }
+    }
+}
+
+/// In-process store evicting the least recently used entry once `max_entries` is reached.
+pub struct MemoryStore {
+    max_entries: usize,
+    inner: Mutex<Lru>,
+}
+
+impl MemoryStore {
+    pub fn new(max_entries: usize) -> Self {
+        MemoryStore {
+            max_entries: max_entries.max(1),
+            inner: Mutex::new(Lru::default()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryStore {
+    async fn get(&self, key: &str) -> Option<CachedValue> {
+        let mut lru = self.lock();
+        let expires_ms = lru.entries.get(key)?.expires_ms;
+        if expires_ms <= now_ms() {
+            lru.remove(key);
+            return None;
+        }
+        lru.touch(key);
+        lru.entries.get(key).map(|e| e.cached.clone())
+    }
+
+    async fn put(&self, key: &str, entry: CachedValue, retain: Duration) {
+        let mut lru = self.lock();
+        lru.remove(key);
+        while lru.entries.len() >= self.max_entries {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            lru.entries.remove(&oldest);
+        }
+        let expires_ms = entry.stored_ms + retain.as_millis() as u64;
+        lru.entries.insert(
+            key.to_string(),
+            Entry {
+                cached: entry,
+                expires_ms,
+                last_used: 0,
+            },
+        );
+        lru.touch(key);
+    }
+
+    async fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    async fn purge(&self, matches: &KeyFilter<'_>) -> usize {
+        let mut lru = self.lock();
+        let purged: Vec<String> = lru.entries.keys().filter(|k| matches(k)).cloned().collect();
+        for key in &purged {
+            lru.remove(key);
+        }
+        purged.len()
+    }
+}
+
+/// Redis store shared by every replica pointing at the same server. Entries are JSON values
+/// that Redis expires itself. Redis errors are logged and treated as misses so an
+/// unavailable cache never fails a query, and an unavailable Redis is skipped for a while
+/// (see `redis_conn`) rather than waited on by every query.
+pub struct RedisStore {
+    redis: RedisConnection,
+    prefix: String,
+}
+
+impl RedisStore {
+    pub fn new(url: &str, prefix: String) -> anyhow::Result<Self> {
+        let redis = RedisConnection::open("cache", url)
+            .map_err(|e| anyhow::anyhow!("Invalid cache redis_url '{}': {}", url, e))?;
+        Ok(RedisStore { redis, prefix })
+    }
+
+    /// Every key under the prefix, without it.
+    async fn keys(&self) -> Vec<String> {
+        let mut keys = Vec::new();
+        let mut cursor = 0u64;
+        loop {
+            let page: Option<(u64, Vec<String>)> = self
+                .redis
+                .query(
+                    "SCAN",
+                    redis::cmd("SCAN")
+                        .arg(cursor)
+                        .arg("MATCH")
+                        .arg(format!("{}*", self.prefix))
+                        .arg("COUNT")
+                        .arg(1000),
+                )
+                .await;
+            let Some((next, page)) = page else {
+                return keys;
+            };
+            keys.extend(
+                page.into_iter()
+                    .filter_map(|k| k.strip_prefix(&self.prefix).map(str::to_string)),
+            );
+            if next == 0 {
+                return keys;
+            }
+            cursor = next;
+        }
+    }
+}
+
+#[async_trait]
+impl CacheStore for RedisStore {
+    async fn get(&self, key: &str) -> Option<CachedValue> {
+        let raw: Option<Vec<u8>> = self
+            .redis
+            .query(
+                "GET",
+                redis::cmd("GET").arg(format!("{}{}", self.prefix, key)),
+            )
+            .await?;
+        serde_json::from_slice(&raw?).ok()
+    }
+
+    async fn put(&self, key: &str, entry: CachedValue, retain: Duration) {
+        let Ok(raw) = serde_json::to_vec(&entry) else {
+            return;
+        };
+        let _: Option<()> = self
+            .redis
+            .query(
+                "SET",
+                redis::cmd("SET")
+                    .arg(format!("{}{}", self.prefix, key))
+                    .arg(raw)
+                    .arg("PX")
+                    .arg(retain.as_millis().max(1) as u64),
+            )
+            .await;
+    }
+
+    async fn len(&self) -> usize {
+        self.keys().await.len()
+    }
+
+    async fn purge(&self, matches: &KeyFilter<'_>) -> usize {
+        let purged: Vec<String> = self
+            .keys()
+            .await
+            .into_iter()
+            .filter(|k| matches(k))
+            .map(|k| format!("{}{}", self.prefix, k))
+            .collect();
+        let mut deleted = 0;
+        for batch in purged.chunks(500) {
+            let n: Option<usize> = self.redis.query("DEL", redis::cmd("DEL").arg(batch)).await;
+            deleted += n.unwrap_or_default();
+        }
+        deleted
+    }
+}
+
+pub struct ResponseCache {
+    ttl: Duration,
+    stale_ttl: Duration,
+    // "memory" or "redis"
+    store_kind: &'static str,
+    store: Box<dyn CacheStore>,
+    // Keys with a background refresh in flight on this instance
+    refreshing: Mutex<HashSet<String>>,
+}
+
+impl ResponseCache {
+    pub fn from_config(cfg: &CacheConfig) -> anyhow::Result<Self> {
+        let store: (&'static str, Box<dyn CacheStore>) = match &cfg.redis_url {
+            Some(url) => {
+                info!("Response cache stored in redis");
+                let prefix = cfg
+                    .redis_key_prefix
+                    .clone()
+                    .unwrap_or_else(|| "kairos-proxy:".to_string());
+                ("redis", Box::new(RedisStore::new(url, prefix)?))
+            }
+            None => (
+                "memory",
+                Box::new(MemoryStore::new(cfg.max_entries.unwrap_or(1000))),
+            ),
+        };
+        let mut cache = Self::with_store(
+            Duration::from_secs(cfg.ttl_secs.unwrap_or(30)),
+            Duration::from_secs(cfg.stale_ttl_secs.unwrap_or(0)),
+            store.1,
+        );
+        cache.store_kind = store.0;
+        Ok(cache)
+    }
+
+    pub fn with_store(ttl: Duration, stale_ttl: Duration, store: Box<dyn CacheStore>) -> Self {
+        ResponseCache {
+            ttl,
+            stale_ttl,
+            store_kind: "custom",
+            store,
+            refreshing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn refreshing(&self) -> std::sync::MutexGuard<'_, HashSet<String>> {
+        self.refreshing.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub async fn get(&self, key: &str) -> Lookup {
+        let Some(cached) = self.store.get(key).await else {
+            return Lookup::Miss;
+        };
+        let age = Duration::from_millis(now_ms().saturating_sub(cached.stored_ms));
+        if age < self.ttl {
+            Lookup::Fresh(cached.value)
+        } else if age < self.ttl + self.stale_ttl {
+            let refresh = self.refreshing().insert(key.to_string());
+            Lookup::Stale {
+                value: cached.value,
+                refresh,
+            }
+        } else {
+            Lookup::Miss
+        }
+    }
+
+    pub async fn insert(&self, key: String, value: serde_json::Value) {
+        let entry = CachedValue {
+            value,
+            stored_ms: now_ms(),
+        };
+        self.store.put(&key, entry, self.ttl + self.stale_ttl).await;
+        self.refreshing().remove(&key);
+    }
+
+    /// Lets the next stale hit on `key` try again after a failed background refresh.
+    pub fn refresh_failed(&self, key: &str) {
+        self.refreshing().remove(key);
+    }
+
+    /// The store, entry count and lifetimes, for `/admin/cache/stats`.
+    pub async fn stats(&self) -> serde_json::Value {
+        serde_json::json!({
+            "store": self.store_kind,
+            "entries": self.store.len().await,
+            "ttl_secs": self.ttl.as_secs(),
+            "stale_ttl_secs": self.stale_ttl.as_secs(),
+        })
+    }
+
+    /// Drops every entry, or those for queries naming a metric `pattern` matches; returns how
+    /// many were dropped.
+    pub async fn purge(&self, pattern: Option<&Regex>) -> usize {
+        match pattern {
+            None => self.store.purge(&|_| true).await,
+            Some(pattern) => {
+                self.store
+                    .purge(&|key| metric_names(key).iter().any(|n| pattern.is_match(n)))
+                    .await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Instant;
+
+    fn cache(ttl_secs: u64, stale_ttl_secs: u64, max_entries: usize) -> ResponseCache {
+        ResponseCache::from_config(&CacheConfig {
+            ttl_secs: Some(ttl_secs),
+            stale_ttl_secs: Some(stale_ttl_secs),
+            max_entries: Some(max_entries),
+            ..Default::default()
+        })
+        .expect("cache")
+    }
+
+    #[tokio::test]
+    async fn serves_stale_entries_and_hands_out_one_refresh() {
+        let c = cache(0, 60, 10);
+        assert_eq!(c.get("q").await, Lookup::Miss);
+        c.insert("q".to_string(), json!(1)).await;
+        assert_eq!(
+            c.get("q").await,
+            Lookup::Stale {
+                value: json!(1),
+                refresh: true
+            }
+        );
+        assert_eq!(
+            c.get("q").await,
+            Lookup::Stale {
+                value: json!(1),
+                refresh: false
+            }
+        );
+        c.refresh_failed("q");
+        assert!(matches!(
+            c.get("q").await,
+            Lookup::Stale { refresh: true, .. }
+        ));
+
+        let fresh = cache(60, 0, 10);
+        fresh.insert("q".to_string(), json!(2)).await;
+        assert_eq!(fresh.get("q").await, Lookup::Fresh(json!(2)));
+
+        let expired = cache(0, 0, 10);
+        expired.insert("q".to_string(), json!(3)).await;
+        assert_eq!(expired.get("q").await, Lookup::Miss);
+    }
+
+    #[tokio::test]
+    async fn memory_store_evicts_least_recently_used() {
+        let store = MemoryStore::new(2);
+        let entry = |v: &str| CachedValue {
+            value: json!(v),
+            stored_ms: now_ms(),
+        };
+        let retain = Duration::from_secs(60);
+        store.put("a", entry("a"), retain).await;
+        store.put("b", entry("b"), retain).await;
+        assert!(store.get("a").await.is_some());
+        store.put("c", entry("c"), retain).await;
+        assert!(store.get("b").await.is_none());
+        assert!(store.get("a").await.is_some());
+        assert!(store.get("c").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn purges_entries_by_metric_name() {
+        let c = cache(60, 0, 10);
+        let key =
+            |query: serde_json::Value| format!("/api/v1/datapoints/query x-team=a{{b }}{}", query);
+        let cpu = key(json!({ "metrics": [{ "name": "cpu.load" }, { "name": "mem.free" }] }));
+        let disk = key(json!({ "metrics": [{ "name": "disk.used" }] }));
+        c.insert(cpu.clone(), json!(1)).await;
+        c.insert(disk.clone(), json!(2)).await;
+        assert_eq!(c.stats().await["entries"], 2);
+
+        assert_eq!(c.purge(Some(&Regex::new("^cpu\\.").unwrap())).await, 1);
+        assert_eq!(c.get(&cpu).await, Lookup::Miss);
+        assert_eq!(c.get(&disk).await, Lookup::Fresh(json!(2)));
+        assert_eq!(c.purge(None).await, 1);
+        assert_eq!(c.stats().await["entries"], 0);
+    }
+
+    #[tokio::test]
+    async fn an_unresponsive_redis_is_a_quick_miss() {
+        let url = crate::redis_conn::unresponsive_redis().await;
+        let store = RedisStore::new(&url, "kp:".to_string()).unwrap();
+        assert_eq!(store.get("q").await, None);
+        // Skipped without waiting while backing off
+        let started = Instant::now();
+        assert_eq!(store.get("q").await, None);
+        let entry = CachedValue {
+            value: json!(1),
+            stored_ms: 0,
+        };
+        store.put("q", entry, Duration::from_secs(1)).await;
+        assert_eq!(store.len().await, 0);
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn rejects_invalid_redis_url() {
+        let err = ResponseCache::from_config(&CacheConfig {
+            redis_url: Some("not a url".to_string()),
+            ..Default::default()
+        })
+        .err()
+        .expect("invalid url");
+        assert!(err.to_string().contains("redis_url"));
+    }
+}
@@ -0,0 +1,193 @@
+//! Fault injection for rehearsing partial failures.
+//!
+//! With `[chaos]` and `enabled = true`, queries sent to the listed backends can be slowed down,
+//! answered with an error status without reaching the backend, or have their answer cut off
+//! halfway, each for a configured share of requests. Dashboards and alerts can then be checked
+//! against degraded clusters in staging. The injected faults take the same paths as real ones:
+//! an injected delay longer than the timeout times out, a truncated answer is an invalid one.
+
+use crate::config::{ChaosConfig, FaultConfig};
+use crate::state::{AppState, BackendTarget};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Backend name whose faults apply to backends not listed.
+const ANY_BACKEND: &str = "*";
+
+#[derive(Debug, Clone, PartialEq)]
+struct Fault {
+    latency: Duration,
+    error_percent: f64,
+    error_status: reqwest::StatusCode,
+    truncate_percent: f64,
+}
+
+impl Fault {
+    fn from_config(backend: &str, cfg: &FaultConfig) -> anyhow::Result<Self> {
+        let percent = |key: &str, value: Option<f64>| {
+            let value = value.unwrap_or(0.0);
+            anyhow::ensure!(
+                (0.0..=100.0).contains(&value),
+                "chaos.backends.{}.{} must be between 0 and 100",
+                backend,
+                key
+            );
+            Ok(value)
+        };
+        let status = cfg.error_status.unwrap_or(500);
+        let error_status = reqwest::StatusCode::from_u16(status)
+            .ok()
+            .filter(|s| s.is_client_error() || s.is_server_error())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "chaos.backends.{}.error_status {} is not an error status",
+                    backend,
+                    status
+                )
+            })?;
+        Ok(Fault {
+            latency: Duration::from_millis(cfg.latency_ms.unwrap_or(0)),
+            error_percent: percent("error_percent", cfg.error_percent)?,
+            error_status,
+            truncate_percent: percent("truncate_percent", cfg.truncate_percent)?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Chaos {
+    faults: BTreeMap<String, Fault>,
+}
+
+impl Chaos {
+    pub fn from_config(cfg: &Option<ChaosConfig>) -> anyhow::Result<Self> {
+        let Some(cfg) = cfg.as_ref().filter(|c| c.enabled) else {
+            return Ok(Chaos::default());
+        };
+        let faults = cfg
+            .backends
+            .iter()
+            .map(|(name, f)| Ok((name.clone(), Fault::from_config(name, f)?)))
+            .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
+        if !faults.is_empty() {
+            warn!(
+                "Chaos mode is enabled: injecting faults into requests to {}",
+                faults.keys().cloned().collect::<Vec<_>>().join(", ")
+            );
+        }
+        Ok(Chaos { faults })
+    }
+
+    fn fault(&self, backend: &str) -> Option<&Fault> {
+        self.faults
+            .get(backend)
+            .or_else(|| self.faults.get(ANY_BACKEND))
+    }
+}
+
+fn roll(percent: f64) -> bool {
+    percent > 0.0 && fastrand::f64() * 100.0 < percent
+}
+
+/// Sends a request to `target`, which has `timeout` to answer, with the faults configured
+/// for it.
+pub async fn send(
+    state: &AppState,
+    target: &BackendTarget,
+    mut builder: reqwest::RequestBuilder,
+    timeout: Duration,
+) -> reqwest::Result<reqwest::Response> {
+    let backend = target.name.as_str();
+    let Some(fault) = state.chaos.fault(backend) else {
+        return target.client.send(&state.metrics, backend, builder).await;
+    };
+    let count = |kind: &str| {
+        debug!(backend, "Chaos: injecting {}", kind);
+        state.metrics.inc_counter(
+            "kairos_proxy_chaos_faults_total",
+            &[("backend", backend), ("fault", kind)],
+        );
+    };
+    if !fault.latency.is_zero() {
+        count("latency");
+        let delay = fault.latency.min(timeout);
+        tokio::time::sleep(delay).await;
+        // Whatever the delay left of the timeout; none makes the request time out for real
+        builder = builder.timeout(timeout.saturating_sub(delay).max(Duration::from_millis(1)));
+    }
+    if roll(fault.error_percent) {
+        count("error");
+        let answer = hyper::Response::builder()
+            .status(fault.error_status)
+            .body("error injected by chaos mode")
+            .expect("static response");
+        return Ok(answer.into());
+    }
+    let response = target.client.send(&state.metrics, backend, builder).await?;
+    if !roll(fault.truncate_percent) {
+        return Ok(response);
+    }
+    count("truncate");
+    let status = response.status();
+    let mut headers = response.headers().clone();
+    headers.remove(reqwest::header::CONTENT_LENGTH);
+    let body = response.bytes().await?;
+    let mut answer = hyper::Response::builder()
+        .status(status)
+        .body(body.slice(..body.len() / 2))
+        .expect("static response");
+    *answer.headers_mut() = headers;
+    Ok(answer.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faults_apply_only_when_enabled() {
+        let mut cfg = ChaosConfig {
+            enabled: false,
+            backends: BTreeMap::from([
+                (
+                    "eu".to_string(),
+                    FaultConfig {
+                        latency_ms: Some(250),
+                        error_percent: Some(10.0),
+                        error_status: Some(503),
+                        truncate_percent: None,
+                    },
+                ),
+                (
+                    "*".to_string(),
+                    FaultConfig {
+                        truncate_percent: Some(5.0),
+                        ..Default::default()
+                    },
+                ),
+            ]),
+        };
+        let off = Chaos::from_config(&Some(cfg.clone())).expect("chaos");
+        assert!(off.fault("eu").is_none());
+
+        cfg.enabled = true;
+        let chaos = Chaos::from_config(&Some(cfg.clone())).expect("chaos");
+        let eu = chaos.fault("eu").expect("eu fault");
+        assert_eq!(eu.latency, Duration::from_millis(250));
+        assert_eq!(eu.error_status, reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(eu.truncate_percent, 0.0);
+        assert_eq!(
+            chaos.fault("us").expect("default fault").truncate_percent,
+            5.0
+        );
+
+        cfg.backends.get_mut("eu").unwrap().error_percent = Some(150.0);
+        assert!(Chaos::from_config(&Some(cfg.clone())).is_err());
+        cfg.backends.get_mut("eu").unwrap().error_percent = None;
+        cfg.backends.get_mut("eu").unwrap().error_status = Some(200);
+        assert!(Chaos::from_config(&Some(cfg)).is_err());
+        assert!(!roll(0.0));
+        assert!(roll(100.0));
+    }
+}
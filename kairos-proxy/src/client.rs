@@ -0,0 +1,548 @@
+//! Outbound HTTP clients. Each backend gets its own so that proxy and TLS settings can differ
+//! between backends; clients are rebuilt in place when their connections must be dropped.
+//!
+//! A backend has two clients on separate pools: one asking for gzip and decompressing answers,
+//! for the answers the proxy reads, and a pass-through one that leaves `Accept-Encoding` to the
+//! caller and hands compressed answers on as they are, for Simple mode's streamed responses.
+//!
+//! Queries and writes sent through `BackendClient::send` are tracked per connection (its local
+//! and remote address), since the pool itself reports nothing: a connection is pooled from its
+//! first answer until it has been unused for the pool's idle timeout. `/metrics` shows
+//! `kairos_proxy_backend_connections{backend,state}`, `active` being requests waiting on their
+//! answer and `idle` the other pooled connections, with `kairos_proxy_backend_connections_opened_total`
+//! counting new ones. `kairos_proxy_backend_response_headers_seconds{backend,connection}`
+//! times answers on `new` and `reused` connections; the gap between the two is what setting
+//! up a connection costs.
+
+use crate::config::Backend;
+use crate::metrics::Metrics;
+use hyper::client::connect::HttpInfo;
+use reqwest::{Certificate, Client, Proxy, Url};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// reqwest closes connections idle for this long unless told otherwise
+const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Settings a backend's client is built from.
+#[derive(Clone, Debug, Default)]
+pub struct ClientOptions {
+    pub timeout: Duration,
+    pub connect_timeout: Option<Duration>,
+    // Idle connections are closed after this long
+    pub pool_idle_timeout: Option<Duration>,
+    // Idle connections kept per host
+    pub pool_max_idle: Option<usize>,
+    // Explicit proxy for every request; overrides HTTP(S)_PROXY
+    pub proxy_url: Option<String>,
+    // Ignore HTTP(S)_PROXY and connect directly
+    pub no_proxy: bool,
+    // PEM bundle of extra trusted roots; re-read on every rebuild so rotated CAs are picked up
+    pub ca_cert_path: Option<String>,
+    pub insecure_skip_verify: bool,
+    // TLS name requested URLs use instead of the configured host, and the host:port it
+    // actually connects to; see `BackendClient::pin`
+    pub sni: Option<(String, String, u16)>,
+}
+
+impl ClientOptions {
+    /// Options for backend `b` at `url`, its configured URL. Pooled connections are closed
+    /// after `pool_idle_timeout` at the latest, whatever the backend asks for.
+    pub fn for_backend(
+        b: &Backend,
+        url: &Url,
+        timeout: Duration,
+        pool_idle_timeout: Option<Duration>,
+    ) -> Self {
+        let sni = b.sni_hostname.as_ref().and_then(|name| {
+            let host = url.host_str()?.trim_matches(|c| c == '[' || c == ']');
+            Some((name.clone(), host.to_string(), url.port_or_known_default()?))
+        });
+        let own_idle_timeout = b.pool_idle_timeout_secs.map(Duration::from_secs);
+        ClientOptions {
+            timeout,
+            connect_timeout: b.connect_timeout_secs.map(Duration::from_secs),
+            pool_idle_timeout: match (own_idle_timeout, pool_idle_timeout) {
+                (Some(own), Some(max)) => Some(own.min(max)),
+                (own, max) => own.or(max),
+            },
+            pool_max_idle: b.pool_max_idle_per_host,
+            proxy_url: b.proxy_url.clone(),
+            no_proxy: b.no_proxy.unwrap_or(false),
+            ca_cert_path: b.ca_cert_path.clone(),
+            insecure_skip_verify: b.insecure_skip_verify.unwrap_or(false),
+            sni,
+        }
+    }
+
+    /// Current addresses of the host the SNI name connects to; none without one.
+    async fn pinned_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        let Some((_, host, port)) = &self.sni else {
+            return Ok(Vec::new());
+        };
+        let addrs = tokio::net::lookup_host((host.as_str(), *port))
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to resolve backend host '{}': {}", host, e))?;
+        Ok(addrs.collect())
+    }
+
+    fn build(&self, decompress: bool, pinned: &[SocketAddr]) -> anyhow::Result<Client> {
+        let mut builder = Client::builder().timeout(self.timeout).gzip(decompress);
+        if let Some(connect) = self.connect_timeout {
+            builder = builder.connect_timeout(connect);
+        }
+        if let Some(idle) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(idle);
+        }
+        if let Some(max) = self.pool_max_idle {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        // Without either, reqwest honours HTTP_PROXY / HTTPS_PROXY / NO_PROXY from the environment
+        if self.no_proxy {
+            builder = builder.no_proxy();
+        } else if let Some(proxy) = &self.proxy_url {
+            let proxy = Proxy::all(proxy)
+                .map_err(|e| anyhow::anyhow!("Invalid proxy_url '{}': {}", proxy, e))?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = &self.ca_cert_path {
+            let pem = std::fs::read(path)
+                .map_err(|e| anyhow::anyhow!("Failed to read ca_cert_path '{}': {}", path, e))?;
+            for cert in Certificate::from_pem_bundle(&pem)
+                .map_err(|e| anyhow::anyhow!("Invalid certificate in '{}': {}", path, e))?
+            {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if self.insecure_skip_verify {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        // Requests address the SNI name; pin it to the configured host's addresses
+        if let (Some((name, _, _)), false) = (&self.sni, pinned.is_empty()) {
+            builder = builder.resolve_to_addrs(name, pinned);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// What the pool is doing, as far as the answers it delivered tell.
+#[derive(Default)]
+struct PoolStats {
+    in_flight: AtomicUsize,
+    // Local and remote address of each connection -> when it last delivered an answer
+    connections: Mutex<HashMap<(SocketAddr, SocketAddr), Instant>>,
+}
+
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct BackendClient {
+    options: ClientOptions,
+    client: RwLock<Client>,
+    passthrough: RwLock<Client>,
+    stats: PoolStats,
+}
+
+impl BackendClient {
+    pub fn new(options: ClientOptions) -> anyhow::Result<Self> {
+        Ok(BackendClient {
+            client: RwLock::new(options.build(true, &[])?),
+            passthrough: RwLock::new(options.build(false, &[])?),
+            options,
+            stats: PoolStats::default(),
+        })
+    }
+
+    /// Sends a request built from this client, tracking the connection it goes over.
+    pub async fn send(
+        &self,
+        metrics: &Metrics,
+        backend: &str,
+        builder: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        self.stats.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _in_flight = InFlight(&self.stats.in_flight);
+        let started = Instant::now();
+        let response = builder.send().await?;
+        if let Some(info) = response.extensions().get::<HttpInfo>() {
+            let now = Instant::now();
+            let mut connections = self
+                .stats
+                .connections
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            let new = connections
+                .insert((info.local_addr(), info.remote_addr()), now)
+                .is_none();
+            drop(connections);
+            if new {
+                metrics.inc_counter(
+                    "kairos_proxy_backend_connections_opened_total",
+                    &[("backend", backend)],
+                );
+            }
+            metrics.observe_duration(
+                "kairos_proxy_backend_response_headers_seconds",
+                &[
+                    ("backend", backend),
+                    ("connection", if new { "new" } else { "reused" }),
+                ],
+                now - started,
+            );
+        }
+        Ok(response)
+    }
+
+    /// Active and idle connections in the pool, forgetting those it has closed by now.
+    pub fn connections(&self) -> (usize, usize) {
+        let idle_timeout = self
+            .options
+            .pool_idle_timeout
+            .unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT);
+        let mut connections = self
+            .stats
+            .connections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        connections.retain(|_, used| used.elapsed() < idle_timeout);
+        let active = self.stats.in_flight.load(Ordering::Relaxed);
+        (active, connections.len().saturating_sub(active))
+    }
+
+    /// The current client, which decompresses gzip answers. Cheap to clone; clones share one
+    /// connection pool.
+    pub fn get(&self) -> Client {
+        self.client
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// The current client leaving answers encoded as the backend sent them.
+    pub fn passthrough(&self) -> Client {
+        self.passthrough
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Resolves the configured host and pins the SNI name to its addresses. Clients start
+    /// unpinned, so this must run before a backend with `sni_hostname` is used; it does
+    /// nothing for the others.
+    pub async fn pin(&self) -> anyhow::Result<()> {
+        if self.options.sni.is_none() {
+            return Ok(());
+        }
+        self.rebuild().await
+    }
+
+    /// Swaps in clients with empty connection pools, the SNI name pinned to the configured
+    /// host's current addresses. Requests in flight finish on the old ones.
+    pub async fn rebuild(&self) -> anyhow::Result<()> {
+        let pinned = self.options.pinned_addrs().await?;
+        let client = self.options.build(true, &pinned)?;
+        let passthrough = self.options.build(false, &pinned)?;
+        *self.client.write().unwrap_or_else(|e| e.into_inner()) = client;
+        *self.passthrough.write().unwrap_or_else(|e| e.into_inner()) = passthrough;
+        self.stats
+            .connections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Router};
+
+    #[tokio::test]
+    async fn explicit_proxy_receives_backend_requests() {
+        // A forward proxy sees the absolute target URL; answer for any path
+        let app = Router::new().fallback(get(|uri: axum::http::Uri| async move {
+            format!("proxied {}", uri)
+        }));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        let backend = Backend {
+            proxy_url: Some(format!("http://{}", addr)),
+            ..Default::default()
+        };
+        let url = Url::parse("http://kairosdb.internal:8080").unwrap();
+        let client = BackendClient::new(ClientOptions::for_backend(
+            &backend,
+            &url,
+            Duration::from_secs(5),
+            None,
+        ))
+        .expect("client");
+        let body = client
+            .get()
+            .get("http://kairosdb.internal:8080/api/v1/version")
+            .send()
+            .await
+            .expect("response")
+            .text()
+            .await
+            .expect("body");
+        assert_eq!(body, "proxied http://kairosdb.internal:8080/api/v1/version");
+
+        let invalid = Backend {
+            proxy_url: Some("not a url".to_string()),
+            ..Default::default()
+        };
+        assert!(BackendClient::new(ClientOptions::for_backend(
+            &invalid,
+            &url,
+            Duration::ZERO,
+            None
+        ))
+        .is_err());
+
+        let pooled = Backend {
+            pool_max_idle_per_host: Some(4),
+            pool_idle_timeout_secs: Some(600),
+            connect_timeout_secs: Some(2),
+            ..Default::default()
+        };
+        let options = ClientOptions::for_backend(
+            &pooled,
+            &url,
+            Duration::from_secs(5),
+            Some(Duration::from_secs(60)),
+        );
+        assert_eq!(options.pool_max_idle, Some(4));
+        assert_eq!(options.connect_timeout, Some(Duration::from_secs(2)));
+        // DNS refresh still closes connections at its own interval
+        assert_eq!(options.pool_idle_timeout, Some(Duration::from_secs(60)));
+        let options = ClientOptions::for_backend(&pooled, &url, Duration::from_secs(5), None);
+        assert_eq!(options.pool_idle_timeout, Some(Duration::from_secs(600)));
+        BackendClient::new(options).expect("pooled client");
+    }
+
+    #[tokio::test]
+    async fn sni_hostname_is_pinned_to_the_configured_address() {
+        // Plain HTTP keeps the test free of certificates; the name pinning is the same
+        let app = Router::new().fallback(get(|headers: axum::http::HeaderMap| async move {
+            headers["host"].to_str().unwrap().to_string()
+        }));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        let backend = Backend {
+            sni_hostname: Some("kairosdb.internal".to_string()),
+            insecure_skip_verify: Some(true),
+            ..Default::default()
+        };
+        let url = Url::parse(&format!("http://{}", addr)).unwrap();
+        let options = ClientOptions::for_backend(&backend, &url, Duration::from_secs(5), None);
+        let client = BackendClient::new(options).expect("client");
+        client.pin().await.expect("pin");
+        let host = client
+            .get()
+            .get(format!("http://kairosdb.internal:{}/", addr.port()))
+            .send()
+            .await
+            .expect("response")
+            .text()
+            .await
+            .expect("body");
+        assert_eq!(host, format!("kairosdb.internal:{}", addr.port()));
+
+        let missing_ca = Backend {
+            ca_cert_path: Some("/nonexistent/ca.pem".to_string()),
+            ..Default::default()
+        };
+        let err = BackendClient::new(ClientOptions::for_backend(
+            &missing_ca,
+            &url,
+            Duration::ZERO,
+            None,
+        ))
+        .err()
+        .expect("error");
+        assert!(err.to_string().contains("ca_cert_path"));
+    }
+
+    #[tokio::test]
+    async fn decompresses_or_passes_gzip_answers_through() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        // Answers gzip to clients asking for it
+        let app = Router::new().fallback(get(|headers: axum::http::HeaderMap| async move {
+            let gzip = headers
+                .get("accept-encoding")
+                .is_some_and(|v| v.to_str().unwrap().contains("gzip"));
+            if !gzip {
+                return axum::response::IntoResponse::into_response("plain");
+            }
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(b"plain").unwrap();
+            axum::response::IntoResponse::into_response((
+                [("content-encoding", "gzip")],
+                encoder.finish().unwrap(),
+            ))
+        }));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let options =
+            ClientOptions::for_backend(&Backend::default(), &url, Duration::from_secs(5), None);
+        let client = BackendClient::new(options).expect("client");
+        let decoded = client
+            .get()
+            .get(url.clone())
+            .send()
+            .await
+            .expect("response");
+        assert!(!decoded.headers().contains_key("content-encoding"));
+        assert_eq!(decoded.text().await.unwrap(), "plain");
+
+        let plain = client.passthrough().get(url.clone()).send().await.unwrap();
+        assert_eq!(plain.text().await.unwrap(), "plain");
+        let encoded = client
+            .passthrough()
+            .get(url)
+            .header("accept-encoding", "gzip")
+            .send()
+            .await
+            .expect("response");
+        assert_eq!(encoded.headers()["content-encoding"], "gzip");
+        let body = encoded.bytes().await.unwrap();
+        assert_eq!(&body[..2], [0x1f, 0x8b]);
+    }
+
+    #[tokio::test]
+    async fn counts_pooled_connections_from_their_answers() {
+        let app = Router::new().fallback(get(|| async { "ok" }));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        let url = Url::parse(&format!("http://{}", addr)).unwrap();
+        let client = BackendClient::new(ClientOptions::for_backend(
+            &Backend::default(),
+            &url,
+            Duration::from_secs(5),
+            None,
+        ))
+        .expect("client");
+        let metrics = Metrics::default();
+        for _ in 0..3 {
+            let answer = client
+                .send(&metrics, "local", client.get().get(url.clone()))
+                .await
+                .expect("response");
+            answer.bytes().await.expect("body");
+        }
+        assert_eq!(
+            metrics.counter(
+                "kairos_proxy_backend_connections_opened_total",
+                &[("backend", "local")]
+            ),
+            1
+        );
+        assert_eq!(client.connections(), (0, 1));
+        client.rebuild().await.expect("rebuild");
+        assert_eq!(client.connections(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn pools_by_each_backends_own_settings_or_the_defaults() {
+        let app = Router::new().fallback(get(|| async { "ok" }));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+        let url = Url::parse(&format!("http://{}", addr)).unwrap();
+
+        // Connections each of `backends` opens for two requests `pause` apart
+        let opened = |backends: Vec<Backend>, pause: Duration| {
+            let url = url.clone();
+            async move {
+                let clients: Vec<_> = backends
+                    .iter()
+                    .map(|b| {
+                        let options =
+                            ClientOptions::for_backend(b, &url, Duration::from_secs(5), None);
+                        BackendClient::new(options).expect("client")
+                    })
+                    .collect();
+                let metrics = Metrics::default();
+                for round in 0..2 {
+                    if round > 0 {
+                        tokio::time::sleep(pause).await;
+                    }
+                    for (i, client) in clients.iter().enumerate() {
+                        let name = i.to_string();
+                        let answer = client
+                            .send(&metrics, &name, client.get().get(url.clone()))
+                            .await
+                            .expect("response");
+                        answer.bytes().await.expect("body");
+                    }
+                }
+                let counts: Vec<_> = (0..clients.len())
+                    .map(|i| {
+                        metrics.counter(
+                            "kairos_proxy_backend_connections_opened_total",
+                            &[("backend", &i.to_string())],
+                        )
+                    })
+                    .collect();
+                (counts, clients)
+            }
+        };
+
+        let unpooled = Backend {
+            pool_max_idle_per_host: Some(0),
+            ..Default::default()
+        };
+        let (counts, _) = opened(vec![unpooled, Backend::default()], Duration::ZERO).await;
+        assert_eq!(counts, [2, 1]);
+
+        let short_lived = Backend {
+            pool_idle_timeout_secs: Some(1),
+            ..Default::default()
+        };
+        let (counts, clients) = opened(
+            vec![short_lived, Backend::default()],
+            Duration::from_millis(1500),
+        )
+        .await;
+        assert_eq!(counts, [2, 1]);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(clients[0].connections(), (0, 0));
+        assert_eq!(clients[1].connections(), (0, 1));
+    }
+}
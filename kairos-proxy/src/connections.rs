@@ -0,0 +1,260 @@
+//! Limits on the connections the listener keeps open.
+//!
+//! With `max_connections`, a connection arriving while that many are open is closed as soon as
+//! it is accepted, before anything is read from it; `max_connections_per_ip` does the same per
+//! client address (the peer's, not `X-Forwarded-For`). Refused connections are counted in
+//! `kairos_proxy_connections_rejected_total{reason}` (`total` or `per_ip`) and open ones in the
+//! `kairos_proxy_open_connections` gauge. Without limits connections are only counted.
+
+use crate::config::Config;
+use crate::metrics::Metrics;
+use hyper::server::conn::AddrStream;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tower::Service;
+use tracing::debug;
+
+#[derive(Debug)]
+struct Open {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+pub struct ConnectionLimits {
+    max: Option<usize>,
+    max_per_ip: Option<usize>,
+    open: Mutex<Open>,
+    metrics: Arc<Metrics>,
+}
+
+/// Why a connection was refused.
+#[derive(Debug)]
+pub struct Rejected(&'static str);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connection limit reached ({})", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Holds a connection's place until the connection closes.
+pub struct Slot {
+    limits: Arc<ConnectionLimits>,
+    ip: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock().unwrap_or_else(|e| e.into_inner());
+        open.total -= 1;
+        if let Some(n) = open.per_ip.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                open.per_ip.remove(&self.ip);
+            }
+        }
+        self.limits.report(open.total);
+    }
+}
+
+impl ConnectionLimits {
+    pub fn from_config(cfg: &Config, metrics: Arc<Metrics>) -> anyhow::Result<Arc<Self>> {
+        anyhow::ensure!(
+            cfg.max_connections != Some(0) && cfg.max_connections_per_ip != Some(0),
+            "max_connections and max_connections_per_ip must be at least 1"
+        );
+        Ok(Arc::new(ConnectionLimits {
+            max: cfg.max_connections,
+            max_per_ip: cfg.max_connections_per_ip,
+            open: Mutex::new(Open {
+                total: 0,
+                per_ip: HashMap::new(),
+            }),
+            metrics,
+        }))
+    }
+
+    /// A place for a connection from `ip`, unless a limit is reached.
+    fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<Slot, Rejected> {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        let from_ip = open.per_ip.get(&ip).copied().unwrap_or(0);
+        let refused = if self.max.is_some_and(|max| open.total >= max) {
+            Some("total")
+        } else if self.max_per_ip.is_some_and(|max| from_ip >= max) {
+            Some("per_ip")
+        } else {
+            None
+        };
+        if let Some(reason) = refused {
+            drop(open);
+            debug!("Refusing connection from {}: {} limit reached", ip, reason);
+            self.metrics.inc_counter(
+                "kairos_proxy_connections_rejected_total",
+                &[("reason", reason)],
+            );
+            return Err(Rejected(reason));
+        }
+        open.total += 1;
+        *open.per_ip.entry(ip).or_default() += 1;
+        self.report(open.total);
+        Ok(Slot {
+            limits: self.clone(),
+            ip,
+        })
+    }
+
+    fn report(&self, total: usize) {
+        self.metrics
+            .set_gauge("kairos_proxy_open_connections", &[], total as f64);
+    }
+
+    /// `make_service`, refusing connections beyond the limits.
+    pub fn wrap<M>(self: &Arc<Self>, make_service: M) -> Limited<M> {
+        Limited {
+            limits: self.clone(),
+            inner: make_service,
+        }
+    }
+}
+
+/// A make-service that only makes services for connections within the limits; hyper closes
+/// the others unread.
+#[derive(Clone)]
+pub struct Limited<M> {
+    limits: Arc<ConnectionLimits>,
+    inner: M,
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+impl<'a, M> Service<&'a AddrStream> for Limited<M>
+where
+    M: Service<&'a AddrStream>,
+    M::Future: Unpin,
+    M::Error: Into<BoxError>,
+{
+    type Response = Counted<M::Response>;
+    type Error = BoxError;
+    type Future = Admitting<M::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, conn: &'a AddrStream) -> Self::Future {
+        match self.limits.admit(conn.remote_addr().ip()) {
+            Ok(slot) => Admitting::Admitted(self.inner.call(conn), Some(slot)),
+            Err(rejected) => Admitting::Refused(Some(rejected)),
+        }
+    }
+}
+
+/// The service for a new connection, or why it was refused.
+pub enum Admitting<F> {
+    Admitted(F, Option<Slot>),
+    Refused(Option<Rejected>),
+}
+
+impl<F, S, E> Future for Admitting<F>
+where
+    F: Future<Output = Result<S, E>> + Unpin,
+    E: Into<BoxError>,
+{
+    type Output = Result<Counted<S>, BoxError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.get_mut() {
+            Admitting::Admitted(service, slot) => {
+                let service = std::task::ready!(Pin::new(service).poll(cx)).map_err(Into::into)?;
+                let slot = slot.take().expect("polled after completion");
+                Poll::Ready(Ok(Counted {
+                    inner: service,
+                    _slot: Arc::new(slot),
+                }))
+            }
+            Admitting::Refused(rejected) => Poll::Ready(Err(rejected
+                .take()
+                .expect("polled after completion")
+                .into())),
+        }
+    }
+}
+
+/// A connection's service, releasing its slot when the connection closes.
+#[derive(Clone)]
+pub struct Counted<S> {
+    inner: S,
+    _slot: Arc<Slot>,
+}
+
+impl<S, R> Service<R> for Counted<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_connections_beyond_the_limits() {
+        let cfg = Config {
+            max_connections: Some(3),
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::default());
+        let limits = ConnectionLimits::from_config(&cfg, metrics.clone()).expect("limits");
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let first = limits.admit(a).expect("first");
+        let _second = limits.admit(a).expect("second");
+        assert!(limits.admit(a).is_err());
+        let _third = limits.admit(b).expect("third");
+        assert!(limits.admit(b).is_err());
+        assert_eq!(
+            metrics.counter(
+                "kairos_proxy_connections_rejected_total",
+                &[("reason", "per_ip")]
+            ),
+            1
+        );
+        assert_eq!(
+            metrics.counter(
+                "kairos_proxy_connections_rejected_total",
+                &[("reason", "total")]
+            ),
+            1
+        );
+
+        drop(first);
+        limits
+            .admit(a)
+            .expect("a place freed by a closed connection");
+
+        let zero = Config {
+            max_connections: Some(0),
+            ..Default::default()
+        };
+        assert!(ConnectionLimits::from_config(&zero, metrics).is_err());
+    }
+}
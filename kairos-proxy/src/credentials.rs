@@ -0,0 +1,216 @@
+//! Bearer tokens presented to backends.
+//!
+//! A backend's token is either fixed in the config, obtained from an OAuth2 token endpoint
+//! with the client-credentials grant, or read from Vault (see `vault`). Obtained tokens are
+//! refreshed in the background before they expire, so long-running proxies never fall back
+//! to an expired token.
+
+use crate::config::{OAuth2Config, VaultConfig, VaultSecret};
+use crate::state::AppState;
+use crate::vault;
+use reqwest::Client;
+use serde::Deserialize;
+use std::sync::RwLock;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+// Refresh once this share of a token's lifetime has passed
+const REFRESH_AT: f64 = 0.8;
+const MIN_REFRESH: Duration = Duration::from_secs(5);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a backend's token comes from.
+#[derive(Clone, Default)]
+pub enum Source {
+    #[default]
+    Fixed,
+    OAuth2(OAuth2Config),
+    Vault(VaultSecret),
+}
+
+/// The current bearer token of a backend.
+#[derive(Default)]
+pub struct Token {
+    current: RwLock<Option<String>>,
+    source: Source,
+}
+
+impl Token {
+    pub fn fixed(token: Option<String>) -> Self {
+        Token {
+            current: RwLock::new(token),
+            source: Source::Fixed,
+        }
+    }
+
+    /// A token kept current by a background task; empty until it first succeeds.
+    pub fn managed(source: Source) -> Self {
+        Token {
+            current: RwLock::new(None),
+            source,
+        }
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn set(&self, token: String) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Some(token);
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+/// Requests a token and returns how long it is valid for, if the server said.
+async fn fetch(client: &Client, cfg: &OAuth2Config) -> anyhow::Result<(String, Option<Duration>)> {
+    let mut form = vec![
+        ("grant_type", "client_credentials"),
+        ("client_id", cfg.client_id.as_str()),
+        ("client_secret", cfg.client_secret.as_str()),
+    ];
+    if let Some(scope) = &cfg.scope {
+        form.push(("scope", scope));
+    }
+    if let Some(audience) = &cfg.audience {
+        form.push(("audience", audience));
+    }
+    let resp: TokenResponse = client
+        .post(&cfg.token_url)
+        .timeout(REQUEST_TIMEOUT)
+        .form(&form)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok((resp.access_token, resp.expires_in.map(Duration::from_secs)))
+}
+
+/// When to refresh a token valid for `expires_in`. Tokens without an expiry are still
+/// refreshed hourly.
+fn refresh_after(expires_in: Option<Duration>) -> Duration {
+    expires_in
+        .map_or(Duration::from_secs(3600), |d| d.mul_f64(REFRESH_AT))
+        .max(MIN_REFRESH)
+}
+
+/// Obtains a token for `token` and keeps refreshing it. Failures retry with backoff while
+/// the previous token, if any, stays in use.
+async fn maintain(name: String, token: &Token, cfg: &OAuth2Config) {
+    let client = Client::new();
+    let mut retry = MIN_REFRESH;
+    loop {
+        let wait = match fetch(&client, cfg).await {
+            Ok((access_token, expires_in)) => {
+                debug!("Obtained OAuth2 token for backend '{}'", name);
+                token.set(access_token);
+                retry = MIN_REFRESH;
+                refresh_after(expires_in)
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to obtain OAuth2 token for backend '{}': {}",
+                    name, e
+                );
+                let wait = retry;
+                retry = (retry * 2).min(MAX_RETRY);
+                wait
+            }
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Starts token management for every backend using OAuth2 or Vault.
+pub fn spawn_all(state: &std::sync::Arc<AppState>, vault: &VaultConfig) -> anyhow::Result<()> {
+    for (i, target) in state.backends.iter().enumerate() {
+        let name = target.name.clone();
+        let state = state.clone();
+        match target.token.source.clone() {
+            Source::Fixed => {}
+            Source::OAuth2(cfg) => {
+                info!(
+                    "Managing OAuth2 token for backend '{}' via {}",
+                    name, cfg.token_url
+                );
+                tokio::spawn(async move { maintain(name, &state.backends[i].token, &cfg).await });
+            }
+            Source::Vault(secret) => {
+                let vault = vault::Vault::new(vault)?;
+                info!(
+                    "Reading token for backend '{}' from Vault {}",
+                    name, secret.path
+                );
+                tokio::spawn(async move {
+                    vault
+                        .maintain(name, &state.backends[i].token, &secret)
+                        .await
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Form, Json, Router};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn obtains_client_credentials_tokens() {
+        let counter = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/token",
+            post(move |Form(form): Form<HashMap<String, String>>| {
+                let counter = counter.clone();
+                async move {
+                    assert_eq!(form["grant_type"], "client_credentials");
+                    assert_eq!(form["client_id"], "proxy");
+                    assert_eq!(form["scope"], "kairos.read");
+                    let n = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    Json(json!({ "access_token": format!("token-{}", n), "expires_in": 1 }))
+                }
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        let cfg = OAuth2Config {
+            token_url: format!("http://{}/token", addr),
+            client_id: "proxy".to_string(),
+            client_secret: "secret".to_string(),
+            scope: Some("kairos.read".to_string()),
+            ..Default::default()
+        };
+        let token = std::sync::Arc::new(Token::managed(Source::OAuth2(cfg.clone())));
+        assert_eq!(token.get(), None);
+        let managed = token.clone();
+        let task = tokio::spawn(async move { maintain("b".to_string(), &managed, &cfg).await });
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(token.get().as_deref(), Some("token-0"));
+        task.abort();
+
+        assert_eq!(
+            refresh_after(Some(Duration::from_secs(100))),
+            Duration::from_secs(80)
+        );
+        assert_eq!(refresh_after(Some(Duration::from_secs(1))), MIN_REFRESH);
+    }
+}
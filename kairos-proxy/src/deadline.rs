@@ -0,0 +1,125 @@
+//! Client deadline propagation.
+//!
+//! Clients may announce how long they are willing to wait with `X-Request-Timeout-Ms`
+//! (milliseconds) or `Request-Timeout` (seconds, fractions allowed). Outbound backend
+//! requests are then capped to whatever is left of that budget, so the proxy stops
+//! querying backends for a client that has already given up. The budget is read once, when
+//! the request arrives, so retries and later backend requests share what is left of it.
+
+use axum::{http::Request, middleware::Next, response::Response};
+use hyper::{Body, HeaderMap};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+pub(crate) const TIMEOUT_MS_HEADER: &str = "x-request-timeout-ms";
+pub(crate) const REQUEST_TIMEOUT_HEADER: &str = "request-timeout";
+
+/// Point in time after which the client no longer cares about the answer.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline(Option<Instant>);
+
+tokio::task_local! {
+    static REQUEST: Deadline;
+}
+
+/// Runs `f` with `deadline` as the deadline of the current request.
+pub(crate) fn scope<F: Future>(deadline: Deadline, f: F) -> impl Future<Output = F::Output> {
+    REQUEST.scope(deadline, f)
+}
+
+/// Starts the clock on each request's deadline as it arrives.
+pub async fn layer(req: Request<Body>, next: Next<Body>) -> Response {
+    let deadline = Deadline::from_headers(req.headers());
+    scope(deadline, next.run(req)).await
+}
+
+impl Deadline {
+    /// The deadline of the request being served; none outside of one, e.g. in background
+    /// tasks.
+    pub fn current() -> Self {
+        REQUEST.try_with(|d| *d).unwrap_or(Deadline(None))
+    }
+
+    /// Reads the client's budget from `headers`, starting the clock now. Malformed values
+    /// are ignored. `X-Request-Timeout-Ms` wins when both headers are present.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let budget = header(TIMEOUT_MS_HEADER)
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_millis)
+            .or_else(|| {
+                header(REQUEST_TIMEOUT_HEADER)
+                    .and_then(|v| v.trim().parse::<f64>().ok())
+                    .and_then(|s| Duration::try_from_secs_f64(s).ok())
+            });
+        Deadline(budget.map(|b| Instant::now() + b))
+    }
+
+    /// Time left before the deadline, or `None` if the client did not set one.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.0
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Timeout for an outbound request: `configured` capped to the remaining budget.
+    /// Returns `None` once the deadline has passed.
+    pub fn outbound_timeout(&self, configured: Duration) -> Option<Duration> {
+        match self.remaining() {
+            Some(left) if left.is_zero() => None,
+            Some(left) => Some(left.min(configured)),
+            None => Some(configured),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        for (k, v) in pairs {
+            h.insert(*k, v.parse().unwrap());
+        }
+        h
+    }
+
+    #[test]
+    fn caps_configured_timeout_to_client_budget() {
+        let configured = Duration::from_secs(5);
+        let none = Deadline::from_headers(&headers(&[]));
+        assert_eq!(none.outbound_timeout(configured), Some(configured));
+
+        let ms = Deadline::from_headers(&headers(&[
+            ("X-Request-Timeout-Ms", "200"),
+            ("Request-Timeout", "60"),
+        ]));
+        assert!(ms.outbound_timeout(configured).unwrap() <= Duration::from_millis(200));
+
+        let secs = Deadline::from_headers(&headers(&[("Request-Timeout", "1.5")]));
+        let t = secs.outbound_timeout(configured).unwrap();
+        assert!(t > Duration::from_secs(1) && t <= Duration::from_millis(1500));
+
+        let long = Deadline::from_headers(&headers(&[("Request-Timeout", "600")]));
+        assert_eq!(long.outbound_timeout(configured), Some(configured));
+
+        let expired = Deadline::from_headers(&headers(&[("X-Request-Timeout-Ms", "0")]));
+        assert_eq!(expired.outbound_timeout(configured), None);
+
+        let garbage = Deadline::from_headers(&headers(&[("Request-Timeout", "-3")]));
+        assert_eq!(garbage.outbound_timeout(configured), Some(configured));
+    }
+
+    #[tokio::test]
+    async fn is_read_once_per_request() {
+        assert!(Deadline::current().remaining().is_none());
+        let deadline = Deadline::from_headers(&headers(&[("X-Request-Timeout-Ms", "100")]));
+        scope(deadline, async {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            // The clock started when the request arrived, not when the budget is asked for
+            let left = Deadline::current().remaining().unwrap();
+            assert!(left <= Duration::from_millis(40));
+        })
+        .await;
+    }
+}
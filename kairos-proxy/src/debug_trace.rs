@@ -0,0 +1,276 @@
+//! Per-request routing diagnostics.
+//!
+//! A request carrying `X-Proxy-Debug: 1` is traced while it is served: routing records the
+//! rule each metric matched, outbound requests their backend, latency and outcome, and the
+//! response cache whether it answered. The trace comes back as response headers, each a
+//! JSON value, so users can see where their data was looked for without access to the logs:
+//!
+//! - `X-Proxy-Debug-Routes`: `[{"metric", "backend", "rule", "pattern"}, ...]`
+//! - `X-Proxy-Debug-Backends`: `[{"backend", "latency_ms", "status"}, ...]`
+//! - `X-Proxy-Debug-Cache`: `"hit"`, `"stale"`, `"miss"` or `"coalesced"`, when consulted
+//!
+//! Every response that involved backends also gets a `Server-Timing` header with one
+//! `backend` entry per outbound request and the time spent merging, e.g.
+//! `backend;desc="eu";dur=12.4, backend;desc="us";dur=30.1, merge;dur=0.8`, which browser
+//! devtools and Grafana's query inspector display.
+
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const DEBUG_HEADER: &str = "x-proxy-debug";
+
+tokio::task_local! {
+    static TRACE: Arc<Mutex<Trace>>;
+}
+
+#[derive(Default)]
+struct Trace {
+    routes: Vec<Value>,
+    backends: Vec<Value>,
+    cache: Option<&'static str>,
+    // `Server-Timing` entries
+    timings: Vec<String>,
+}
+
+/// Middleware tracing every request, for `Server-Timing` and, when asked for and enabled by
+/// `debug_header`, the debug headers.
+pub async fn layer<B>(
+    State(state): State<Arc<AppState>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let requested = req
+        .headers()
+        .get(DEBUG_HEADER)
+        .is_some_and(|v| v.as_bytes() == b"1" || v.as_bytes().eq_ignore_ascii_case(b"true"));
+    let trace = Arc::new(Mutex::new(Trace::default()));
+    let mut response = TRACE.scope(trace.clone(), next.run(req)).await;
+    let trace = trace.lock().unwrap_or_else(|e| e.into_inner());
+    let headers = response.headers_mut();
+    if state.server_timing && !trace.timings.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&trace.timings.join(", ")) {
+            headers.append("server-timing", value);
+        }
+    }
+    if !state.debug_header || !requested {
+        return response;
+    }
+    for (name, value) in [
+        (
+            "x-proxy-debug-routes",
+            Some(Value::from(trace.routes.clone())),
+        ),
+        (
+            "x-proxy-debug-backends",
+            Some(Value::from(trace.backends.clone())),
+        ),
+        ("x-proxy-debug-cache", trace.cache.map(Value::from)),
+    ] {
+        if let Some(value) = value.and_then(|v| header_value(&v)) {
+            headers.insert(name, value);
+        }
+    }
+    response
+}
+
+/// Records on the current request's trace, if it is being traced.
+fn record(f: impl FnOnce(&mut Trace)) {
+    let _ = TRACE.try_with(|trace| f(&mut trace.lock().unwrap_or_else(|e| e.into_inner())));
+}
+
+/// `metric` was routed to `backend` by the rule at `position` with metric `pattern`.
+pub fn route(metric: &str, backend: &str, position: usize, pattern: Option<&str>) {
+    record(|t| {
+        t.routes.push(json!({
+            "metric": metric,
+            "backend": backend,
+            "rule": position,
+            "pattern": pattern,
+        }))
+    });
+}
+
+/// A request to `backend` finished after `latency` with HTTP `status`, or failed without a
+/// response when `status` is `None`.
+pub fn backend(backend: &str, latency: Duration, status: Option<u16>) {
+    record(|t| {
+        t.backends.push(json!({
+            "backend": backend,
+            "latency_ms": latency.as_millis() as u64,
+            "status": status,
+        }));
+        t.timings.push(format!(
+            "backend;desc={};dur={}",
+            quoted(backend),
+            millis(latency)
+        ));
+    });
+}
+
+/// Merging the backend responses took `took`.
+pub fn merge(took: Duration) {
+    record(|t| t.timings.push(format!("merge;dur={}", millis(took))));
+}
+
+fn millis(d: Duration) -> String {
+    format!("{:.1}", d.as_secs_f64() * 1000.0)
+}
+
+/// A `Server-Timing` quoted string, dropping what a header value cannot carry.
+fn quoted(s: &str) -> String {
+    let escaped: String = s
+        .chars()
+        .filter(|c| c.is_ascii_graphic() || *c == ' ')
+        .flat_map(|c| match c {
+            '"' | '\\' => vec!['\\', c],
+            _ => vec![c],
+        })
+        .collect();
+    format!("\"{}\"", escaped)
+}
+
+/// The response cache answered `result`.
+pub fn cache(result: &'static str) {
+    record(|t| t.cache = Some(result));
+}
+
+/// Serializes `v` with non-ASCII characters escaped, as header values must be visible ASCII.
+fn header_value(v: &Value) -> Option<HeaderValue> {
+    let mut out = String::new();
+    for c in v.to_string().chars() {
+        if c.is_ascii() {
+            out.push(c);
+        } else {
+            let mut units = [0u16; 2];
+            for unit in c.encode_utf16(&mut units) {
+                out.push_str(&format!("\\u{:04x}", unit));
+            }
+        }
+    }
+    HeaderValue::from_str(&out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, Config, Mode};
+    use axum::{body::Body, routing::post, Router};
+    use tower::ServiceExt;
+
+    async fn spawn_backend() -> String {
+        let app = Router::new().route(
+            "/api/v1/datapoints/query",
+            post(|| async { axum::Json(json!({ "queries": [{ "results": [] }] })) }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+        format!("http://127.0.0.1:{}", addr.port())
+    }
+
+    #[tokio::test]
+    async fn reports_routes_and_backends() {
+        let cfg = Config {
+            backends: vec![Backend {
+                name: Some("eu".to_string()),
+                pattern: "^cpu\\..*".to_string(),
+                url: spawn_backend().await,
+                ..Default::default()
+            }],
+            mode: Some(Mode::Multi),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+        let app = Router::new()
+            .route(
+                "/api/v1/datapoints/query",
+                post(crate::proxy::query_metric_handler),
+            )
+            .layer(axum::middleware::from_fn_with_state(state.clone(), layer))
+            .with_state(state);
+        let query = |debug: bool| {
+            let mut req = Request::builder()
+                .method("POST")
+                .uri("/api/v1/datapoints/query")
+                .body(Body::from(r#"{"metrics":[{"name":"cpu.ü"}]}"#))
+                .unwrap();
+            if debug {
+                req.headers_mut()
+                    .insert(DEBUG_HEADER, HeaderValue::from_static("1"));
+            }
+            req
+        };
+
+        let resp = app.clone().oneshot(query(false)).await.unwrap();
+        assert!(resp.headers().get("x-proxy-debug-routes").is_none());
+
+        let resp = app.oneshot(query(true)).await.unwrap();
+        let header = |name: &str| -> Value {
+            serde_json::from_slice(resp.headers().get(name).expect(name).as_bytes()).unwrap()
+        };
+        assert_eq!(
+            header("x-proxy-debug-routes"),
+            json!([{ "metric": "cpu.ü", "backend": "eu", "rule": 1, "pattern": "^cpu\\..*" }])
+        );
+        let backends = header("x-proxy-debug-backends");
+        assert_eq!(backends[0]["backend"], "eu");
+        assert_eq!(backends[0]["status"], 200);
+        assert!(resp.headers().get("x-proxy-debug-cache").is_none());
+    }
+
+    #[tokio::test]
+    async fn times_backends_and_merge_unless_disabled() {
+        let url = spawn_backend().await;
+        let app = |server_timing: Option<bool>| {
+            let cfg = Config {
+                backends: vec![Backend {
+                    name: Some("eu \"west\"".to_string()),
+                    pattern: "^cpu\\..*".to_string(),
+                    url: url.clone(),
+                    ..Default::default()
+                }],
+                mode: Some(Mode::Multi),
+                server_timing,
+                ..Default::default()
+            };
+            let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+            Router::new()
+                .route(
+                    "/api/v1/datapoints/query",
+                    post(crate::proxy::query_metric_handler),
+                )
+                .layer(axum::middleware::from_fn_with_state(state.clone(), layer))
+                .with_state(state)
+        };
+        let query = || {
+            Request::builder()
+                .method("POST")
+                .uri("/api/v1/datapoints/query")
+                .body(Body::from(r#"{"metrics":[{"name":"cpu.load"}]}"#))
+                .unwrap()
+        };
+
+        let resp = app(None).oneshot(query()).await.unwrap();
+        let timing = resp.headers()["server-timing"].to_str().unwrap();
+        assert!(
+            timing.starts_with("backend;desc=\"eu \\\"west\\\"\";dur="),
+            "{}",
+            timing
+        );
+        assert!(timing.contains(", merge;dur="), "{}", timing);
+
+        let resp = app(Some(false)).oneshot(query()).await.unwrap();
+        assert!(resp.headers().get("server-timing").is_none());
+    }
+}
@@ -0,0 +1,181 @@
+//! Thinning dense series in Multi-mode query results, for clients that only chart them.
+//!
+//! `X-Proxy-Max-Points: <n>` keeps at most `n` points per result after merging.
+//! Largest-triangle-three-buckets (the default) keeps the points that shape the line;
+//! `X-Proxy-Decimation: stride` keeps evenly spaced ones instead. Series holding values other
+//! than numbers (histograms) are always strided. `sample_size` still counts the points read.
+
+use hyper::HeaderMap;
+use serde_json::Value;
+
+pub const MAX_POINTS_HEADER: &str = "x-proxy-max-points";
+pub const METHOD_HEADER: &str = "x-proxy-decimation";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Lttb,
+    Stride,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decimation {
+    max_points: usize,
+    method: Method,
+}
+
+impl Decimation {
+    /// The decimation the request asks for, if any.
+    pub fn requested(headers: &HeaderMap) -> Result<Option<Self>, String> {
+        let header = |name: &str| headers.get(name).map(|v| v.to_str().unwrap_or_default());
+        let Some(max_points) = header(MAX_POINTS_HEADER) else {
+            return Ok(None);
+        };
+        let max_points = match max_points.trim().parse() {
+            Ok(n) if n >= 2 => n,
+            _ => {
+                return Err(format!(
+                    "{} must be an integer of at least 2",
+                    MAX_POINTS_HEADER
+                ))
+            }
+        };
+        let method = match header(METHOD_HEADER).map(str::trim) {
+            None | Some("lttb") => Method::Lttb,
+            Some("stride") => Method::Stride,
+            Some(other) => return Err(format!("unknown decimation '{}'", other)),
+        };
+        Ok(Some(Decimation { max_points, method }))
+    }
+
+    /// Thins every result of a KairosDB response holding more than `max_points` points.
+    pub fn apply(&self, response: &mut Value) {
+        let values = response
+            .get_mut("queries")
+            .and_then(Value::as_array_mut)
+            .into_iter()
+            .flatten()
+            .filter_map(|q| q.get_mut("results").and_then(Value::as_array_mut))
+            .flatten()
+            .filter_map(|r| r.get_mut("values").and_then(Value::as_array_mut));
+        for values in values {
+            if values.len() <= self.max_points {
+                continue;
+            }
+            let points = std::mem::take(values);
+            *values = match (self.method, numeric(&points)) {
+                (Method::Lttb, Some(xy)) => lttb(&xy, self.max_points)
+                    .into_iter()
+                    .map(|i| points[i].clone())
+                    .collect(),
+                _ => stride(points, self.max_points),
+            };
+        }
+    }
+}
+
+/// The points as (timestamp, value) pairs, if every value is a number.
+fn numeric(points: &[Value]) -> Option<Vec<(f64, f64)>> {
+    points
+        .iter()
+        .map(|p| Some((p.get(0)?.as_f64()?, p.get(1)?.as_f64()?)))
+        .collect()
+}
+
+/// `n` evenly spaced points, always including the first and last.
+fn stride(points: Vec<Value>, n: usize) -> Vec<Value> {
+    let last = points.len() - 1;
+    let mut keep = (0..n).map(|i| i * last / (n - 1)).peekable();
+    points
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, p)| keep.next_if_eq(&i).map(|_| p))
+        .collect()
+}
+
+/// Indices of the `n` points largest-triangle-three-buckets keeps.
+fn lttb(xy: &[(f64, f64)], n: usize) -> Vec<usize> {
+    let mut kept = vec![0];
+    let every = (xy.len() - 2) as f64 / (n - 2) as f64;
+    let bucket = |i: usize| {
+        let start = (i as f64 * every) as usize + 1;
+        let end = (((i + 1) as f64 * every) as usize + 1).min(xy.len() - 1);
+        start..end
+    };
+    let mut a = 0;
+    for i in 0..n - 2 {
+        // The next point is the average of the following bucket (or the last point)
+        let next = bucket(i + 1);
+        let (cx, cy) = if next.is_empty() {
+            xy[xy.len() - 1]
+        } else {
+            let len = next.len() as f64;
+            let (sx, sy) = xy[next]
+                .iter()
+                .fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+            (sx / len, sy / len)
+        };
+        let (ax, ay) = xy[a];
+        let area = |&j: &usize| {
+            let (bx, by) = xy[j];
+            ((ax - cx) * (by - ay) - (ax - bx) * (cy - ay)).abs()
+        };
+        if let Some(b) = bucket(i).max_by(|p, q| area(p).total_cmp(&area(q))) {
+            kept.push(b);
+            a = b;
+        }
+    }
+    kept.push(xy.len() - 1);
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn keeps_peaks_and_strides_other_values() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Decimation::requested(&headers), Ok(None));
+        headers.insert(MAX_POINTS_HEADER, "1".parse().unwrap());
+        assert!(Decimation::requested(&headers).is_err());
+        headers.insert(MAX_POINTS_HEADER, "5".parse().unwrap());
+        let lttb = Decimation::requested(&headers).unwrap().unwrap();
+        headers.insert(METHOD_HEADER, "stride".parse().unwrap());
+        assert_eq!(
+            Decimation::requested(&headers),
+            Ok(Some(Decimation {
+                max_points: 5,
+                method: Method::Stride
+            }))
+        );
+
+        // A flat line with one spike, which striding would miss
+        let values: Vec<Value> = (0..100)
+            .map(|t| json!([t, if t == 37 { 50 } else { 1 }]))
+            .collect();
+        let histograms: Vec<Value> = (0..10).map(|t| json!([t, { "bins": {} }])).collect();
+        let mut response = json!({ "queries": [{ "results": [
+            { "name": "cpu", "values": values },
+            { "name": "lat", "values": histograms },
+            { "name": "mem", "values": [[1, 1], [2, 2]] },
+        ] }] });
+        lttb.apply(&mut response);
+        let results = &response["queries"][0]["results"];
+        let cpu = results[0]["values"].as_array().unwrap();
+        assert_eq!(cpu.len(), 5);
+        assert_eq!(
+            (cpu[0][0].as_i64(), cpu[4][0].as_i64()),
+            (Some(0), Some(99))
+        );
+        assert!(cpu.contains(&json!([37, 50])));
+        let times: Vec<i64> = results[1]["values"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p[0].as_i64().unwrap())
+            .collect();
+        assert_eq!(times, vec![0, 2, 4, 6, 9]);
+        assert_eq!(results[2]["values"], json!([[1, 1], [2, 2]]));
+    }
+}
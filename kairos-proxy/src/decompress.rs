@@ -0,0 +1,130 @@
+//! Decompression of gzip-encoded request bodies.
+//!
+//! A small compressed body can expand enormously, so output is produced in chunks and
+//! decompression stops, with `413 Payload Too Large`, as soon as it passes
+//! `max_decompressed_body_bytes` or, if set, `max_compression_ratio` times the compressed size.
+//! Repetitive ingest batches routinely compress several hundred-fold, so there is no ratio
+//! limit by default. Decompressing runs on the blocking thread pool, off the request workers.
+
+use axum::http::StatusCode;
+use bytes::Bytes;
+use flate2::read::MultiGzDecoder;
+use std::io::Read;
+use tracing::warn;
+
+/// Limits on what a compressed body may expand to.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_bytes: usize,
+    pub max_ratio: Option<usize>,
+}
+
+/// Decompresses a gzip body (several concatenated members are allowed). Bodies over the
+/// limits are `PAYLOAD_TOO_LARGE`, corrupt ones `BAD_REQUEST`.
+pub async fn gunzip(compressed: Bytes, limits: Limits) -> Result<Bytes, StatusCode> {
+    tokio::task::spawn_blocking(move || inflate(&compressed, limits))
+        .await
+        .map_err(|e| {
+            warn!("Decompression task failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+}
+
+fn inflate(compressed: &[u8], limits: Limits) -> Result<Bytes, StatusCode> {
+    let max = match limits.max_ratio {
+        Some(ratio) => limits.max_bytes.min(compressed.len().saturating_mul(ratio)),
+        None => limits.max_bytes,
+    };
+    let mut decoder = MultiGzDecoder::new(compressed);
+    let mut out = Vec::new();
+    let mut chunk = [0u8; 16 * 1024];
+    loop {
+        let n = decoder.read(&mut chunk).map_err(|e| {
+            warn!("Cannot decompress request body: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+        if n == 0 {
+            return Ok(Bytes::from(out));
+        }
+        if out.len() + n > max {
+            warn!(
+                "Refused gzip body of {} bytes expanding past {} bytes",
+                compressed.len(),
+                max
+            );
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        out.extend_from_slice(&chunk[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn stops_bombs_at_the_size_and_ratio_limits() {
+        let limits = Limits {
+            max_bytes: 1 << 20,
+            max_ratio: Some(100),
+        };
+        let gunzip = |body: Vec<u8>, limits| gunzip(Bytes::from(body), limits);
+        let query = br#"{"metrics":[{"name":"cpu.load"}]}"#;
+        assert_eq!(gunzip(gzip(query), limits).await.unwrap(), &query[..]);
+
+        // 10 MB of zeros compress to about 10 KB
+        let bomb = gzip(&vec![0u8; 10 << 20]);
+        assert!(bomb.len() < 20_000);
+        assert_eq!(
+            gunzip(bomb, limits).await,
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        // Within the size limit but expanding more than 100-fold
+        let dense = gzip(&vec![b'a'; 500_000]);
+        assert_eq!(
+            gunzip(dense, limits).await,
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+
+        assert_eq!(
+            gunzip(b"not gzip".to_vec(), limits).await,
+            Err(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[tokio::test]
+    async fn repetitive_batches_are_only_bounded_by_size_unless_a_ratio_is_set() {
+        let batch = format!(
+            "[{}]",
+            vec![
+                r#"{"name":"cpu.load","timestamp":1700000000000,"value":1,"tags":{"host":"a"}}"#;
+                20_000
+            ]
+            .join(",")
+        );
+        let compressed = Bytes::from(gzip(batch.as_bytes()));
+        assert!(batch.len() / compressed.len() > 100);
+        let limits = Limits {
+            max_bytes: 4 << 20,
+            max_ratio: None,
+        };
+        let body = gunzip(compressed.clone(), limits).await.unwrap();
+        assert_eq!(body, batch.as_bytes());
+        let ratio = Limits {
+            max_ratio: Some(100),
+            ..limits
+        };
+        assert_eq!(
+            gunzip(compressed, ratio).await,
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+    }
+}
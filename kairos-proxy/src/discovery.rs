@@ -0,0 +1,317 @@
+//! Kubernetes Endpoints discovery for backends whose instances come and go.
+//!
+//! A discovering backend lists the matching Endpoints objects, then watches them, keeping
+//! the set of ready pod addresses current. Requests are spread across ready pods by the
+//! backend's load-balancing strategy; while none is known the backend's configured `url` is used. After an error, or when the
+//! server ends the watch, it is re-established from a fresh list.
+
+use crate::balance::{Balancer, InstanceLoad};
+use crate::config::{KubernetesDiscovery, LoadBalancing, PortRef};
+use crate::state::AppState;
+use futures::StreamExt;
+use reqwest::{Client, Url};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+const RETRY: Duration = Duration::from_secs(5);
+const LIST_TIMEOUT: Duration = Duration::from_secs(10);
+// Server-side bound on one watch request
+const WATCH_SECS: u64 = 300;
+
+/// Ready instances of one backend, kept current by its watch.
+pub struct Endpoints {
+    source: KubernetesDiscovery,
+    // Configured backend URL; instances reuse its scheme and path
+    base: Url,
+    pub balancer: Balancer,
+}
+
+impl Endpoints {
+    pub fn new(source: KubernetesDiscovery, base: Url, strategy: LoadBalancing) -> Self {
+        Endpoints {
+            source,
+            base,
+            balancer: Balancer::new(strategy),
+        }
+    }
+
+    /// Ready instance for the next request. `None` until any pod is ready.
+    pub fn pick(&self) -> Option<InstanceLoad> {
+        self.balancer.pick()
+    }
+
+    /// Replaces the instances with those of every watched Endpoints object.
+    fn publish(&self, objects: &BTreeMap<String, Vec<Url>>) {
+        let mut all: Vec<Url> = objects.values().flatten().cloned().collect();
+        all.sort();
+        all.dedup();
+        let count = all.len();
+        if self.balancer.set(all) {
+            info!("Discovered {} ready instance(s) for {}", count, self.base);
+        }
+    }
+
+    /// Ready addresses of one Endpoints object as instance URLs.
+    fn ready_instances(&self, object: &Value) -> Vec<Url> {
+        let mut urls = Vec::new();
+        for subset in object["subsets"].as_array().into_iter().flatten() {
+            let ports = subset["ports"].as_array();
+            let port = ports
+                .into_iter()
+                .flatten()
+                .find(|p| match &self.source.port {
+                    Some(PortRef::Number(n)) => p["port"].as_u64() == Some(u64::from(*n)),
+                    Some(PortRef::Name(name)) => p["name"].as_str() == Some(name.as_str()),
+                    None => true,
+                });
+            let Some(port) = port.and_then(|p| p["port"].as_u64()) else {
+                continue;
+            };
+            // `addresses` holds only ready pods; `notReadyAddresses` is ignored
+            for address in subset["addresses"].as_array().into_iter().flatten() {
+                let Some(ip) = address["ip"].as_str() else {
+                    continue;
+                };
+                let mut url = self.base.clone();
+                let host = if ip.contains(':') {
+                    format!("[{}]", ip)
+                } else {
+                    ip.to_string()
+                };
+                if url.set_host(Some(&host)).is_ok() && url.set_port(Some(port as u16)).is_ok() {
+                    urls.push(url);
+                }
+            }
+        }
+        urls
+    }
+}
+
+/// Connection to the Kubernetes API server.
+struct Api {
+    client: Client,
+    url: Url,
+    namespace: String,
+}
+
+impl Api {
+    fn new(source: &KubernetesDiscovery) -> anyhow::Result<Self> {
+        let url = match &source.api_url {
+            Some(url) => url.clone(),
+            None => {
+                let host = std::env::var("KUBERNETES_SERVICE_HOST").map_err(|_| {
+                    anyhow::anyhow!("Kubernetes discovery outside a cluster needs an api_url")
+                })?;
+                let port =
+                    std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".into());
+                if host.contains(':') {
+                    format!("https://[{}]:{}", host, port)
+                } else {
+                    format!("https://{}:{}", host, port)
+                }
+            }
+        };
+        let url = Url::parse(&url)
+            .map_err(|e| anyhow::anyhow!("Invalid Kubernetes api_url '{}': {}", url, e))?;
+        let mut builder = Client::builder().connect_timeout(LIST_TIMEOUT);
+        if let Ok(ca) = std::fs::read(format!("{}/ca.crt", SERVICE_ACCOUNT)) {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&ca)?);
+        }
+        let namespace = source
+            .namespace
+            .clone()
+            .or_else(|| std::fs::read_to_string(format!("{}/namespace", SERVICE_ACCOUNT)).ok())
+            .map(|ns| ns.trim().to_string())
+            .unwrap_or_else(|| "default".to_string());
+        Ok(Api {
+            client: builder.build()?,
+            url,
+            namespace,
+        })
+    }
+
+    /// Lists the matching Endpoints, or watches them from `resource_version`.
+    async fn endpoints(
+        &self,
+        source: &KubernetesDiscovery,
+        resource_version: Option<&str>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut url = self
+            .url
+            .join(&format!("/api/v1/namespaces/{}/endpoints", self.namespace))?;
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(service) = &source.service {
+                query.append_pair("fieldSelector", &format!("metadata.name={}", service));
+            }
+            if let Some(selector) = &source.label_selector {
+                query.append_pair("labelSelector", selector);
+            }
+            if let Some(rv) = resource_version {
+                query
+                    .append_pair("watch", "true")
+                    .append_pair("resourceVersion", rv)
+                    .append_pair("timeoutSeconds", &WATCH_SECS.to_string());
+            }
+        }
+        let mut request = self.client.get(url);
+        if resource_version.is_none() {
+            request = request.timeout(LIST_TIMEOUT);
+        }
+        // Projected service account tokens rotate, so re-read it for every request
+        if let Ok(token) = std::fs::read_to_string(format!("{}/token", SERVICE_ACCOUNT)) {
+            request = request.bearer_auth(token.trim());
+        }
+        Ok(request.send().await?.error_for_status()?)
+    }
+}
+
+/// One list-then-watch cycle. Returns once the watch ends.
+async fn sync(endpoints: &Endpoints, api: &Api) -> anyhow::Result<()> {
+    let list: Value = api.endpoints(&endpoints.source, None).await?.json().await?;
+    let name = |o: &Value| {
+        o["metadata"]["name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string()
+    };
+    let mut objects: BTreeMap<String, Vec<Url>> = list["items"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|o| (name(o), endpoints.ready_instances(o)))
+        .collect();
+    endpoints.publish(&objects);
+
+    let version = list["metadata"]["resourceVersion"]
+        .as_str()
+        .unwrap_or_default();
+    let mut stream = api
+        .endpoints(&endpoints.source, Some(version))
+        .await?
+        .bytes_stream();
+    let mut buf = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        buf.extend_from_slice(&chunk?);
+        while let Some(end) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=end).collect();
+            let event: Value = serde_json::from_slice(&line)?;
+            let object = &event["object"];
+            match event["type"].as_str() {
+                Some("ADDED") | Some("MODIFIED") => {
+                    objects.insert(name(object), endpoints.ready_instances(object));
+                }
+                Some("DELETED") => {
+                    objects.remove(&name(object));
+                }
+                // e.g. 410 Gone once the resource version is too old
+                Some("ERROR") => anyhow::bail!("watch error: {}", object["message"]),
+                _ => continue,
+            }
+            endpoints.publish(&objects);
+        }
+    }
+    debug!("Endpoints watch for {} ended", endpoints.base);
+    Ok(())
+}
+
+/// Starts watching Endpoints for every discovering backend.
+pub fn spawn_all(state: &AppState) -> anyhow::Result<()> {
+    for target in &state.backends {
+        let Some(endpoints) = target.endpoints.clone() else {
+            continue;
+        };
+        let api = Api::new(&endpoints.source)?;
+        info!(
+            "Discovering instances of backend '{}' from Kubernetes namespace {}",
+            target.name, api.namespace
+        );
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = sync(&endpoints, &api).await {
+                    warn!("Kubernetes discovery for {} failed: {}", endpoints.base, e);
+                }
+                tokio::time::sleep(RETRY).await;
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::Query;
+    use axum::{routing::get, Router};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn endpoints_object(name: &str, ips: &[&str]) -> Value {
+        let addresses: Vec<Value> = ips.iter().map(|ip| json!({ "ip": ip })).collect();
+        json!({
+            "metadata": { "name": name },
+            "subsets": [{
+                "addresses": addresses,
+                "notReadyAddresses": [{ "ip": "10.0.0.99" }],
+                "ports": [{ "name": "metrics", "port": 9100 }, { "name": "http", "port": 8080 }]
+            }]
+        })
+    }
+
+    #[tokio::test]
+    async fn lists_then_follows_watch_events() {
+        let app = Router::new().route(
+            "/api/v1/namespaces/kairos/endpoints",
+            get(|Query(q): Query<HashMap<String, String>>| async move {
+                assert_eq!(q["fieldSelector"], "metadata.name=kairosdb-read");
+                if q.contains_key("watch") {
+                    assert_eq!(q["resourceVersion"], "41");
+                    let event = json!({
+                        "type": "MODIFIED",
+                        "object": endpoints_object("kairosdb-read", &["10.0.0.2", "10.0.0.3"])
+                    });
+                    format!("{}\n", event)
+                } else {
+                    json!({
+                        "metadata": { "resourceVersion": "41" },
+                        "items": [endpoints_object("kairosdb-read", &["10.0.0.1"])]
+                    })
+                    .to_string()
+                }
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let source = KubernetesDiscovery {
+            namespace: Some("kairos".to_string()),
+            service: Some("kairosdb-read".to_string()),
+            port: Some(PortRef::Name("http".to_string())),
+            api_url: Some(format!("http://{}", addr)),
+            ..Default::default()
+        };
+        let api = Api::new(&source).expect("api");
+        let endpoints = Endpoints::new(
+            source,
+            Url::parse("http://kairosdb-read:8080/").unwrap(),
+            LoadBalancing::RoundRobin,
+        );
+        assert!(endpoints.pick().is_none());
+
+        sync(&endpoints, &api).await.expect("sync");
+        let mut picked: Vec<String> = (0..4)
+            .map(|_| endpoints.pick().unwrap().url().to_string())
+            .collect();
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked, ["http://10.0.0.2:8080/", "http://10.0.0.3:8080/"]);
+    }
+}
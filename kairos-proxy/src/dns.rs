@@ -0,0 +1,144 @@
+//! Periodic re-resolution of backend hostnames.
+//!
+//! Pooled connections stay on the address a hostname had when they were opened, so when a
+//! backend's DNS record moves (Kubernetes service churn, failover) the proxy would keep
+//! talking to the old address. With `dns_refresh_secs` set, backend hosts are re-resolved on
+//! that interval and, when any address changed, the HTTP client is replaced: new requests
+//! open fresh connections while requests in flight finish on the old client.
+
+use crate::state::AppState;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+type Addresses = BTreeMap<String, BTreeSet<SocketAddr>>;
+
+/// `host:port` of every backend, canary and mirror URL.
+fn hosts(state: &AppState) -> BTreeSet<String> {
+    state
+        .backends
+        .iter()
+        .flat_map(|b| {
+            [
+                Some(&b.url),
+                b.canary.as_ref().map(|c| &c.url),
+                b.mirror.as_ref().map(|m| &m.url),
+            ]
+        })
+        .flatten()
+        .filter_map(|url| {
+            Some(format!(
+                "{}:{}",
+                url.host_str()?,
+                url.port_or_known_default()?
+            ))
+        })
+        .collect()
+}
+
+/// Resolves `hosts`. A host that fails to resolve keeps its `previous` addresses so a DNS
+/// hiccup is not mistaken for a move.
+async fn resolve(hosts: &BTreeSet<String>, previous: &Addresses) -> Addresses {
+    let mut resolved = Addresses::new();
+    for host in hosts {
+        match tokio::net::lookup_host(host.as_str()).await {
+            Ok(addrs) => {
+                resolved.insert(host.clone(), addrs.collect());
+            }
+            Err(e) => {
+                warn!("Failed to re-resolve backend host {}: {}", host, e);
+                if let Some(addrs) = previous.get(host) {
+                    resolved.insert(host.clone(), addrs.clone());
+                }
+            }
+        }
+    }
+    resolved
+}
+
+/// Re-resolves backend hosts every `every` for the lifetime of the process.
+pub fn spawn(state: Arc<AppState>, every: Duration) {
+    tokio::spawn(async move {
+        let hosts = hosts(&state);
+        let mut known = resolve(&hosts, &Addresses::new()).await;
+        info!(
+            "Re-resolving {} backend host(s) every {:?}",
+            hosts.len(),
+            every
+        );
+        loop {
+            tokio::time::sleep(every).await;
+            let current = resolve(&hosts, &known).await;
+            refresh(&state, &known, &current).await;
+            known = current;
+        }
+    });
+}
+
+/// Replaces the client when any host's addresses changed. Returns whether it did.
+async fn refresh(state: &AppState, known: &Addresses, current: &Addresses) -> bool {
+    let moved: Vec<&String> = current
+        .iter()
+        .filter(|(host, addrs)| known.get(*host).is_some_and(|k| k != *addrs))
+        .map(|(host, _)| host)
+        .collect();
+    if moved.is_empty() {
+        debug!("Backend addresses unchanged");
+        return false;
+    }
+    match state.rebuild_clients().await {
+        Ok(()) => {
+            info!("Backend addresses changed for {:?}; reconnecting", moved);
+            state
+                .metrics
+                .inc_counter("kairos_proxy_dns_changes_total", &[]);
+            true
+        }
+        Err(e) => {
+            warn!("Failed to rebuild HTTP clients after DNS change: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, Config};
+
+    #[tokio::test]
+    async fn replaces_client_only_when_addresses_move() {
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: ".*".to_string(),
+                url: "http://127.0.0.1:8080".to_string(),
+                canary_url: Some("http://localhost:9090".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let state = AppState::from_config(&cfg).expect("state");
+        let hosts = hosts(&state);
+        assert_eq!(
+            hosts.iter().collect::<Vec<_>>(),
+            ["127.0.0.1:8080", "localhost:9090"]
+        );
+
+        let known = resolve(&hosts, &Addresses::new()).await;
+        assert!(known["127.0.0.1:8080"].contains(&"127.0.0.1:8080".parse().unwrap()));
+        assert!(!refresh(&state, &known, &known).await);
+
+        let mut moved = known.clone();
+        moved.insert(
+            "localhost:9090".to_string(),
+            BTreeSet::from(["10.0.0.9:9090".parse().unwrap()]),
+        );
+        assert!(refresh(&state, &known, &moved).await);
+        assert_eq!(
+            state.metrics.counter("kairos_proxy_dns_changes_total", &[]),
+            1
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! `X-Proxy-Dry-Run: 1` on the query endpoints.
+//!
+//! The query is checked, routed and split as usual, but instead of being sent the backend
+//! requests it would have caused are returned: `{"dry_run": true, "requests": [{"backend",
+//! "url", "body"}]}`, with the sub-range of requests split across time tiers. Nothing reaches
+//! a backend or the cache and nothing is charged to an API key, so new routing rules can be
+//! tried against production traffic. Canary and instance choices are not made; `url` is the
+//! backend's configured URL, and tokens are never shown.
+
+use crate::fanout;
+use crate::state::{AppState, BackendTarget};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::HeaderMap;
+use serde_json::{json, Value};
+
+pub const DRY_RUN_HEADER: &str = "x-proxy-dry-run";
+
+/// Whether the request asks for a dry run.
+pub fn requested(headers: &HeaderMap) -> bool {
+    headers
+        .get(DRY_RUN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| matches!(v.trim(), "1" | "true"))
+}
+
+fn request(target: &BackendTarget, endpoint: &str, body: Value) -> Value {
+    let url = target
+        .endpoint_url(&target.url, endpoint)
+        .map_or_else(|_| target.url.to_string(), |u| u.to_string());
+    json!({ "backend": target.name, "url": url, "body": body })
+}
+
+fn respond(requests: Vec<Value>) -> Response {
+    (
+        StatusCode::OK,
+        Json(json!({ "dry_run": true, "requests": requests })),
+    )
+        .into_response()
+}
+
+/// The single request Simple mode would forward to `target`.
+pub fn simple(target: &BackendTarget, endpoint: &str, body: &[u8]) -> Response {
+    // Simple mode forwards the body as it came; show it as JSON if it is
+    let body = serde_json::from_slice(body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()));
+    respond(vec![request(target, endpoint, body)])
+}
+
+/// The requests the Multi-mode fan-out of `query` would send to `endpoint`.
+pub fn multi(state: &AppState, headers: &HeaderMap, query: &Value, endpoint: &str) -> Response {
+    let planned = match fanout::plan(state, headers, query) {
+        Ok(planned) => planned,
+        Err(e) => return e.into_response(),
+    };
+    let requests = planned
+        .into_iter()
+        .map(|r| {
+            let target = &state.backends[r.backend];
+            let mut body = r.body;
+            if let Some(tag_keys) = &target.tag_keys {
+                tag_keys.outbound(&mut body);
+            }
+            if let Some(prefix) = &target.name_prefix {
+                prefix.outbound(&mut body);
+            }
+            let mut request = request(target, endpoint, body);
+            if let Some(range) = r.range {
+                request["range"] = json!({ "start_ms": range.start_ms, "end_ms": range.end_ms });
+            }
+            request
+        })
+        .collect();
+    respond(requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, Config};
+
+    #[tokio::test]
+    async fn shows_the_split_without_sending_it() {
+        let cfg = Config {
+            backends: vec![
+                Backend {
+                    name: Some("cpu".to_string()),
+                    pattern: "^cpu\\.".to_string(),
+                    url: "http://kairos-cpu:8080".to_string(),
+                    ..Default::default()
+                },
+                Backend {
+                    name: Some("rest".to_string()),
+                    pattern: ".*".to_string(),
+                    url: "http://kairos-rest:8080/kairos/".to_string(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let state = AppState::from_config(&cfg).expect("state");
+        let mut headers = HeaderMap::new();
+        assert!(!requested(&headers));
+        headers.insert(DRY_RUN_HEADER, "1".parse().unwrap());
+        assert!(requested(&headers));
+
+        let query = json!({
+            "start_relative": { "value": 1, "unit": "hours" },
+            "metrics": [{ "name": "cpu.load" }, { "name": "disk.free" }, { "name": "cpu.idle" }],
+        });
+        let resp = multi(&state, &headers, &query, "api/v1/datapoints/query");
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["dry_run"], true);
+        let requests = body["requests"].as_array().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0]["backend"], "cpu");
+        assert_eq!(
+            requests[0]["url"],
+            "http://kairos-cpu:8080/api/v1/datapoints/query"
+        );
+        assert_eq!(
+            requests[0]["body"]["metrics"],
+            json!([{ "name": "cpu.load" }, { "name": "cpu.idle" }])
+        );
+        assert_eq!(requests[0]["body"]["start_relative"]["unit"], "hours");
+        assert_eq!(
+            requests[1]["url"],
+            "http://kairos-rest:8080/kairos/api/v1/datapoints/query"
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! Rolling estimates of how a backend (or one of its instances) is doing right now.
+//!
+//! Latency and error rate are exponentially weighted moving averages of the requests sent,
+//! so they follow real-time conditions within a few dozen requests rather than reflecting
+//! static weights. Backends export theirs as `kairos_proxy_backend_latency_ewma_seconds` and
+//! `kairos_proxy_backend_error_rate_ewma` and list them in `/admin/backends`; load balancing
+//! across instances chooses by them.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Weight of the newest sample in the averages. Errors move slower, so that one failure does
+// not outweigh a run of successes.
+const LATENCY_WEIGHT: f64 = 0.3;
+const ERROR_WEIGHT: f64 = 0.1;
+
+/// A moving average updated without locks; empty until its first sample.
+pub struct Ewma {
+    weight: f64,
+    // f64 bits; NaN while empty
+    bits: AtomicU64,
+}
+
+impl Ewma {
+    pub fn new(weight: f64) -> Self {
+        Ewma {
+            weight,
+            bits: AtomicU64::new(f64::NAN.to_bits()),
+        }
+    }
+
+    pub fn get(&self) -> Option<f64> {
+        let value = f64::from_bits(self.bits.load(Ordering::Relaxed));
+        (!value.is_nan()).then_some(value)
+    }
+
+    /// Folds `sample` into the average; the first sample becomes the average.
+    pub fn observe(&self, sample: f64) {
+        let _ = self
+            .bits
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let avg = f64::from_bits(bits);
+                let next = if avg.is_nan() {
+                    sample
+                } else {
+                    avg * (1.0 - self.weight) + sample * self.weight
+                };
+                Some(next.to_bits())
+            });
+    }
+}
+
+/// Latency and error rate of the requests sent to one target.
+pub struct Estimates {
+    latency: Ewma,
+    errors: Ewma,
+}
+
+impl Default for Estimates {
+    fn default() -> Self {
+        Estimates {
+            latency: Ewma::new(LATENCY_WEIGHT),
+            errors: Ewma::new(ERROR_WEIGHT),
+        }
+    }
+}
+
+impl Estimates {
+    pub fn observe(&self, success: bool, elapsed: Duration) {
+        self.latency.observe(elapsed.as_secs_f64());
+        self.errors.observe(if success { 0.0 } else { 1.0 });
+    }
+
+    /// Average latency; `None` before the first request finished.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency.get().map(Duration::from_secs_f64)
+    }
+
+    /// Share of recent requests that failed, between 0 and 1.
+    pub fn error_rate(&self) -> f64 {
+        self.errors.get().unwrap_or(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_recent_latency_and_errors() {
+        let estimates = Estimates::default();
+        assert_eq!(estimates.latency(), None);
+        assert_eq!(estimates.error_rate(), 0.0);
+
+        estimates.observe(true, Duration::from_millis(100));
+        assert_eq!(estimates.latency(), Some(Duration::from_millis(100)));
+        estimates.observe(false, Duration::from_millis(200));
+        assert_eq!(estimates.latency(), Some(Duration::from_millis(130)));
+        assert!((estimates.error_rate() - 0.1).abs() < 1e-9);
+
+        for _ in 0..50 {
+            estimates.observe(false, Duration::from_millis(20));
+        }
+        assert!(estimates.latency().unwrap() < Duration::from_millis(21));
+        assert!(estimates.error_rate() > 0.99);
+    }
+}
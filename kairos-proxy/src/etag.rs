@@ -0,0 +1,91 @@
+//! Entity tags on merged query responses.
+//!
+//! With a `[cache]` configured, Multi-mode query responses carry a strong `ETag` derived from
+//! the bytes of the body, and `Vary: Accept` since JSON and MessagePack bodies differ. A
+//! client repeating a query with `If-None-Match` naming that tag gets `304 Not Modified` and
+//! no body while the result is unchanged, so a dashboard refreshing faster than the data
+//! changes stops re-downloading it. Queries are sent as `POST` but change nothing, so they
+//! are answered as the safe reads they are. The result is still produced (usually from the
+//! cache); only the transfer is saved.
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use ring::digest;
+
+/// The strong entity tag of `body`: a quoted prefix of its SHA-256 digest.
+pub fn tag(body: &[u8]) -> String {
+    let digest = digest::digest(&digest::SHA256, body);
+    let hex: String = digest.as_ref()[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("\"{}\"", hex)
+}
+
+/// Whether `If-None-Match` in `headers` names `etag`. The comparison is weak, as RFC 9110
+/// asks for this header, so `W/` prefixes are ignored.
+fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|t| t.trim())
+        .any(|t| t == "*" || t.trim_start_matches("W/") == etag)
+}
+
+/// `body` with its `ETag`, or `304 Not Modified` if the request already has it.
+pub fn respond(request: &HeaderMap, content_type: &'static str, body: Vec<u8>) -> Response {
+    let etag = tag(&body);
+    let Ok(value) = HeaderValue::from_str(&etag) else {
+        return ([(header::CONTENT_TYPE, content_type)], body).into_response();
+    };
+    let vary = (header::VARY, HeaderValue::from_static("accept"));
+    if not_modified(request, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, value), vary]).into_response();
+    }
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static(content_type)),
+            (header::ETAG, value),
+            vary,
+        ],
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_not_modified_for_a_known_tag() {
+        let body = br#"{"queries":[]}"#.to_vec();
+        let etag = tag(&body);
+        assert_eq!(etag.len(), 34);
+        assert_ne!(etag, tag(br#"{"queries":[{}]}"#));
+
+        let fresh = respond(&HeaderMap::new(), "application/json", body.clone());
+        assert_eq!(fresh.status(), StatusCode::OK);
+        assert_eq!(fresh.headers()[header::ETAG], etag.as_str());
+        assert_eq!(fresh.headers()[header::VARY], "accept");
+
+        let mut headers = HeaderMap::new();
+        let known = format!("\"0000\", W/{}", etag);
+        headers.insert(header::IF_NONE_MATCH, known.parse().unwrap());
+        let cached = respond(&headers, "application/json", body.clone());
+        assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(cached.headers()[header::ETAG], etag.as_str());
+        assert_eq!(cached.headers()[header::VARY], "accept");
+
+        headers.insert(header::IF_NONE_MATCH, "\"0000\"".parse().unwrap());
+        assert_eq!(
+            respond(&headers, "application/json", body).status(),
+            StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Server-sent events on backend health and configuration changes, at `/admin/events`.
+//!
+//! Tooling that pages on a backend going down subscribes instead of polling. A backend goes
+//! `backend_down` when a request to it times out or cannot connect and `backend_up` with the
+//! next request it answers; `backend_drained` / `backend_undrained` follow the admin API and
+//! `config_reloaded` / `config_rolled_back` the routing generations, and `cache_purged` the
+//! cache purges. Each event's data is a
+//! JSON object with the event's details and `at_ms`. Subscribers too slow to keep up get a
+//! `lagged` event with the number of events they missed.
+
+use crate::state::AppState;
+use crate::timerange::now_ms;
+use crate::upstream::Failure;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::Stream;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+/// Events buffered per subscriber before it lags.
+const CAPACITY: usize = 256;
+
+pub struct Events {
+    tx: broadcast::Sender<(&'static str, Value)>,
+    // Backends whose last request failed to reach them
+    down: Mutex<HashSet<String>>,
+    // Size of `down`, so successful requests skip the lock while everything is up
+    down_count: AtomicUsize,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            tx: broadcast::channel(CAPACITY).0,
+            down: Mutex::new(HashSet::new()),
+            down_count: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Events {
+    /// Sends `kind` with `data` (an object) to every subscriber.
+    pub fn publish(&self, kind: &'static str, mut data: Value) {
+        data["at_ms"] = now_ms().into();
+        debug!("Event {}: {}", kind, data);
+        // No subscribers is not an error
+        let _ = self.tx.send((kind, data));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<(&'static str, Value)> {
+        self.tx.subscribe()
+    }
+
+    fn down(&self) -> std::sync::MutexGuard<'_, HashSet<String>> {
+        self.down.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Notes a failed request to `backend`; timeouts and connection failures mark it down.
+    pub fn backend_failed(&self, backend: &str, failure: Failure) {
+        if !matches!(failure, Failure::Timeout | Failure::Unreachable) {
+            return;
+        }
+        let newly_down = {
+            let mut down = self.down();
+            let inserted = down.insert(backend.to_string());
+            self.down_count.store(down.len(), Ordering::Relaxed);
+            inserted
+        };
+        if newly_down {
+            warn!("Backend '{}' is down: {:?}", backend, failure);
+            let reason = format!("{:?}", failure).to_lowercase();
+            self.publish(
+                "backend_down",
+                json!({ "backend": backend, "reason": reason }),
+            );
+        }
+    }
+
+    /// Notes a request `backend` answered, marking it up again if it was down.
+    pub fn backend_answered(&self, backend: &str) {
+        if self.down_count.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let recovered = {
+            let mut down = self.down();
+            let removed = down.remove(backend);
+            self.down_count.store(down.len(), Ordering::Relaxed);
+            removed
+        };
+        if recovered {
+            warn!("Backend '{}' is up again", backend);
+            self.publish("backend_up", json!({ "backend": backend }));
+        }
+    }
+}
+
+/// `GET /admin/events`: the event stream.
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    crate::admin::authorize(&state, &headers)?;
+    let rx = state.events.subscribe();
+    let stream = futures::stream::unfold(rx, |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok((kind, data)) => Event::default().event(kind).data(data.to_string()),
+            Err(broadcast::error::RecvError::Lagged(missed)) => Event::default()
+                .event("lagged")
+                .data(json!({ "missed": missed }).to_string()),
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((Ok(event), rx))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_transitions_once() {
+        let events = Events::default();
+        let mut rx = events.subscribe();
+        events.backend_answered("eu");
+        events.backend_failed("eu", Failure::Status(500));
+        events.backend_failed("eu", Failure::Timeout);
+        events.backend_failed("eu", Failure::Unreachable);
+        events.backend_answered("us");
+        events.backend_answered("eu");
+        events.backend_answered("eu");
+
+        let (kind, data) = rx.try_recv().expect("down");
+        assert_eq!(kind, "backend_down");
+        assert_eq!(data["backend"], "eu");
+        assert_eq!(data["reason"], "timeout");
+        assert!(data["at_ms"].as_i64().is_some());
+        let (kind, data) = rx.try_recv().expect("up");
+        assert_eq!((kind, &data["backend"]), ("backend_up", &json!("eu")));
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,823 @@
+//! Multi-mode query execution: split a KairosDB query by backend, fan the pieces out with
+//! bounded concurrency, and merge the JSON responses back into a single KairosDB response.
+
+use crate::admission::Priority;
+use crate::cache::Lookup;
+use crate::deadline::{Deadline, REQUEST_TIMEOUT_HEADER, TIMEOUT_MS_HEADER};
+use crate::debug_trace;
+use crate::memory::ReadError;
+use crate::merge_policy;
+use crate::mirror::mirror_query;
+use crate::routes::Subject;
+use crate::state::AppState;
+use crate::stitch::{stitch, Answer};
+use crate::timerange::{now_ms, TimeRange};
+use crate::upstream::{BackendError, Failure, QueryError};
+use axum::http::StatusCode;
+use bytes::Bytes;
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, error, info, warn};
+
+/// One outbound request of a fan-out: the backend index and the payload carrying only its metrics.
+pub(crate) struct BackendRequest {
+    pub backend: usize,
+    pub body: serde_json::Value,
+    /// Sub-range of the query this piece covers when time tiers split it.
+    pub range: Option<TimeRange>,
+    /// Index in the client's query of each metric in `body`.
+    pub metrics: Vec<usize>,
+}
+
+/// Groups the metrics of `query` by backend and builds one payload per backend. Top-level
+/// fields other than `metrics` (time range, cache_time, ...) are copied into every payload.
+/// With time-tiered backends a metric may be split into several payloads, each carrying the
+/// absolute sub-range its backend holds.
+pub(crate) fn plan(
+    state: &AppState,
+    headers: &hyper::HeaderMap,
+    query: &serde_json::Value,
+) -> Result<Vec<BackendRequest>, QueryError> {
+    // Extract metrics array
+    let metrics = match query.get("metrics").and_then(|v| v.as_array()) {
+        Some(m) if !m.is_empty() => m,
+        _ => {
+            warn!("Request contains no metrics or invalid metrics array");
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+    };
+
+    debug!(
+        "Processing request in Multi mode with {} metric(s)",
+        metrics.len()
+    );
+
+    let range = TimeRange::of_query(query, now_ms());
+
+    // Group metrics by backend (and sub-range), keeping first-seen order for stable output
+    type Slot = (usize, Option<TimeRange>);
+    let mut backend_metrics: Vec<(Slot, Vec<(usize, serde_json::Value)>)> = Vec::new();
+    let mut slots: HashMap<Slot, usize> = HashMap::new();
+    for (m, metric) in metrics.iter().enumerate() {
+        let name = metric.get("name").and_then(|v| v.as_str());
+        let routes = name
+            .map(|n| {
+                let subject = Subject {
+                    metric: n,
+                    tags: metric.get("tags"),
+                    headers: Some(headers),
+                };
+                state.route(&subject, range)
+            })
+            .unwrap_or_default();
+        if routes.is_empty() {
+            error!("No backend matched metric: {:?}", name);
+            return Err(state.unroutable(name.unwrap_or_default()));
+        }
+        for slot in routes {
+            debug!(
+                "Metric '{}' matched backend: {} ({:?})",
+                name.unwrap_or_default(),
+                state.backends[slot.0].url,
+                slot.1
+            );
+            let index = *slots.entry(slot).or_insert_with(|| {
+                backend_metrics.push((slot, Vec::new()));
+                backend_metrics.len() - 1
+            });
+            backend_metrics[index].1.push((m, metric.clone()));
+        }
+    }
+
+    info!(
+        metric_count = metrics.len(),
+        backend_count = backend_metrics.len(),
+        "Routing {} metric(s) to {} backend(s)",
+        metrics.len(),
+        backend_metrics.len()
+    );
+
+    let requests = backend_metrics
+        .into_iter()
+        .map(|((backend, sub_range), metrics_for_backend)| {
+            // Build a small payload: copy top-level fields except "metrics", insert only relevant metrics
+            let mut payload_map = serde_json::Map::new();
+            if let Some(obj) = query.as_object() {
+                for (k, v) in obj.iter() {
+                    if k == "metrics" {
+                        continue;
+                    }
+                    payload_map.insert(k.clone(), v.clone());
+                }
+            }
+            if let Some(r) = sub_range {
+                r.apply(&mut payload_map);
+            }
+            let (indexes, metrics_for_backend): (Vec<usize>, Vec<serde_json::Value>) =
+                metrics_for_backend.into_iter().unzip();
+            payload_map.insert(
+                "metrics".to_string(),
+                serde_json::Value::Array(metrics_for_backend),
+            );
+            BackendRequest {
+                backend,
+                body: serde_json::Value::Object(payload_map),
+                range: sub_range,
+                metrics: indexes,
+            }
+        })
+        .collect();
+    Ok(requests)
+}
+
+/// Number of metrics in `query`, as charged to API key budgets.
+pub(crate) fn metric_count(query: &serde_json::Value) -> usize {
+    query
+        .get("metrics")
+        .and_then(|m| m.as_array())
+        .map_or(0, Vec::len)
+}
+
+/// [`metric_count`] of a query body, without building its JSON value. A body that is not a
+/// query counts as one metric, so that forwarding it is still charged.
+pub(crate) fn body_metric_count(body: &[u8]) -> usize {
+    #[derive(serde::Deserialize)]
+    struct Metrics {
+        #[serde(default)]
+        metrics: Vec<serde::de::IgnoredAny>,
+    }
+    serde_json::from_slice::<Metrics>(body).map_or(1, |q| q.metrics.len().max(1))
+}
+
+/// Sends every planned request to `endpoint` (relative to the backend URL) with bounded
+/// concurrency and returns the parsed JSON bodies that came back, with the sub-range each
+/// answers for and its metrics' indexes, and the failures of the backends that did not answer.
+pub(crate) async fn execute(
+    state: &AppState,
+    headers: &hyper::HeaderMap,
+    requests: Vec<BackendRequest>,
+    endpoint: &str,
+) -> (Vec<Answer>, Vec<BackendError>) {
+    let mut futs = FuturesUnordered::new();
+    for (i, request) in requests.into_iter().enumerate() {
+        let range = request.range;
+        let metrics = request.metrics.clone();
+        futs.push(async move {
+            (
+                i,
+                send(state, headers, request, endpoint)
+                    .await
+                    .map(|r| (range, metrics, r)),
+            )
+        });
+    }
+
+    let mut results = Vec::new();
+    let mut failures = Vec::new();
+    while let Some((i, res)) = futs.next().await {
+        match res {
+            Ok(res) => results.push((i, res)),
+            Err(e) => failures.push(e),
+        }
+    }
+    // Responses in plan order, whichever backend answered first
+    results.sort_by_key(|(i, _)| *i);
+    let results: Vec<_> = results.into_iter().map(|(_, r)| r).collect();
+    debug!("Received {} response(s) from backend(s)", results.len());
+    (results, failures)
+}
+
+/// Sends a single planned request, holding an outbound permit for its duration. Waiting for
+/// the permit and the request itself are both bounded by the client's deadline, if any.
+pub(crate) async fn send(
+    state: &AppState,
+    headers: &hyper::HeaderMap,
+    request: BackendRequest,
+    endpoint: &str,
+) -> Result<serde_json::Value, BackendError> {
+    let deadline = Deadline::current();
+    let target = &state.backends[request.backend];
+    let fail = |failure| BackendError::new(state, &target.name, failure);
+    let selected = target.select();
+    let url = selected.url.clone();
+    let mut body = request.body;
+    if let Some(tag_keys) = &target.tag_keys {
+        tag_keys.outbound(&mut body);
+    }
+    let original_names = target.name_prefix.as_ref().map(|p| p.outbound(&mut body));
+    let body = match serde_json::to_vec(&body) {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Failed to serialize backend payload: {}", e);
+            return Err(fail(Failure::Internal));
+        }
+    };
+
+    if let Err(wait) = state
+        .throttle(target, deadline.remaining().unwrap_or(target.timeout))
+        .await
+    {
+        return Err(fail(Failure::Throttled).with_retry_after(wait));
+    }
+
+    // Acquire permit for bounded concurrency
+    let permit = state.admission.acquire(Priority::from_headers(headers));
+    let _permit = match deadline.remaining() {
+        Some(left) => tokio::time::timeout(left, permit)
+            .await
+            .map_err(|_| fail(Failure::Timeout))?,
+        None => permit.await,
+    };
+    let Some(timeout) = deadline.outbound_timeout(target.timeout) else {
+        warn!("Client deadline passed before querying {}", url);
+        return Err(fail(Failure::Timeout));
+    };
+
+    // Build request URL using Url::join to avoid repeated parsing
+    let request_url = match target.endpoint_url(&url, endpoint) {
+        Ok(u) => u,
+        Err(e) => {
+            error!("Failed to build request URL: {}", e);
+            return Err(fail(Failure::Internal));
+        }
+    };
+
+    let body = Bytes::from(body);
+    let shadow = mirror_query(state, target, endpoint, body.clone(), headers);
+
+    let mut builder = target
+        .client
+        .get()
+        .post(request_url)
+        .timeout(timeout)
+        .header(hyper::http::header::CONTENT_TYPE, "application/json")
+        .header(hyper::http::header::ACCEPT, "application/json");
+    for (name, value) in headers.iter() {
+        // The payload is rebuilt per backend as JSON and the answer parsed as JSON, so inbound
+        // framing and content negotiation headers no longer apply. The client asks for gzip
+        // itself and decompresses the answer before it is merged.
+        if name == hyper::http::header::HOST
+            || name == hyper::http::header::CONTENT_LENGTH
+            || name == hyper::http::header::CONTENT_TYPE
+            || name == hyper::http::header::ACCEPT
+            || name == hyper::http::header::ACCEPT_ENCODING
+            || target.signer.as_ref().is_some_and(|s| s.owns(name))
+        {
+            continue;
+        }
+        builder = builder.header(name, value);
+    }
+    if let Some(t) = &selected.token {
+        builder = builder.header("Authorization", format!("Bearer {}", t));
+    }
+    if let Some(signer) = &target.signer {
+        builder = signer.sign(builder, &body);
+    }
+    builder = builder.body(body);
+    let started = Instant::now();
+    let sent = crate::chaos::send(state, target, builder, timeout).await;
+    let succeeded = AppState::succeeded(&sent);
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let backend = target.name.as_str();
+    debug_trace::backend(
+        backend,
+        started.elapsed(),
+        sent.as_ref().ok().map(|r| r.status().as_u16()),
+    );
+    let result = match sent {
+        Ok(r) if r.status().is_success() => {
+            debug!(
+                backend,
+                latency_ms,
+                "Backend {} answered {}",
+                url,
+                r.status()
+            );
+            match crate::memory::read_response(r).await {
+                Ok(body) => serde_json::from_slice::<serde_json::Value>(&body).map_err(|e| {
+                    error!(backend, "Cannot parse the answer of {}: {}", url, e);
+                    Failure::Invalid
+                }),
+                Err(ReadError::Exhausted) => {
+                    warn!(backend, "Answer of {} exceeds the memory budget", url);
+                    Err(Failure::MemoryBudget)
+                }
+                Err(ReadError::Body(e)) => {
+                    error!(backend, "Cannot read the answer of {}: {}", url, e);
+                    Err(match Failure::of(&e) {
+                        Failure::Timeout => Failure::Timeout,
+                        _ => Failure::Invalid,
+                    })
+                }
+            }
+        }
+        Ok(r) => {
+            error!(
+                backend,
+                latency_ms,
+                "Backend {} answered {}",
+                url,
+                r.status()
+            );
+            Err(Failure::Status(r.status().as_u16()))
+        }
+        Err(e) => {
+            error!(
+                backend,
+                latency_ms, "Backend request to {} failed: {}", url, e
+            );
+            Err(Failure::of(&e))
+        }
+    };
+    state.record_outcome(target, &selected, succeeded, started.elapsed());
+    let mut response = result.map_err(fail)?;
+    if let Some(shadow) = shadow {
+        crate::shadow::spawn(
+            state.metrics.clone(),
+            target.name.clone(),
+            response.clone(),
+            shadow,
+        );
+    }
+    if let Some(tag_keys) = &target.tag_keys {
+        tag_keys.inbound(&mut response);
+    }
+    if let Some(original) = &original_names {
+        crate::name_prefix::inbound(original, &mut response);
+    }
+    if let Some(tag) = &state.annotate_backend {
+        annotate(&mut response, tag, &target.name);
+    }
+    Ok(response)
+    // permit dropped here
+}
+
+/// Adds tag `tag` naming `backend` to every result of a response. Merged results list all
+/// the backends they came from.
+fn annotate(response: &mut serde_json::Value, tag: &str, backend: &str) {
+    let Some(queries) = response.get_mut("queries").and_then(|q| q.as_array_mut()) else {
+        return;
+    };
+    for query in queries {
+        let Some(results) = query.get_mut("results").and_then(|r| r.as_array_mut()) else {
+            continue;
+        };
+        for result in results.iter_mut().filter_map(|r| r.as_object_mut()) {
+            let tags = result
+                .entry("tags")
+                .or_insert_with(|| serde_json::Value::Object(Default::default()));
+            if let Some(tags) = tags.as_object_mut() {
+                tags.insert(tag.to_string(), serde_json::json!([backend]));
+            }
+        }
+    }
+}
+
+/// Merges backend responses into `{ "queries": [ { "results": [...] } ] }`, combining
+/// results that share a metric name by unioning their tags and concatenating their values.
+/// Results of different value types (numbers, strings, histograms) are kept apart, and
+/// histograms several backends return for the same timestamp are combined into one. The
+/// `sample_size` of the merged query (datapoints read) is the sum over the responses.
+pub(crate) fn merge(responses: Vec<serde_json::Value>) -> serde_json::Value {
+    // Map: (metric name, value type) -> Vec<result objects from all backends>
+    let mut metric_results: BTreeMap<(String, ValueType), Vec<serde_json::Value>> = BTreeMap::new();
+    let mut sample_size: Option<u64> = None;
+    for resp in responses.into_iter() {
+        if let Some(queries) = resp.get("queries").and_then(|q| q.as_array()) {
+            for query in queries {
+                if let Some(n) = query.get("sample_size").and_then(|n| n.as_u64()) {
+                    sample_size = Some(sample_size.unwrap_or(0) + n);
+                }
+                if let Some(results) = query.get("results").and_then(|r| r.as_array()) {
+                    for result in results {
+                        if let Some(name) = result.get("name").and_then(|v| v.as_str()) {
+                            metric_results
+                                .entry((name.to_string(), ValueType::of(result)))
+                                .or_default()
+                                .push(result.clone());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    // Merge tags and values for each metric
+    let mut merged_results = Vec::new();
+    for ((name, value_type), result_vec) in metric_results {
+        let merged_count = result_vec.len();
+        // Carries the value type, e.g. `{"name": "type", "type": "number"}`
+        let group_by = result_vec[0].get("group_by").cloned();
+        let mut merged_tags: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut merged_values: Vec<serde_json::Value> = Vec::new();
+        for result in result_vec {
+            // Merge tags
+            if let Some(tags) = result.get("tags").and_then(|t| t.as_object()) {
+                for (k, v) in tags {
+                    if let Some(arr) = v.as_array() {
+                        for val in arr {
+                            if let Some(s) = val.as_str() {
+                                let entry = merged_tags.entry(k.clone()).or_default();
+                                if !entry.contains(&s.to_string()) {
+                                    entry.push(s.to_string());
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            // Merge values
+            if let Some(values) = result.get("values").and_then(|v| v.as_array()) {
+                for v in values {
+                    merged_values.push(v.clone());
+                }
+            }
+        }
+        if merged_count > 1 {
+            // Pieces of a time-split query arrive in any order
+            merged_values.sort_by_key(|v| v.get(0).and_then(|t| t.as_i64()).unwrap_or(i64::MIN));
+            if value_type == ValueType::Histogram {
+                merged_values = combine_histograms(merged_values);
+            }
+        }
+        // Build merged result object
+        let mut merged_result = serde_json::Map::new();
+        merged_result.insert("name".to_string(), serde_json::Value::String(name));
+        // Insert merged tags
+        let tags_obj = merged_tags
+            .into_iter()
+            .map(|(k, v)| {
+                (
+                    k,
+                    serde_json::Value::Array(
+                        v.into_iter().map(serde_json::Value::String).collect(),
+                    ),
+                )
+            })
+            .collect();
+        merged_result.insert("tags".to_string(), serde_json::Value::Object(tags_obj));
+        if let Some(group_by) = group_by {
+            merged_result.insert("group_by".to_string(), group_by);
+        }
+        // Insert merged values
+        merged_result.insert(
+            "values".to_string(),
+            serde_json::Value::Array(merged_values),
+        );
+        merged_results.push(serde_json::Value::Object(merged_result));
+    }
+    // Build final response: { "queries": [ { "results": [ ... ] } ] }
+    let mut queries_arr = Vec::new();
+    let mut query_obj = serde_json::Map::new();
+    if let Some(n) = sample_size {
+        query_obj.insert("sample_size".to_string(), n.into());
+    }
+    query_obj.insert(
+        "results".to_string(),
+        serde_json::Value::Array(merged_results),
+    );
+    queries_arr.push(serde_json::Value::Object(query_obj));
+    let mut response = serde_json::Map::new();
+    response.insert("queries".to_string(), serde_json::Value::Array(queries_arr));
+    serde_json::Value::Object(response)
+}
+
+/// Kind of the values of a result, judged by its first value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ValueType {
+    Number,
+    String,
+    // `{"bins": {"<bound>": count, ...}, "min", "max", "sum", ...}`
+    Histogram,
+}
+
+impl ValueType {
+    fn of(result: &serde_json::Value) -> Self {
+        match result.pointer("/values/0/1") {
+            Some(serde_json::Value::Object(_)) => ValueType::Histogram,
+            Some(serde_json::Value::String(_)) => ValueType::String,
+            _ => ValueType::Number,
+        }
+    }
+}
+
+/// Combines histograms sharing a timestamp in time-sorted `values`: bin counts and sums add
+/// up, min and max widen, and the mean is recomputed from the combined sum and count.
+fn combine_histograms(values: Vec<serde_json::Value>) -> Vec<serde_json::Value> {
+    let mut combined: Vec<serde_json::Value> = Vec::with_capacity(values.len());
+    for value in values {
+        let into = combined
+            .last_mut()
+            .filter(|p| p.get(0) == value.get(0))
+            .and_then(|p| p.get_mut(1))
+            .and_then(|h| h.as_object_mut());
+        let (Some(into), Some(next)) = (into, value.get(1).and_then(|h| h.as_object())) else {
+            combined.push(value);
+            continue;
+        };
+        let number = |h: &serde_json::Map<String, serde_json::Value>, k: &str| {
+            h.get(k).and_then(|v| v.as_f64())
+        };
+        let bins = into
+            .entry("bins")
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+        if let (Some(bins), Some(more)) = (
+            bins.as_object_mut(),
+            next.get("bins").and_then(|b| b.as_object()),
+        ) {
+            for (bound, count) in more {
+                let sum = bins.get(bound).and_then(|c| c.as_u64()).unwrap_or(0)
+                    + count.as_u64().unwrap_or(0);
+                bins.insert(bound.clone(), sum.into());
+            }
+        }
+        let pairs = [
+            ("min", number(into, "min"), number(next, "min")),
+            ("max", number(into, "max"), number(next, "max")),
+            ("sum", number(into, "sum"), number(next, "sum")),
+        ];
+        for (key, a, b) in pairs {
+            let v = match (key, a, b) {
+                ("min", Some(a), Some(b)) => a.min(b),
+                ("max", Some(a), Some(b)) => a.max(b),
+                (_, Some(a), Some(b)) => a + b,
+                (_, a, b) => match a.or(b) {
+                    Some(v) => v,
+                    None => continue,
+                },
+            };
+            into.insert(key.to_string(), v.into());
+        }
+        let count: u64 = into
+            .get("bins")
+            .and_then(|b| b.as_object())
+            .map_or(0, |b| b.values().filter_map(|c| c.as_u64()).sum());
+        if let (Some(sum), true) = (number(into, "sum"), count > 0) {
+            into.insert("mean".to_string(), (sum / count as f64).into());
+        }
+    }
+    combined
+}
+
+/// Applies each metric's `limit` and `order` to merged results. Every backend honours the limit
+/// on its own, so the pieces merged together can hold up to that many points per backend.
+pub(crate) fn limit(query: &serde_json::Value, merged: &mut serde_json::Value) {
+    let Some(metrics) = query.get("metrics").and_then(|m| m.as_array()) else {
+        return;
+    };
+    let Some(results) = merged
+        .pointer_mut("/queries/0/results")
+        .and_then(|r| r.as_array_mut())
+    else {
+        return;
+    };
+    for metric in metrics {
+        let Some(name) = metric.get("name").and_then(|n| n.as_str()) else {
+            continue;
+        };
+        let limit = match metric.get("limit") {
+            Some(serde_json::Value::Number(n)) => n.as_u64(),
+            Some(serde_json::Value::String(s)) => s.parse().ok(),
+            _ => None,
+        };
+        let descending = metric.get("order").and_then(|o| o.as_str()) == Some("desc");
+        if limit.is_none() && !descending {
+            continue;
+        }
+        for result in results.iter_mut().filter(|r| r["name"] == name) {
+            let Some(values) = result.get_mut("values").and_then(|v| v.as_array_mut()) else {
+                continue;
+            };
+            if descending {
+                // Merging sorted pieces oldest first
+                values.sort_by_key(|v| {
+                    std::cmp::Reverse(v.get(0).and_then(|t| t.as_i64()).unwrap_or(i64::MIN))
+                });
+            }
+            if let Some(limit) = limit {
+                values.truncate(limit as usize);
+            }
+        }
+    }
+}
+
+/// Plans, executes and merges `query` against `endpoint`. Results come from the response
+/// cache when enabled, and concurrent calls with an identical query share a single fan-out.
+pub(crate) async fn run_query(
+    state: &Arc<AppState>,
+    headers: &hyper::HeaderMap,
+    query: &serde_json::Value,
+    endpoint: &str,
+) -> Result<serde_json::Value, QueryError> {
+    // Queries built by the proxy (Grafana, Graphite) have not been scoped yet
+    let scoped = crate::quota::scope_query(query).map_err(|e| {
+        warn!("Refused query: {}", e);
+        QueryError::Status(StatusCode::FORBIDDEN)
+    })?;
+    let query = scoped.as_ref().unwrap_or(query);
+    // Object keys serialize in sorted order, so equal queries give equal keys regardless of
+    // how the client ordered its fields
+    let key = format!("{} {}{}", endpoint, state.routing_key(headers), query);
+
+    if let Some(cache) = &state.cache {
+        match cache.get(&key).await {
+            Lookup::Fresh(value) => {
+                debug!("Serving query from cache");
+                record_cache(state, "hit");
+                return Ok(value);
+            }
+            Lookup::Stale { value, refresh } => {
+                debug!("Serving stale query from cache");
+                record_cache(state, "stale");
+                if refresh {
+                    spawn_refresh(state.clone(), headers.clone(), query.clone(), endpoint, key);
+                }
+                return Ok(value);
+            }
+            Lookup::Miss => record_cache(state, "miss"),
+        }
+    }
+
+    coalesced_fan_out(state, headers, query, endpoint, key)
+        .await
+        .map(|(merged, _)| merged)
+}
+
+fn record_cache(state: &AppState, result: &'static str) {
+    debug_trace::cache(result);
+    state
+        .metrics
+        .inc_counter("kairos_proxy_cache_requests_total", &[("result", result)]);
+}
+
+/// Repopulates a stale cache entry without holding up the client that found it.
+fn spawn_refresh(
+    state: Arc<AppState>,
+    mut headers: hyper::HeaderMap,
+    query: serde_json::Value,
+    endpoint: &str,
+    key: String,
+) {
+    // The refresh outlives the request, so the client's deadline no longer applies
+    headers.remove(TIMEOUT_MS_HEADER);
+    headers.remove(REQUEST_TIMEOUT_HEADER);
+    let endpoint = endpoint.to_string();
+    tokio::spawn(async move {
+        let refreshed = coalesced_fan_out(&state, &headers, &query, &endpoint, key.clone()).await;
+        if !matches!(refreshed, Ok((_, true))) {
+            warn!("Background refresh of a stale cache entry failed");
+            if let Some(cache) = &state.cache {
+                cache.refresh_failed(&key);
+            }
+        }
+    });
+}
+
+/// Runs the fan-out for `key` unless an identical one is already in flight, and caches
+/// complete results. The bool is `true` when every backend answered.
+async fn coalesced_fan_out(
+    state: &AppState,
+    headers: &hyper::HeaderMap,
+    query: &serde_json::Value,
+    endpoint: &str,
+    key: String,
+) -> Result<(serde_json::Value, bool), QueryError> {
+    let (result, shared) = state
+        .inflight
+        .run(key.clone(), || async {
+            let result = fan_out(state, headers, query, endpoint).await;
+            if let (Some(cache), Ok((merged, true))) = (&state.cache, &result) {
+                cache.insert(key, merged.clone()).await;
+            }
+            result
+        })
+        .await;
+    if shared {
+        debug!("Shared result of an identical in-flight query");
+        debug_trace::cache("coalesced");
+        state
+            .metrics
+            .inc_counter("kairos_proxy_coalesced_queries_total", &[]);
+    }
+    result
+}
+
+async fn fan_out(
+    state: &AppState,
+    headers: &hyper::HeaderMap,
+    query: &serde_json::Value,
+    endpoint: &str,
+) -> Result<(serde_json::Value, bool), QueryError> {
+    let requests = plan(state, headers, query)?;
+    crate::quota::charge_metrics(metric_count(query));
+    let strategies = merge_policy::strategies(state, headers, query);
+    let backend_count = requests.len();
+    let (responses, failures) = execute(state, headers, requests, endpoint).await;
+    // Partial results beat none; only a query no backend answered fails
+    if let (true, Some(failure)) = (responses.is_empty(), failures.into_iter().next()) {
+        return Err(failure.into());
+    }
+    // Partial results are returned to the client but never cached
+    let complete = responses.len() == backend_count;
+    let merging = Instant::now();
+    let mut responses = stitch(responses);
+    crate::merge_conflicts::report(state, &responses);
+    merge_policy::apply(state, &mut responses, &strategies)?;
+    let mut merged = merge(responses);
+    limit(query, &mut merged);
+    debug_trace::merge(merging.elapsed());
+    info!(
+        "Successfully merged responses from {} backend(s)",
+        backend_count
+    );
+    Ok((merged, complete))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn applies_the_limit_to_merged_pieces() {
+        let pieces = vec![
+            json!({ "queries": [{ "results": [
+                { "name": "cpu", "tags": {}, "values": [[3, 1], [4, 1]] },
+                { "name": "mem", "tags": {}, "values": [[1, 1], [2, 1]] }
+            ] }] }),
+            json!({ "queries": [{ "results": [
+                { "name": "cpu", "tags": {}, "values": [[1, 1], [2, 1]] }
+            ] }] }),
+        ];
+        let query = json!({ "metrics": [
+            { "name": "cpu", "limit": 3, "order": "desc" },
+            { "name": "mem", "limit": "5" }
+        ] });
+        let mut merged = merge(pieces);
+        limit(&query, &mut merged);
+        let results = &merged["queries"][0]["results"];
+        assert_eq!(results[0]["values"], json!([[4, 1], [3, 1], [2, 1]]));
+        assert_eq!(results[1]["values"], json!([[1, 1], [2, 1]]));
+    }
+
+    #[test]
+    fn sums_sample_sizes_of_backends_and_tier_pieces() {
+        let cold = TimeRange {
+            start_ms: 0,
+            end_ms: 99,
+        };
+        let hot = TimeRange {
+            start_ms: 100,
+            end_ms: 200,
+        };
+        let answer = |sample_size: u64, name: &str, ts: i64| json!({ "queries": [{ "sample_size": sample_size, "results": [{ "name": name, "tags": {}, "values": [[ts, 1]] }] }] });
+        // cpu is split across tiers; mem comes whole from another backend
+        let responses = stitch(vec![
+            (Some(hot), vec![0], answer(5, "cpu", 150)),
+            (Some(cold), vec![0], answer(7, "cpu", 50)),
+            (None, vec![1], answer(2, "mem", 10)),
+        ]);
+        let merged = merge(responses);
+        assert_eq!(merged["queries"][0]["sample_size"], 14);
+        assert_eq!(
+            merged["queries"][0]["results"][0]["values"],
+            json!([[50, 1], [150, 1]])
+        );
+
+        let no_sizes = merge(vec![json!({ "queries": [{ "results": [] }] })]);
+        assert!(no_sizes["queries"][0].get("sample_size").is_none());
+    }
+
+    #[test]
+    fn combines_histograms_and_keeps_value_types_apart() {
+        let histogram = |bins: serde_json::Value, min: f64, max: f64, sum: f64| json!({ "bins": bins, "min": min, "max": max, "sum": sum, "mean": 0.0 });
+        let group_by = json!([{ "name": "type", "type": "kairos_histogram_v2" }]);
+        let pieces = vec![
+            json!({ "queries": [{ "results": [
+                { "name": "lat", "group_by": group_by, "tags": {}, "values": [
+                    [1, histogram(json!({ "1.0": 2, "2.0": 1 }), 1.0, 2.0, 4.0)],
+                    [2, histogram(json!({ "1.0": 1 }), 1.0, 1.0, 1.0)]
+                ] },
+                { "name": "lat", "tags": {}, "values": [[1, 7]] }
+            ] }] }),
+            json!({ "queries": [{ "results": [
+                { "name": "lat", "group_by": group_by, "tags": {}, "values": [
+                    [1, histogram(json!({ "2.0": 1, "4.0": 1 }), 2.0, 4.0, 6.0)]
+                ] }
+            ] }] }),
+        ];
+        let merged = merge(pieces);
+        let results = merged["queries"][0]["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["values"], json!([[1, 7]]));
+        assert_eq!(results[1]["group_by"], group_by);
+        assert_eq!(
+            results[1]["values"],
+            json!([
+                [1, { "bins": { "1.0": 2, "2.0": 2, "4.0": 1 }, "min": 1.0, "max": 4.0, "sum": 10.0, "mean": 2.0 }],
+                [2, histogram(json!({ "1.0": 1 }), 1.0, 1.0, 1.0)]
+            ])
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! The original client address behind ingresses and load balancers.
+//!
+//! A request arriving from an address in `trusted_proxies` may say who it came from in
+//! `X-Forwarded-For`; its client is the right-most address there that is not itself a trusted
+//! proxy. Anyone else's `X-Forwarded-For` is ignored and the peer address is the client. The
+//! result is stored as a [`ClientIp`] request extension for access logs and client-based
+//! checks. On the way to the backends `X-Forwarded-For` gains the peer address (or is reset to
+//! it for untrusted peers, so clients cannot forge a history) and `Via` gains the proxy.
+
+use crate::state::AppState;
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use hyper::{Body, HeaderMap};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const VIA: &str = "1.1 kairos-proxy";
+
+/// Address of the client a request originates from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Parses the entries of the `key` setting: CIDR ranges such as `10.0.0.0/8`, or single
+/// addresses.
+pub fn parse_networks(key: &str, entries: &[String]) -> anyhow::Result<Vec<IpNet>> {
+    entries
+        .iter()
+        .map(|e| {
+            e.parse::<IpNet>()
+                .or_else(|_| e.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| anyhow::anyhow!("Invalid {} entry '{}'", key, e))
+        })
+        .collect()
+}
+
+pub fn contains(networks: &[IpNet], ip: IpAddr) -> bool {
+    networks.iter().any(|net| net.contains(&ip))
+}
+
+/// The addresses in `X-Forwarded-For`, nearest last; `None` if any entry is malformed.
+fn forwarded_for(headers: &HeaderMap) -> Option<Vec<IpAddr>> {
+    let mut hops = Vec::new();
+    for value in headers.get_all(X_FORWARDED_FOR) {
+        for hop in value.to_str().ok()?.split(',') {
+            let hop = hop.trim();
+            if hop.is_empty() {
+                continue;
+            }
+            // Some proxies append the port
+            let ip = hop
+                .parse::<IpAddr>()
+                .or_else(|_| hop.parse::<SocketAddr>().map(|a| a.ip()))
+                .ok()?;
+            hops.push(ip);
+        }
+    }
+    Some(hops)
+}
+
+/// The client a request from `peer` originates from.
+pub fn client_ip(proxies: &[IpNet], peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    if !contains(proxies, peer) {
+        return peer;
+    }
+    let Some(hops) = forwarded_for(headers) else {
+        return peer;
+    };
+    // Walk back through the trusted hops; if all are trusted the first one is the client
+    hops.iter()
+        .rev()
+        .find(|ip| !contains(proxies, **ip))
+        .or(hops.first())
+        .copied()
+        .unwrap_or(peer)
+}
+
+/// Records the client address and updates the forwarding headers for the backends.
+pub async fn layer(
+    State(state): State<Arc<AppState>>,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    // Missing when the service is driven without a listener, as in tests
+    let Some(peer) = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0.ip())
+    else {
+        return next.run(req).await;
+    };
+    let client = client_ip(&state.trusted_proxies, peer, req.headers());
+    req.extensions_mut().insert(ClientIp(client));
+
+    let headers = req.headers_mut();
+    let chain = match headers.get(X_FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+        Some(existing) if contains(&state.trusted_proxies, peer) => {
+            format!("{}, {}", existing, peer)
+        }
+        _ => peer.to_string(),
+    };
+    headers.remove(X_FORWARDED_FOR);
+    if let Ok(value) = HeaderValue::from_str(&chain) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+    let via = match headers
+        .get(hyper::header::VIA)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(existing) => format!("{}, {}", existing, VIA),
+        None => VIA.to_string(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&via) {
+        headers.insert(hyper::header::VIA, value);
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trusts_forwarded_for_only_from_trusted_proxies() {
+        let proxies = parse_networks(
+            "trusted_proxies",
+            &["10.0.0.0/8".to_string(), "192.0.2.1".to_string()],
+        )
+        .expect("proxies");
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            "198.51.100.7, 203.0.113.9:4711, 10.1.2.3".parse().unwrap(),
+        );
+
+        // The right-most untrusted hop; whatever is left of it the client may have made up
+        assert_eq!(
+            client_ip(&proxies, ip("10.0.0.5"), &headers),
+            ip("203.0.113.9")
+        );
+        // A client claiming to be someone else
+        assert_eq!(
+            client_ip(&proxies, ip("203.0.113.50"), &headers),
+            ip("203.0.113.50")
+        );
+        headers.insert(X_FORWARDED_FOR, "10.9.9.9".parse().unwrap());
+        assert_eq!(
+            client_ip(&proxies, ip("192.0.2.1"), &headers),
+            ip("10.9.9.9")
+        );
+        headers.insert(X_FORWARDED_FOR, "unknown".parse().unwrap());
+        assert_eq!(
+            client_ip(&proxies, ip("10.0.0.5"), &headers),
+            ip("10.0.0.5")
+        );
+        assert!(parse_networks("trusted_proxies", &["10.0.0.0/33".to_string()]).is_err());
+    }
+}
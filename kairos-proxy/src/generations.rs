@@ -0,0 +1,122 @@
+//! Generations of the routing config.
+//!
+//! The config loaded at startup is generation 1; every reload compiles a new routing table
+//! and adds the next generation. The last `config_history` generations stay in memory so a
+//! reload that breaks routing can be rolled back without the old file. Requests read the
+//! current generation once and keep it for their whole routing decision.
+
+use crate::routes::Route;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+
+pub struct Generation {
+    pub number: u64,
+    /// The config it was loaded from, credentials redacted.
+    pub config: Value,
+    pub routes: Vec<Route>,
+    /// Metric names refused before routing.
+    pub blocked: Vec<Regex>,
+}
+
+struct Inner {
+    // Oldest first
+    kept: VecDeque<Arc<Generation>>,
+    current: Arc<Generation>,
+}
+
+pub struct Generations {
+    inner: RwLock<Inner>,
+    keep: usize,
+}
+
+impl Generations {
+    pub fn new(config: Value, routes: Vec<Route>, blocked: Vec<Regex>, keep: usize) -> Self {
+        let first = Arc::new(Generation {
+            number: 1,
+            config,
+            routes,
+            blocked,
+        });
+        Generations {
+            inner: RwLock::new(Inner {
+                kept: VecDeque::from([first.clone()]),
+                current: first,
+            }),
+            keep: keep.max(1),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Generation> {
+        self.inner
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .current
+            .clone()
+    }
+
+    /// Numbers of the generations kept, oldest first.
+    pub fn kept(&self) -> Vec<u64> {
+        let inner = self.inner.read().unwrap_or_else(|e| e.into_inner());
+        inner.kept.iter().map(|g| g.number).collect()
+    }
+
+    /// Makes a newly loaded config current and returns its generation number.
+    pub fn push(&self, config: Value, routes: Vec<Route>, blocked: Vec<Regex>) -> u64 {
+        let mut inner = self.inner.write().unwrap_or_else(|e| e.into_inner());
+        let number = inner.kept.back().map_or(0, |g| g.number) + 1;
+        let generation = Arc::new(Generation {
+            number,
+            config,
+            routes,
+            blocked,
+        });
+        inner.kept.push_back(generation.clone());
+        while inner.kept.len() > self.keep {
+            inner.kept.pop_front();
+        }
+        inner.current = generation;
+        number
+    }
+
+    /// Makes generation `to`, or the one before the current one, current again.
+    pub fn rollback(&self, to: Option<u64>) -> Result<u64, String> {
+        let mut inner = self.inner.write().unwrap_or_else(|e| e.into_inner());
+        let current = inner.current.number;
+        let target = match to {
+            Some(n) => inner.kept.iter().find(|g| g.number == n),
+            None => inner.kept.iter().rev().find(|g| g.number < current),
+        };
+        let Some(target) = target.cloned() else {
+            return Err(match to {
+                Some(n) => format!("generation {} is not kept", n),
+                None => format!("no generation before {} is kept", current),
+            });
+        };
+        inner.current = target;
+        Ok(inner.current.number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn keeps_the_last_generations_and_rolls_back() {
+        let generations = Generations::new(json!(1), Vec::new(), Vec::new(), 2);
+        assert_eq!(generations.push(json!(2), Vec::new(), Vec::new()), 2);
+        assert_eq!(generations.push(json!(3), Vec::new(), Vec::new()), 3);
+        assert_eq!(generations.kept(), [2, 3]);
+
+        assert_eq!(generations.rollback(None), Ok(2));
+        assert_eq!(generations.current().config, json!(2));
+        assert!(generations.rollback(None).is_err());
+        assert!(generations.rollback(Some(1)).is_err());
+        assert_eq!(generations.rollback(Some(3)), Ok(3));
+        // Reloading after a rollback still numbers generations upwards
+        assert_eq!(generations.push(json!(4), Vec::new(), Vec::new()), 4);
+    }
+}
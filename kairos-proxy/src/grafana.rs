@@ -0,0 +1,306 @@
+//! Endpoints compatible with Grafana's SimpleJSON / JSON datasource plugins.
+//!
+//! `/search` lists metric names across all backends, `/query` translates panel targets into a
+//! routed KairosDB query, and `/annotations` answers with an empty list since KairosDB has
+//! no annotation store.
+
+use crate::admission::Priority;
+use crate::deadline::Deadline;
+use crate::fanout;
+use crate::proxy::read_body;
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::{stream::FuturesUnordered, StreamExt};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tracing::{debug, error, warn};
+
+const QUERY_ENDPOINT: &str = "api/v1/datapoints/query";
+const METRIC_NAMES_ENDPOINT: &str = "api/v1/metricnames";
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct SearchRequest {
+    #[serde(default)]
+    target: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Range {
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct QueryTarget {
+    target: String,
+    #[serde(default, rename = "type")]
+    kind: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct QueryRequest {
+    range: Range,
+    #[serde(default)]
+    targets: Vec<QueryTarget>,
+}
+
+/// Parses an RFC 3339 UTC timestamp as sent by Grafana (`2024-01-31T06:33:44.866Z`)
+/// into epoch milliseconds. Numeric offsets are honoured; leap seconds are not.
+pub(crate) fn rfc3339_to_millis(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let (date, time) = s.split_once(['T', 't', ' '])?;
+    let mut d = date.splitn(3, '-');
+    let year: i64 = d.next()?.parse().ok()?;
+    let month: i64 = d.next()?.parse().ok()?;
+    let day: i64 = d.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (clock, offset_mins) = if let Some(c) = time.strip_suffix(['Z', 'z']) {
+        (c, 0)
+    } else {
+        let idx = time.rfind(['+', '-'])?;
+        let (c, off) = time.split_at(idx);
+        let sign = if off.starts_with('-') { -1 } else { 1 };
+        let (h, m) = off[1..].split_once(':')?;
+        let (h, m) = (h.parse::<i64>().ok()?, m.parse::<i64>().ok()?);
+        if !(0..24).contains(&h) || !(0..60).contains(&m) {
+            return None;
+        }
+        (c, sign * (h * 60 + m))
+    };
+    let (hms, frac) = clock.split_once('.').unwrap_or((clock, ""));
+    let mut t = hms.splitn(3, ':');
+    let hour: i64 = t.next()?.parse().ok()?;
+    let minute: i64 = t.next()?.parse().ok()?;
+    let second: i64 = t.next()?.parse().ok()?;
+    if !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0..=60).contains(&second) {
+        return None;
+    }
+    let millis: i64 = if frac.is_empty() {
+        0
+    } else {
+        format!("{:0<3}", &frac[..frac.len().min(3)]).parse().ok()?
+    };
+
+    // Days since the epoch for a proleptic Gregorian date (Howard Hinnant's algorithm)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    // Years far outside the epoch overflow rather than wrap
+    let days = era.checked_mul(146_097)?.checked_add(doe - 719_468)?;
+
+    let secs = days
+        .checked_mul(86_400)?
+        .checked_add(hour * 3_600 + minute * 60 + second - offset_mins * 60)?;
+    secs.checked_mul(1_000)?.checked_add(millis)
+}
+
+/// Converts one merged KairosDB result into the Grafana timeserie or table shape.
+fn to_grafana(
+    target: &str,
+    kind: Option<&str>,
+    result: Option<&serde_json::Value>,
+) -> serde_json::Value {
+    let values = result
+        .and_then(|r| r.get("values"))
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    if kind == Some("table") {
+        return json!({
+            "type": "table",
+            "columns": [{ "text": "Time", "type": "time" }, { "text": target, "type": "number" }],
+            "rows": values,
+        });
+    }
+    let datapoints: Vec<serde_json::Value> = values
+        .iter()
+        .filter_map(|pair| Some(json!([pair.get(1)?, pair.get(0)?])))
+        .collect();
+    json!({ "target": target, "datapoints": datapoints })
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(
+    state: &AppState,
+    req: Request<Body>,
+) -> Result<T, StatusCode> {
+    let mut req = req;
+    let body_bytes = match read_body(state, &mut req).await {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Failed to read request body: {:?}", e);
+            return Err(e);
+        }
+    };
+    if let Err(rejection) = state.query_checks.json_limits.check(&body_bytes) {
+        warn!("Refused Grafana request: {:?}", rejection);
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    serde_json::from_slice(&body_bytes).map_err(|e| {
+        warn!("Failed to parse Grafana request: {}", e);
+        StatusCode::BAD_REQUEST
+    })
+}
+
+/// `POST /search`: returns the union of metric names known to all backends, filtered
+/// by the (substring) search target.
+pub async fn grafana_search_handler(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    debug!("Received grafana search request");
+    let deadline = Deadline::current();
+    let search: SearchRequest = read_json(&state, req).await?;
+    let timeout = deadline
+        .outbound_timeout(state.timeout)
+        .ok_or(StatusCode::GATEWAY_TIMEOUT)?;
+
+    // Several routing rules may share a backend; ask each distinct URL once
+    let mut urls: BTreeMap<(reqwest::Url, Option<String>), reqwest::Client> = BTreeMap::new();
+    for b in state.backends.iter().filter(|b| !b.is_draining()) {
+        let Ok(url) = b.endpoint_url(&b.url, METRIC_NAMES_ENDPOINT) else {
+            continue;
+        };
+        urls.entry((url, b.token.get()))
+            .or_insert_with(|| b.client.get());
+    }
+    let mut futs = FuturesUnordered::new();
+    for ((url, token), client) in urls {
+        let admission = state.admission.clone();
+        futs.push(async move {
+            let _permit = admission.acquire(Priority::Interactive).await;
+            let mut builder = client.get(url.clone()).timeout(timeout);
+            if let Some(t) = token {
+                builder = builder.header("Authorization", format!("Bearer {}", t));
+            }
+            match builder.send().await {
+                Ok(r) => r.json::<serde_json::Value>().await.ok(),
+                Err(e) => {
+                    error!("Metric name request to {} failed: {}", url, e);
+                    None
+                }
+            }
+        });
+    }
+
+    let mut names = BTreeSet::new();
+    while let Some(res) = futs.next().await {
+        let Some(res) = res else { continue };
+        if let Some(results) = res.get("results").and_then(|r| r.as_array()) {
+            names.extend(
+                results
+                    .iter()
+                    .filter_map(|n| n.as_str())
+                    .filter(|n| n.contains(&search.target))
+                    .map(str::to_string),
+            );
+        }
+    }
+    Ok(Json(names).into_response())
+}
+
+/// `POST /query`: runs each panel target as a KairosDB metric over the requested range.
+pub async fn grafana_query_handler(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    debug!("Received grafana query request");
+    let headers = req.headers().clone();
+    let request: QueryRequest = read_json(&state, req).await?;
+    let (Some(start), Some(end)) = (
+        rfc3339_to_millis(&request.range.from),
+        rfc3339_to_millis(&request.range.to),
+    ) else {
+        warn!("Invalid Grafana time range: {:?}", request.range);
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let targets: Vec<&QueryTarget> = request
+        .targets
+        .iter()
+        .filter(|t| !t.target.is_empty())
+        .collect();
+    if targets.is_empty() {
+        return Ok(Json(json!([])).into_response());
+    }
+
+    let query = json!({
+        "start_absolute": start,
+        "end_absolute": end,
+        "metrics": targets.iter().map(|t| json!({ "name": t.target })).collect::<Vec<_>>(),
+    });
+    let merged = match fanout::run_query(&state, &headers, &query, QUERY_ENDPOINT).await {
+        Ok(v) => v,
+        Err(e) => return Ok(e.into_response()),
+    };
+    let results = merged
+        .pointer("/queries/0/results")
+        .and_then(|r| r.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let out: Vec<serde_json::Value> = targets
+        .iter()
+        .map(|t| {
+            let result = results
+                .iter()
+                .find(|r| r.get("name").and_then(|n| n.as_str()) == Some(t.target.as_str()));
+            to_grafana(&t.target, t.kind.as_deref(), result)
+        })
+        .collect();
+    Ok(Json(out).into_response())
+}
+
+/// `POST /annotations`: KairosDB has no annotations, so there is never anything to return.
+pub async fn grafana_annotations_handler() -> impl IntoResponse {
+    Json(json!([]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_grafana_timestamps() {
+        assert_eq!(rfc3339_to_millis("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(
+            rfc3339_to_millis("2016-10-31T06:33:44.866Z"),
+            Some(1_477_895_624_866)
+        );
+        assert_eq!(
+            rfc3339_to_millis("2016-10-31T08:33:44.866+02:00"),
+            Some(1_477_895_624_866)
+        );
+        assert_eq!(rfc3339_to_millis("yesterday"), None);
+        assert_eq!(
+            rfc3339_to_millis("9223372036854775807-01-01T00:00:00Z"),
+            None
+        );
+        assert_eq!(rfc3339_to_millis("2016-10-31T99:00:00Z"), None);
+    }
+
+    #[test]
+    fn converts_results_to_timeserie_and_table() {
+        let result = json!({ "name": "cpu", "values": [[1000, 1.5], [2000, 2.5]] });
+        assert_eq!(
+            to_grafana("cpu", None, Some(&result)),
+            json!({ "target": "cpu", "datapoints": [[1.5, 1000], [2.5, 2000]] })
+        );
+        let table = to_grafana("cpu", Some("table"), Some(&result));
+        assert_eq!(table["rows"], json!([[1000, 1.5], [2000, 2.5]]));
+        assert_eq!(
+            to_grafana("mem", None, None),
+            json!({ "target": "mem", "datapoints": [] })
+        );
+    }
+}
@@ -0,0 +1,367 @@
+//! Graphite `/render` translation: each simple target expression is turned into a KairosDB
+//! query of its own, routed and merged like any Multi-mode query, and rendered as Graphite
+//! JSON. Targets that read the same metric differently (e.g. raw and summarized) thus keep
+//! their own results.
+//!
+//! Supported expressions: plain metric paths, `alias(expr, "name")` and
+//! `summarize(expr, "interval", "func")`. Wildcards are rejected because KairosDB
+//! cannot expand metric name globs.
+
+use crate::fanout;
+use crate::proxy::read_body;
+use crate::state::AppState;
+use crate::timerange::kairos_unit;
+use axum::{
+    body::Body,
+    extract::{RawQuery, State},
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, warn};
+
+const QUERY_ENDPOINT: &str = "api/v1/datapoints/query";
+
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Path(String),
+    Str(String),
+    Call(String, Vec<Expr>),
+}
+
+/// A target resolved to the KairosDB metric it reads and how it should be presented.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Target {
+    pub metric: String,
+    pub display: String,
+    pub aggregators: Vec<serde_json::Value>,
+}
+
+fn parse_expr(input: &str) -> Result<(Expr, &str), String> {
+    let input = input.trim_start();
+    if let Some(quote) = input.chars().next().filter(|c| *c == '"' || *c == '\'') {
+        let rest = &input[1..];
+        let end = rest
+            .find(quote)
+            .ok_or_else(|| format!("unterminated string in: {}", input))?;
+        return Ok((Expr::Str(rest[..end].to_string()), &rest[end + 1..]));
+    }
+    let end = input
+        .find(|c: char| matches!(c, '(' | ')' | ',') || c.is_whitespace())
+        .unwrap_or(input.len());
+    let ident = &input[..end];
+    if ident.is_empty() {
+        return Err(format!("expected expression at: {:?}", input));
+    }
+    let rest = input[end..].trim_start();
+    let Some(mut rest) = rest.strip_prefix('(') else {
+        return Ok((Expr::Path(ident.to_string()), rest));
+    };
+    let mut args = Vec::new();
+    loop {
+        if let Some(r) = rest.trim_start().strip_prefix(')') {
+            return Ok((Expr::Call(ident.to_string(), args), r));
+        }
+        let (arg, r) = parse_expr(rest)?;
+        args.push(arg);
+        let r = r.trim_start();
+        rest = match r.strip_prefix(',') {
+            Some(r) => r,
+            None if r.starts_with(')') => r,
+            None => return Err(format!("expected ',' or ')' in call to {}", ident)),
+        };
+    }
+}
+
+/// Parses an interval such as `10min` into KairosDB's `{ value, unit }` form.
+fn parse_interval(s: &str) -> Result<serde_json::Value, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("interval is missing a unit: {}", s))?;
+    let value: u64 = s[..split]
+        .parse()
+        .map_err(|_| format!("invalid interval: {}", s))?;
+    let unit = kairos_unit(&s[split..]).ok_or_else(|| format!("unknown time unit in: {}", s))?;
+    Ok(json!({ "value": value, "unit": unit }))
+}
+
+fn resolve(expr: Expr) -> Result<Target, String> {
+    match expr {
+        Expr::Path(p) => {
+            if p.contains(['*', '?', '[', '{']) {
+                return Err(format!("wildcards are not supported: {}", p));
+            }
+            Ok(Target {
+                display: p.clone(),
+                metric: p,
+                aggregators: Vec::new(),
+            })
+        }
+        Expr::Str(s) => Err(format!("expected a series, got string {:?}", s)),
+        Expr::Call(name, args) => {
+            let mut args = args.into_iter();
+            let series = args
+                .next()
+                .ok_or_else(|| format!("{} requires a series argument", name))?;
+            let mut target = resolve(series)?;
+            let mut string_arg = || match args.next() {
+                Some(Expr::Str(s)) => Ok(Some(s)),
+                Some(other) => Err(format!("{}: expected a string, got {:?}", name, other)),
+                None => Ok(None),
+            };
+            match name.as_str() {
+                "alias" => {
+                    target.display =
+                        string_arg()?.ok_or("alias requires a name argument".to_string())?;
+                }
+                "summarize" => {
+                    let interval =
+                        string_arg()?.ok_or("summarize requires an interval".to_string())?;
+                    let func = string_arg()?.unwrap_or_else(|| "sum".to_string());
+                    let agg = match func.as_str() {
+                        "sum" | "max" | "min" | "last" | "count" => func.clone(),
+                        "avg" | "average" => "avg".to_string(),
+                        other => return Err(format!("unsupported summarize function: {}", other)),
+                    };
+                    target.aggregators.push(json!({
+                        "name": agg,
+                        "sampling": parse_interval(&interval)?,
+                    }));
+                    target.display = format!(
+                        "summarize({}, \"{}\", \"{}\")",
+                        target.display, interval, func
+                    );
+                }
+                other => return Err(format!("unsupported function: {}", other)),
+            }
+            Ok(target)
+        }
+    }
+}
+
+/// Parses one Graphite target expression.
+pub(crate) fn parse_target(input: &str) -> Result<Target, String> {
+    let (expr, rest) = parse_expr(input)?;
+    if !rest.trim().is_empty() {
+        return Err(format!("unexpected trailing input: {}", rest));
+    }
+    resolve(expr)
+}
+
+/// Translates a Graphite `from`/`until` value into the matching KairosDB time field.
+/// Returns `None` for `now`, which KairosDB expresses by omitting the end time.
+fn time_field(value: &str, prefix: &str) -> Result<Option<(String, serde_json::Value)>, String> {
+    let value = value.trim();
+    if value == "now" {
+        return Ok(None);
+    }
+    if let Some(rel) = value.strip_prefix('-') {
+        return Ok(Some((format!("{}_relative", prefix), parse_interval(rel)?)));
+    }
+    match value.parse::<i64>() {
+        Ok(secs) => {
+            let ms = secs
+                .checked_mul(1000)
+                .ok_or_else(|| format!("time out of range: {}", value))?;
+            Ok(Some((format!("{}_absolute", prefix), json!(ms))))
+        }
+        Err(_) => Err(format!("unsupported time format: {}", value)),
+    }
+}
+
+/// Builds the KairosDB query for a set of targets and a time range.
+pub(crate) fn build_query(
+    targets: &[Target],
+    from: &str,
+    until: &str,
+) -> Result<serde_json::Value, String> {
+    let mut query = serde_json::Map::new();
+    match time_field(from, "start")? {
+        Some((k, v)) => {
+            query.insert(k, v);
+        }
+        None => return Err("'from' cannot be now".into()),
+    }
+    if let Some((k, v)) = time_field(until, "end")? {
+        query.insert(k, v);
+    }
+    let metrics = targets
+        .iter()
+        .map(|t| {
+            let mut m = json!({ "name": t.metric });
+            if !t.aggregators.is_empty() {
+                m["aggregators"] = json!(t.aggregators);
+            }
+            m
+        })
+        .collect();
+    query.insert("metrics".into(), serde_json::Value::Array(metrics));
+    Ok(serde_json::Value::Object(query))
+}
+
+/// Renders merged KairosDB responses as Graphite JSON (`[value, epoch_seconds]` pairs), each
+/// target from the response to its own query (`answers` in target order).
+pub(crate) fn render(targets: &[Target], answers: &[serde_json::Value]) -> serde_json::Value {
+    let series = targets
+        .iter()
+        .zip(answers)
+        .map(|(t, merged)| {
+            let datapoints: Vec<serde_json::Value> = merged
+                .pointer("/queries/0/results")
+                .and_then(|r| r.as_array())
+                .into_iter()
+                .flatten()
+                .find(|r| r.get("name").and_then(|n| n.as_str()) == Some(t.metric.as_str()))
+                .and_then(|r| r.get("values"))
+                .and_then(|v| v.as_array())
+                .map(|values| {
+                    values
+                        .iter()
+                        .filter_map(|pair| {
+                            let ts = pair.get(0)?.as_i64()?;
+                            Some(json!([pair.get(1)?, ts / 1000]))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            json!({ "target": t.display, "datapoints": datapoints })
+        })
+        .collect();
+    serde_json::Value::Array(series)
+}
+
+fn bad_request(msg: String) -> Response {
+    warn!("Rejecting render request: {}", msg);
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": msg }))).into_response()
+}
+
+/// Graphite-compatible `/render` endpoint (GET with query string or POST with a form body).
+pub async fn graphite_render_handler(
+    State(state): State<Arc<AppState>>,
+    RawQuery(query): RawQuery,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    debug!("Received graphite render request");
+
+    let mut req = req;
+    let body_bytes = match read_body(&state, &mut req).await {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Failed to read request body: {:?}", e);
+            return Err(e);
+        }
+    };
+
+    let mut targets = Vec::new();
+    let mut from = "-24h".to_string();
+    let mut until = "now".to_string();
+    let mut format = "json".to_string();
+    let pairs = form_urlencoded::parse(query.as_deref().unwrap_or_default().as_bytes())
+        .chain(form_urlencoded::parse(&body_bytes));
+    for (k, v) in pairs {
+        match k.as_ref() {
+            "target" => match parse_target(&v) {
+                Ok(t) => targets.push(t),
+                Err(e) => return Ok(bad_request(e)),
+            },
+            "from" => from = v.into_owned(),
+            "until" => until = v.into_owned(),
+            "format" => format = v.into_owned(),
+            _ => {}
+        }
+    }
+    if format != "json" {
+        return Ok(bad_request(format!("unsupported format: {}", format)));
+    }
+    if targets.is_empty() {
+        return Ok(Json(json!([])).into_response());
+    }
+
+    let queries = match targets
+        .iter()
+        .map(|t| build_query(std::slice::from_ref(t), &from, &until))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(q) => q,
+        Err(e) => return Ok(bad_request(e)),
+    };
+    let headers = hyper::HeaderMap::new();
+    let answers = match futures::future::try_join_all(
+        queries
+            .iter()
+            .map(|q| fanout::run_query(&state, &headers, q, QUERY_ENDPOINT)),
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(e) => return Ok(e.into_response()),
+    };
+    Ok(Json(render(&targets, &answers)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_functions() {
+        let t =
+            parse_target("alias(summarize(cpu.load, \"10min\", \"avg\"), 'load')").expect("parse");
+        assert_eq!(t.metric, "cpu.load");
+        assert_eq!(t.display, "load");
+        assert_eq!(
+            t.aggregators,
+            vec![json!({ "name": "avg", "sampling": { "value": 10, "unit": "minutes" } })]
+        );
+    }
+
+    #[test]
+    fn rejects_wildcards_and_unknown_functions() {
+        assert!(parse_target("servers.*.cpu").is_err());
+        assert!(parse_target("movingAverage(cpu.load, 5)").is_err());
+        assert!(parse_target("alias(cpu.load").is_err());
+    }
+
+    #[test]
+    fn builds_query_and_renders_graphite_json() {
+        let targets = vec![parse_target("cpu.load").unwrap()];
+        let q = build_query(&targets, "-2h", "1700000000").expect("query");
+        assert_eq!(q["start_relative"], json!({ "value": 2, "unit": "hours" }));
+        assert_eq!(q["end_absolute"], 1_700_000_000_000i64);
+        assert_eq!(q["metrics"][0]["name"], "cpu.load");
+
+        let merged = json!({ "queries": [{ "results": [
+            { "name": "cpu.load", "tags": {}, "values": [[1700000000000i64, 1.5]] }
+        ]}]});
+        let out = render(&targets, &[merged]);
+        assert_eq!(
+            out,
+            json!([{ "target": "cpu.load", "datapoints": [[1.5, 1700000000]] }])
+        );
+    }
+
+    #[test]
+    fn renders_each_target_from_its_own_query() {
+        let targets = vec![
+            parse_target("cpu.load").unwrap(),
+            parse_target("summarize(cpu.load, \"1h\", \"max\")").unwrap(),
+        ];
+        let answer = |v: i64| {
+            json!({ "queries": [{ "results": [
+                { "name": "cpu.load", "tags": {}, "values": [[1700000000000i64, v]] }
+            ]}]})
+        };
+        let out = render(&targets, &[answer(1), answer(9)]);
+        assert_eq!(out[0]["datapoints"], json!([[1, 1700000000]]));
+        assert_eq!(out[1]["datapoints"], json!([[9, 1700000000]]));
+    }
+
+    #[test]
+    fn rejects_absolute_times_out_of_range() {
+        let targets = vec![parse_target("cpu.load").unwrap()];
+        assert!(build_query(&targets, &i64::MAX.to_string(), "now").is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! The standard gRPC health-checking protocol (`grpc.health.v1.Health`).
+//!
+//! With `grpc_health_listen` set, an HTTP/2 listener on that address answers `Check` for the
+//! service names `""` and `kairos-proxy` with `SERVING` while the proxy is ready (see
+//! `AppState::is_ready`) and `NOT_SERVING` otherwise, for meshes and load balancers that only
+//! speak gRPC health checks. Other names get `NOT_FOUND`; `Watch` is not implemented. The two
+//! messages involved are small enough to encode by hand; request bodies over
+//! `MAX_REQUEST_BYTES` get `RESOURCE_EXHAUSTED` without being read further.
+
+use crate::state::AppState;
+use bytes::{Buf, Bytes};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, HeaderMap, Request, Response};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{debug, error, info};
+
+const CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+const SERVICE_NAMES: [&str; 2] = ["", "kairos-proxy"];
+// A framed `HealthCheckRequest` is a few bytes plus the service name
+const MAX_REQUEST_BYTES: usize = 4096;
+
+// `HealthCheckResponse.ServingStatus`
+const SERVING: u8 = 1;
+const NOT_SERVING: u8 = 2;
+
+// gRPC status codes
+const OK: u8 = 0;
+const NOT_FOUND: u8 = 5;
+const RESOURCE_EXHAUSTED: u8 = 8;
+const UNIMPLEMENTED: u8 = 12;
+const INTERNAL: u8 = 13;
+
+/// Serves health checks on `addr` until the process exits.
+pub fn spawn(state: Arc<AppState>, addr: SocketAddr) {
+    tokio::spawn(async move {
+        let make_service = make_service_fn(move |_| {
+            let state = state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let state = state.clone();
+                    async move { Ok::<_, Infallible>(handle(&state, req).await) }
+                }))
+            }
+        });
+        let server = match hyper::Server::try_bind(&addr) {
+            Ok(builder) => builder.http2_only(true).serve(make_service),
+            Err(e) => {
+                error!("Cannot listen for gRPC health checks on {}: {}", addr, e);
+                return;
+            }
+        };
+        info!("Serving gRPC health checks on {}", addr);
+        if let Err(e) = server.await {
+            error!("gRPC health server failed: {}", e);
+        }
+    });
+}
+
+async fn handle(state: &AppState, req: Request<Body>) -> Response<Body> {
+    if req.uri().path() != CHECK_PATH {
+        return reply(UNIMPLEMENTED, None);
+    }
+    let limited = http_body::Limited::new(req.into_body(), MAX_REQUEST_BYTES);
+    let body = match hyper::body::to_bytes(limited).await {
+        Ok(body) => body,
+        Err(e) if e.is::<http_body::LengthLimitError>() => {
+            return reply(RESOURCE_EXHAUSTED, None);
+        }
+        Err(_) => return reply(INTERNAL, None),
+    };
+    let Some(service) = service_name(body) else {
+        return reply(INTERNAL, None);
+    };
+    if !SERVICE_NAMES.contains(&service.as_str()) {
+        return reply(NOT_FOUND, None);
+    }
+    let status = if state.is_ready() {
+        SERVING
+    } else {
+        NOT_SERVING
+    };
+    debug!("gRPC health check for '{}': {}", service, status);
+    // One uncompressed message: `HealthCheckResponse { status }`, field 1 as a varint
+    reply(OK, Some(Bytes::from(vec![0, 0, 0, 0, 2, 0x08, status])))
+}
+
+/// Reads `HealthCheckRequest.service` (field 1) from a gRPC-framed request body.
+fn service_name(mut body: Bytes) -> Option<String> {
+    if body.is_empty() {
+        return Some(String::new());
+    }
+    if body.len() < 5 || body[0] != 0 {
+        return None;
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    body.advance(5);
+    let mut message = body.get(..len)?;
+    let mut service = String::new();
+    while !message.is_empty() {
+        let key = varint(&mut message)?;
+        match key & 7 {
+            0 => {
+                varint(&mut message)?;
+            }
+            2 => {
+                let n = varint(&mut message)? as usize;
+                let field = message.get(..n)?;
+                if key >> 3 == 1 {
+                    service = String::from_utf8(field.to_vec()).ok()?;
+                }
+                message = &message[n..];
+            }
+            _ => return None,
+        }
+    }
+    Some(service)
+}
+
+fn varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first()?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// A gRPC response: `message` as the body, `status` in the trailers.
+fn reply(status: u8, message: Option<Bytes>) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if let Some(message) = message {
+            if sender.send_data(message).await.is_err() {
+                return;
+            }
+        }
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", u16::from(status).into());
+        let _ = sender.send_trailers(trailers).await;
+    });
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/grpc")
+        .body(body)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, Config};
+    use hyper::body::HttpBody;
+
+    #[tokio::test]
+    async fn answers_check_with_the_readiness() {
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: ".*".to_string(),
+                url: "http://127.0.0.1:9".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .expect("port")
+            .port();
+        let addr: SocketAddr = ([127, 0, 0, 1], port).into();
+        spawn(state.clone(), addr);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let client = hyper::Client::builder()
+            .http2_only(true)
+            .build_http::<Body>();
+        let check = |service: &'static str| {
+            let mut message = vec![0x0a, service.len() as u8];
+            message.extend_from_slice(service.as_bytes());
+            let mut framed = vec![0, 0, 0, 0, message.len() as u8];
+            framed.extend(message);
+            let req = Request::post(format!("http://{}{}", addr, CHECK_PATH))
+                .header("content-type", "application/grpc")
+                .header("te", "trailers")
+                .body(Body::from(framed))
+                .unwrap();
+            let client = client.clone();
+            async move {
+                let mut body = client.request(req).await.expect("response").into_body();
+                let mut data = Vec::new();
+                while let Some(chunk) = body.data().await {
+                    data.extend_from_slice(&chunk.expect("chunk"));
+                }
+                let trailers = body.trailers().await.expect("trailers").expect("some");
+                (data, trailers["grpc-status"].to_str().unwrap().to_string())
+            }
+        };
+
+        assert_eq!(
+            check("kairos-proxy").await,
+            (vec![0, 0, 0, 0, 2, 0x08, SERVING], "0".to_string())
+        );
+        state.backends[0].set_draining(true);
+        assert_eq!(check("").await.0, vec![0, 0, 0, 0, 2, 0x08, NOT_SERVING]);
+        assert_eq!(check("other").await, (vec![], "5".to_string()));
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_requests() {
+        let state = AppState::from_config(&Config::default()).expect("state");
+        let req = Request::post(CHECK_PATH)
+            .body(Body::from(vec![0u8; MAX_REQUEST_BYTES + 1]))
+            .unwrap();
+        let mut body = handle(&state, req).await.into_body();
+        while body.data().await.is_some() {}
+        let trailers = body.trailers().await.expect("trailers").expect("some");
+        assert_eq!(trailers["grpc-status"], "8");
+    }
+}
@@ -0,0 +1,85 @@
+//! Consistent-hash sharding of metric names across backends.
+//!
+//! Each backend of the `[hash_ring]` owns `virtual_nodes` points on a 64-bit ring; a metric
+//! belongs to the backend owning the first point at or after the hash of its name. Adding or
+//! removing a backend therefore only moves the metrics on the arcs it gains or loses. Hashes
+//! are computed by a fixed function of the names, so every proxy replica and every restart
+//! agree on the owner of a metric.
+
+pub struct HashRing {
+    // Sorted by point
+    points: Vec<(u64, usize)>,
+}
+
+/// FNV-1a followed by the MurmurHash3 finalizer, which spreads similar names such as the
+/// virtual node labels `a#1`, `a#2` evenly over the ring.
+pub(crate) fn hash(s: &str) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in s.bytes() {
+        h ^= u64::from(b);
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+impl HashRing {
+    /// Builds the ring from `(backend index, backend name)` pairs. Points are derived from the
+    /// names, so the ring does not depend on the order backends are declared in.
+    pub fn new<'a>(
+        nodes: impl IntoIterator<Item = (usize, &'a str)>,
+        virtual_nodes: usize,
+    ) -> Self {
+        let mut points: Vec<(u64, usize)> = nodes
+            .into_iter()
+            .flat_map(|(i, name)| {
+                (0..virtual_nodes.max(1)).map(move |n| (hash(&format!("{}#{}", name, n)), i))
+            })
+            .collect();
+        points.sort_unstable();
+        HashRing { points }
+    }
+
+    /// Index of the backend owning `metric`.
+    pub fn pick(&self, metric: &str) -> usize {
+        let h = hash(metric);
+        let at = self.points.partition_point(|(p, _)| *p < h);
+        self.points[at % self.points.len()].1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spreads_metrics_and_moves_few_when_a_backend_leaves() {
+        let three = HashRing::new([(0, "a"), (1, "b"), (2, "c")], 160);
+        let two = HashRing::new([(0, "a"), (1, "b")], 160);
+        let metrics: Vec<String> = (0..3000).map(|n| format!("app.metric.{}", n)).collect();
+
+        let mut counts = [0; 3];
+        for m in &metrics {
+            counts[three.pick(m)] += 1;
+        }
+        assert!(
+            counts.iter().all(|&c| (700..1300).contains(&c)),
+            "{:?}",
+            counts
+        );
+
+        // Only the metrics of the removed backend change owner
+        for m in &metrics {
+            let before = three.pick(m);
+            if before != 2 {
+                assert_eq!(two.pick(m), before);
+            }
+        }
+        // Declaration order does not matter
+        let reordered = HashRing::new([(1, "b"), (0, "a"), (2, "c")], 160);
+        assert!(metrics.iter().all(|m| reordered.pick(m) == three.pick(m)));
+    }
+}
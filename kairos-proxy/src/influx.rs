@@ -0,0 +1,315 @@
+use crate::ingest::forward_datapoints;
+use crate::proxy::read_body;
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, warn};
+
+/// Timestamp precision of the incoming line protocol, as passed in the `precision` query parameter.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+pub(crate) enum Precision {
+    #[default]
+    #[serde(rename = "ns", alias = "n")]
+    Nanoseconds,
+    #[serde(rename = "us", alias = "u")]
+    Microseconds,
+    #[serde(rename = "ms")]
+    Milliseconds,
+    #[serde(rename = "s")]
+    Seconds,
+    #[serde(rename = "m")]
+    Minutes,
+    #[serde(rename = "h")]
+    Hours,
+}
+
+impl Precision {
+    /// Converts a timestamp in this precision to KairosDB's epoch milliseconds.
+    fn to_millis(self, ts: i64) -> Option<i64> {
+        match self {
+            Precision::Nanoseconds => Some(ts / 1_000_000),
+            Precision::Microseconds => Some(ts / 1_000),
+            Precision::Milliseconds => Some(ts),
+            Precision::Seconds => ts.checked_mul(1_000),
+            Precision::Minutes => ts.checked_mul(60_000),
+            Precision::Hours => ts.checked_mul(3_600_000),
+        }
+    }
+}
+
+/// Query parameters accepted by `/write`. `db`, `rp` and friends are accepted and ignored.
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct WriteParams {
+    precision: Option<Precision>,
+}
+
+/// Splits `s` at the first occurrence of `delim` that is neither backslash-escaped
+/// nor (when `respect_quotes` is set) inside a double-quoted string.
+fn split_once_unescaped(s: &str, delim: char, respect_quotes: bool) -> (&str, Option<&str>) {
+    let mut escaped = false;
+    let mut in_quotes = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '"' if respect_quotes => in_quotes = !in_quotes,
+            c if c == delim && !in_quotes => return (&s[..i], Some(&s[i + c.len_utf8()..])),
+            _ => {}
+        }
+    }
+    (s, None)
+}
+
+fn split_unescaped(mut s: &str, delim: char, respect_quotes: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    loop {
+        let (head, rest) = split_once_unescaped(s, delim, respect_quotes);
+        parts.push(head);
+        match rest {
+            Some(r) => s = r,
+            None => return parts,
+        }
+    }
+}
+
+/// Removes line-protocol escapes (`\,`, `\ `, `\=`, `\"`, `\\`); other backslashes are literal.
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(&next) = chars.peek() {
+                if matches!(next, ',' | ' ' | '=' | '"' | '\\') {
+                    out.push(next);
+                    chars.next();
+                    continue;
+                }
+            }
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn parse_field_value(raw: &str) -> Result<serde_json::Value, String> {
+    if let Some(inner) = raw.strip_prefix('"') {
+        let inner = inner
+            .strip_suffix('"')
+            .ok_or_else(|| format!("unterminated string field value: {}", raw))?;
+        return Ok(serde_json::Value::String(unescape(inner)));
+    }
+    match raw {
+        "t" | "T" | "true" | "True" | "TRUE" => return Ok(json!(1)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Ok(json!(0)),
+        _ => {}
+    }
+    if let Some(int) = raw.strip_suffix('i') {
+        return int
+            .parse::<i64>()
+            .map(|v| json!(v))
+            .map_err(|_| format!("invalid integer field value: {}", raw));
+    }
+    if let Some(uint) = raw.strip_suffix('u') {
+        return uint
+            .parse::<u64>()
+            .map(|v| json!(v))
+            .map_err(|_| format!("invalid unsigned field value: {}", raw));
+    }
+    match raw.parse::<f64>() {
+        Ok(v) if v.is_finite() => Ok(json!(v)),
+        _ => Err(format!("invalid float field value: {}", raw)),
+    }
+}
+
+/// Parses InfluxDB line protocol into KairosDB datapoint objects.
+///
+/// Each field becomes its own metric named `<measurement>.<field>`; a field called `value`
+/// maps to the bare measurement name. Lines without a timestamp are stamped with `now_ms`.
+/// KairosDB refuses datapoints without tags, so lines without tags are errors too, rather than
+/// failing the backend write they would share with other lines.
+pub(crate) fn parse_lines(
+    input: &str,
+    precision: Precision,
+    now_ms: i64,
+) -> Result<Vec<serde_json::Value>, String> {
+    let mut datapoints = Vec::new();
+    for (lineno, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |msg: String| format!("line {}: {}", lineno + 1, msg);
+
+        let (series, rest) = split_once_unescaped(line, ' ', false);
+        let rest = rest.ok_or_else(|| err("missing fields".into()))?;
+        let (fields, timestamp) = split_once_unescaped(rest.trim_start(), ' ', true);
+
+        let mut series_parts = split_unescaped(series, ',', false).into_iter();
+        let measurement = unescape(series_parts.next().unwrap_or_default());
+        if measurement.is_empty() {
+            return Err(err("missing measurement".into()));
+        }
+        let mut tags = serde_json::Map::new();
+        for tag in series_parts {
+            match split_once_unescaped(tag, '=', false) {
+                (k, Some(v)) if !k.is_empty() && !v.is_empty() => {
+                    tags.insert(unescape(k), serde_json::Value::String(unescape(v)));
+                }
+                _ => return Err(err(format!("invalid tag: {}", tag))),
+            }
+        }
+        if tags.is_empty() {
+            return Err(err(format!(
+                "no tags on measurement {}; KairosDB needs at least one",
+                measurement
+            )));
+        }
+
+        let timestamp = match timestamp.map(str::trim).filter(|t| !t.is_empty()) {
+            Some(t) => {
+                let raw = t
+                    .parse::<i64>()
+                    .map_err(|_| err(format!("invalid timestamp: {}", t)))?;
+                precision
+                    .to_millis(raw)
+                    .ok_or_else(|| err(format!("timestamp out of range: {}", t)))?
+            }
+            None => now_ms,
+        };
+
+        for field in split_unescaped(fields, ',', true) {
+            let (key, value) = match split_once_unescaped(field, '=', true) {
+                (k, Some(v)) if !k.is_empty() => (unescape(k), v),
+                _ => return Err(err(format!("invalid field: {}", field))),
+            };
+            let value = parse_field_value(value).map_err(err)?;
+            let name = if key == "value" {
+                measurement.clone()
+            } else {
+                format!("{}.{}", measurement, key)
+            };
+            datapoints.push(json!({
+                "name": name,
+                "timestamp": timestamp,
+                "value": value,
+                "tags": tags.clone(),
+            }));
+        }
+    }
+    Ok(datapoints)
+}
+
+/// InfluxDB-compatible `/write` endpoint: translates line protocol into KairosDB
+/// datapoints and forwards them to the backends their metric names route to.
+pub async fn influx_write_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<WriteParams>,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    debug!("Received influx write request");
+
+    let mut req = req;
+    let body_bytes = match read_body(&state, &mut req).await {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Failed to read request body: {:?}", e);
+            return Err(e);
+        }
+    };
+    let text = std::str::from_utf8(&body_bytes).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default();
+    let datapoints = match parse_lines(text, params.precision.unwrap_or_default(), now_ms) {
+        Ok(d) => d,
+        Err(e) => {
+            warn!("Rejecting line protocol: {}", e);
+            // Mirror InfluxDB's error envelope so agents log something useful
+            return Ok((StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response());
+        }
+    };
+
+    if !datapoints.is_empty() {
+        forward_datapoints(&state, req.headers(), datapoints).await?;
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tags_fields_and_timestamp() {
+        let dps = parse_lines(
+            "cpu,host=a,region=eu usage_idle=98.5,value=3i 1700000000000000000",
+            Precision::Nanoseconds,
+            0,
+        )
+        .expect("parse");
+        assert_eq!(dps.len(), 2);
+        assert_eq!(dps[0]["name"], "cpu.usage_idle");
+        assert_eq!(dps[0]["value"], 98.5);
+        assert_eq!(dps[0]["timestamp"], 1_700_000_000_000i64);
+        assert_eq!(dps[0]["tags"]["host"], "a");
+        assert_eq!(dps[1]["name"], "cpu");
+        assert_eq!(dps[1]["value"], 3);
+    }
+
+    #[test]
+    fn handles_escapes_strings_and_booleans() {
+        let dps = parse_lines(
+            "disk\\ io,path=/var\\,log msg=\"a \\\"quoted\\\" value, here\",ok=true",
+            Precision::Seconds,
+            42,
+        )
+        .expect("parse");
+        assert_eq!(dps.len(), 2);
+        assert_eq!(dps[0]["name"], "disk io.msg");
+        assert_eq!(dps[0]["tags"]["path"], "/var,log");
+        assert_eq!(dps[0]["value"], "a \"quoted\" value, here");
+        assert_eq!(dps[0]["timestamp"], 42);
+        assert_eq!(dps[1]["value"], 1);
+    }
+
+    #[test]
+    fn applies_precision() {
+        let dps = parse_lines("m,host=a v=1 1700000000", Precision::Seconds, 0).expect("parse");
+        assert_eq!(dps[0]["timestamp"], 1_700_000_000_000i64);
+    }
+
+    #[test]
+    fn reports_line_number_on_error() {
+        let err = parse_lines("ok,host=a v=1\n\nbad", Precision::Nanoseconds, 0).unwrap_err();
+        assert!(err.starts_with("line 3:"), "unexpected error: {}", err);
+        assert!(parse_lines("m,host=a v=abc", Precision::Nanoseconds, 0).is_err());
+    }
+
+    #[test]
+    fn rejects_lines_without_tags() {
+        let err = parse_lines(
+            "cpu,host=a value=1\ncpu value=1 1700000000",
+            Precision::Seconds,
+            0,
+        )
+        .unwrap_err();
+        assert!(
+            err.starts_with("line 2: no tags"),
+            "unexpected error: {}",
+            err
+        );
+    }
+}
@@ -0,0 +1,579 @@
+use crate::admission::Priority;
+use crate::config::IngestBatching;
+use crate::mirror::mirror_request;
+use crate::routes::Subject;
+use crate::state::AppState;
+use crate::timerange::now_ms;
+use axum::http::StatusCode;
+use bytes::Bytes;
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, warn};
+
+/// KairosDB ingest endpoint that all translated datapoints are posted to.
+const DATAPOINTS_ENDPOINT: &str = "api/v1/datapoints";
+
+/// Why a batch is written to a backend: the datapoints route there, or the backend is the
+/// dual-write secondary of their route.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Role {
+    Primary,
+    Secondary { required: bool },
+}
+
+impl Role {
+    fn label(self) -> &'static str {
+        match self {
+            Role::Primary => "primary",
+            Role::Secondary { .. } => "secondary",
+        }
+    }
+
+    fn required(self) -> bool {
+        match self {
+            Role::Primary => true,
+            Role::Secondary { required } => required,
+        }
+    }
+}
+
+/// How a backend answered a batch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Posted {
+    Accepted,
+    /// The backend refused the batch itself with this `4xx`; sending it again cannot help.
+    Refused(StatusCode),
+    /// A `5xx`, timeout or connection error, worth trying again later.
+    Failed,
+}
+
+impl Posted {
+    pub fn accepted(self) -> bool {
+        self == Posted::Accepted
+    }
+}
+
+/// Where a batch comes from. Batches replayed from the spool were mirrored when they were
+/// first written, and yield to client traffic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Origin {
+    Client,
+    Spool,
+}
+
+/// Datapoints waiting to be flushed to one backend, and the requests waiting on them.
+#[derive(Default)]
+struct Pending {
+    points: Vec<serde_json::Value>,
+    bytes: usize,
+    waiters: Vec<oneshot::Sender<Posted>>,
+    // Incremented on every flush so a timer never flushes a later batch early
+    generation: u64,
+}
+
+impl Pending {
+    fn take(&mut self) -> (Vec<serde_json::Value>, Vec<oneshot::Sender<Posted>>) {
+        self.bytes = 0;
+        self.generation += 1;
+        (
+            std::mem::take(&mut self.points),
+            std::mem::take(&mut self.waiters),
+        )
+    }
+}
+
+/// Collects datapoints bound for one backend and posts them together once `max_delay` has
+/// passed since the first of them or they add up to `max_bytes`. Each write is answered
+/// when the batch holding its datapoints has been accepted or rejected.
+pub struct Batcher {
+    max_delay: Duration,
+    max_bytes: usize,
+    pending: Mutex<Pending>,
+}
+
+impl Batcher {
+    pub fn new(cfg: &IngestBatching) -> Self {
+        Batcher {
+            max_delay: Duration::from_millis(cfg.max_delay_ms.unwrap_or(50)),
+            max_bytes: cfg.max_bytes.unwrap_or(1_048_576),
+            pending: Mutex::new(Pending::default()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Pending> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Adds `points` to the batch of backend `i` and waits for that batch to be posted.
+    async fn submit(
+        &self,
+        state: &Arc<AppState>,
+        i: usize,
+        points: Vec<serde_json::Value>,
+    ) -> Posted {
+        let bytes: usize = points
+            .iter()
+            .map(|p| serde_json::to_vec(p).map_or(0, |v| v.len()))
+            .sum();
+        let (tx, rx) = oneshot::channel();
+        let full = {
+            let mut pending = self.lock();
+            let first = pending.points.is_empty();
+            pending.points.extend(points);
+            pending.bytes += bytes;
+            pending.waiters.push(tx);
+            if pending.bytes >= self.max_bytes {
+                Some(pending.take())
+            } else {
+                if first {
+                    self.flush_later(state.clone(), i, pending.generation);
+                }
+                None
+            }
+        };
+        // Flushes run detached so a disconnecting client cannot strand the other writers
+        if let Some((points, waiters)) = full {
+            tokio::spawn(flush(state.clone(), i, points, waiters));
+        }
+        rx.await.unwrap_or(Posted::Failed)
+    }
+
+    fn flush_later(&self, state: Arc<AppState>, i: usize, generation: u64) {
+        let delay = self.max_delay;
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let Some(batcher) = &state.backends[i].batcher else {
+                return;
+            };
+            let due = {
+                let mut pending = batcher.lock();
+                (pending.generation == generation).then(|| pending.take())
+            };
+            if let Some((points, waiters)) = due {
+                flush(state.clone(), i, points, waiters).await;
+            }
+        });
+    }
+}
+
+async fn flush(
+    state: Arc<AppState>,
+    i: usize,
+    points: Vec<serde_json::Value>,
+    waiters: Vec<oneshot::Sender<Posted>>,
+) {
+    debug!(
+        "Flushing {} buffered datapoint(s) from {} write(s) to '{}'",
+        points.len(),
+        waiters.len(),
+        state.backends[i].name
+    );
+    let posted = deliver(&state, i, points).await;
+    for waiter in waiters {
+        let _ = waiter.send(posted);
+    }
+}
+
+/// Groups KairosDB datapoint objects (`{ name, timestamp, value, tags }`) by the
+/// backend their metric name routes to, plus the `secondary_backend` of their rule, and posts
+/// one batch per backend, or adds them to the backend's buffered batch when `[ingest_batching]`
+/// is configured.
+///
+/// Returns `BAD_GATEWAY` if any datapoint is unroutable or any backend fails to take its batch,
+/// unless that backend is a best-effort secondary
+/// (`SERVICE_UNAVAILABLE` if a datapoint only matches drained backends). A backend refusing
+/// its batch with a `4xx` returns that status instead, as the datapoints themselves are at fault;
+/// KairosDB ingest is not transactional, so batches already accepted by other backends stay written.
+pub(crate) async fn forward_datapoints(
+    state: &Arc<AppState>,
+    inbound_headers: &hyper::HeaderMap,
+    datapoints: Vec<serde_json::Value>,
+) -> Result<(), StatusCode> {
+    // Keyed by backend and whether the write depends on it; a primary write always does
+    let mut per_backend: HashMap<(usize, Role), Vec<serde_json::Value>> = HashMap::new();
+    for dp in datapoints {
+        let name = dp.get("name").and_then(|v| v.as_str()).unwrap_or_default();
+        let subject = Subject {
+            metric: name,
+            tags: dp.get("tags"),
+            headers: Some(inbound_headers),
+        };
+        let at_ms = dp
+            .get("timestamp")
+            .and_then(|v| v.as_i64())
+            .unwrap_or_else(now_ms);
+        match state.select_write(&subject, at_ms) {
+            Some((backend, secondary)) => {
+                if let Some(s) = secondary {
+                    let role = Role::Secondary {
+                        required: s.required,
+                    };
+                    per_backend
+                        .entry((s.backend, role))
+                        .or_default()
+                        .push(dp.clone());
+                }
+                per_backend
+                    .entry((backend, Role::Primary))
+                    .or_default()
+                    .push(dp);
+            }
+            None => {
+                error!("No backend matched ingested metric: {:?}", name);
+                return Err(state.unroutable_status(name));
+            }
+        }
+    }
+
+    info!(
+        "Forwarding ingest batch to {} backend(s)",
+        per_backend.len()
+    );
+
+    let mut futs = FuturesUnordered::new();
+    for ((i, role), batch) in per_backend {
+        futs.push(async move {
+            let posted = match &state.backends[i].batcher {
+                Some(batcher) => batcher.submit(state, i, batch).await,
+                None => deliver(state, i, batch).await,
+            };
+            let ok = posted.accepted();
+            state.metrics.inc_counter(
+                "kairos_proxy_ingest_writes_total",
+                &[
+                    ("backend", &state.backends[i].name),
+                    ("role", role.label()),
+                    ("outcome", if ok { "ok" } else { "error" }),
+                ],
+            );
+            if !ok && !role.required() {
+                warn!(
+                    "Best-effort secondary write to '{}' failed",
+                    state.backends[i].name
+                );
+            }
+            match posted {
+                _ if ok || !role.required() => Ok(()),
+                Posted::Refused(status) => Err(status),
+                _ => Err(StatusCode::BAD_GATEWAY),
+            }
+        });
+    }
+
+    let mut result = Ok(());
+    while let Some(res) = futs.next().await {
+        // A refusal says more about the write than another backend being down
+        match (res, result) {
+            (Err(status), Ok(())) => result = Err(status),
+            (Err(status), Err(_)) if status.is_client_error() => result = Err(status),
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Posts one batch of datapoints to backend `i`, spooling it to disk if the backend fails to
+/// take it and `[ingest_spool]` is configured. A spooled batch counts as accepted; refused ones
+/// are never spooled.
+async fn deliver(state: &AppState, i: usize, batch: Vec<serde_json::Value>) -> Posted {
+    let Some(spool) = &state.backends[i].spool else {
+        return post(state, i, batch, Origin::Client).await;
+    };
+    let posted = post(state, i, batch.clone(), Origin::Client).await;
+    if posted != Posted::Failed {
+        return posted;
+    }
+    if !spool.push(&batch).await {
+        return Posted::Failed;
+    }
+    warn!(
+        "Spooled {} datapoint(s) for '{}' to disk",
+        batch.len(),
+        state.backends[i].name
+    );
+    state.metrics.inc_counter_by(
+        "kairos_proxy_ingest_spooled_datapoints_total",
+        &[("backend", &state.backends[i].name)],
+        batch.len() as u64,
+    );
+    Posted::Accepted
+}
+
+/// Posts one batch of datapoints to backend `i`.
+pub(crate) async fn post(
+    state: &AppState,
+    i: usize,
+    mut batch: Vec<serde_json::Value>,
+    origin: Origin,
+) -> Posted {
+    let target = &state.backends[i];
+    if let Some(prefix) = &target.name_prefix {
+        prefix.outbound_datapoints(&mut batch);
+    }
+    let mut headers = hyper::HeaderMap::new();
+    headers.insert(
+        hyper::http::header::CONTENT_TYPE,
+        hyper::http::HeaderValue::from_static("application/json"),
+    );
+    let body = match serde_json::to_vec(&batch) {
+        Ok(b) => Bytes::from(b),
+        Err(e) => {
+            error!("Failed to serialize ingest batch: {}", e);
+            return Posted::Failed;
+        }
+    };
+    let count = batch.len();
+    let priority = match origin {
+        Origin::Client => {
+            mirror_request(state, target, DATAPOINTS_ENDPOINT, body.clone(), &headers);
+            Priority::Interactive
+        }
+        Origin::Spool => Priority::Batch,
+    };
+    if state.throttle(target, target.timeout).await.is_err() {
+        return Posted::Failed;
+    }
+    let _permit = state.admission.acquire(priority).await;
+    let selected = target.select();
+    let url = selected.url.clone();
+    let request_url = match target.endpoint_url(&url, DATAPOINTS_ENDPOINT) {
+        Ok(u) => u,
+        Err(e) => {
+            error!("Failed to build request URL: {}", e);
+            return Posted::Failed;
+        }
+    };
+    let mut builder = target
+        .client
+        .get()
+        .post(request_url)
+        .timeout(target.timeout)
+        .headers(headers);
+    if let Some(signer) = &target.signer {
+        builder = signer.sign(builder, &body);
+    }
+    builder = builder.body(body);
+    if let Some(t) = &selected.token {
+        builder = builder.header("Authorization", format!("Bearer {}", t));
+    }
+    let started = Instant::now();
+    let resp = target
+        .client
+        .send(&state.metrics, &target.name, builder)
+        .await;
+    state.record_outcome(
+        target,
+        &selected,
+        AppState::succeeded(&resp),
+        started.elapsed(),
+    );
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let backend = target.name.as_str();
+    match resp {
+        Ok(r) if r.status().is_success() => {
+            debug!(
+                backend,
+                latency_ms,
+                datapoint_count = count,
+                "Backend {} accepted {} datapoint(s)",
+                url,
+                count
+            );
+            Posted::Accepted
+        }
+        Ok(r) => {
+            error!(
+                backend,
+                latency_ms,
+                "Backend {} rejected ingest batch: {}",
+                url,
+                r.status()
+            );
+            if r.status().is_client_error() {
+                Posted::Refused(r.status())
+            } else {
+                Posted::Failed
+            }
+        }
+        Err(e) => {
+            error!(
+                backend,
+                latency_ms, "Ingest request to {} failed: {}", url, e
+            );
+            Posted::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, Config, RouteConfig};
+    use axum::{routing::post, Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Starts a backend accepting every write; returns its URL and the sizes of the batches
+    /// it received.
+    fn spawn_backend() -> (String, Arc<std::sync::Mutex<Vec<usize>>>) {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = received.clone();
+        let app = Router::new().route(
+            "/api/v1/datapoints",
+            post(move |Json(points): Json<Vec<serde_json::Value>>| {
+                seen.lock().unwrap().push(points.len());
+                async { StatusCode::NO_CONTENT }
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+        (format!("http://{}", addr), received)
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_share_one_batch() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let seen = requests.clone();
+        let app = Router::new().route(
+            "/api/v1/datapoints",
+            post(move |Json(points): Json<Vec<serde_json::Value>>| {
+                let seen = seen.clone();
+                async move {
+                    assert_eq!(points.len(), 2);
+                    seen.fetch_add(1, Ordering::SeqCst);
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: ".*".to_string(),
+                url: format!("http://{}", addr),
+                ..Default::default()
+            }],
+            ingest_batching: Some(IngestBatching {
+                max_delay_ms: Some(50),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+        let headers = hyper::HeaderMap::new();
+        let write = |value: i64| {
+            let point = serde_json::json!({ "name": "m", "timestamp": 1, "value": value });
+            forward_datapoints(&state, &headers, vec![point])
+        };
+        let (a, b) = tokio::join!(write(1), write(2));
+        assert_eq!((a, b), (Ok(()), Ok(())));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn dual_writes_to_the_secondary_backend() {
+        let (old, old_received) = spawn_backend();
+        let (new, new_received) = spawn_backend();
+        // Nothing listens on port 9 of localhost
+        let down = "http://127.0.0.1:9".to_string();
+        let state = |secondary: &str, required: bool| {
+            let backend = |name: &str, url: &String| Backend {
+                name: Some(name.to_string()),
+                url: url.clone(),
+                ..Default::default()
+            };
+            let cfg = Config {
+                backends: vec![
+                    backend("old", &old),
+                    backend("new", &new),
+                    backend("down", &down),
+                ],
+                routes: vec![RouteConfig {
+                    backend: "old".to_string(),
+                    metric: Some(".*".to_string()),
+                    secondary_backend: Some(secondary.to_string()),
+                    secondary_required: Some(required),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            Arc::new(AppState::from_config(&cfg).expect("state"))
+        };
+        let headers = hyper::HeaderMap::new();
+        let points = || vec![serde_json::json!({ "name": "m", "timestamp": 1, "value": 1 })];
+
+        let migrating = state("new", true);
+        assert_eq!(
+            forward_datapoints(&migrating, &headers, points()).await,
+            Ok(())
+        );
+        assert_eq!(*old_received.lock().unwrap(), [1]);
+        assert_eq!(*new_received.lock().unwrap(), [1]);
+        let labels = [("backend", "new"), ("outcome", "ok"), ("role", "secondary")];
+        assert_eq!(
+            migrating
+                .metrics
+                .counter("kairos_proxy_ingest_writes_total", &labels),
+            1
+        );
+
+        let best_effort = state("down", false);
+        assert_eq!(
+            forward_datapoints(&best_effort, &headers, points()).await,
+            Ok(())
+        );
+        let required = state("down", true);
+        assert_eq!(
+            forward_datapoints(&required, &headers, points()).await,
+            Err(StatusCode::BAD_GATEWAY)
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_batches_are_returned_rather_than_spooled() {
+        let app = Router::new().route(
+            "/api/v1/datapoints",
+            post(|| async { StatusCode::BAD_REQUEST }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let url = format!("http://{}", listener.local_addr().expect("addr"));
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .expect("server")
+                .serve(app.into_make_service()),
+        );
+        let dir = std::env::temp_dir().join(format!("kairos-spool-{}", fastrand::u64(..)));
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: ".*".to_string(),
+                url,
+                ..Default::default()
+            }],
+            ingest_spool: Some(crate::config::IngestSpool {
+                dir: dir.to_string_lossy().into_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+        let point = serde_json::json!({ "name": "m", "timestamp": 1, "value": "x" });
+        assert_eq!(
+            forward_datapoints(&state, &hyper::HeaderMap::new(), vec![point]).await,
+            Err(StatusCode::BAD_REQUEST)
+        );
+        let spool = state.backends[0].spool.as_ref().expect("spool");
+        assert_eq!(spool.depth().await.bytes, 0);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
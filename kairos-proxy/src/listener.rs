@@ -0,0 +1,70 @@
+//! Binding the listening sockets.
+//!
+//! With `reuse_port = true` the listener is bound with `SO_REUSEPORT` (Linux and other Unix
+//! systems), so several proxy processes can listen on the same address: a new binary can be
+//! started next to the running one and the old one stopped once the new one is ready, without
+//! refusing connections in between (both must set `reuse_port`). `acceptors` then binds that
+//! many sockets in this process, each accepting on its own task, and the kernel spreads new
+//! connections across them.
+
+use crate::config::Config;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{SocketAddr, TcpListener};
+
+// Pending connections the kernel queues per socket before they are accepted
+const BACKLOG: i32 = 1024;
+
+/// One listening socket on `addr`, with `SO_REUSEPORT` when `reuse_port` is set.
+fn bind(addr: SocketAddr, reuse_port: bool) -> anyhow::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        anyhow::bail!("reuse_port is only supported on Unix systems");
+    }
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .map_err(|e| anyhow::anyhow!("Cannot listen on {}: {}", addr, e))?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+/// The sockets to accept connections on, as `reuse_port` and `acceptors` ask.
+pub fn bind_all(addr: SocketAddr, cfg: &Config) -> anyhow::Result<Vec<TcpListener>> {
+    let reuse_port = cfg.reuse_port.unwrap_or(false);
+    let acceptors = cfg.acceptors.unwrap_or(1);
+    anyhow::ensure!(acceptors > 0, "acceptors must be at least 1");
+    anyhow::ensure!(
+        acceptors == 1 || reuse_port,
+        "acceptors above 1 need reuse_port = true"
+    );
+    (0..acceptors).map(|_| bind(addr, reuse_port)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn binds_one_address_several_times_with_reuse_port() {
+        let first = bind("127.0.0.1:0".parse().unwrap(), true).expect("first");
+        let addr = first.local_addr().unwrap();
+        let cfg = Config {
+            reuse_port: Some(true),
+            acceptors: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(bind_all(addr, &cfg).expect("shared").len(), 2);
+
+        let taken = Config {
+            acceptors: Some(2),
+            ..Default::default()
+        };
+        assert!(bind_all(addr, &taken).is_err());
+        assert!(bind_all(addr, &Config::default()).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+//! Log output and per-request log context.
+//!
+//! Logs are human-readable lines by default. With `log_format = "json"` (or `LOG_FORMAT=json`)
+//! every event is one JSON object whose `fields` carry structured values such as `backend`,
+//! `metric_count` and `latency_ms`, and whose `span` carries the `request_id` and `client_ip` of
+//! the request being served, so log pipelines need no regexes to pick them apart.
+//!
+//! The filter can be changed while running through `PUT /admin/loglevel`, optionally for a
+//! limited time, so an incident can be debugged without a restart.
+
+use crate::auth::Principal;
+use crate::config::LogFormat;
+use crate::forwarded::ClientIp;
+use axum::http::{HeaderValue, Request};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tower_http::request_id::{MakeRequestId, RequestId};
+use tracing::{warn, Span, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+/// Installs the global subscriber. `LOG_LEVEL` (default `info`) or `RUST_LOG` set the initial
+/// filter, which the returned control can change at runtime.
+pub fn init(format: LogFormat) -> Arc<LogControl> {
+    let log_level = std::env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string());
+    let env_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&log_level));
+    let format = match std::env::var("LOG_FORMAT").ok().as_deref() {
+        Some("json") => LogFormat::Json,
+        Some("text") => LogFormat::Text,
+        _ => format,
+    };
+
+    let (filter, handle) = reload::Layer::new(env_filter);
+    let text = (format == LogFormat::Text).then(|| fmt::layer().with_target(false));
+    let json = (format == LogFormat::Json).then(|| json_layer(std::io::stdout));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .init();
+    Arc::new(LogControl::new(handle))
+}
+
+/// One JSON object per event, written to `writer`, with the current request's span.
+fn json_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    fmt::layer()
+        .with_target(false)
+        .json()
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_writer(writer)
+}
+
+/// Runtime control of the log filter, for `/admin/loglevel`.
+pub struct LogControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+    // Incremented on every change so a pending revert never undoes a later one
+    changes: AtomicU64,
+}
+
+impl LogControl {
+    fn new(handle: reload::Handle<EnvFilter, Registry>) -> Self {
+        LogControl {
+            handle,
+            changes: AtomicU64::new(0),
+        }
+    }
+
+    /// The active filter directives, e.g. `info,kairos_proxy::fanout=debug`.
+    pub fn current(&self) -> String {
+        self.handle
+            .with_current(|f| f.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Switches to `directives` (`EnvFilter` syntax: a level and/or `target=level` pairs) and
+    /// returns the filter it replaced. With `revert_after`, that filter comes back once the
+    /// time is up unless the filter was changed again meanwhile.
+    pub fn set(
+        self: &Arc<Self>,
+        directives: &str,
+        revert_after: Option<Duration>,
+    ) -> Result<String, String> {
+        let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+        let previous = self.current();
+        self.handle.reload(filter).map_err(|e| e.to_string())?;
+        let change = self.changes.fetch_add(1, Ordering::SeqCst) + 1;
+        warn!("Log filter changed from '{}' to '{}'", previous, directives);
+        if let Some(after) = revert_after {
+            let control = self.clone();
+            let restore = previous.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(after).await;
+                if control.changes.load(Ordering::SeqCst) != change {
+                    return;
+                }
+                if let Ok(filter) = EnvFilter::try_new(&restore) {
+                    if control.handle.reload(filter).is_ok() {
+                        warn!("Log filter reverted to '{}'", restore);
+                    }
+                }
+            });
+        }
+        Ok(previous)
+    }
+}
+
+/// Random 16-hex-digit ids for requests that arrive without `X-Request-Id`.
+#[derive(Clone, Copy, Default)]
+pub struct RandomRequestId;
+
+impl MakeRequestId for RandomRequestId {
+    fn make_request_id<B>(&mut self, _request: &Request<B>) -> Option<RequestId> {
+        let id = format!("{:016x}", fastrand::u64(..));
+        HeaderValue::from_str(&id).ok().map(RequestId::new)
+    }
+}
+
+/// Span every event logged while serving `request` is recorded in.
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let client_ip = request
+        .extensions()
+        .get::<ClientIp>()
+        .map(|c| c.0.to_string())
+        .unwrap_or_default();
+    let principal = request.extensions().get::<Arc<Principal>>();
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        request_id = %request_id,
+        client_ip = %client_ip,
+        api_key = principal.map(|p| p.name.as_str()),
+        team = principal.and_then(|p| p.team.as_deref()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_events_carry_fields_and_the_request_context() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry().with(json_layer(move || writer.clone()));
+        let mut request = Request::builder()
+            .uri("/api/v1/datapoints/query")
+            .header("x-request-id", "0123456789abcdef")
+            .body(())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ClientIp("10.0.0.7".parse().unwrap()));
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = request_span(&request).entered();
+            tracing::info!(backend = "eu", metric_count = 3, latency_ms = 12, "Queried");
+        });
+
+        let line = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let event: serde_json::Value = serde_json::from_str(line.trim()).expect(&line);
+        assert_eq!(event["level"], "INFO");
+        assert_eq!(event["fields"]["message"], "Queried");
+        assert_eq!(event["fields"]["backend"], "eu");
+        assert_eq!(event["fields"]["metric_count"], 3);
+        assert_eq!(event["fields"]["latency_ms"], 12);
+        assert_eq!(event["span"]["request_id"], "0123456789abcdef");
+        assert_eq!(event["span"]["client_ip"], "10.0.0.7");
+        assert_eq!(event["span"]["path"], "/api/v1/datapoints/query");
+        assert!(event.get("target").is_none());
+    }
+
+    #[tokio::test]
+    async fn changes_the_filter_and_reverts_it() {
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        // The handle only works while a subscriber holds the layer
+        let _subscriber = tracing_subscriber::registry().with(filter);
+        let control = Arc::new(LogControl::new(handle));
+
+        assert!(control.set("not a filter[", None).is_err());
+        assert_eq!(control.current(), "info");
+        let previous = control
+            .set(
+                "warn,kairos_proxy::fanout=debug",
+                Some(Duration::from_millis(50)),
+            )
+            .unwrap();
+        assert_eq!(previous, "info");
+        assert!(control.current().contains("kairos_proxy::fanout=debug"));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(control.current(), "info");
+    }
+}
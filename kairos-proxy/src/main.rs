@@ -1,39 +1,139 @@
+mod access;
+mod admin;
+mod admission;
+mod auth;
+mod balance;
+mod body_prefix;
+mod cache;
+mod chaos;
+mod client;
 mod config;
+mod connections;
+mod credentials;
+mod deadline;
+mod debug_trace;
+mod decimate;
+mod decompress;
+mod discovery;
+mod dns;
+mod dry_run;
+mod estimate;
+mod etag;
+mod events;
+mod fanout;
+mod forwarded;
+mod generations;
+mod grafana;
+mod graphite;
+mod grpc_health;
+mod hash_ring;
+mod influx;
+mod ingest;
+mod listener;
+mod logging;
+mod memory;
+mod merge_conflicts;
+mod merge_policy;
+mod methods;
+mod metrics;
+mod mirror;
+mod mock_backend;
+mod name_prefix;
+mod opentsdb;
+mod pagination;
 mod proxy;
+mod query_csv;
+mod query_export;
 mod query_metric;
 mod query_metric_tags;
+mod query_stream;
+mod quota;
+mod ratelimit;
+mod recorder;
+mod redis_conn;
+mod replay;
+mod result_fields;
+mod routes;
+mod sd_notify;
+mod shadow;
+mod signing;
+mod singleflight;
+mod slow_client;
+mod spool;
 mod state;
+mod stitch;
+mod system;
+mod tag_keys;
+mod telemetry;
+mod timerange;
+mod unroutable;
+mod upstream;
+mod validate;
+mod vault;
+mod warmup;
 
-use axum::Router;
+use axum::{Router, ServiceExt};
 use config::Config;
 use state::AppState;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 use std::{net::SocketAddr, sync::Arc};
 use tokio::signal;
-use tracing::{debug, info, warn};
+use tower::ServiceBuilder;
+use tower_http::request_id::{PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
+use tracing::{debug, error, info, warn};
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
+    // Needs no configuration
+    if let Some(args) = mock_backend::Args::parse(std::env::args().skip(1))? {
+        return runtime(&Config::default())?.block_on(async {
+            logging::init(Default::default());
+            mock_backend::run(args).await
+        });
+    }
+    let config_path = std::env::var("KAIROS_PROXY_CONFIG").unwrap_or_else(|_| "config.toml".into());
+    let cfg = Config::from_file(&config_path)
+        .map_err(|e| anyhow::anyhow!("Cannot load configuration from {}: {}", config_path, e))?;
+    runtime(&cfg)?.block_on(serve(config_path, cfg))
+}
+
+/// The tokio runtime, sized by `worker_threads` and `max_blocking_threads` where set.
+fn runtime(cfg: &Config) -> anyhow::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = cfg.worker_threads {
+        builder.worker_threads(threads.get());
+    }
+    if let Some(threads) = cfg.max_blocking_threads {
+        builder.max_blocking_threads(threads.get());
+    }
+    Ok(builder.build()?)
+}
+
+async fn serve(config_path: String, cfg: Config) -> anyhow::Result<()> {
     // Configure logging with proper defaults for container environments
     // LOG_LEVEL env var controls the log level (default: info)
     // Supports: error, warn, info, debug, trace
-    let log_level = std::env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string());
-    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(&log_level));
-
-    tracing_subscriber::fmt()
-        .with_env_filter(env_filter)
-        .with_target(false)
-        .init();
-
-    let config_path = std::env::var("KAIROS_PROXY_CONFIG").unwrap_or_else(|_| "config.toml".into());
-    info!("Loading configuration from: {}", config_path);
-    let cfg = Config::from_file(&config_path)?;
+    let log_control = logging::init(cfg.log_format.unwrap_or_default());
+    info!("Loaded configuration from: {}", config_path);
     debug!(
         "Configuration loaded successfully with {} backend(s)",
         cfg.backends.len()
     );
 
-    let state = Arc::new(AppState::from_config(&cfg)?);
+    let mut state = AppState::from_config(&cfg)?;
+    let replay = replay::Args::parse(std::env::args().skip(1))?;
+    if replay.is_some() {
+        // Recordings carry no API keys, and replayed queries are not recorded again
+        state.api_keys = None;
+        state.quotas = None;
+        state.recorder = None;
+    }
+    state.config_path = Some(config_path.clone());
+    state.log_control = Some(log_control);
+    let state = Arc::new(state);
+    state.pin_backend_addresses().await?;
     info!(
         "Proxy configured with mode: {:?}, max_outbound_concurrency: {}, timeout: {}s",
         state.mode,
@@ -41,8 +141,79 @@ async fn main() -> anyhow::Result<()> {
         cfg.timeout_secs.unwrap_or(5)
     );
 
-    let app = Router::new()
+    discovery::spawn_all(&state)?;
+    credentials::spawn_all(&state, &cfg.vault.clone().unwrap_or_default())?;
+    if let Some(args) = replay {
+        return replay::run(router(&state), args).await;
+    }
+    if let Some(every) = state.dns_refresh {
+        dns::spawn(state.clone(), every);
+    }
+    spawn_reload_on_hangup(state.clone());
+    telemetry::spawn(state.clone());
+    if let Some(listen) = &cfg.grpc_health_listen {
+        grpc_health::spawn(state.clone(), listen.parse()?);
+    }
+    if let Some(every) = state.spool_replay {
+        spool::spawn(state.clone(), every);
+    }
+    recorder::spawn(state.clone());
+
+    let app = router(&state);
+    // Around the router rather than inside it, where 405 responses carry no Allow header yet
+    let app = tower::Layer::layer(&axum::middleware::from_fn(methods::layer), app);
+
+    let limits = connections::ConnectionLimits::from_config(&cfg, state.metrics.clone())?;
+    let listen = cfg.listen.as_deref().unwrap_or("0.0.0.0:8080");
+    let addr: SocketAddr = listen.parse()?;
+    info!("Starting kairos-proxy server on {}", addr);
+    info!("Available endpoints: /health, /metrics, /api/v1/datapoints/query, /api/v1/datapoints/query/tags, /api/v1/datapoints/query/stream, /api/v1/datapoints/query/csv, /api/v1/datapoints/query/arrow, /api/v1/datapoints/query/parquet, /api/v1/features, /api/v1/health/status, /api/v1/health/check, /write, /api/put, /render, /search, /query, /annotations, /admin/backends, /admin/config, /admin/loglevel, /admin/status, /admin/buildinfo, /admin/ui, /admin/events, /admin/usage");
+
+    let header_read_timeout = cfg.header_read_timeout_secs.map_or(
+        slow_client::DEFAULT_HEADER_READ_TIMEOUT,
+        Duration::from_secs,
+    );
+    if let Some(connections) = cfg.warmup_connections.filter(|n| *n > 0) {
+        let timeout = cfg
+            .warmup_timeout_secs
+            .map_or(warmup::DEFAULT_TIMEOUT, Duration::from_secs);
+        warmup::run(&state, connections, timeout).await;
+    }
+    let (stop, stopping) = tokio::sync::watch::channel(false);
+    let mut servers = Vec::new();
+    for listener in listener::bind_all(addr, &cfg)? {
+        let mut stopping = stopping.clone();
+        let server = axum::Server::from_tcp(listener)?
+            .http1_header_read_timeout(header_read_timeout)
+            .serve(
+                limits.wrap(
+                    app.clone()
+                        .into_make_service_with_connect_info::<SocketAddr>(),
+                ),
+            )
+            .with_graceful_shutdown(async move {
+                let _ = stopping.changed().await;
+            });
+        servers.push(server);
+    }
+    sd_notify::ready();
+
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        state.shutting_down.store(true, Ordering::Relaxed);
+        sd_notify::notify("STOPPING=1");
+        let _ = stop.send(true);
+    });
+    futures::future::try_join_all(servers).await?;
+    Ok(())
+}
+
+/// Every endpoint with the middleware in front of them.
+fn router(state: &Arc<AppState>) -> Router {
+    Router::new()
+        .route("/", axum::routing::get(proxy::health_handler))
         .route("/health", axum::routing::get(proxy::health_handler))
+        .route("/metrics", axum::routing::get(proxy::metrics_handler))
         .route(
             "/api/v1/datapoints/query/tags",
             axum::routing::post(proxy::query_metric_tags_handler),
@@ -51,18 +222,109 @@ async fn main() -> anyhow::Result<()> {
             "/api/v1/datapoints/query",
             axum::routing::post(proxy::query_metric_handler),
         )
-        .with_state(state);
-
-    let listen = cfg.listen.unwrap_or_else(|| "0.0.0.0:8080".into());
-    let addr: SocketAddr = listen.parse()?;
-    info!("Starting kairos-proxy server on {}", addr);
-    info!("Available endpoints: /health, /api/v1/datapoints/query, /api/v1/datapoints/query/tags");
-
-    let server = axum::Server::bind(&addr).serve(app.into_make_service());
+        .route(
+            "/api/v1/datapoints/query/stream",
+            axum::routing::post(proxy::query_stream_handler),
+        )
+        .route(
+            "/api/v1/datapoints/query/csv",
+            axum::routing::post(proxy::query_csv_handler),
+        )
+        .route(
+            "/api/v1/datapoints/query/arrow",
+            axum::routing::post(proxy::query_arrow_handler),
+        )
+        .route(
+            "/api/v1/datapoints/query/parquet",
+            axum::routing::post(proxy::query_parquet_handler),
+        )
+        .route(
+            "/api/v1/features",
+            axum::routing::get(system::features_handler),
+        )
+        .route(
+            "/api/v1/features/:category",
+            axum::routing::get(system::feature_category_handler),
+        )
+        .route(
+            "/api/v1/health/status",
+            axum::routing::get(system::health_status_handler),
+        )
+        .route(
+            "/api/v1/health/check",
+            axum::routing::get(system::health_check_handler),
+        )
+        .route("/write", axum::routing::post(proxy::influx_write_handler))
+        .route("/api/put", axum::routing::post(proxy::opentsdb_put_handler))
+        .route(
+            "/render",
+            axum::routing::get(proxy::graphite_render_handler).post(proxy::graphite_render_handler),
+        )
+        .route(
+            "/search",
+            axum::routing::post(proxy::grafana_search_handler),
+        )
+        .route("/query", axum::routing::post(proxy::grafana_query_handler))
+        .route(
+            "/annotations",
+            axum::routing::post(proxy::grafana_annotations_handler),
+        )
+        .nest("/admin", admin::router())
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            debug_trace::layer,
+        ))
+        .with_state(state.clone())
+        .layer(
+            ServiceBuilder::new()
+                .layer(SetRequestIdLayer::x_request_id(logging::RandomRequestId))
+                .layer(PropagateRequestIdLayer::x_request_id())
+                .layer(axum::middleware::from_fn(deadline::layer))
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    forwarded::layer,
+                ))
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    access::layer,
+                ))
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    slow_client::layer,
+                ))
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    auth::layer,
+                ))
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    quota::layer,
+                ))
+                .layer(axum::middleware::from_fn_with_state(
+                    state.clone(),
+                    memory::layer,
+                ))
+                .layer(TraceLayer::new_for_http().make_span_with(logging::request_span)),
+        )
+}
 
-    let graceful = server.with_graceful_shutdown(shutdown_signal());
-    graceful.await?;
-    Ok(())
+/// Reloads the routing config on SIGHUP.
+fn spawn_reload_on_hangup(state: Arc<AppState>) {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        let Ok(mut hangups) = signal::unix::signal(signal::unix::SignalKind::hangup()) else {
+            warn!("Cannot listen for SIGHUP; reload the config via /admin/config/reload");
+            return;
+        };
+        while hangups.recv().await.is_some() {
+            info!("SIGHUP received, reloading the routing config");
+            if let Err(e) = state.reload_from_file() {
+                error!("Config reload failed, keeping the current routing: {}", e);
+            }
+        }
+    });
+    #[cfg(not(unix))]
+    let _ = state;
 }
 
 async fn shutdown_signal() {
@@ -0,0 +1,199 @@
+//! Budget for the bodies buffered by in-flight requests.
+//!
+//! Request bodies are read into memory before they are checked and routed, and Multi-mode
+//! answers are read whole before they are merged. With `max_buffered_bytes` set, every
+//! request's buffers are charged to one budget shared by all requests and released when the
+//! request finishes. A request arriving while the budget is spent gets `503 Service Unavailable`
+//! with `Retry-After`, as does one whose buffers would overrun it, so many medium-sized
+//! queries cannot add up to more memory than the pod has.
+
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::{Bytes, BytesMut};
+use hyper::Body;
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Seconds clients are asked to wait while the budget is spent.
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Bytes buffered across all requests, out of `limit`.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    fn try_reserve(&self, n: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(n).filter(|total| *total <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, n: usize) {
+        self.used.fetch_sub(n, Ordering::AcqRel);
+    }
+}
+
+/// What the current request holds of the budget; released when it is dropped.
+struct Charge {
+    budget: Arc<MemoryBudget>,
+    held: AtomicUsize,
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.budget.release(*self.held.get_mut());
+    }
+}
+
+tokio::task_local! {
+    static REQUEST: Arc<Charge>;
+}
+
+/// The budget has no room for a buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Exhausted;
+
+/// Charges `n` buffered bytes to the current request. Outside a request, or without a
+/// budget, everything fits.
+pub fn charge(n: usize) -> Result<(), Exhausted> {
+    REQUEST
+        .try_with(|c| {
+            if !c.budget.try_reserve(n) {
+                return Err(Exhausted);
+            }
+            c.held.fetch_add(n, Ordering::Relaxed);
+            Ok(())
+        })
+        .unwrap_or(Ok(()))
+}
+
+/// Why a backend answer could not be buffered.
+#[derive(Debug)]
+pub enum ReadError {
+    Exhausted,
+    Body(reqwest::Error),
+}
+
+/// Reads a backend answer whole, charging it to the current request as it arrives.
+pub async fn read_response(mut response: reqwest::Response) -> Result<Bytes, ReadError> {
+    let mut buf = BytesMut::new();
+    if let Some(len) = response.content_length() {
+        charge(len as usize).map_err(|_| ReadError::Exhausted)?;
+        buf.reserve(len as usize);
+    }
+    let mut charged = buf.capacity();
+    while let Some(chunk) = response.chunk().await.map_err(ReadError::Body)? {
+        let needed = buf.len() + chunk.len();
+        if needed > charged {
+            charge(needed - charged).map_err(|_| ReadError::Exhausted)?;
+            charged = needed;
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
+
+/// `503 Service Unavailable` for a request the budget has no room for.
+pub fn exhausted_response(state: &AppState) -> Response {
+    state
+        .metrics
+        .inc_counter("kairos_proxy_memory_rejected_total", &[]);
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+        Json(json!({ "error": "memory budget exhausted, retry later" })),
+    )
+        .into_response()
+}
+
+/// Refuses new requests while the budget is spent and charges the buffers of the others.
+pub async fn layer(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(budget) = &state.memory_budget else {
+        return next.run(req).await;
+    };
+    let path = req.uri().path();
+    if path == "/health" || path == "/metrics" || path == "/admin" || path.starts_with("/admin/") {
+        return next.run(req).await;
+    }
+    if budget.used() >= budget.limit {
+        warn!(
+            "Refusing request: {} bytes buffered, budget {}",
+            budget.used(),
+            budget.limit
+        );
+        return exhausted_response(&state);
+    }
+    let charge = Arc::new(Charge {
+        budget: budget.clone(),
+        held: AtomicUsize::new(0),
+    });
+    let response = REQUEST.scope(charge, next.run(req)).await;
+    state
+        .metrics
+        .set_gauge("kairos_proxy_buffered_bytes", &[], budget.used() as f64);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn releases_what_a_request_held_when_it_ends() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let request = || {
+            Arc::new(Charge {
+                budget: budget.clone(),
+                held: AtomicUsize::new(0),
+            })
+        };
+
+        let first = request();
+        REQUEST
+            .scope(first.clone(), async {
+                assert_eq!(charge(60), Ok(()));
+                assert_eq!(charge(30), Ok(()));
+                // Another request's buffers do not fit next to this one's
+                let second = REQUEST.scope(request(), async {
+                    let over = charge(20);
+                    assert_eq!(budget.used(), 90);
+                    over
+                });
+                assert_eq!(second.await, Err(Exhausted));
+            })
+            .await;
+        assert_eq!(budget.used(), 90);
+        drop(first);
+        assert_eq!(budget.used(), 0);
+        // Outside a request nothing is counted
+        assert_eq!(charge(1_000), Ok(()));
+    }
+}
@@ -0,0 +1,133 @@
+//! Detecting backends that disagree about the same series.
+//!
+//! Two backends answering for one series (same metric name and tags) with different values
+//! at the same timestamp usually means something writes to both, for example an ingest rule
+//! and a legacy writer overlapping. With `detect_merge_conflicts = true`, Multi-mode queries
+//! compare the backends' answers before merging them; each series found with conflicting
+//! values is logged with its first conflicting timestamp and counted in
+//! `kairos_proxy_merge_conflicts_total`. The answers are merged as usual. The tag added by
+//! `annotate_backend` is ignored when telling series apart.
+
+use crate::state::AppState;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use tracing::warn;
+
+// Metric name and tags, each tag's values sorted
+type Series = (String, BTreeMap<String, BTreeSet<String>>);
+
+fn series(result: &Value, ignored_tag: Option<&str>) -> Option<Series> {
+    let name = result.get("name")?.as_str()?.to_string();
+    let tags = result
+        .get("tags")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .filter(|(k, _)| Some(k.as_str()) != ignored_tag)
+        .map(|(k, values)| {
+            let values = match values {
+                Value::Array(values) => values.iter().map(Value::to_string).collect(),
+                other => BTreeSet::from([other.to_string()]),
+            };
+            (k.clone(), values)
+        })
+        .collect();
+    Some((name, tags))
+}
+
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Series in `responses` (one per backend) holding different values at a timestamp, with
+/// the first such timestamp.
+fn conflicts(responses: &[Value], ignored_tag: Option<&str>) -> Vec<(Series, i64)> {
+    // Series -> timestamp -> (response, value)
+    let mut seen: HashMap<Series, HashMap<i64, (usize, &Value)>> = HashMap::new();
+    let mut found: BTreeMap<Series, i64> = BTreeMap::new();
+    for (i, response) in responses.iter().enumerate() {
+        let results = response
+            .get("queries")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|q| q.get("results").and_then(Value::as_array))
+            .flatten();
+        for result in results {
+            let Some(key) = series(result, ignored_tag) else {
+                continue;
+            };
+            let points = seen.entry(key.clone()).or_default();
+            let values = result.get("values").and_then(Value::as_array);
+            for point in values.into_iter().flatten() {
+                let (Some(t), Some(v)) = (point.get(0).and_then(Value::as_i64), point.get(1))
+                else {
+                    continue;
+                };
+                match points.get(&t) {
+                    Some((other, seen_value)) if *other != i && !same_value(seen_value, v) => {
+                        let first = found.entry(key.clone()).or_insert(t);
+                        *first = (*first).min(t);
+                    }
+                    Some(_) => {}
+                    None => {
+                        points.insert(t, (i, v));
+                    }
+                }
+            }
+        }
+    }
+    found.into_iter().collect()
+}
+
+/// Logs and counts the conflicting series among backend `responses`, if detection is on.
+pub(crate) fn report(state: &AppState, responses: &[Value]) {
+    if !state.detect_merge_conflicts || responses.len() < 2 {
+        return;
+    }
+    for ((metric, tags), at_ms) in conflicts(responses, state.annotate_backend.as_deref()) {
+        warn!(
+            metric = metric.as_str(),
+            "Backends returned different values for '{}' {:?} at {}", metric, tags, at_ms
+        );
+        state
+            .metrics
+            .inc_counter("kairos_proxy_merge_conflicts_total", &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn finds_series_with_differing_values_at_a_timestamp() {
+        let answer = |tags: Value, values: Value| json!({ "queries": [{ "results": [{ "name": "cpu", "tags": tags, "values": values }] }] });
+        let responses = vec![
+            answer(
+                json!({ "host": ["a"], "_proxy_backend": ["eu"] }),
+                json!([[1, 1.0], [2, 2.0]]),
+            ),
+            // Same series, agreeing at 1 (as an integer) and disagreeing at 2
+            answer(
+                json!({ "host": ["a"], "_proxy_backend": ["us"] }),
+                json!([[1, 1], [2, 5.0], [3, 3.0]]),
+            ),
+            // Another series entirely
+            answer(json!({ "host": ["b"] }), json!([[2, 9.0]])),
+        ];
+        let found = conflicts(&responses, Some("_proxy_backend"));
+        assert_eq!(found.len(), 1);
+        let ((metric, tags), at_ms) = &found[0];
+        assert_eq!(metric, "cpu");
+        assert_eq!(tags["host"], BTreeSet::from(["\"a\"".to_string()]));
+        assert_eq!(*at_ms, 2);
+
+        // Without ignoring the backend tag, the answers are different series
+        assert!(conflicts(&responses, None).is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+//! Per-rule `merge.strategy` for metrics several backends answer for.
+//!
+//! Merging unions the results of every backend that returned a metric, which is wrong when
+//! clusters with overlapping retention hold the same series. A rule can instead keep only the
+//! first backend's results (`prefer_first`, in the order the query lists its metrics) or fail
+//! the query with `502 Bad Gateway` (`error_on_overlap`). Only backends that returned points
+//! count, and the pieces of a range split across time tiers are one answer. Both are counted in
+//! `kairos_proxy_merge_overlaps_total{strategy}`. Streamed queries are not merged and pass
+//! every backend's results through.
+
+use crate::config::MergeStrategy;
+use crate::routes::Subject;
+use crate::state::AppState;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tracing::warn;
+
+/// A metric more than one backend returned data for under `error_on_overlap`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Overlap {
+    pub metric: String,
+}
+
+impl IntoResponse for Overlap {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::BAD_GATEWAY,
+            Json(json!({
+                "error": format!("more than one backend returned data for '{}'", self.metric),
+            })),
+        )
+            .into_response()
+    }
+}
+
+/// The strategies other than `union` that apply to the metrics of `query`. A metric listed
+/// more than once gets the strictest of its rules' strategies.
+pub(crate) fn strategies(
+    state: &AppState,
+    headers: &hyper::HeaderMap,
+    query: &Value,
+) -> HashMap<String, MergeStrategy> {
+    let mut strategies = HashMap::new();
+    let metrics = query.get("metrics").and_then(Value::as_array);
+    for metric in metrics.into_iter().flatten() {
+        let Some(name) = metric.get("name").and_then(Value::as_str) else {
+            continue;
+        };
+        let subject = Subject {
+            metric: name,
+            tags: metric.get("tags"),
+            headers: Some(headers),
+        };
+        let strategy = state.merge_strategy(&subject);
+        if strategy != MergeStrategy::Union {
+            let entry = strategies.entry(name.to_string()).or_insert(strategy);
+            *entry = (*entry).max(strategy);
+        }
+    }
+    strategies
+}
+
+fn results(response: &Value) -> impl Iterator<Item = &Value> {
+    response
+        .get("queries")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|q| q.get("results").and_then(Value::as_array))
+        .flatten()
+}
+
+fn has_data(response: &Value, metric: &str) -> bool {
+    results(response).any(|r| {
+        r.get("name").and_then(Value::as_str) == Some(metric)
+            && r.get("values")
+                .and_then(Value::as_array)
+                .is_some_and(|v| !v.is_empty())
+    })
+}
+
+/// Applies `strategies` to backend responses in plan order, before they are merged.
+pub(crate) fn apply(
+    state: &AppState,
+    responses: &mut [Value],
+    strategies: &HashMap<String, MergeStrategy>,
+) -> Result<(), Overlap> {
+    for (metric, &strategy) in strategies {
+        let mut holders = (0..responses.len()).filter(|&i| has_data(&responses[i], metric));
+        let Some(first) = holders.next() else {
+            continue;
+        };
+        if holders.next().is_none() {
+            continue;
+        }
+        let label = match strategy {
+            MergeStrategy::Union => "union",
+            MergeStrategy::PreferFirst => "prefer_first",
+            MergeStrategy::ErrorOnOverlap => "error_on_overlap",
+        };
+        state
+            .metrics
+            .inc_counter("kairos_proxy_merge_overlaps_total", &[("strategy", label)]);
+        if strategy == MergeStrategy::ErrorOnOverlap {
+            warn!("More than one backend returned data for '{}'", metric);
+            return Err(Overlap {
+                metric: metric.clone(),
+            });
+        }
+        for (i, response) in responses.iter_mut().enumerate() {
+            if i == first {
+                continue;
+            }
+            let queries = response.get_mut("queries").and_then(Value::as_array_mut);
+            for query in queries.into_iter().flatten() {
+                if let Some(results) = query.get_mut("results").and_then(Value::as_array_mut) {
+                    results.retain(|r| r.get("name").and_then(Value::as_str) != Some(metric));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, Config, MergeConfig, RouteConfig};
+
+    #[test]
+    fn keeps_the_first_answer_or_refuses_overlaps() {
+        let route = |metric: &str, backend: &str, strategy| RouteConfig {
+            backend: backend.to_string(),
+            metric: Some(metric.to_string()),
+            merge: Some(MergeConfig {
+                strategy: Some(strategy),
+            }),
+            ..Default::default()
+        };
+        let cfg = Config {
+            backends: ["hot", "archive"]
+                .map(|name| Backend {
+                    name: Some(name.to_string()),
+                    url: format!("http://{}:8080", name),
+                    ..Default::default()
+                })
+                .into(),
+            routes: vec![
+                route("^cpu$", "hot", MergeStrategy::PreferFirst),
+                route("^mem$", "hot", MergeStrategy::ErrorOnOverlap),
+                route(".*", "archive", MergeStrategy::Union),
+            ],
+            ..Default::default()
+        };
+        let state = AppState::from_config(&cfg).expect("state");
+        let query =
+            json!({ "metrics": [{ "name": "cpu" }, { "name": "mem" }, { "name": "disk" }] });
+        let strategies = strategies(&state, &hyper::HeaderMap::new(), &query);
+        assert_eq!(strategies.len(), 2);
+        assert_eq!(strategies["cpu"], MergeStrategy::PreferFirst);
+
+        let answer = |host: &str, mem: Value| {
+            json!({ "queries": [{ "results": [
+                { "name": "cpu", "tags": { "host": [host] }, "values": [[1, 1]] },
+                { "name": "mem", "tags": { "host": [host] }, "values": mem },
+            ] }] })
+        };
+        let mut responses = vec![answer("a", json!([])), answer("b", json!([]))];
+        apply(&state, &mut responses, &strategies).expect("no conflict");
+        assert!(has_data(&responses[0], "cpu"));
+        assert!(!has_data(&responses[1], "cpu"));
+
+        let mut responses = vec![answer("a", json!([[1, 2]])), answer("b", json!([[1, 2]]))];
+        let overlap = apply(&state, &mut responses, &strategies).unwrap_err();
+        assert_eq!(overlap.metric, "mem");
+    }
+}
@@ -0,0 +1,137 @@
+//! `OPTIONS` on every route.
+//!
+//! Routes only register the methods they serve, so `OPTIONS` would be `405 Method Not Allowed`
+//! and break clients that probe capabilities first. It is answered with `204 No Content` and
+//! the route's `Allow` header instead, plus the `Access-Control-Allow-Methods` and
+//! `-Headers` a CORS preflight asks for. (`HEAD` is served wherever `GET` is.) No
+//! `Access-Control-Allow-Origin` is sent, so cross-origin browser access stays disabled.
+//!
+//! The layer wraps the whole router: route-level layers run before axum adds `Allow`.
+
+use axum::{
+    body::{boxed, Empty},
+    http::{header, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use hyper::Body;
+
+/// Seconds browsers may cache a preflight answer.
+const PREFLIGHT_MAX_AGE_SECS: u32 = 600;
+
+pub async fn layer(req: Request<Body>, next: Next<Body>) -> Response {
+    if req.method() != Method::OPTIONS {
+        return next.run(req).await;
+    }
+    let preflight_headers = req
+        .headers()
+        .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        .then(|| {
+            req.headers()
+                .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+                .cloned()
+        });
+    let response = next.run(req).await;
+    // Anything else (404, or a route serving OPTIONS itself) passes through
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
+    }
+    let mut allow = response
+        .headers()
+        .get(header::ALLOW)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if !allow.is_empty() {
+        allow.push(',');
+    }
+    allow.push_str("OPTIONS");
+    let Ok(allow) = HeaderValue::from_str(&allow) else {
+        return response;
+    };
+
+    // Keep the headers set on the way out (request id and the like)
+    let (mut parts, _) = response.into_parts();
+    parts.status = StatusCode::NO_CONTENT;
+    let headers = &mut parts.headers;
+    headers.remove(header::CONTENT_TYPE);
+    headers.remove(header::CONTENT_LENGTH);
+    headers.insert(header::ALLOW, allow.clone());
+    if let Some(requested) = preflight_headers {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, allow);
+        if let Some(requested) = requested {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested);
+        }
+        headers.insert(
+            header::ACCESS_CONTROL_MAX_AGE,
+            PREFLIGHT_MAX_AGE_SECS.into(),
+        );
+    }
+    Response::from_parts(parts, boxed(Empty::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn answers_options_with_the_allowed_methods() {
+        let router = Router::new()
+            .route("/health", get(|| async { "OK" }))
+            .route("/api/v1/datapoints/query", post(|| async { "{}" }));
+        let app = tower::Layer::layer(&axum::middleware::from_fn(layer), router);
+        let request = |method: &str, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let resp = app
+            .clone()
+            .oneshot(request("OPTIONS", "/api/v1/datapoints/query"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()[header::ALLOW], "POST,OPTIONS");
+        assert!(resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_METHODS)
+            .is_none());
+
+        let mut preflight = request("OPTIONS", "/health");
+        preflight.headers_mut().insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            HeaderValue::from_static("GET"),
+        );
+        preflight.headers_mut().insert(
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            HeaderValue::from_static("x-api-key"),
+        );
+        let resp = app.clone().oneshot(preflight).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            resp.headers()[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET,HEAD,OPTIONS"
+        );
+        assert_eq!(
+            resp.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "x-api-key"
+        );
+
+        let resp = app
+            .clone()
+            .oneshot(request("HEAD", "/health"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = app.oneshot(request("OPTIONS", "/missing")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
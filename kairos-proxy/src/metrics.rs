@@ -0,0 +1,216 @@
+//! Minimal in-process metrics registry rendered in the Prometheus text exposition format.
+//!
+//! Series are keyed by metric name plus a sorted label set. Everything lives behind a single
+//! mutex; updates are a map lookup and an add, so contention stays negligible next to the
+//! network I/O the proxy does per request.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds (seconds) of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+type Labels = Vec<(String, String)>;
+
+/// One series value: metric name, labels and current value.
+pub type Sample = (String, Labels, f64);
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<String, BTreeMap<Labels, u64>>,
+    gauges: BTreeMap<String, BTreeMap<Labels, f64>>,
+    histograms: BTreeMap<String, BTreeMap<Labels, Histogram>>,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Registry>,
+}
+
+fn labels(pairs: &[(&str, &str)]) -> Labels {
+    let mut l: Labels = pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    l.sort();
+    l
+}
+
+fn format_labels(labels: &Labels, extra: Option<(&str, &str)>) -> String {
+    let mut parts: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect();
+    if let Some((k, v)) = extra {
+        parts.push(format!("{}=\"{}\"", k, v));
+    }
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    /// Adds `by` to a counter.
+    pub fn inc_counter_by(&self, name: &str, pairs: &[(&str, &str)], by: u64) {
+        let mut reg = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        *reg.counters
+            .entry(name.to_string())
+            .or_default()
+            .entry(labels(pairs))
+            .or_default() += by;
+    }
+
+    pub fn inc_counter(&self, name: &str, pairs: &[(&str, &str)]) {
+        self.inc_counter_by(name, pairs, 1);
+    }
+
+    /// Sets a gauge to `value`.
+    pub fn set_gauge(&self, name: &str, pairs: &[(&str, &str)], value: f64) {
+        let mut reg = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        reg.gauges
+            .entry(name.to_string())
+            .or_default()
+            .insert(labels(pairs), value);
+    }
+
+    /// Records a duration into a latency histogram.
+    pub fn observe_duration(&self, name: &str, pairs: &[(&str, &str)], elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let mut reg = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let h = reg
+            .histograms
+            .entry(name.to_string())
+            .or_default()
+            .entry(labels(pairs))
+            .or_default();
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *bound {
+                h.buckets[i] += 1;
+            }
+        }
+        h.sum += secs;
+        h.count += 1;
+    }
+
+    /// Current value of a counter.
+    #[cfg(test)]
+    pub fn counter(&self, name: &str, pairs: &[(&str, &str)]) -> u64 {
+        let reg = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        reg.counters
+            .get(name)
+            .and_then(|series| series.get(&labels(pairs)))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Current value of every series. Histograms contribute their `_count` and `_sum`.
+    pub fn snapshot(&self) -> Vec<Sample> {
+        let reg = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let mut samples = Vec::new();
+        for (name, series) in &reg.counters {
+            for (l, v) in series {
+                samples.push((name.clone(), l.clone(), *v as f64));
+            }
+        }
+        for (name, series) in &reg.gauges {
+            for (l, v) in series {
+                samples.push((name.clone(), l.clone(), *v));
+            }
+        }
+        for (name, series) in &reg.histograms {
+            for (l, h) in series {
+                samples.push((format!("{}_count", name), l.clone(), h.count as f64));
+                samples.push((format!("{}_sum", name), l.clone(), h.sum));
+            }
+        }
+        samples
+    }
+
+    /// Renders every series in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let reg = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+        for (name, series) in &reg.counters {
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (l, v) in series {
+                let _ = writeln!(out, "{}{} {}", name, format_labels(l, None), v);
+            }
+        }
+        for (name, series) in &reg.gauges {
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for (l, v) in series {
+                let _ = writeln!(out, "{}{} {}", name, format_labels(l, None), v);
+            }
+        }
+        for (name, series) in &reg.histograms {
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            for (l, h) in series {
+                for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+                    let le = bound.to_string();
+                    let _ = writeln!(
+                        out,
+                        "{}_bucket{} {}",
+                        name,
+                        format_labels(l, Some(("le", &le))),
+                        h.buckets[i]
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    name,
+                    format_labels(l, Some(("le", "+Inf"))),
+                    h.count
+                );
+                let _ = writeln!(out, "{}_sum{} {}", name, format_labels(l, None), h.sum);
+                let _ = writeln!(out, "{}_count{} {}", name, format_labels(l, None), h.count);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_and_histograms() {
+        let m = Metrics::default();
+        m.inc_counter("requests_total", &[("target", "canary"), ("backend", "a")]);
+        m.inc_counter("requests_total", &[("backend", "a"), ("target", "canary")]);
+        m.observe_duration("latency_seconds", &[], Duration::from_millis(30));
+        m.set_gauge("queue_bytes", &[], 1.0);
+        m.set_gauge("queue_bytes", &[], 2.5);
+        assert_eq!(
+            m.counter("requests_total", &[("backend", "a"), ("target", "canary")]),
+            2
+        );
+
+        let text = m.render();
+        assert!(text.contains("requests_total{backend=\"a\",target=\"canary\"} 2"));
+        assert!(text.contains("latency_seconds_bucket{le=\"0.025\"} 0"));
+        assert!(text.contains("latency_seconds_bucket{le=\"0.05\"} 1"));
+        assert!(text.contains("latency_seconds_count 1"));
+        assert!(text.contains("# TYPE queue_bytes gauge\nqueue_bytes 2.5\n"));
+    }
+}
@@ -0,0 +1,94 @@
+use crate::admission::Priority;
+use crate::state::{AppState, BackendTarget};
+use bytes::Bytes;
+use serde_json::Value;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// Duplicates a request to the backend's shadow cluster, if one is configured and this
+/// request falls within `mirror_percent`. Fire-and-forget: the response is discarded and
+/// failures are only logged.
+///
+/// Mirrors only run on spare outbound capacity: if no permit is immediately available the
+/// copy is skipped rather than queued ahead of real traffic.
+pub(crate) fn mirror_request(
+    state: &AppState,
+    target: &BackendTarget,
+    endpoint: &str,
+    body: Bytes,
+    headers: &hyper::HeaderMap,
+) {
+    mirror(state, target, endpoint, body, headers, false);
+}
+
+/// Like `mirror_request`, but when the mirror is set to `mirror_compare` its parsed answer is
+/// kept, for comparing with the backend's (see `shadow`).
+pub(crate) fn mirror_query(
+    state: &AppState,
+    target: &BackendTarget,
+    endpoint: &str,
+    body: Bytes,
+    headers: &hyper::HeaderMap,
+) -> Option<JoinHandle<Option<Value>>> {
+    mirror(state, target, endpoint, body, headers, true)
+}
+
+fn mirror(
+    state: &AppState,
+    target: &BackendTarget,
+    endpoint: &str,
+    body: Bytes,
+    headers: &hyper::HeaderMap,
+    keep_answer: bool,
+) -> Option<JoinHandle<Option<Value>>> {
+    let mirror = target.mirror.as_ref()?;
+    if fastrand::f64() * 100.0 >= mirror.percent {
+        return None;
+    }
+    let Some(permit) = state.admission.try_acquire(Priority::Batch) else {
+        debug!(
+            "Skipping mirror to {}: no spare outbound capacity",
+            mirror.url
+        );
+        return None;
+    };
+    let request_url = match mirror.url.join(endpoint) {
+        Ok(u) => u,
+        Err(e) => {
+            warn!("Failed to build mirror URL: {}", e);
+            return None;
+        }
+    };
+
+    let mut builder = target.client.get().post(request_url).body(body);
+    for (name, value) in headers.iter() {
+        if name == hyper::http::header::HOST
+            || name == hyper::http::header::CONTENT_LENGTH
+            || name == hyper::http::header::AUTHORIZATION
+        {
+            continue;
+        }
+        builder = builder.header(name, value);
+    }
+    if let Some(t) = &mirror.token {
+        builder = builder.header("Authorization", format!("Bearer {}", t));
+    }
+
+    let mirror_url = mirror.url.clone();
+    let keep_answer = keep_answer && mirror.compare;
+    let sent = tokio::spawn(async move {
+        let _permit = permit;
+        match builder.send().await {
+            Ok(r) if r.status().is_success() => {
+                debug!("Mirror {} answered {}", mirror_url, r.status());
+                if keep_answer {
+                    return r.json::<Value>().await.ok();
+                }
+            }
+            Ok(r) => warn!("Mirror {} answered {}", mirror_url, r.status()),
+            Err(e) => warn!("Mirror request to {} failed: {}", mirror_url, e),
+        }
+        None
+    });
+    keep_answer.then_some(sent)
+}
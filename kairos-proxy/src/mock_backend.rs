@@ -0,0 +1,204 @@
+//! `kairos-proxy mock-backend --port <port> [--name <name>]`: a stand-in KairosDB.
+//!
+//! Answers queries with made-up series so routing configs can be developed and demoed without
+//! a cluster: every metric gets one result tagged `host=<name>` (default `mock`), with a
+//! point per minute of the queried range (at most 500, the last hour if the query has no
+//! start) following a wave derived from the metric name. Tag queries list `host` and `region`,
+//! writes are accepted and dropped, and `/api/v1/health/check` and `/api/v1/version` answer as
+//! KairosDB does. Running one mock per backend name shows in the `host` tag where each metric
+//! was routed.
+
+use crate::timerange::{now_ms, TimeRange};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::info;
+
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_RANGE_MS: i64 = 3_600_000;
+const STEP_MS: i64 = 60_000;
+const MAX_POINTS: i64 = 500;
+
+pub struct Args {
+    port: u16,
+    name: String,
+}
+
+impl Args {
+    /// The mock backend's arguments, if the command line asks for one.
+    pub fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Option<Self>> {
+        if args.next().as_deref() != Some("mock-backend") {
+            return Ok(None);
+        }
+        let mut parsed = Args {
+            port: DEFAULT_PORT,
+            name: "mock".to_string(),
+        };
+        while let Some(arg) = args.next() {
+            let value = args.next();
+            match arg.as_str() {
+                "--port" => {
+                    parsed.port = value
+                        .and_then(|p| p.parse().ok())
+                        .ok_or_else(|| anyhow::anyhow!("--port needs a port number"))?;
+                }
+                "--name" => {
+                    parsed.name = value.ok_or_else(|| anyhow::anyhow!("--name needs a name"))?;
+                }
+                _ => anyhow::bail!(
+                    "usage: kairos-proxy mock-backend [--port <port>] [--name <name>]"
+                ),
+            }
+        }
+        Ok(Some(parsed))
+    }
+}
+
+fn metric_names(query: &Value) -> impl Iterator<Item = &str> {
+    query
+        .get("metrics")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|m| m.get("name").and_then(Value::as_str))
+}
+
+/// A repeatable series for `metric`: a wave with a period and offset of its own.
+fn values(metric: &str, range: TimeRange) -> Vec<Value> {
+    let seed = crate::hash_ring::hash(metric);
+    let period = (30 + seed % 90) as f64 * STEP_MS as f64;
+    let base = (seed % 100) as f64;
+    let first = range.start_ms.div_euclid(STEP_MS) * STEP_MS + STEP_MS;
+    let step = ((range.end_ms - first) / MAX_POINTS).max(STEP_MS);
+    (0..)
+        .map(|i| first + i * step)
+        .take_while(|t| *t <= range.end_ms)
+        .take(MAX_POINTS as usize)
+        .map(|t| {
+            let phase = t as f64 / period * std::f64::consts::TAU;
+            let v = base + 10.0 * phase.sin();
+            json!([t, (v * 100.0).round() / 100.0])
+        })
+        .collect()
+}
+
+fn query(name: &str, body: &Value) -> Value {
+    let now = now_ms();
+    let range = TimeRange::of_query(body, now).unwrap_or(TimeRange {
+        start_ms: now - DEFAULT_RANGE_MS,
+        end_ms: now,
+    });
+    let queries: Vec<Value> = metric_names(body)
+        .map(|metric| {
+            let values = values(metric, range);
+            json!({
+                "sample_size": values.len(),
+                "results": [{
+                    "name": metric,
+                    "group_by": [{ "name": "type", "type": "number" }],
+                    "tags": { "host": [name] },
+                    "values": values,
+                }],
+            })
+        })
+        .collect();
+    json!({ "queries": queries })
+}
+
+fn tags(name: &str, body: &Value) -> Value {
+    let results: Vec<Value> = metric_names(body)
+        .map(|metric| {
+            json!({
+                "name": metric,
+                "tags": { "host": [name], "region": ["us-east-1"] },
+                "values": [],
+            })
+        })
+        .collect();
+    json!({ "queries": [{ "results": results }] })
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let name = Arc::new(args.name);
+    let app = Router::new()
+        .route("/health", get(|| async { Json(json!({ "status": "ok" })) }))
+        .route(
+            "/api/v1/health/check",
+            get(|| async { StatusCode::NO_CONTENT }),
+        )
+        .route(
+            "/api/v1/version",
+            get(|| async { Json(json!({ "version": "KairosDB (kairos-proxy mock)" })) }),
+        )
+        .route(
+            "/api/v1/datapoints",
+            post(|| async { StatusCode::NO_CONTENT }),
+        )
+        .route(
+            "/api/v1/datapoints/query",
+            post(
+                |State(name): State<Arc<String>>, Json(body): Json<Value>| async move {
+                    Json(query(&name, &body))
+                },
+            ),
+        )
+        .route(
+            "/api/v1/datapoints/query/tags",
+            post(
+                |State(name): State<Arc<String>>, Json(body): Json<Value>| async move {
+                    Json(tags(&name, &body))
+                },
+            ),
+        )
+        .with_state(name.clone());
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], args.port));
+    info!("Mock KairosDB backend '{}' listening on {}", name, addr);
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_every_metric_across_the_range() {
+        let body = json!({
+            "start_absolute": 1_700_000_000_000i64,
+            "end_absolute": 1_700_000_000_000i64 + 10 * STEP_MS,
+            "metrics": [{ "name": "cpu.load" }, { "name": "mem.free" }],
+        });
+        let answer = query("eu", &body);
+        let queries = answer["queries"].as_array().unwrap();
+        assert_eq!(queries.len(), 2);
+        let result = &queries[0]["results"][0];
+        assert_eq!(result["name"], "cpu.load");
+        assert_eq!(result["tags"]["host"], json!(["eu"]));
+        let points = result["values"].as_array().unwrap();
+        assert_eq!(points.len(), 10);
+        assert!(points
+            .iter()
+            .all(|p| (1_700_000_000_000i64..=1_700_000_600_000).contains(&p[0].as_i64().unwrap())));
+        // The same metric always gets the same series
+        assert_eq!(query("eu", &body)["queries"][0], queries[0]);
+
+        assert_eq!(
+            tags("eu", &body)["queries"][0]["results"][1]["tags"]["region"],
+            json!(["us-east-1"])
+        );
+        let args = |a: &[&str]| Args::parse(a.iter().map(|s| s.to_string()));
+        let parsed = args(&["mock-backend", "--port", "9001"]).unwrap().unwrap();
+        assert_eq!((parsed.port, parsed.name.as_str()), (9001, "mock"));
+        assert!(args(&["mock-backend", "--port"]).is_err());
+        assert!(args(&["serve"]).unwrap().is_none());
+    }
+}
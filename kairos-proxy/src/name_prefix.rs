@@ -0,0 +1,107 @@
+//! Per-backend translation of metric name prefixes, for clusters storing names without the
+//! namespace clients use (or with one of their own). `strip_prefix` is removed from the names
+//! of outbound queries and writes, then `add_prefix` is put in front. Results get the names the
+//! client asked for back.
+
+use serde_json::Value;
+use std::collections::HashMap;
+
+pub struct NamePrefix {
+    strip: Option<String>,
+    add: Option<String>,
+}
+
+impl NamePrefix {
+    /// The translation of a backend's `strip_prefix` and `add_prefix`; `None` if it sets neither.
+    pub fn new(strip: &Option<String>, add: &Option<String>) -> Option<Self> {
+        let nonempty = |p: &Option<String>| p.clone().filter(|p| !p.is_empty());
+        let (strip, add) = (nonempty(strip), nonempty(add));
+        (strip.is_some() || add.is_some()).then_some(NamePrefix { strip, add })
+    }
+
+    /// The name the backend knows `name` by.
+    pub fn translate(&self, name: &str) -> String {
+        let name = self
+            .strip
+            .as_deref()
+            .and_then(|p| name.strip_prefix(p))
+            .unwrap_or(name);
+        match &self.add {
+            Some(add) => format!("{}{}", add, name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Translates the metric names of a query sent to the backend. Returns the client's name
+    /// for each translated one, for `inbound`.
+    pub fn outbound(&self, query: &mut Value) -> HashMap<String, String> {
+        let mut original = HashMap::new();
+        let metrics = query.get_mut("metrics").and_then(Value::as_array_mut);
+        for metric in metrics.into_iter().flatten() {
+            if let Some(Value::String(name)) = metric.get_mut("name") {
+                let translated = self.translate(name);
+                original.insert(translated.clone(), std::mem::replace(name, translated));
+            }
+        }
+        original
+    }
+
+    /// Translates the names of ingested datapoints.
+    pub fn outbound_datapoints(&self, datapoints: &mut [Value]) {
+        for dp in datapoints {
+            if let Some(Value::String(name)) = dp.get_mut("name") {
+                *name = self.translate(name);
+            }
+        }
+    }
+}
+
+/// Gives the results of a backend response the names in `original` (from `outbound`).
+pub fn inbound(original: &HashMap<String, String>, response: &mut Value) {
+    let results = response
+        .get_mut("queries")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(|q| q.get_mut("results").and_then(Value::as_array_mut))
+        .flatten();
+    for result in results {
+        if let Some(Value::String(name)) = result.get_mut("name") {
+            if let Some(client) = original.get(name.as_str()) {
+                *name = client.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn strips_and_restores_the_namespace() {
+        assert!(NamePrefix::new(&None, &Some(String::new())).is_none());
+        let prefix = NamePrefix::new(&Some("prod.".to_string()), &None).unwrap();
+        let mut query = json!({ "metrics": [{ "name": "prod.cpu.load" }, { "name": "mem" }] });
+        let original = prefix.outbound(&mut query);
+        assert_eq!(query["metrics"][0]["name"], "cpu.load");
+        assert_eq!(query["metrics"][1]["name"], "mem");
+
+        let mut response = json!({ "queries": [
+            { "results": [{ "name": "cpu.load", "values": [] }] },
+            { "results": [{ "name": "mem", "values": [] }] },
+        ] });
+        inbound(&original, &mut response);
+        assert_eq!(
+            response["queries"][0]["results"][0]["name"],
+            "prod.cpu.load"
+        );
+        assert_eq!(response["queries"][1]["results"][0]["name"], "mem");
+
+        let both = NamePrefix::new(&Some("prod.".to_string()), &Some("eu.".to_string())).unwrap();
+        let mut datapoints = vec![json!({ "name": "prod.cpu.load", "value": 1 })];
+        both.outbound_datapoints(&mut datapoints);
+        assert_eq!(datapoints[0]["name"], "eu.cpu.load");
+    }
+}
@@ -0,0 +1,172 @@
+use crate::ingest::forward_datapoints;
+use crate::proxy::read_body;
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, warn};
+
+/// OpenTSDB treats timestamps with more than 10 digits as milliseconds.
+const MAX_SECONDS_TIMESTAMP: i64 = 9_999_999_999;
+
+/// Query parameters of OpenTSDB's `/api/put`. Their presence (not value) switches
+/// the response from `204` to a JSON summary.
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct PutParams {
+    summary: Option<String>,
+    details: Option<String>,
+}
+
+/// Converts one OpenTSDB datapoint (`{ metric, timestamp, value, tags }`) into KairosDB's
+/// datapoint shape. Seconds timestamps are widened to milliseconds.
+pub(crate) fn to_kairos_datapoint(dp: &serde_json::Value) -> Result<serde_json::Value, String> {
+    let metric = dp
+        .get("metric")
+        .and_then(|v| v.as_str())
+        .filter(|m| !m.is_empty())
+        .ok_or("missing or empty 'metric'")?;
+    let timestamp = dp
+        .get("timestamp")
+        .and_then(|v| v.as_i64())
+        .ok_or("missing or non-integer 'timestamp'")?;
+    let timestamp = if timestamp <= MAX_SECONDS_TIMESTAMP {
+        timestamp
+            .checked_mul(1_000)
+            .ok_or("'timestamp' out of range")?
+    } else {
+        timestamp
+    };
+    // OpenTSDB allows numeric strings for values
+    let value = match dp.get("value") {
+        Some(v @ serde_json::Value::Number(_)) => v.clone(),
+        Some(serde_json::Value::String(s)) => {
+            if let Ok(i) = s.parse::<i64>() {
+                json!(i)
+            } else {
+                match s.parse::<f64>() {
+                    Ok(f) if f.is_finite() => json!(f),
+                    _ => return Err(format!("invalid 'value': {}", s)),
+                }
+            }
+        }
+        _ => return Err("missing or non-numeric 'value'".into()),
+    };
+    let tags = match dp.get("tags") {
+        Some(serde_json::Value::Object(t)) => t.clone(),
+        None => serde_json::Map::new(),
+        Some(_) => return Err("'tags' must be an object".into()),
+    };
+    Ok(json!({
+        "name": metric,
+        "timestamp": timestamp,
+        "value": value,
+        "tags": tags,
+    }))
+}
+
+/// OpenTSDB-compatible `/api/put`: accepts a single datapoint or an array of them and
+/// forwards the translated datapoints to the routed KairosDB backends.
+pub async fn opentsdb_put_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<PutParams>,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    debug!("Received OpenTSDB put request");
+
+    let mut req = req;
+    let body_bytes = match read_body(&state, &mut req).await {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Failed to read request body: {:?}", e);
+            return Err(e);
+        }
+    };
+    if let Err(rejection) = state.query_checks.json_limits.check(&body_bytes) {
+        return Ok(rejection.into_response());
+    }
+    let json: serde_json::Value = match serde_json::from_slice(&body_bytes) {
+        Ok(j) => j,
+        Err(e) => {
+            error!("Failed to parse JSON body: {}", e);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+    let raw = match json {
+        serde_json::Value::Array(items) => items,
+        obj @ serde_json::Value::Object(_) => vec![obj],
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let mut datapoints = Vec::with_capacity(raw.len());
+    let mut errors = Vec::new();
+    for dp in &raw {
+        match to_kairos_datapoint(dp) {
+            Ok(d) => datapoints.push(d),
+            Err(e) => errors.push(json!({ "datapoint": dp, "error": e })),
+        }
+    }
+    // Like OpenTSDB, reject the whole request if any datapoint is invalid
+    if !errors.is_empty() {
+        warn!("Rejecting {} invalid OpenTSDB datapoint(s)", errors.len());
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "success": 0, "failed": raw.len(), "errors": errors })),
+        )
+            .into_response());
+    }
+
+    let success = datapoints.len();
+    if !datapoints.is_empty() {
+        forward_datapoints(&state, req.headers(), datapoints).await?;
+    }
+
+    if params.details.is_some() {
+        Ok(Json(json!({ "success": success, "failed": 0, "errors": [] })).into_response())
+    } else if params.summary.is_some() {
+        Ok(Json(json!({ "success": success, "failed": 0 })).into_response())
+    } else {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_seconds_and_milliseconds_timestamps() {
+        let secs = to_kairos_datapoint(&json!({
+            "metric": "sys.cpu.user", "timestamp": 1346846400, "value": 18, "tags": { "host": "web01" }
+        }))
+        .expect("convert");
+        assert_eq!(secs["name"], "sys.cpu.user");
+        assert_eq!(secs["timestamp"], 1_346_846_400_000i64);
+        assert_eq!(secs["tags"]["host"], "web01");
+
+        let millis = to_kairos_datapoint(&json!({
+            "metric": "m", "timestamp": 1346846400123i64, "value": "1.5"
+        }))
+        .expect("convert");
+        assert_eq!(millis["timestamp"], 1_346_846_400_123i64);
+        assert_eq!(millis["value"], 1.5);
+    }
+
+    #[test]
+    fn rejects_invalid_datapoints() {
+        assert!(to_kairos_datapoint(&json!({ "timestamp": 1, "value": 1 })).is_err());
+        assert!(to_kairos_datapoint(&json!({ "metric": "m", "value": 1 })).is_err());
+        assert!(
+            to_kairos_datapoint(&json!({ "metric": "m", "timestamp": 1, "value": "x" })).is_err()
+        );
+        assert!(
+            to_kairos_datapoint(&json!({ "metric": "m", "timestamp": i64::MIN, "value": 1 }))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! Paging through the series of large Multi-mode query results.
+//!
+//! A client sending `X-Proxy-Page-Size: <n>` gets at most `n` results (series, counted across
+//! the queries of the response in order) instead of all of them. Queries whose results fall
+//! outside the page keep their place with an empty `results`. When more follow, the response
+//! carries `X-Proxy-Next-Cursor`; sending the same query again with that value in
+//! `X-Proxy-Page-Cursor` returns the next page. `X-Proxy-Total-Results` gives the count of the
+//! whole result. Each page runs the query again, so pages of a cached query are cheap and
+//! pages of an uncached one can reflect newer data.
+
+use axum::response::Response;
+use hyper::header::HeaderValue;
+use hyper::HeaderMap;
+use serde_json::Value;
+
+pub const PAGE_SIZE_HEADER: &str = "x-proxy-page-size";
+pub const PAGE_CURSOR_HEADER: &str = "x-proxy-page-cursor";
+pub const NEXT_CURSOR_HEADER: &str = "x-proxy-next-cursor";
+pub const TOTAL_RESULTS_HEADER: &str = "x-proxy-total-results";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Page {
+    // Index of the first result of the page
+    cursor: usize,
+    size: usize,
+}
+
+/// Where a paged response stands in the whole result.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Paged {
+    pub total: usize,
+    pub next: Option<usize>,
+}
+
+impl Page {
+    /// The page the request asks for, if any.
+    pub fn requested(headers: &HeaderMap) -> Result<Option<Self>, String> {
+        let header = |name: &str| -> Result<Option<usize>, String> {
+            headers
+                .get(name)
+                .map(|v| {
+                    v.to_str()
+                        .ok()
+                        .and_then(|v| v.trim().parse().ok())
+                        .ok_or_else(|| format!("{} must be a non-negative integer", name))
+                })
+                .transpose()
+        };
+        let cursor = header(PAGE_CURSOR_HEADER)?;
+        let Some(size) = header(PAGE_SIZE_HEADER)? else {
+            return match cursor {
+                Some(_) => Err(format!("{} needs {}", PAGE_CURSOR_HEADER, PAGE_SIZE_HEADER)),
+                None => Ok(None),
+            };
+        };
+        if size == 0 {
+            return Err(format!("{} must be at least 1", PAGE_SIZE_HEADER));
+        }
+        Ok(Some(Page {
+            cursor: cursor.unwrap_or(0),
+            size,
+        }))
+    }
+
+    /// Cuts a KairosDB response down to this page.
+    pub fn apply(&self, response: &mut Value) -> Paged {
+        let end = self.cursor.saturating_add(self.size);
+        let mut seen = 0;
+        let results = response
+            .get_mut("queries")
+            .and_then(Value::as_array_mut)
+            .into_iter()
+            .flatten()
+            .filter_map(|q| q.get_mut("results").and_then(Value::as_array_mut));
+        for results in results {
+            let first = seen;
+            seen += results.len();
+            let keep = self.cursor.clamp(first, seen) - first..end.clamp(first, seen) - first;
+            *results = results.drain(keep).collect();
+        }
+        Paged {
+            total: seen,
+            next: (end < seen).then_some(end),
+        }
+    }
+}
+
+impl Paged {
+    /// Adds the paging headers to the response carrying the page.
+    pub fn annotate(&self, response: &mut Response) {
+        let headers = response.headers_mut();
+        headers.insert(TOTAL_RESULTS_HEADER, HeaderValue::from(self.total));
+        if let Some(next) = self.next {
+            headers.insert(NEXT_CURSOR_HEADER, HeaderValue::from(next));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn pages_through_results_across_queries() {
+        let response = json!({ "queries": [
+            { "sample_size": 3, "results": [{ "name": "a" }, { "name": "b" }, { "name": "c" }] },
+            { "sample_size": 2, "results": [{ "name": "d" }, { "name": "e" }] },
+        ] });
+        let mut headers = HeaderMap::new();
+        assert_eq!(Page::requested(&headers), Ok(None));
+        headers.insert(PAGE_CURSOR_HEADER, "2".parse().unwrap());
+        assert!(Page::requested(&headers).is_err());
+        headers.insert(PAGE_SIZE_HEADER, "2".parse().unwrap());
+        let page = Page::requested(&headers).unwrap().unwrap();
+
+        let mut paged = response.clone();
+        let at = page.apply(&mut paged);
+        assert_eq!(
+            at,
+            Paged {
+                total: 5,
+                next: Some(4)
+            }
+        );
+        assert_eq!(paged["queries"][0]["results"], json!([{ "name": "c" }]));
+        assert_eq!(paged["queries"][1]["results"], json!([{ "name": "d" }]));
+        assert_eq!(paged["queries"][1]["sample_size"], 2);
+
+        let mut last = response;
+        let at = Page { cursor: 4, size: 2 }.apply(&mut last);
+        assert_eq!(
+            at,
+            Paged {
+                total: 5,
+                next: None
+            }
+        );
+        assert_eq!(last["queries"][0]["results"], json!([]));
+        assert_eq!(last["queries"][1]["results"], json!([{ "name": "e" }]));
+
+        headers.insert(PAGE_SIZE_HEADER, "0".parse().unwrap());
+        assert!(Page::requested(&headers).is_err());
+    }
+}
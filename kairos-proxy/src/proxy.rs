@@ -1,10 +1,355 @@
+pub use crate::grafana::{
+    grafana_annotations_handler, grafana_query_handler, grafana_search_handler,
+};
+pub use crate::graphite::graphite_render_handler;
+pub use crate::influx::influx_write_handler;
+pub use crate::opentsdb::opentsdb_put_handler;
+pub use crate::query_csv::query_csv_handler;
+pub use crate::query_export::{query_arrow_handler, query_parquet_handler};
 pub use crate::query_metric::query_metric_handler;
 pub use crate::query_metric_tags::query_metric_tags_handler;
+pub use crate::query_stream::query_stream_handler;
 
-use axum::{response::IntoResponse, Json};
+use crate::deadline::Deadline;
+use crate::decompress;
+use crate::mirror::mirror_request;
+use crate::state::{AppState, BackendTarget};
+use crate::upstream::{BackendError, Failure};
+use axum::{
+    body::{Body, StreamBody},
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::Bytes;
+use futures::StreamExt;
 use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
 
 pub async fn health_handler() -> impl IntoResponse {
     // Simple readiness/health endpoint. Keep it lightweight.
     Json(json!({ "status": "ok" }))
 }
+
+pub async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    // Rules naming the same backend each have a client; report their pools together
+    let mut connections: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for target in state.backends.iter() {
+        let (active, idle) = target.client.connections();
+        let total = connections.entry(&target.name).or_default();
+        *total = (total.0 + active, total.1 + idle);
+    }
+    for (backend, (active, idle)) in connections {
+        for (kind, count) in [("active", active), ("idle", idle)] {
+            state.metrics.set_gauge(
+                "kairos_proxy_backend_connections",
+                &[("backend", backend), ("state", kind)],
+                count as f64,
+            );
+        }
+    }
+    (
+        [(
+            hyper::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )],
+        state.metrics.render(),
+    )
+}
+
+/// `503 Service Unavailable` with `Retry-After` while adaptive concurrency is shedding load.
+pub(crate) fn shed_load(state: &AppState) -> Option<Response> {
+    let retry_after = state.admission.shed()?;
+    tracing::warn!("Shedding query: too many outbound requests queued");
+    state
+        .metrics
+        .inc_counter("kairos_proxy_shed_requests_total", &[]);
+    Some(
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(
+                hyper::http::header::RETRY_AFTER,
+                retry_after.as_secs().max(1).to_string(),
+            )],
+            Json(json!({ "error": "overloaded, retry later" })),
+        )
+            .into_response(),
+    )
+}
+
+/// Whether the `Accept` header asks for MessagePack, and prefers it to JSON if it names both.
+fn accepts_msgpack(headers: &hyper::HeaderMap) -> bool {
+    let msgpack = accept_quality(headers, &["application/msgpack", "application/x-msgpack"]);
+    let json = accept_quality(headers, &["application/json"]);
+    msgpack.is_some_and(|q| q > 0.0 && json.is_none_or(|json| q >= json))
+}
+
+/// The highest quality value `Accept` in `headers` gives any of `types`, `None` if it names
+/// none of them. Media ranges without a `q` parameter have quality 1.
+fn accept_quality(headers: &hyper::HeaderMap, types: &[&str]) -> Option<f32> {
+    headers
+        .get_all(hyper::http::header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let media = params.next().unwrap_or_default().trim();
+            if !types.iter().any(|t| media.eq_ignore_ascii_case(t)) {
+                return None;
+            }
+            let q = params
+                .filter_map(|p| p.split_once('='))
+                .find(|(k, _)| k.trim().eq_ignore_ascii_case("q"))
+                .map_or(Some(1.0), |(_, v)| v.trim().parse::<f32>().ok())?;
+            Some(q)
+        })
+        .reduce(f32::max)
+}
+
+/// A merged query response, as MessagePack if the client accepts it and JSON otherwise, with
+/// `Vary: Accept` for the caches in between. With a response cache it carries an `ETag`, and
+/// requests already holding it get `304`.
+pub(crate) fn encode_response(
+    state: &AppState,
+    headers: &hyper::HeaderMap,
+    v: serde_json::Value,
+) -> Result<Response, StatusCode> {
+    let mut response = encode(state, headers, v)?;
+    response.headers_mut().insert(
+        hyper::http::header::VARY,
+        hyper::http::HeaderValue::from_static("accept"),
+    );
+    Ok(response)
+}
+
+fn encode(
+    state: &AppState,
+    headers: &hyper::HeaderMap,
+    v: serde_json::Value,
+) -> Result<Response, StatusCode> {
+    if !accepts_msgpack(headers) && state.cache.is_none() {
+        return Ok((StatusCode::OK, Json(v)).into_response());
+    }
+    let (content_type, body) = if accepts_msgpack(headers) {
+        let body = rmp_serde::to_vec(&v).map_err(|e| {
+            tracing::error!("Failed to encode MessagePack response: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        ("application/msgpack", body)
+    } else {
+        let body = serde_json::to_vec(&v).map_err(|e| {
+            tracing::error!("Failed to encode JSON response: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        ("application/json", body)
+    };
+    if state.cache.is_some() {
+        return Ok(crate::etag::respond(headers, content_type, body));
+    }
+    Ok((
+        StatusCode::OK,
+        [(hyper::http::header::CONTENT_TYPE, content_type)],
+        body,
+    )
+        .into_response())
+}
+
+/// Reads the body of `req` within the body limits of its path, decompressing it if it is
+/// gzip-encoded. The encoding headers are removed so the decompressed body is forwarded as-is.
+pub(crate) async fn read_body(
+    state: &AppState,
+    req: &mut hyper::Request<Body>,
+) -> Result<Bytes, StatusCode> {
+    let (max_body_bytes, decompression) = state.body_limits(req.uri().path());
+    let body = to_bytes(req.body_mut(), max_body_bytes).await?;
+    let headers = req.headers_mut();
+    let Some(encoding) = headers.get(hyper::http::header::CONTENT_ENCODING) else {
+        return Ok(body);
+    };
+    let body = match encoding.to_str().map(|e| e.trim().to_ascii_lowercase()) {
+        Ok(e) if e == "identity" => body,
+        Ok(e) if e == "gzip" || e == "x-gzip" => {
+            let body = decompress::gunzip(body, decompression).await?;
+            crate::memory::charge(body.len()).map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+            body
+        }
+        _ => {
+            tracing::warn!("Unsupported request Content-Encoding: {:?}", encoding);
+            return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+    };
+    headers.remove(hyper::http::header::CONTENT_ENCODING);
+    headers.remove(hyper::http::header::CONTENT_LENGTH);
+    Ok(body)
+}
+
+// Helper to read the full body with size limit
+pub(crate) async fn to_bytes(body: &mut Body, max_size: usize) -> Result<Bytes, StatusCode> {
+    use axum::body::HttpBody;
+    use bytes::BytesMut;
+
+    let mut buf = BytesMut::new();
+    let mut total_size: usize = 0;
+
+    while let Some(chunk_res) = body.data().await {
+        let chunk = match chunk_res {
+            Ok(chunk) => chunk,
+            Err(e) if crate::slow_client::stalled(&e) => return Err(StatusCode::REQUEST_TIMEOUT),
+            Err(_) => return Err(StatusCode::BAD_REQUEST),
+        };
+
+        // Check for overflow and size limit
+        total_size = match total_size.checked_add(chunk.len()) {
+            Some(new_size) if new_size <= max_size => new_size,
+            _ => return Err(StatusCode::PAYLOAD_TOO_LARGE),
+        };
+
+        crate::memory::charge(chunk.len()).map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(buf.freeze())
+}
+
+/// Body of a Simple-mode request: read whole, or streamed on as it arrives (see `body_prefix`).
+pub(crate) enum SimpleBody {
+    Buffered(Bytes),
+    Streamed(Body),
+}
+
+impl From<Bytes> for SimpleBody {
+    fn from(bytes: Bytes) -> Self {
+        SimpleBody::Buffered(bytes)
+    }
+}
+
+/// Helper function to forward a request to a backend in Simple mode, streaming the response back.
+/// The `metrics` the query names are charged to the request's API key once the backend is
+/// contacted.
+pub(crate) async fn forward_to_backend_simple(
+    state: &AppState,
+    target: &BackendTarget,
+    body: SimpleBody,
+    metrics: usize,
+    headers: &hyper::HeaderMap,
+    endpoint: &str,
+) -> Result<Response, StatusCode> {
+    // Build request URL using Url::join to avoid repeated parsing
+    // endpoint should start with '/' to be treated as absolute path from root
+    let error = |failure| BackendError::new(state, &target.name, failure);
+    let fail = |failure| Ok(error(failure).into_response());
+    let deadline = Deadline::current();
+    if let Err(wait) = state
+        .throttle(target, deadline.remaining().unwrap_or(target.timeout))
+        .await
+    {
+        let throttled = error(Failure::Throttled).with_retry_after(wait);
+        return Ok(throttled.into_response());
+    }
+    // Whatever waiting for the rate limit left of the client's budget
+    let Some(timeout) = deadline.outbound_timeout(target.timeout) else {
+        return fail(Failure::Timeout);
+    };
+    let selected = target.select();
+    let request_url = target
+        .endpoint_url(&selected.url, endpoint)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Streamed bodies are never mirrored or signed; `body_prefix` buffers those
+    if let SimpleBody::Buffered(bytes) = &body {
+        mirror_request(state, target, endpoint, bytes.clone(), headers);
+    }
+
+    // The client's Accept-Encoding goes through, and so does the answer, compressed or not
+    let mut builder = target
+        .client
+        .passthrough()
+        .post(request_url)
+        .timeout(timeout);
+    for (name, value) in headers.iter() {
+        if name == hyper::http::header::HOST || target.signer.as_ref().is_some_and(|s| s.owns(name))
+        {
+            continue;
+        }
+        builder = builder.header(name, value);
+    }
+    if let Some(t) = &selected.token {
+        builder = builder.header("Authorization", format!("Bearer {}", t));
+    }
+    builder = match body {
+        SimpleBody::Buffered(bytes) => {
+            if let Some(signer) = &target.signer {
+                builder = signer.sign(builder, &bytes);
+            }
+            builder.body(bytes)
+        }
+        SimpleBody::Streamed(body) => builder.body(reqwest::Body::wrap_stream(body)),
+    };
+    crate::quota::charge_metrics(metrics);
+    let started = Instant::now();
+    let resp = crate::chaos::send(state, target, builder, timeout).await;
+    // Time to response headers; the body is streamed straight through to the client
+    crate::debug_trace::backend(
+        &target.name,
+        started.elapsed(),
+        resp.as_ref().ok().map(|r| r.status().as_u16()),
+    );
+    state.record_outcome(
+        target,
+        &selected,
+        AppState::succeeded(&resp),
+        started.elapsed(),
+    );
+    let resp = match resp {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::error!(
+                backend = target.name.as_str(),
+                "Backend request failed: {}",
+                e
+            );
+            return fail(Failure::of(&e));
+        }
+    };
+    // Clone backend headers before consuming the body
+    let mut headers = resp.headers().clone();
+    let status = resp.status();
+    // Rate limits and refused credentials are the proxy's problem, not the client's
+    if matches!(status.as_u16(), 401 | 403 | 429) {
+        return fail(Failure::Status(status.as_u16()));
+    }
+
+    // Stream the backend response body directly to the client to keep memory usage low
+    let stream = resp
+        .bytes_stream()
+        .map(|res| res.map_err(|e| std::io::Error::other(format!("upstream error: {}", e))));
+    let body = StreamBody::new(stream);
+
+    // Standard hop-by-hop headers that should not be forwarded
+    const HOP_BY_HOP: [&str; 9] = [
+        "connection",
+        "keep-alive",
+        "proxy-authenticate",
+        "proxy-authorization",
+        "te",
+        "trailers",
+        "transfer-encoding",
+        "upgrade",
+        "host",
+    ];
+    for name in HOP_BY_HOP.iter() {
+        headers.remove(*name);
+    }
+
+    let resp_builder = hyper::Response::builder().status(status);
+    let mut response = resp_builder
+        .body(body)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Attach remaining headers from backend
+    response.headers_mut().extend(headers);
+    Ok(response.into_response())
+}
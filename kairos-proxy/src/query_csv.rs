@@ -0,0 +1,127 @@
+use crate::query_export;
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    response::Response,
+};
+use serde_json::Value;
+use std::fmt::Write;
+use std::sync::Arc;
+
+const HEADER: &str = "metric,tags,timestamp,value\n";
+
+/// Quotes a CSV field if it contains a separator, quote or line break.
+fn field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Tags of a result as `key=value` pairs separated by `;`, multiple values joined by `|`.
+pub(crate) fn tags(result: &Value) -> String {
+    let Some(tags) = result.get("tags").and_then(Value::as_object) else {
+        return String::new();
+    };
+    let mut pairs: Vec<String> = tags
+        .iter()
+        .map(|(k, v)| {
+            let values: Vec<String> = match v {
+                Value::Array(a) => a.iter().map(scalar).collect(),
+                v => vec![scalar(v)],
+            };
+            format!("{}={}", k, values.join("|"))
+        })
+        .collect();
+    pairs.sort();
+    pairs.join(";")
+}
+
+/// Strings without their JSON quotes; anything else (numbers, histograms) as JSON.
+fn scalar(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+/// The `metric,tags,timestamp,value` rows of one result.
+fn rows(result: &Value) -> String {
+    let mut out = String::new();
+    let name = field(
+        result
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default(),
+    );
+    let tags = field(&tags(result));
+    let values = result.get("values").and_then(Value::as_array);
+    for point in values.into_iter().flatten() {
+        let (Some(ts), Some(value)) = (point.get(0), point.get(1)) else {
+            continue;
+        };
+        let _ = writeln!(out, "{},{},{},{}", name, tags, ts, field(&scalar(value)));
+    }
+    out
+}
+
+/// Flattens a KairosDB response into `metric,tags,timestamp,value` rows.
+#[cfg(test)]
+fn to_csv(response: &Value) -> String {
+    let mut out = String::from(HEADER);
+    for result in query_export::results(response) {
+        out.push_str(&rows(result));
+    }
+    out
+}
+
+/// Writes the CSV of a KairosDB response, one result's rows at a time.
+fn write_csv(
+    response: &Value,
+    out: &mut (dyn std::io::Write + Send),
+    _batch_rows: usize,
+) -> Result<(), String> {
+    out.write_all(HEADER.as_bytes())
+        .map_err(|e| e.to_string())?;
+    for result in query_export::results(response) {
+        out.write_all(rows(result).as_bytes())
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Runs a query like `/api/v1/datapoints/query` in Multi mode and returns the merged results
+/// as CSV, one row per data point.
+pub async fn query_csv_handler(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    query_export::export(state, req, "text/csv; charset=utf-8", write_csv).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn flattens_results_into_rows() {
+        let response = json!({ "queries": [{ "results": [
+            {
+                "name": "cpu.load",
+                "tags": { "host": ["a", "b"], "dc": ["eu,west"] },
+                "values": [[1000, 0.5], [2000, 1]]
+            },
+            { "name": "empty", "tags": {}, "values": [] }
+        ]}]});
+        assert_eq!(
+            to_csv(&response),
+            "metric,tags,timestamp,value\n\
+             cpu.load,\"dc=eu,west;host=a|b\",1000,0.5\n\
+             cpu.load,\"dc=eu,west;host=a|b\",2000,1\n"
+        );
+    }
+}
@@ -0,0 +1,349 @@
+//! Merged query results in formats for analysis tools: Arrow IPC streams and Parquet (CSV is
+//! in `query_csv`). Every format has one row per data point with the metric name, its tags,
+//! the timestamp and the value.
+//!
+//! The merged result is encoded on a blocking thread and streamed out as it is encoded: a
+//! record batch (and, in Parquet, a row group) per `BATCH_ROWS` data points, sent in chunks
+//! of about `CHUNK_BYTES`, so the encoded export is never held in memory whole.
+
+use crate::fanout;
+use crate::proxy::{read_body, shed_load};
+use crate::query_csv::tags;
+use crate::state::AppState;
+use arrow_array::builder::{Float64Builder, StringBuilder, TimestampMillisecondBuilder};
+use arrow_array::RecordBatch;
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use serde_json::Value;
+use std::io::Write;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+
+const QUERY_ENDPOINT: &str = "api/v1/datapoints/query";
+// Data points per record batch
+const BATCH_ROWS: usize = 65_536;
+// Encoded bytes collected before they are sent to the client
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Writes a merged response to `out`, in record batches of at most the given number of rows.
+pub(crate) type Encode = fn(&Value, &mut (dyn Write + Send), usize) -> Result<(), String>;
+
+/// The results of every query in a KairosDB response.
+pub(crate) fn results(response: &Value) -> impl Iterator<Item = &Value> {
+    response
+        .get("queries")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|q| q.get("results").and_then(Value::as_array))
+        .flatten()
+}
+
+fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("metric", DataType::Utf8, false),
+        Field::new("tags", DataType::Utf8, false),
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            true,
+        ),
+        Field::new("value", DataType::Float64, true),
+    ]))
+}
+
+/// Data points collected into record batches. Values that are not numbers (e.g. histograms)
+/// are null.
+struct Rows {
+    metrics: StringBuilder,
+    tags: StringBuilder,
+    timestamps: TimestampMillisecondBuilder,
+    values: Float64Builder,
+    len: usize,
+}
+
+impl Rows {
+    fn new() -> Self {
+        Rows {
+            metrics: StringBuilder::new(),
+            tags: StringBuilder::new(),
+            timestamps: TimestampMillisecondBuilder::new().with_timezone("UTC"),
+            values: Float64Builder::new(),
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, name: &str, tags: &str, ts: &Value, value: &Value) {
+        self.metrics.append_value(name);
+        self.tags.append_value(tags);
+        self.timestamps.append_option(ts.as_i64());
+        self.values.append_option(value.as_f64());
+        self.len += 1;
+    }
+
+    /// The rows pushed since the last batch.
+    fn batch(&mut self, schema: &SchemaRef) -> Result<RecordBatch, String> {
+        self.len = 0;
+        RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(self.metrics.finish()),
+                Arc::new(self.tags.finish()),
+                Arc::new(self.timestamps.finish()),
+                Arc::new(self.values.finish()),
+            ],
+        )
+        .map_err(|e| e.to_string())
+    }
+}
+
+/// Calls `write` with each batch of at most `batch_rows` data points of a KairosDB response.
+fn for_each_batch(
+    response: &Value,
+    batch_rows: usize,
+    mut write: impl FnMut(&RecordBatch) -> Result<(), String>,
+) -> Result<(), String> {
+    let schema = schema();
+    let mut rows = Rows::new();
+    for result in results(response) {
+        let name = result
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let tags = tags(result);
+        let values = result.get("values").and_then(Value::as_array);
+        for point in values.into_iter().flatten() {
+            if let (Some(ts), Some(value)) = (point.get(0), point.get(1)) {
+                rows.push(name, &tags, ts, value);
+                if rows.len >= batch_rows {
+                    write(&rows.batch(&schema)?)?;
+                }
+            }
+        }
+    }
+    if rows.len > 0 {
+        write(&rows.batch(&schema)?)?;
+    }
+    Ok(())
+}
+
+/// An Arrow IPC stream.
+fn write_arrow(
+    response: &Value,
+    out: &mut (dyn Write + Send),
+    batch_rows: usize,
+) -> Result<(), String> {
+    let mut writer =
+        arrow_ipc::writer::StreamWriter::try_new(out, &schema()).map_err(|e| e.to_string())?;
+    for_each_batch(response, batch_rows, |batch| {
+        writer.write(batch).map_err(|e| e.to_string())
+    })?;
+    writer.finish().map_err(|e| e.to_string())
+}
+
+/// A Parquet file with a row group per batch.
+fn write_parquet(
+    response: &Value,
+    out: &mut (dyn Write + Send),
+    batch_rows: usize,
+) -> Result<(), String> {
+    let mut writer =
+        parquet::arrow::ArrowWriter::try_new(out, schema(), None).map_err(|e| e.to_string())?;
+    for_each_batch(response, batch_rows, |batch| {
+        writer.write(batch).map_err(|e| e.to_string())?;
+        writer.flush().map_err(|e| e.to_string())
+    })?;
+    writer.close().map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Sends what is written to it to the response body in chunks of about `CHUNK_BYTES`.
+struct Chunks {
+    tx: mpsc::Sender<std::io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl Write for Chunks {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.buf));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| std::io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+/// A body streaming `response` as `encode` writes it. An encoding failure aborts the body, so
+/// the client cannot mistake a truncated export for a complete one.
+fn stream(response: Value, content_type: &'static str, encode: Encode) -> Body {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut out = Chunks {
+            tx: tx.clone(),
+            buf: Vec::new(),
+        };
+        let written = encode(&response, &mut out, BATCH_ROWS)
+            .and_then(|()| out.flush().map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            error!("Failed to encode {} response: {}", content_type, e);
+            let _ = tx.blocking_send(Err(std::io::Error::other(e)));
+        }
+    });
+    Body::wrap_stream(futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
+}
+
+/// Runs a query like `/api/v1/datapoints/query` in Multi mode and answers with the merged
+/// results encoded by `encode` as `content_type`.
+pub(crate) async fn export(
+    state: Arc<AppState>,
+    mut req: Request<Body>,
+    content_type: &'static str,
+    encode: Encode,
+) -> Result<Response, StatusCode> {
+    debug!("Received {} query request", content_type);
+    if let Some(shed) = shed_load(&state) {
+        return Ok(shed);
+    }
+
+    let body_bytes = match read_body(&state, &mut req).await {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Failed to read request body: {:?}", e);
+            return Err(e);
+        }
+    };
+    let body_bytes = match state.query_checks.apply(body_bytes) {
+        Ok(b) => b,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    crate::recorder::record(&state, &req, &body_bytes);
+    let json: Value = match serde_json::from_slice(&body_bytes) {
+        Ok(j) => j,
+        Err(e) => {
+            error!("Failed to parse JSON body: {}", e);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    if crate::dry_run::requested(req.headers()) {
+        return Ok(crate::dry_run::multi(
+            &state,
+            req.headers(),
+            &json,
+            QUERY_ENDPOINT,
+        ));
+    }
+    let v = match fanout::run_query(&state, req.headers(), &json, QUERY_ENDPOINT).await {
+        Ok(v) => v,
+        Err(e) => return Ok(e.into_response()),
+    };
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, content_type)],
+        axum::body::boxed(stream(v, content_type, encode)),
+    )
+        .into_response())
+}
+
+pub async fn query_arrow_handler(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    export(
+        state,
+        req,
+        "application/vnd.apache.arrow.stream",
+        write_arrow,
+    )
+    .await
+}
+
+pub async fn query_parquet_handler(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    export(state, req, "application/vnd.apache.parquet", write_parquet).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::Float64Type;
+    use serde_json::json;
+
+    fn response() -> Value {
+        json!({ "queries": [{ "results": [
+            {
+                "name": "cpu.load",
+                "tags": { "host": ["a", "b"], "dc": ["eu,west"] },
+                "values": [[1000, 0.5], [2000, 1]]
+            },
+            { "name": "empty", "tags": {}, "values": [] }
+        ]}]})
+    }
+
+    #[test]
+    fn encodes_arrow_streams_and_parquet() {
+        let mut ipc = Vec::new();
+        write_arrow(&response(), &mut ipc, BATCH_ROWS).unwrap();
+        let mut reader =
+            arrow_ipc::reader::StreamReader::try_new(std::io::Cursor::new(ipc), None).unwrap();
+        let batch = reader.next().unwrap().unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.column(0).as_string::<i32>().value(1), "cpu.load");
+        assert_eq!(batch.column(3).as_primitive::<Float64Type>().value(1), 1.0);
+
+        let mut parquet = Vec::new();
+        write_parquet(&response(), &mut parquet, BATCH_ROWS).unwrap();
+        assert!(parquet.starts_with(b"PAR1") && parquet.ends_with(b"PAR1"));
+    }
+
+    #[test]
+    fn writes_a_batch_per_slice_of_rows() {
+        let mut ipc = Vec::new();
+        write_arrow(&response(), &mut ipc, 1).unwrap();
+        let reader =
+            arrow_ipc::reader::StreamReader::try_new(std::io::Cursor::new(ipc), None).unwrap();
+        let rows: Vec<usize> = reader.map(|b| b.unwrap().num_rows()).collect();
+        assert_eq!(rows, vec![1, 1]);
+    }
+
+    #[tokio::test]
+    async fn streams_the_encoded_export_in_chunks() {
+        let points: Vec<Value> = (0..20_000).map(|i| json!([i, i])).collect();
+        let response = json!({ "queries": [{ "results": [
+            { "name": "cpu.load", "tags": {}, "values": points }
+        ]}]});
+        let mut body = stream(response, "application/vnd.apache.arrow.stream", write_arrow);
+        let mut chunks = 0;
+        let mut ipc = Vec::new();
+        while let Some(chunk) = hyper::body::HttpBody::data(&mut body).await {
+            chunks += 1;
+            ipc.extend_from_slice(&chunk.expect("chunk"));
+        }
+        assert!(chunks > 1, "{} chunk(s)", chunks);
+        let reader =
+            arrow_ipc::reader::StreamReader::try_new(std::io::Cursor::new(ipc), None).unwrap();
+        let rows: usize = reader.map(|b| b.unwrap().num_rows()).sum();
+        assert_eq!(rows, 20_000);
+    }
+}
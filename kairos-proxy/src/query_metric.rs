@@ -1,87 +1,35 @@
+use crate::decimate::Decimation;
+use crate::fanout;
+use crate::pagination::Page;
+use crate::proxy::{encode_response, forward_to_backend_simple, read_body, shed_load};
+use crate::routes::Subject;
 use crate::state::AppState;
+use crate::timerange::{now_ms, TimeFields};
 use axum::{
-    body::{Body, StreamBody},
+    body::Body,
     extract::State,
     http::{Request, StatusCode},
     response::{IntoResponse, Response},
 };
-use bytes::Bytes;
-use futures::{stream::FuturesUnordered, StreamExt};
 use std::sync::Arc;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, warn};
 
 /// Minimal struct for extracting only the metric name during routing.
 /// This avoids deep allocations and HashMap creation from serde_json::Value.
 #[derive(serde::Deserialize)]
 struct MetricNameOnly {
     name: String,
+    #[serde(default)]
+    tags: Option<serde_json::Value>,
 }
 
-/// Minimal struct for extracting first metric's name from request body.
+/// Minimal struct for extracting first metric's name (and the time range, for time-tiered
+/// backends) from request body.
 #[derive(serde::Deserialize)]
 struct MetricsRequest {
     metrics: Vec<MetricNameOnly>,
-}
-
-/// Helper function to forward a request to a backend in Simple mode
-async fn forward_to_backend_simple(
-    state: &AppState,
-    url: &reqwest::Url,
-    token: &Option<String>,
-    body_bytes: Bytes,
-    headers: &hyper::HeaderMap,
-    endpoint: &str,
-) -> Result<Response, StatusCode> {
-    // Build request URL using Url::join to avoid repeated parsing
-    // endpoint should start with '/' to be treated as absolute path from root
-    let request_url = url
-        .join(endpoint)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let mut builder = state.client.post(request_url).body(body_bytes);
-    for (name, value) in headers.iter() {
-        if name == hyper::http::header::HOST {
-            continue;
-        }
-        builder = builder.header(name, value);
-    }
-    if let Some(t) = token {
-        builder = builder.header("Authorization", format!("Bearer {}", t));
-    }
-    let resp = builder.send().await.map_err(|_| StatusCode::BAD_GATEWAY)?;
-    // Clone backend headers before consuming the body
-    let mut headers = resp.headers().clone();
-    let status = resp.status();
-
-    // Stream the backend response body directly to the client to keep memory usage low
-    let stream = resp
-        .bytes_stream()
-        .map(|res| res.map_err(|e| std::io::Error::other(format!("upstream error: {}", e))));
-    let body = StreamBody::new(stream);
-
-    // Standard hop-by-hop headers that should not be forwarded
-    const HOP_BY_HOP: [&str; 9] = [
-        "connection",
-        "keep-alive",
-        "proxy-authenticate",
-        "proxy-authorization",
-        "te",
-        "trailers",
-        "transfer-encoding",
-        "upgrade",
-        "host",
-    ];
-    for name in HOP_BY_HOP.iter() {
-        headers.remove(*name);
-    }
-
-    let resp_builder = hyper::Response::builder().status(status);
-    let mut response = resp_builder
-        .body(body)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    // Attach remaining headers from backend
-    response.headers_mut().extend(headers);
-    Ok(response.into_response())
+    #[serde(flatten)]
+    time: TimeFields,
 }
 
 pub async fn query_metric_handler(
@@ -89,6 +37,9 @@ pub async fn query_metric_handler(
     req: Request<Body>,
 ) -> Result<Response, StatusCode> {
     debug!("Received query_metric request");
+    if let Some(shed) = shed_load(&state) {
+        return Ok(shed);
+    }
 
     // Only POST is allowed for this endpoint
     if req.method() != axum::http::Method::POST {
@@ -98,13 +49,25 @@ pub async fn query_metric_handler(
 
     // Read and parse the JSON body
     let mut req = req;
-    let body_bytes = match to_bytes(req.body_mut(), state.max_request_body_bytes).await {
+    if matches!(state.mode, crate::config::Mode::Simple) {
+        if let Some(streamed) =
+            crate::body_prefix::forward(&state, &mut req, "/api/v1/datapoints/query").await?
+        {
+            return Ok(streamed);
+        }
+    }
+    let body_bytes = match read_body(&state, &mut req).await {
         Ok(b) => b,
         Err(e) => {
             error!("Failed to read request body: {:?}", e);
             return Err(e);
         }
     };
+    let body_bytes = match state.query_checks.apply(body_bytes) {
+        Ok(b) => b,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    crate::recorder::record(&state, &req, &body_bytes);
 
     // If running in simple mode, check for X-METRICNAME header first
     if matches!(state.mode, crate::config::Mode::Simple) {
@@ -115,9 +78,11 @@ pub async fn query_metric_handler(
             .and_then(|value| value.to_str().ok())
             .map(|s| s.to_string());
 
-        let metric_name = if let Some(name) = metric_name_from_header {
-            // Use header value for routing, skip body parsing
-            name
+        // Simple mode cannot split a query across time tiers; it routes by the end of the range
+        let (metric, metrics, at_ms) = if let Some(name) = metric_name_from_header {
+            // Use header value for routing; the body is only counted, for API key budgets
+            let metrics = fanout::body_metric_count(&body_bytes);
+            (MetricNameOnly { name, tags: None }, metrics, now_ms())
         } else {
             // Parse JSON body using minimal typed deserialization to extract only metric name.
             // This avoids deep allocations and HashMap creation from serde_json::Value.
@@ -126,34 +91,43 @@ pub async fn query_metric_handler(
                 Err(_) => return Err(StatusCode::BAD_REQUEST),
             };
 
+            let now = now_ms();
+            let at_ms = request.time.range(now).map_or(now, |r| r.end_ms);
+            let metrics = request.metrics.len();
             // Extract first metric name (move out of Vec to avoid clone)
-            request
+            let metric = request
                 .metrics
                 .into_iter()
                 .next()
-                .ok_or(StatusCode::BAD_REQUEST)?
-                .name
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            (metric, metrics, at_ms)
         };
 
         // Find backend matching the metric name
-        let mut target: Option<(&reqwest::Url, Option<String>)> = None;
-        for (re, url, token) in state.backends.iter() {
-            if re.is_match(&metric_name) {
-                target = Some((url, token.clone()));
-                break;
-            }
-        }
-        let (url, token) = match target {
-            Some(t) => t,
-            None => return Err(StatusCode::BAD_GATEWAY),
+        let subject = Subject {
+            metric: &metric.name,
+            tags: metric.tags.as_ref(),
+            headers: Some(req.headers()),
+        };
+        let target = match state.select(&subject, at_ms) {
+            Some((_, t)) => t,
+            None => return Ok(state.unroutable(&metric.name).into_response()),
         };
 
+        if crate::dry_run::requested(req.headers()) {
+            return Ok(crate::dry_run::simple(
+                target,
+                "/api/v1/datapoints/query",
+                &body_bytes,
+            ));
+        }
+
         // Forward request to chosen backend using helper function
         return forward_to_backend_simple(
             &state,
-            url,
-            &token,
-            body_bytes,
+            target,
+            body_bytes.into(),
+            metrics,
             req.headers(),
             "/api/v1/datapoints/query",
         )
@@ -161,7 +135,7 @@ pub async fn query_metric_handler(
     }
 
     // Parse JSON body for metric extraction (Multi mode)
-    let mut json: serde_json::Value = match serde_json::from_slice(&body_bytes) {
+    let json: serde_json::Value = match serde_json::from_slice(&body_bytes) {
         Ok(j) => j,
         Err(e) => {
             error!("Failed to parse JSON body: {}", e);
@@ -169,251 +143,66 @@ pub async fn query_metric_handler(
         }
     };
 
-    // Extract metrics array
-    let metrics = json.get_mut("metrics").and_then(|v| v.as_array_mut());
-    let metrics = match metrics {
-        Some(m) if !m.is_empty() => m,
-        _ => {
-            warn!("Request contains no metrics or invalid metrics array");
-            return Err(StatusCode::BAD_REQUEST);
+    let omit = match state.omit_result_fields.with_request(req.uri().query()) {
+        Ok(o) => o,
+        Err(e) => {
+            warn!("Invalid omit parameter: {}", e);
+            let body = axum::Json(serde_json::json!({ "error": e }));
+            return Ok((StatusCode::BAD_REQUEST, body).into_response());
         }
     };
-
-    // Group metrics by backend (Multi mode)
-    use std::collections::HashMap;
-    let mut backend_metrics: HashMap<usize, Vec<serde_json::Value>> = HashMap::new();
-    let mut backend_info: HashMap<usize, (&reqwest::Url, Option<&str>)> = HashMap::new();
-
-    debug!(
-        "Processing request in Multi mode with {} metric(s)",
-        metrics.len()
-    );
-
-    for metric in metrics.iter() {
-        let name = metric.get("name").and_then(|v| v.as_str());
-        let mut found = false;
-        if let Some(name) = name {
-            for (i, (re, url, token)) in state.backends.iter().enumerate() {
-                if re.is_match(name) {
-                    backend_metrics.entry(i).or_default().push(metric.clone());
-                    backend_info.insert(i, (url, token.as_deref()));
-                    debug!("Metric '{}' matched backend: {}", name, url);
-                    found = true;
-                    break;
-                }
-            }
-        }
-        if !found {
-            error!("No backend matched metric: {:?}", name);
-            return Err(StatusCode::BAD_GATEWAY);
-        }
-    }
-
-    info!(
-        "Routing {} metric(s) to {} backend(s)",
-        metrics.len(),
-        backend_metrics.len()
-    );
-
-    // Clone headers once to reuse for outbound requests
-    let headers = req.headers().clone();
-
-    // Store the count before the move
-    let backend_count = backend_metrics.len();
-
-    // For each backend, send a request with only the relevant metrics using bounded concurrency
-    let mut futs = FuturesUnordered::new();
-    for (i, metrics_for_backend) in backend_metrics {
-        let (url, token) = backend_info[&i];
-        let client = state.client.clone();
-        let headers = headers.clone();
-        let sem = state.semaphore.clone();
-        // Build a small payload: copy top-level fields except "metrics", insert only relevant metrics
-        let mut payload_map = serde_json::Map::new();
-        if let Some(obj) = json.as_object() {
-            for (k, v) in obj.iter() {
-                if k == "metrics" {
-                    continue;
-                }
-                payload_map.insert(k.clone(), v.clone());
-            }
-        }
-        payload_map.insert(
-            "metrics".to_string(),
-            serde_json::Value::Array(metrics_for_backend),
-        );
-        let body = match serde_json::to_vec(&serde_json::Value::Object(payload_map)) {
-            Ok(b) => b,
-            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
-        };
-
-        futs.push(async move {
-            // Acquire permit for bounded concurrency
-            let _permit = match sem.acquire_owned().await {
-                Ok(p) => p,
-                Err(_) => return None,
-            };
-
-            // Build request URL using Url::join to avoid repeated parsing
-            let request_url = match url.join("api/v1/datapoints/query") {
-                Ok(u) => u,
-                Err(e) => {
-                    error!("Failed to build request URL: {}", e);
-                    return None;
-                }
-            };
-
-            let mut builder = client.post(request_url).body(body);
-            for (name, value) in headers.iter() {
-                if name == hyper::http::header::HOST {
-                    continue;
-                }
-                builder = builder.header(name, value);
-            }
-            if let Some(t) = token {
-                builder = builder.header("Authorization", format!("Bearer {}", t));
-            }
-            match builder.send().await {
-                Ok(r) => r.json::<serde_json::Value>().await.ok(),
-                Err(e) => {
-                    error!("Backend request to {} failed: {}", url, e);
-                    None
-                }
-            }
-            // permit dropped here
-        });
-    }
-
-    let mut results = Vec::new();
-    while let Some(res_opt) = futs.next().await {
-        if let Some(res) = res_opt {
-            results.push(res);
-        }
-    }
-    debug!("Received {} response(s) from backend(s)", results.len());
-    // Merge all backend responses into queries[0].results[] by metric name
-    use std::collections::BTreeMap;
-    // Map: metric name -> Vec<result objects from all backends>
-    let mut metric_results: BTreeMap<String, Vec<serde_json::Value>> = BTreeMap::new();
-    for resp in results.into_iter() {
-        if let Some(queries) = resp.get("queries").and_then(|q| q.as_array()) {
-            for query in queries {
-                if let Some(results) = query.get("results").and_then(|r| r.as_array()) {
-                    for result in results {
-                        if let Some(name) = result.get("name").and_then(|v| v.as_str()) {
-                            metric_results
-                                .entry(name.to_string())
-                                .or_default()
-                                .push(result.clone());
-                        }
-                    }
-                }
-            }
+    let page = match Page::requested(req.headers()) {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("Invalid page request: {}", e);
+            let body = axum::Json(serde_json::json!({ "error": e }));
+            return Ok((StatusCode::BAD_REQUEST, body).into_response());
         }
-    }
-    // Merge tags and values for each metric
-    let mut merged_results = Vec::new();
-    for (name, result_vec) in metric_results {
-        let mut merged_tags: BTreeMap<String, Vec<String>> = BTreeMap::new();
-        let mut merged_values: Vec<serde_json::Value> = Vec::new();
-        for result in result_vec {
-            // Merge tags
-            if let Some(tags) = result.get("tags").and_then(|t| t.as_object()) {
-                for (k, v) in tags {
-                    if let Some(arr) = v.as_array() {
-                        for val in arr {
-                            if let Some(s) = val.as_str() {
-                                let entry = merged_tags.entry(k.clone()).or_default();
-                                if !entry.contains(&s.to_string()) {
-                                    entry.push(s.to_string());
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            // Merge values
-            if let Some(values) = result.get("values").and_then(|v| v.as_array()) {
-                for v in values {
-                    merged_values.push(v.clone());
-                }
-            }
+    };
+    let decimation = match Decimation::requested(req.headers()) {
+        Ok(d) => d,
+        Err(e) => {
+            warn!("Invalid decimation request: {}", e);
+            let body = axum::Json(serde_json::json!({ "error": e }));
+            return Ok((StatusCode::BAD_REQUEST, body).into_response());
         }
-        // Build merged result object
-        let mut merged_result = serde_json::Map::new();
-        merged_result.insert("name".to_string(), serde_json::Value::String(name));
-        // Insert merged tags
-        let tags_obj = merged_tags
-            .into_iter()
-            .map(|(k, v)| {
-                (
-                    k,
-                    serde_json::Value::Array(
-                        v.into_iter().map(serde_json::Value::String).collect(),
-                    ),
-                )
-            })
-            .collect();
-        merged_result.insert("tags".to_string(), serde_json::Value::Object(tags_obj));
-        // Insert merged values
-        merged_result.insert(
-            "values".to_string(),
-            serde_json::Value::Array(merged_values),
-        );
-        merged_results.push(serde_json::Value::Object(merged_result));
+    };
+    if crate::dry_run::requested(req.headers()) {
+        let endpoint = "api/v1/datapoints/query";
+        return Ok(crate::dry_run::multi(
+            &state,
+            req.headers(),
+            &json,
+            endpoint,
+        ));
     }
-    // Build final response: { "queries": [ { "results": [ ... ] } ] }
-    let mut queries_arr = Vec::new();
-    let mut query_obj = serde_json::Map::new();
-    query_obj.insert(
-        "results".to_string(),
-        serde_json::Value::Array(merged_results),
-    );
-    queries_arr.push(serde_json::Value::Object(query_obj));
-    let mut response = serde_json::Map::new();
-    response.insert("queries".to_string(), serde_json::Value::Array(queries_arr));
-    let v = serde_json::Value::Object(response);
-    info!(
-        "Successfully merged responses from {} backend(s)",
-        backend_count
-    );
-    Ok((StatusCode::OK, axum::Json(v)).into_response())
-}
-
-// Helper to read the full body with size limit
-async fn to_bytes(body: &mut Body, max_size: usize) -> Result<Bytes, StatusCode> {
-    use axum::body::HttpBody;
-    use bytes::BytesMut;
-
-    let mut buf = BytesMut::new();
-    let mut total_size: usize = 0;
-
-    while let Some(chunk_res) = body.data().await {
-        let chunk = match chunk_res {
-            Ok(chunk) => chunk,
-            Err(_) => return Err(StatusCode::BAD_REQUEST),
-        };
-
-        // Check for overflow and size limit
-        total_size = match total_size.checked_add(chunk.len()) {
-            Some(new_size) if new_size <= max_size => new_size,
-            _ => return Err(StatusCode::PAYLOAD_TOO_LARGE),
+    let mut v =
+        match fanout::run_query(&state, req.headers(), &json, "api/v1/datapoints/query").await {
+            Ok(v) => v,
+            Err(e) => return Ok(e.into_response()),
         };
-
-        buf.extend_from_slice(&chunk);
+    omit.apply(&mut v);
+    if let Some(decimation) = decimation {
+        decimation.apply(&mut v);
     }
-
-    Ok(buf.freeze())
+    let Some(page) = page else {
+        return encode_response(&state, req.headers(), v);
+    };
+    let paged = page.apply(&mut v);
+    let mut response = encode_response(&state, req.headers(), v)?;
+    paged.annotate(&mut response);
+    Ok(response)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{Backend, Config, Mode};
+    use crate::config::{Backend, CacheConfig, Config, Mode};
     use axum::{routing::post, Router};
     use serde_json::json;
     use std::sync::Arc;
     use tokio::sync::Mutex;
+    use tower::ServiceExt;
 
     async fn spawn_mock_server() -> (String, Arc<Mutex<Option<serde_json::Value>>>) {
         let received: Arc<Mutex<Option<serde_json::Value>>> = Arc::new(Mutex::new(None));
@@ -495,17 +284,20 @@ mod tests {
                     pattern: "^cpu\\..*".to_string(),
                     url: b1_url.clone(),
                     token: None,
+                    ..Default::default()
                 },
                 Backend {
                     pattern: "^mem\\..*".to_string(),
                     url: b2_url.clone(),
                     token: None,
+                    ..Default::default()
                 },
             ],
             timeout_secs: Some(2),
             max_outbound_concurrency: Some(8),
             mode: Some(Mode::Multi),
             max_request_body_bytes: None,
+            ..Default::default()
         };
         let state = Arc::new(AppState::from_config(&cfg).expect("state"));
 
@@ -539,6 +331,118 @@ mod tests {
         assert!(names.contains(&"mem.test".to_string()));
     }
 
+    #[tokio::test]
+    async fn multi_mode_annotates_results_with_source_backend() {
+        let (b1_url, _r1) = spawn_mock_server().await;
+        let (b2_url, _r2) = spawn_mock_server().await;
+
+        let cfg = Config {
+            backends: vec![
+                Backend {
+                    name: Some("eu".to_string()),
+                    pattern: "^cpu\\..*".to_string(),
+                    url: b1_url,
+                    ..Default::default()
+                },
+                Backend {
+                    name: Some("us".to_string()),
+                    pattern: "^mem\\..*".to_string(),
+                    url: b2_url,
+                    ..Default::default()
+                },
+            ],
+            mode: Some(Mode::Multi),
+            annotate_backend: Some("_proxy_backend".to_string()),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+
+        let payload = json!({ "metrics": [ { "name": "cpu.test" }, { "name": "mem.test" } ] });
+        let req = Request::builder()
+            .method(axum::http::Method::POST)
+            .uri("/api/v1/datapoints/query")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+        let resp = query_metric_handler(State(state), req).await.expect("resp");
+        let bytes = hyper::body::to_bytes(resp.into_body())
+            .await
+            .expect("bytes");
+        let v: serde_json::Value = serde_json::from_slice(&bytes).expect("json");
+        let results = v["queries"][0]["results"].as_array().expect("results");
+        for (name, backend) in [("cpu.test", "eu"), ("mem.test", "us")] {
+            let result = results.iter().find(|r| r["name"] == name).expect(name);
+            assert_eq!(result["tags"]["_proxy_backend"], json!([backend]));
+        }
+    }
+
+    #[tokio::test]
+    async fn multi_mode_encodes_msgpack_when_accepted() {
+        let (b1_url, _r1) = spawn_mock_server().await;
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: ".*".to_string(),
+                url: b1_url,
+                ..Default::default()
+            }],
+            mode: Some(Mode::Multi),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+
+        let payload = json!({ "metrics": [ { "name": "cpu.test" } ] });
+        let req = Request::builder()
+            .method(axum::http::Method::POST)
+            .uri("/api/v1/datapoints/query")
+            .header("accept", "application/msgpack, application/json;q=0.5")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+        let resp = query_metric_handler(State(state), req).await.expect("resp");
+        assert_eq!(resp.headers()["content-type"], "application/msgpack");
+        let bytes = hyper::body::to_bytes(resp.into_body())
+            .await
+            .expect("bytes");
+        let v: serde_json::Value = rmp_serde::from_slice(&bytes).expect("msgpack");
+        assert_eq!(v["queries"][0]["results"][0]["name"], "cpu.test");
+    }
+
+    #[tokio::test]
+    async fn msgpack_refused_or_less_preferred_gets_json() {
+        let (b1_url, _r1) = spawn_mock_server().await;
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: ".*".to_string(),
+                url: b1_url,
+                ..Default::default()
+            }],
+            mode: Some(Mode::Multi),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+
+        let payload = json!({ "metrics": [ { "name": "cpu.test" } ] });
+        for accept in [
+            "application/msgpack;q=0, application/json",
+            "application/msgpack; q=0.2, application/json;q=0.9",
+        ] {
+            let req = Request::builder()
+                .method(axum::http::Method::POST)
+                .uri("/api/v1/datapoints/query")
+                .header("accept", accept)
+                .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+                .unwrap();
+            let resp = query_metric_handler(State(state.clone()), req)
+                .await
+                .expect("resp");
+            assert_eq!(
+                resp.headers()["content-type"],
+                "application/json",
+                "{}",
+                accept
+            );
+            assert_eq!(resp.headers()["vary"], "accept");
+        }
+    }
+
     #[tokio::test]
     async fn simple_mode_forwards_full_payload_to_first_backend() {
         let (b1_url, r1) = spawn_mock_server().await;
@@ -551,17 +455,20 @@ mod tests {
                     pattern: "^cpu\\..*".to_string(),
                     url: b1_url.clone(),
                     token: None,
+                    ..Default::default()
                 },
                 Backend {
                     pattern: "^mem\\..*".to_string(),
                     url: b2_url.clone(),
                     token: None,
+                    ..Default::default()
                 },
             ],
             timeout_secs: Some(2),
             max_outbound_concurrency: Some(8),
             mode: Some(Mode::Simple),
             max_request_body_bytes: None,
+            ..Default::default()
         };
         let state = Arc::new(AppState::from_config(&cfg).expect("state"));
 
@@ -621,6 +528,42 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn simple_mode_streams_the_body_once_the_first_name_arrives() {
+        let (b1_url, r1) = spawn_mock_server().await;
+        let (b2_url, r2) = spawn_mock_server().await;
+        let backend = |pattern: &str, url: &str| Backend {
+            pattern: pattern.to_string(),
+            url: url.to_string(),
+            ..Default::default()
+        };
+        let cfg = Config {
+            backends: vec![backend("^cpu\\.", &b1_url), backend("^mem\\.", &b2_url)],
+            mode: Some(Mode::Simple),
+            stream_simple_queries: Some(true),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+
+        let payload = json!({ "start_relative": { "value": 1, "unit": "hours" },
+            "metrics": [{ "name": "mem.free" }, { "name": "cpu.load" }] });
+        let body = serde_json::to_vec(&payload).unwrap();
+        // The name arrives split across chunks
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> =
+            body.chunks(7).map(|c| Ok(c.to_vec())).collect();
+        let req = Request::builder()
+            .method(axum::http::Method::POST)
+            .uri("/api/v1/datapoints/query")
+            .body(Body::wrap_stream(futures::stream::iter(chunks)))
+            .unwrap();
+
+        let resp = query_metric_handler(State(state), req).await.expect("resp");
+        assert_eq!(resp.status(), StatusCode::OK);
+        hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(r2.lock().await.as_ref(), Some(&payload));
+        assert!(r1.lock().await.is_none());
+    }
+
     #[tokio::test]
     async fn simple_mode_uses_x_metricname_header_when_present() {
         let (b1_url, r1) = spawn_mock_server().await;
@@ -633,17 +576,20 @@ mod tests {
                     pattern: "^cpu\\..*".to_string(),
                     url: b1_url.clone(),
                     token: None,
+                    ..Default::default()
                 },
                 Backend {
                     pattern: "^mem\\..*".to_string(),
                     url: b2_url.clone(),
                     token: None,
+                    ..Default::default()
                 },
             ],
             timeout_secs: Some(2),
             max_outbound_concurrency: Some(8),
             mode: Some(Mode::Simple),
             max_request_body_bytes: None,
+            ..Default::default()
         };
         let state = Arc::new(AppState::from_config(&cfg).expect("state"));
 
@@ -687,17 +633,20 @@ mod tests {
                     pattern: "^cpu\\..*".to_string(),
                     url: b1_url.clone(),
                     token: None,
+                    ..Default::default()
                 },
                 Backend {
                     pattern: "^mem\\..*".to_string(),
                     url: b2_url.clone(),
                     token: None,
+                    ..Default::default()
                 },
             ],
             timeout_secs: Some(2),
             max_outbound_concurrency: Some(8),
             mode: Some(Mode::Simple),
             max_request_body_bytes: None,
+            ..Default::default()
         };
         let state = Arc::new(AppState::from_config(&cfg).expect("state"));
 
@@ -737,17 +686,20 @@ mod tests {
                     pattern: "^cpu\\..*".to_string(),
                     url: b1_url.clone(),
                     token: None,
+                    ..Default::default()
                 },
                 Backend {
                     pattern: "^mem\\..*".to_string(),
                     url: b2_url.clone(),
                     token: None,
+                    ..Default::default()
                 },
             ],
             timeout_secs: Some(2),
             max_outbound_concurrency: Some(8),
             mode: Some(Mode::Simple),
             max_request_body_bytes: None,
+            ..Default::default()
         };
         let state = Arc::new(AppState::from_config(&cfg).expect("state"));
 
@@ -785,11 +737,13 @@ mod tests {
                 pattern: "^cpu\\..*".to_string(),
                 url: b1_url.clone(),
                 token: None,
+                ..Default::default()
             }],
             timeout_secs: Some(2),
             max_outbound_concurrency: Some(8),
             mode: Some(Mode::Simple),
             max_request_body_bytes: Some(TEST_SIZE_LIMIT),
+            ..Default::default()
         };
         let state = Arc::new(AppState::from_config(&cfg).expect("state"));
 
@@ -836,11 +790,13 @@ mod tests {
                 pattern: "^cpu\\..*".to_string(),
                 url: b1_url.clone(),
                 token: None,
+                ..Default::default()
             }],
             timeout_secs: Some(2),
             max_outbound_concurrency: Some(8),
             mode: Some(Mode::Simple),
             max_request_body_bytes: Some(1000), // 1000 bytes limit
+            ..Default::default()
         };
         let state = Arc::new(AppState::from_config(&cfg).expect("state"));
 
@@ -860,6 +816,39 @@ mod tests {
         assert!(result.is_ok(), "should succeed for body within limit");
     }
 
+    #[tokio::test]
+    async fn endpoint_body_limit_overrides_the_global_one() {
+        let (b1_url, _r1) = spawn_mock_server().await;
+
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: "^cpu\\..*".to_string(),
+                url: b1_url.clone(),
+                ..Default::default()
+            }],
+            mode: Some(Mode::Simple),
+            max_request_body_bytes: Some(1000),
+            endpoint_body_limits: Some(
+                [("/api/v1/datapoints/query".to_string(), 20)]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+        assert_eq!(state.body_limits("/api/v1/datapoints/query/tags").0, 1000);
+
+        let payload = json!({ "metrics": [{ "name": "cpu.test" }] });
+        let req = Request::builder()
+            .method(axum::http::Method::POST)
+            .uri("/api/v1/datapoints/query")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+        let result = query_metric_handler(State(state), req).await;
+        assert_eq!(result.unwrap_err(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
     #[tokio::test]
     async fn simple_mode_minimal_deserialization_with_extra_fields() {
         // Test that minimal typed deserialization works even when JSON has extra fields
@@ -871,11 +860,13 @@ mod tests {
                 pattern: "^cpu\\..*".to_string(),
                 url: b1_url.clone(),
                 token: None,
+                ..Default::default()
             }],
             timeout_secs: Some(2),
             max_outbound_concurrency: Some(8),
             mode: Some(Mode::Simple),
             max_request_body_bytes: None,
+            ..Default::default()
         };
         let state = Arc::new(AppState::from_config(&cfg).expect("state"));
 
@@ -919,4 +910,241 @@ mod tests {
             .is_some());
         assert!(received.get("start_absolute").is_some());
     }
+
+    #[tokio::test]
+    async fn mirror_receives_copy_of_matched_traffic() {
+        let (primary_url, primary) = spawn_mock_server().await;
+        let (shadow_url, shadow) = spawn_mock_server().await;
+
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: "^cpu\\..*".to_string(),
+                url: primary_url,
+                mirror_url: Some(shadow_url),
+                mirror_percent: Some(100.0),
+                ..Default::default()
+            }],
+            timeout_secs: Some(2),
+            mode: Some(Mode::Simple),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+
+        let payload = json!({ "metrics": [{ "name": "cpu.test" }] });
+        let req = Request::builder()
+            .method(axum::http::Method::POST)
+            .uri("/api/v1/datapoints/query")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+        let resp = query_metric_handler(State(state), req).await.expect("resp");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(primary.lock().await.is_some());
+
+        // The mirror is fire-and-forget; give the spawned task a moment to land
+        for _ in 0..50 {
+            if shadow.lock().await.is_some() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("shadow backend never received the mirrored request");
+    }
+
+    #[tokio::test]
+    async fn canary_takes_configured_share_and_records_outcome() {
+        let (primary_url, primary) = spawn_mock_server().await;
+        let (canary_url, canary) = spawn_mock_server().await;
+
+        let cfg = Config {
+            backends: vec![Backend {
+                name: Some("cpu".to_string()),
+                pattern: "^cpu\\..*".to_string(),
+                url: primary_url,
+                canary_url: Some(canary_url),
+                canary_percent: Some(100.0),
+                ..Default::default()
+            }],
+            timeout_secs: Some(2),
+            mode: Some(Mode::Multi),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+
+        let payload = json!({ "metrics": [{ "name": "cpu.test" }] });
+        let req = Request::builder()
+            .method(axum::http::Method::POST)
+            .uri("/api/v1/datapoints/query")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+        let resp = query_metric_handler(State(state.clone()), req)
+            .await
+            .expect("resp");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(canary.lock().await.is_some(), "canary should get 100%");
+        assert!(primary.lock().await.is_none());
+        assert_eq!(
+            state.metrics.counter(
+                "kairos_proxy_canary_requests_total",
+                &[
+                    ("backend", "cpu"),
+                    ("target", "canary"),
+                    ("outcome", "success")
+                ]
+            ),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn cached_multi_query_skips_backend() {
+        let (b_url, received) = spawn_mock_server().await;
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: ".*".to_string(),
+                url: b_url,
+                ..Default::default()
+            }],
+            timeout_secs: Some(2),
+            mode: Some(Mode::Multi),
+            cache: Some(CacheConfig {
+                ttl_secs: Some(60),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+
+        // Same query with fields in a different order: one cache key
+        let bodies = [
+            r#"{"start_relative":{"value":1,"unit":"hours"},"metrics":[{"name":"cpu.test"}]}"#,
+            r#"{"metrics":[{"name":"cpu.test"}],"start_relative":{"unit":"hours","value":1}}"#,
+        ];
+        for body in bodies {
+            let req = Request::builder()
+                .method(axum::http::Method::POST)
+                .uri("/api/v1/datapoints/query")
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let resp = query_metric_handler(State(state.clone()), req)
+                .await
+                .expect("resp");
+            assert_eq!(resp.status(), StatusCode::OK);
+            let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let v: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(v["queries"][0]["results"][0]["name"], "cpu.test");
+            // Forget the first request so a second backend call would be visible
+            assert_eq!(received.lock().await.take().is_some(), body == bodies[0]);
+        }
+        assert_eq!(
+            state
+                .metrics
+                .counter("kairos_proxy_cache_requests_total", &[("result", "hit")]),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_queries_holding_the_etag_get_not_modified() {
+        let (b_url, _received) = spawn_mock_server().await;
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: ".*".to_string(),
+                url: b_url,
+                ..Default::default()
+            }],
+            timeout_secs: Some(2),
+            mode: Some(Mode::Multi),
+            cache: Some(CacheConfig {
+                ttl_secs: Some(60),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+        let app = Router::new()
+            .route("/api/v1/datapoints/query", post(query_metric_handler))
+            .with_state(state);
+        let query = |metric: &str, etag: Option<&str>| {
+            let mut req = Request::builder()
+                .method(axum::http::Method::POST)
+                .uri("/api/v1/datapoints/query")
+                .header("content-type", "application/json");
+            if let Some(etag) = etag {
+                req = req.header("if-none-match", etag);
+            }
+            req.body(Body::from(format!(
+                r#"{{"start_relative":{{"value":1,"unit":"hours"}},"metrics":[{{"name":"{}"}}]}}"#,
+                metric
+            )))
+            .unwrap()
+        };
+
+        let first = app.clone().oneshot(query("cpu.test", None)).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let etag = first.headers()["etag"].to_str().unwrap().to_string();
+
+        let again = app
+            .clone()
+            .oneshot(query("cpu.test", Some(&etag)))
+            .await
+            .unwrap();
+        assert_eq!(again.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(again.headers()["etag"], etag.as_str());
+        let body = hyper::body::to_bytes(again.into_body()).await.unwrap();
+        assert!(body.is_empty());
+
+        // A different result is sent in full
+        let other = app.oneshot(query("mem.free", Some(&etag))).await.unwrap();
+        assert_eq!(other.status(), StatusCode::OK);
+        assert_ne!(other.headers()["etag"], etag.as_str());
+    }
+
+    #[tokio::test]
+    async fn multi_mode_splits_query_across_time_tiers() {
+        let (hot_url, hot) = spawn_mock_server().await;
+        let (cold_url, cold) = spawn_mock_server().await;
+        let cfg = Config {
+            backends: vec![
+                Backend {
+                    pattern: ".*".to_string(),
+                    url: hot_url,
+                    newer_than: Some("30d".to_string()),
+                    ..Default::default()
+                },
+                Backend {
+                    pattern: ".*".to_string(),
+                    url: cold_url,
+                    older_than: Some("30d".to_string()),
+                    ..Default::default()
+                },
+            ],
+            timeout_secs: Some(2),
+            mode: Some(Mode::Multi),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+
+        let payload = json!({
+            "start_relative": { "value": 60, "unit": "days" },
+            "metrics": [{ "name": "cpu.test" }]
+        });
+        let req = Request::builder()
+            .method(axum::http::Method::POST)
+            .uri("/api/v1/datapoints/query")
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+        let resp = query_metric_handler(State(state), req).await.expect("resp");
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let hot = hot.lock().await.take().expect("hot queried");
+        let cold = cold.lock().await.take().expect("cold queried");
+        assert!(hot.get("start_relative").is_none());
+        let boundary = hot["start_absolute"].as_i64().unwrap();
+        assert_eq!(cold["end_absolute"].as_i64().unwrap(), boundary - 1);
+        assert!(cold["start_absolute"].as_i64().unwrap() < boundary);
+    }
 }
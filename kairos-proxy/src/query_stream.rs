@@ -0,0 +1,142 @@
+use crate::fanout;
+use crate::proxy::{read_body, shed_load};
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use futures::{
+    stream::{self, FuturesUnordered},
+    StreamExt,
+};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{debug, error, info};
+
+const QUERY_ENDPOINT: &str = "api/v1/datapoints/query";
+
+/// Turns one backend response into SSE events: a `result` event per KairosDB result, or a
+/// single `error` event if the backend failed.
+fn backend_events(
+    url: &reqwest::Url,
+    response: Option<serde_json::Value>,
+) -> Vec<Result<Event, serde_json::Error>> {
+    let Some(response) = response else {
+        return vec![Event::default()
+            .event("error")
+            .json_data(json!({ "backend": url.as_str(), "error": "backend request failed" }))];
+    };
+    let mut events = Vec::new();
+    for query in response
+        .get("queries")
+        .and_then(|q| q.as_array())
+        .into_iter()
+        .flatten()
+    {
+        for result in query
+            .get("results")
+            .and_then(|r| r.as_array())
+            .into_iter()
+            .flatten()
+        {
+            events.push(Event::default().event("result").json_data(result));
+        }
+    }
+    events
+}
+
+/// Multi-mode query whose results are streamed as server-sent events as each backend
+/// responds, rather than after the slowest one. Emits `result` events (one KairosDB result
+/// object each), `error` events for failed backends and a final `done` event.
+pub async fn query_stream_handler(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+) -> Result<Response, StatusCode> {
+    debug!("Received query stream request");
+    if let Some(shed) = shed_load(&state) {
+        return Ok(shed);
+    }
+
+    let mut req = req;
+    let body_bytes = match read_body(&state, &mut req).await {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Failed to read request body: {:?}", e);
+            return Err(e);
+        }
+    };
+    let body_bytes = match state.query_checks.apply(body_bytes) {
+        Ok(b) => b,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    crate::recorder::record(&state, &req, &body_bytes);
+    let json: serde_json::Value = match serde_json::from_slice(&body_bytes) {
+        Ok(j) => j,
+        Err(e) => {
+            error!("Failed to parse JSON body: {}", e);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    if crate::dry_run::requested(req.headers()) {
+        return Ok(crate::dry_run::multi(
+            &state,
+            req.headers(),
+            &json,
+            QUERY_ENDPOINT,
+        ));
+    }
+    let requests = match fanout::plan(&state, req.headers(), &json) {
+        Ok(r) => r,
+        Err(e) => return Ok(e.into_response()),
+    };
+    crate::quota::charge_metrics(fanout::metric_count(&json));
+    let backend_count = requests.len();
+    let headers = Arc::new(req.headers().clone());
+    let futs: FuturesUnordered<_> = requests
+        .into_iter()
+        .map(|request| {
+            let state = state.clone();
+            let headers = headers.clone();
+            async move {
+                let backend = request.backend;
+                let response = fanout::send(&state, &headers, request, QUERY_ENDPOINT).await;
+                backend_events(&state.backends[backend].url, response.ok())
+            }
+        })
+        .collect();
+
+    let events = futs.flat_map(stream::iter).chain(stream::once(async move {
+        info!(
+            "Finished streaming results from {} backend(s)",
+            backend_count
+        );
+        Event::default()
+            .event("done")
+            .json_data(json!({ "backends": backend_count }))
+    }));
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emits_one_event_per_result_or_an_error() {
+        let url = reqwest::Url::parse("http://kairos-1:8080").unwrap();
+        let response = json!({ "queries": [
+            { "results": [{ "name": "a" }, { "name": "b" }] },
+            { "results": [{ "name": "c" }] }
+        ]});
+        assert_eq!(backend_events(&url, Some(response)).len(), 3);
+        assert_eq!(backend_events(&url, None).len(), 1);
+    }
+}
@@ -0,0 +1,638 @@
+//! Query quotas and tag scopes of API keys.
+//!
+//! Requests are authenticated by `auth`; budgets and scopes belong to the key's name, so
+//! entries sharing a name (e.g. an old and a new secret) share them and must agree on them.
+//! Each key may have hourly and daily budgets of queries and of the metrics in them; a key
+//! that has used up any budget gets `429 Too Many Requests` until its window (a clock hour or
+//! a UTC day) ends. Only what reaches the backends is charged: cache hits, coalesced queries
+//! and writes are free.
+//!
+//! A key with `tags` only sees series carrying those tag values: every metric of its queries
+//! gets the tags as filters, narrowed to the values it asks for. A metric asking only for
+//! values outside the scope is refused with `403`.
+//!
+//! Usage is counted in process, or in Redis so that replicas share one count. `GET
+//! /admin/usage` shows every key's usage.
+
+use crate::auth::Principal;
+use crate::config::{ApiKeyConfig, QuotaStoreConfig};
+use crate::redis_conn::RedisConnection;
+use crate::state::AppState;
+use crate::timerange::now_ms;
+use async_trait::async_trait;
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::Body;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+const HOUR_MS: i64 = 3_600_000;
+const DAY_MS: i64 = 24 * HOUR_MS;
+
+/// The API key a request presented and what it has been charged so far.
+struct Scope {
+    key: Arc<ApiKey>,
+    metrics: AtomicU64,
+}
+
+tokio::task_local! {
+    static REQUEST: Arc<Scope>;
+}
+
+/// Charges `n` metrics to the API key of the current request, if it has one.
+pub fn charge_metrics(n: usize) {
+    let _ = REQUEST.try_with(|s| s.metrics.fetch_add(n as u64, Ordering::Relaxed));
+}
+
+/// Tag values the current request's API key is restricted to, if any.
+fn tag_scope() -> Option<Arc<ApiKey>> {
+    REQUEST
+        .try_with(|s| s.key.clone())
+        .ok()
+        .filter(|k| !k.tags.is_empty())
+}
+
+/// Whether the current request's API key has a budget of metrics, which needs its queries
+/// counted.
+pub fn metric_budgeted() -> bool {
+    REQUEST
+        .try_with(|s| s.key.budgets.iter().any(|b| b.metrics))
+        .unwrap_or(false)
+}
+
+/// Whether queries of the current request need [`scope_query`].
+pub fn tag_scoped() -> bool {
+    tag_scope().is_some()
+}
+
+/// `query` restricted to the tags of the current request's API key, or `None` if the key is
+/// not restricted. Fails with the offending field if a metric asks only for tag values outside
+/// the scope.
+pub fn scope_query(query: &Value) -> Result<Option<Value>, String> {
+    let Some(key) = tag_scope() else {
+        return Ok(None);
+    };
+    let mut query = query.clone();
+    restrict(&mut query, &key.tags)?;
+    Ok(Some(query))
+}
+
+fn restrict(query: &mut Value, scope: &BTreeMap<String, Vec<String>>) -> Result<(), String> {
+    let Some(metrics) = query.get_mut("metrics").and_then(Value::as_array_mut) else {
+        return Ok(());
+    };
+    for (i, metric) in metrics.iter_mut().enumerate() {
+        let Some(metric) = metric.as_object_mut() else {
+            continue;
+        };
+        let filters = metric.entry("tags").or_insert_with(|| json!({}));
+        if !filters.is_object() {
+            *filters = json!({});
+        }
+        for (tag, allowed) in scope {
+            // KairosDB takes a single value or a list
+            let requested: Vec<&str> = match filters.get(tag) {
+                Some(Value::String(v)) => vec![v.as_str()],
+                Some(Value::Array(vs)) => vs.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            let permitted: Vec<&String> = if requested.is_empty() {
+                allowed.iter().collect()
+            } else {
+                allowed
+                    .iter()
+                    .filter(|a| requested.contains(&a.as_str()))
+                    .collect()
+            };
+            if permitted.is_empty() {
+                return Err(format!(
+                    "query.metrics[{}].tags.{}: not permitted for this API key",
+                    i, tag
+                ));
+            }
+            filters[tag.as_str()] = json!(permitted);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Budget {
+    name: &'static str,
+    // Whether metrics rather than queries are counted
+    metrics: bool,
+    window_ms: i64,
+    limit: u64,
+}
+
+impl Budget {
+    fn counter(&self, key: &str, now_ms: i64) -> String {
+        format!("quota:{}:{}:{}", key, self.name, now_ms / self.window_ms)
+    }
+
+    fn resets_in(&self, now_ms: i64) -> Duration {
+        Duration::from_millis((self.window_ms - now_ms.rem_euclid(self.window_ms)) as u64)
+    }
+}
+
+#[derive(Debug)]
+pub struct ApiKey {
+    pub name: String,
+    budgets: Vec<Budget>,
+    // Tag values the key's queries are restricted to
+    tags: BTreeMap<String, Vec<String>>,
+}
+
+impl ApiKey {
+    fn from_config(cfg: &ApiKeyConfig) -> Self {
+        let budgets = [
+            ("queries_per_hour", false, HOUR_MS, cfg.queries_per_hour),
+            ("queries_per_day", false, DAY_MS, cfg.queries_per_day),
+            ("metrics_per_hour", true, HOUR_MS, cfg.metrics_per_hour),
+            ("metrics_per_day", true, DAY_MS, cfg.metrics_per_day),
+        ]
+        .into_iter()
+        .filter_map(|(name, metrics, window_ms, limit)| {
+            Some(Budget {
+                name,
+                metrics,
+                window_ms,
+                limit: limit?,
+            })
+        })
+        .collect();
+        ApiKey {
+            name: cfg.name.clone(),
+            budgets,
+            tags: cfg.tags.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Where usage counters live. Counters only ever grow and expire with their window.
+#[async_trait]
+trait UsageStore: Send + Sync {
+    async fn get(&self, counters: &[String]) -> Vec<u64>;
+    async fn add(&self, counters: &[(String, u64)], retain: Duration);
+}
+
+#[derive(Default)]
+struct MemoryUsage {
+    // Counter -> (value, expiry in ms)
+    counters: Mutex<HashMap<String, (u64, i64)>>,
+}
+
+impl MemoryUsage {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, (u64, i64)>> {
+        self.counters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl UsageStore for MemoryUsage {
+    async fn get(&self, counters: &[String]) -> Vec<u64> {
+        let map = self.lock();
+        counters
+            .iter()
+            .map(|c| map.get(c).map_or(0, |(v, _)| *v))
+            .collect()
+    }
+
+    async fn add(&self, counters: &[(String, u64)], retain: Duration) {
+        let now = now_ms();
+        let mut map = self.lock();
+        map.retain(|_, (_, expires)| *expires > now);
+        for (counter, by) in counters {
+            let expires = now + retain.as_millis() as i64;
+            map.entry(counter.clone()).or_insert((0, expires)).0 += by;
+        }
+    }
+}
+
+/// Counters in Redis, shared by every replica using the same server. Redis errors are logged
+/// and count as no usage, and an unavailable Redis is skipped for a while (see `redis_conn`),
+/// so an unavailable Redis never blocks or slows down queries.
+struct RedisUsage {
+    redis: RedisConnection,
+    prefix: String,
+}
+
+#[async_trait]
+impl UsageStore for RedisUsage {
+    async fn get(&self, counters: &[String]) -> Vec<u64> {
+        let zeros = vec![0; counters.len()];
+        if counters.is_empty() {
+            return zeros;
+        }
+        let mut cmd = redis::cmd("MGET");
+        for c in counters {
+            cmd.arg(format!("{}{}", self.prefix, c));
+        }
+        match self.redis.query::<Vec<Option<u64>>>("MGET", &cmd).await {
+            Some(values) => values.into_iter().map(Option::unwrap_or_default).collect(),
+            None => zeros,
+        }
+    }
+
+    async fn add(&self, counters: &[(String, u64)], retain: Duration) {
+        let mut pipe = redis::pipe();
+        for (counter, by) in counters {
+            let key = format!("{}{}", self.prefix, counter);
+            pipe.cmd("INCRBY").arg(&key).arg(*by).ignore();
+            pipe.cmd("PEXPIRE")
+                .arg(&key)
+                .arg(retain.as_millis() as u64)
+                .ignore();
+        }
+        let pipe = &pipe;
+        let _: Option<()> = self
+            .redis
+            .run("update", |mut conn| async move {
+                pipe.query_async(&mut conn).await
+            })
+            .await;
+    }
+}
+
+pub struct Quotas {
+    // By name
+    keys: HashMap<String, Arc<ApiKey>>,
+    store: Box<dyn UsageStore>,
+}
+
+/// A budget `key` has used up.
+#[derive(Debug, PartialEq, Eq)]
+struct Exceeded {
+    budget: &'static str,
+    limit: u64,
+    resets_in: Duration,
+}
+
+impl Quotas {
+    /// `None` unless API keys are configured.
+    pub fn from_config(
+        keys: &[ApiKeyConfig],
+        store: &Option<QuotaStoreConfig>,
+    ) -> anyhow::Result<Option<Self>> {
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        let mut by_name: HashMap<String, Arc<ApiKey>> = HashMap::new();
+        for k in keys {
+            let key = ApiKey::from_config(k);
+            match by_name.get(&k.name) {
+                Some(other) if other.budgets != key.budgets || other.tags != key.tags => {
+                    anyhow::bail!("API keys named '{}' have different budgets or tags", k.name);
+                }
+                Some(_) => {}
+                None => {
+                    by_name.insert(k.name.clone(), Arc::new(key));
+                }
+            }
+        }
+        let store: Box<dyn UsageStore> = match store.as_ref().and_then(|s| s.redis_url.as_ref()) {
+            Some(url) => {
+                info!("Counting API key usage in redis");
+                Box::new(RedisUsage {
+                    redis: RedisConnection::open("quota", url)
+                        .map_err(|e| anyhow::anyhow!("Invalid quota redis_url '{}': {}", url, e))?,
+                    prefix: store
+                        .as_ref()
+                        .and_then(|s| s.redis_key_prefix.clone())
+                        .unwrap_or_else(|| "kairos-proxy:".to_string()),
+                })
+            }
+            None => Box::new(MemoryUsage::default()),
+        };
+        Ok(Some(Quotas {
+            keys: by_name,
+            store,
+        }))
+    }
+
+    async fn used(&self, key: &ApiKey, now_ms: i64) -> Vec<u64> {
+        let counters: Vec<String> = key
+            .budgets
+            .iter()
+            .map(|b| b.counter(&key.name, now_ms))
+            .collect();
+        self.store.get(&counters).await
+    }
+
+    /// The first budget `key` has used up, if any.
+    async fn exceeded(&self, key: &ApiKey, now_ms: i64) -> Option<Exceeded> {
+        let used = self.used(key, now_ms).await;
+        key.budgets
+            .iter()
+            .zip(used)
+            .find(|(b, used)| *used >= b.limit)
+            .map(|(b, _)| Exceeded {
+                budget: b.name,
+                limit: b.limit,
+                resets_in: b.resets_in(now_ms),
+            })
+    }
+
+    /// Charges one query of `metrics` metrics to `key`.
+    async fn charge(&self, key: &ApiKey, metrics: u64, now_ms: i64) {
+        let counters: Vec<(String, u64)> = key
+            .budgets
+            .iter()
+            .map(|b| {
+                (
+                    b.counter(&key.name, now_ms),
+                    if b.metrics { metrics } else { 1 },
+                )
+            })
+            .collect();
+        if !counters.is_empty() {
+            self.store
+                .add(&counters, Duration::from_millis(DAY_MS as u64))
+                .await;
+        }
+    }
+
+    /// Every key's budgets with their usage in the current windows.
+    async fn usage(&self) -> Value {
+        let now = now_ms();
+        let mut keys: Vec<&Arc<ApiKey>> = self.keys.values().collect();
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut out = Vec::new();
+        for key in keys {
+            let used = self.used(key, now).await;
+            let budgets: Vec<Value> = key
+                .budgets
+                .iter()
+                .zip(used)
+                .map(|(b, used)| {
+                    json!({
+                        "budget": b.name,
+                        "limit": b.limit,
+                        "used": used,
+                        "resets_in_secs": b.resets_in(now).as_secs(),
+                    })
+                })
+                .collect();
+            out.push(json!({ "name": key.name, "budgets": budgets }));
+        }
+        json!({ "keys": out })
+    }
+}
+
+/// Enforces and charges the budgets of the key `auth` found on the request.
+pub async fn layer(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(quotas) = &state.quotas else {
+        return next.run(req).await;
+    };
+    let Some(key) = req
+        .extensions()
+        .get::<Arc<Principal>>()
+        .and_then(|p| quotas.keys.get(&p.name))
+        .cloned()
+    else {
+        return next.run(req).await;
+    };
+    if let Some(exceeded) = quotas.exceeded(&key, now_ms()).await {
+        info!(
+            "API key '{}' exhausted its {} budget of {}",
+            key.name, exceeded.budget, exceeded.limit
+        );
+        state.metrics.inc_counter(
+            "kairos_proxy_quota_exceeded_total",
+            &[("key", &key.name), ("budget", exceeded.budget)],
+        );
+        let retry_after = exceeded.resets_in.as_secs().max(1);
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            Json(json!({
+                "error": "quota exceeded",
+                "key": key.name,
+                "budget": exceeded.budget,
+                "limit": exceeded.limit,
+            })),
+        )
+            .into_response();
+    }
+    let scope = Arc::new(Scope {
+        key: key.clone(),
+        metrics: AtomicU64::new(0),
+    });
+    let response = REQUEST.scope(scope.clone(), next.run(req)).await;
+    let metrics = scope.metrics.load(Ordering::Relaxed);
+    if metrics > 0 {
+        quotas.charge(&key, metrics, now_ms()).await;
+    }
+    response
+}
+
+/// `GET /admin/usage`: every API key's budgets and usage.
+pub async fn usage_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    crate::admin::authorize(&state, &headers)?;
+    Ok(Json(match &state.quotas {
+        Some(quotas) => quotas.usage().await,
+        None => json!({ "keys": [] }),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn refuses_keys_past_their_budget_until_the_window_ends() {
+        let keys = vec![
+            ApiKeyConfig {
+                name: "batch".to_string(),
+                key: "k1".to_string(),
+                queries_per_hour: Some(2),
+                metrics_per_day: Some(5),
+                ..Default::default()
+            },
+            ApiKeyConfig {
+                name: "dashboards".to_string(),
+                key: "k2".to_string(),
+                ..Default::default()
+            },
+            // A second secret for the same budgets
+            ApiKeyConfig {
+                name: "batch".to_string(),
+                key: "k3".to_string(),
+                queries_per_hour: Some(2),
+                metrics_per_day: Some(5),
+                ..Default::default()
+            },
+        ];
+        let quotas = Quotas::from_config(&keys, &None).unwrap().expect("quotas");
+        let batch = quotas.keys["batch"].clone();
+
+        // 10:30 on some day
+        let now = 100 * DAY_MS + 10 * HOUR_MS + 30 * 60_000;
+        quotas.charge(&batch, 3, now).await;
+        assert_eq!(quotas.exceeded(&batch, now).await, None);
+        quotas.charge(&batch, 1, now).await;
+        let exceeded = quotas.exceeded(&batch, now).await.expect("exceeded");
+        assert_eq!(exceeded.budget, "queries_per_hour");
+        assert_eq!(exceeded.resets_in, Duration::from_secs(30 * 60));
+        // The next hour has a fresh query budget, but the day's metrics are nearly gone
+        assert_eq!(quotas.exceeded(&batch, now + HOUR_MS).await, None);
+        quotas.charge(&batch, 1, now + HOUR_MS).await;
+        let exceeded = quotas
+            .exceeded(&batch, now + HOUR_MS)
+            .await
+            .expect("exceeded");
+        assert_eq!((exceeded.budget, exceeded.limit), ("metrics_per_day", 5));
+
+        let unlimited = quotas.keys["dashboards"].clone();
+        quotas.charge(&unlimited, 1000, now).await;
+        assert_eq!(quotas.exceeded(&unlimited, now).await, None);
+
+        let mut conflicting = keys;
+        conflicting[2].queries_per_hour = None;
+        assert!(Quotas::from_config(&conflicting, &None).is_err());
+    }
+
+    #[tokio::test]
+    async fn an_unresponsive_redis_allows_queries_without_waiting() {
+        let keys = vec![ApiKeyConfig {
+            name: "batch".to_string(),
+            key: "k1".to_string(),
+            queries_per_hour: Some(1),
+            ..Default::default()
+        }];
+        let store = QuotaStoreConfig {
+            redis_url: Some(crate::redis_conn::unresponsive_redis().await),
+            ..Default::default()
+        };
+        let quotas = Quotas::from_config(&keys, &Some(store))
+            .unwrap()
+            .expect("quotas");
+        let batch = quotas.keys["batch"].clone();
+        let now = 100 * DAY_MS;
+
+        assert_eq!(quotas.exceeded(&batch, now).await, None);
+        // Skipped without waiting while backing off
+        let started = std::time::Instant::now();
+        quotas.charge(&batch, 5, now).await;
+        assert_eq!(quotas.exceeded(&batch, now).await, None);
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn simple_mode_charges_each_metric_of_queries_that_reach_the_backend() {
+        use crate::config::{Backend, Config, Mode};
+        use axum::{routing::post, Router};
+        use tower::{ServiceBuilder, ServiceExt};
+
+        let backend = Router::new().route(
+            "/api/v1/datapoints/query",
+            post(|| async { axum::Json(json!({ "queries": [] })) }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(backend.into_make_service());
+        tokio::spawn(server);
+
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: ".*".to_string(),
+                url: format!("http://{}", addr),
+                // One request, then the rest are throttled
+                max_rps: Some(0.001),
+                ..Default::default()
+            }],
+            mode: Some(Mode::Simple),
+            stream_simple_queries: Some(true),
+            api_keys: vec![ApiKeyConfig {
+                name: "batch".to_string(),
+                key: "k1".to_string(),
+                metrics_per_day: Some(1000),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+        let app = Router::new()
+            .route(
+                "/api/v1/datapoints/query",
+                post(crate::proxy::query_metric_handler),
+            )
+            .with_state(state.clone())
+            .layer(
+                ServiceBuilder::new()
+                    .layer(axum::middleware::from_fn_with_state(
+                        state.clone(),
+                        crate::auth::layer,
+                    ))
+                    .layer(axum::middleware::from_fn_with_state(state.clone(), layer)),
+            );
+        let query = || {
+            Request::builder()
+                .method("POST")
+                .uri("/api/v1/datapoints/query")
+                .header("x-api-key", "k1")
+                .body(Body::from(
+                    r#"{"metrics":[{"name":"cpu"},{"name":"mem"},{"name":"disk"}]}"#,
+                ))
+                .unwrap()
+        };
+        let quotas = state.quotas.as_ref().expect("quotas");
+        let batch = quotas.keys["batch"].clone();
+
+        let resp = app.clone().oneshot(query()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(quotas.used(&batch, now_ms()).await, [3]);
+        // Never sent, never charged
+        let resp = app.oneshot(query()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(quotas.used(&batch, now_ms()).await, [3]);
+    }
+
+    #[test]
+    fn restricts_every_metric_to_the_tag_scope() {
+        let scope = BTreeMap::from([(
+            "team".to_string(),
+            vec!["payments".to_string(), "billing".to_string()],
+        )]);
+        let mut query = json!({ "metrics": [
+            { "name": "a" },
+            { "name": "b", "tags": { "host": ["h1"], "team": ["billing", "search"] } },
+            { "name": "c", "tags": { "team": "payments" } }
+        ] });
+        restrict(&mut query, &scope).unwrap();
+        assert_eq!(
+            query["metrics"][0]["tags"],
+            json!({ "team": ["payments", "billing"] })
+        );
+        assert_eq!(
+            query["metrics"][1]["tags"],
+            json!({ "host": ["h1"], "team": ["billing"] })
+        );
+        assert_eq!(query["metrics"][2]["tags"], json!({ "team": ["payments"] }));
+
+        let mut outside =
+            json!({ "metrics": [{ "name": "a" }, { "name": "b", "tags": { "team": "search" } }] });
+        assert_eq!(
+            restrict(&mut outside, &scope).unwrap_err(),
+            "query.metrics[1].tags.team: not permitted for this API key"
+        );
+    }
+}
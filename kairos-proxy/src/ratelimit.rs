@@ -0,0 +1,66 @@
+//! Per-backend request rate limits.
+//!
+//! A backend with `max_rps` gets a token bucket refilled at that rate and holding up to
+//! `burst` tokens. Requests take a token before they are sent, waiting for one if the bucket
+//! is empty, so the proxy never exceeds the backend's budget however spiky inbound traffic is.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    // Tokens available at the instant; negative while requests wait for reserved tokens
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Reserves a token and returns how long to wait before it may be used. Returns the wait
+    /// as an error, reserving nothing, if it would exceed `max_wait`.
+    pub fn reserve(&self, max_wait: Duration) -> Result<Duration, Duration> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let (tokens, at) = *state;
+        let tokens = (tokens + now.duration_since(at).as_secs_f64() * self.rate).min(self.burst);
+        let wait = if tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - tokens) / self.rate)
+        };
+        if wait > max_wait {
+            *state = (tokens, now);
+            return Err(wait);
+        }
+        *state = (tokens - 1.0, now);
+        Ok(wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spaces_requests_beyond_the_burst() {
+        let bucket = TokenBucket::new(10.0, 2.0);
+        let max = Duration::from_secs(1);
+        assert_eq!(bucket.reserve(max), Ok(Duration::ZERO));
+        assert_eq!(bucket.reserve(max), Ok(Duration::ZERO));
+        let third = bucket.reserve(max).unwrap();
+        let fourth = bucket.reserve(max).unwrap();
+        assert!(third > Duration::from_millis(90) && third <= Duration::from_millis(100));
+        assert!(fourth > Duration::from_millis(190) && fourth <= Duration::from_millis(200));
+        // Too long a wait reserves nothing, and says how long it would have been
+        let refused = bucket.reserve(Duration::from_millis(50)).unwrap_err();
+        assert!(refused > Duration::from_millis(290) && refused <= Duration::from_millis(300));
+        assert!(bucket.reserve(max).unwrap() <= Duration::from_millis(300));
+    }
+}
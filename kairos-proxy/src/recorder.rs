@@ -0,0 +1,199 @@
+//! Sampled recording of inbound queries, for replaying real traffic later.
+//!
+//! With a `[recorder]` table, `sample_percent` of the queries reaching the query endpoints are
+//! appended to `path` as JSON lines: arrival time, endpoint, request id, client address, headers
+//! and the query as checked (API keys and credentials are never recorded). Once the file holds
+//! `max_bytes` it is moved to `<path>.1`, replacing the previous one, so recordings never take
+//! more than twice that. Lines are written by a background task; when it falls behind, queries
+//! are dropped from the recording rather than delayed. `kairos-proxy replay` sends them again
+//! (see `replay`).
+
+use crate::config::RecorderConfig;
+use crate::forwarded::ClientIp;
+use crate::state::AppState;
+use crate::timerange::now_ms;
+use bytes::Bytes;
+use hyper::{Body, HeaderMap, Request};
+use serde_json::{json, Map, Value};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info};
+
+// Recorded queries waiting to be written
+const QUEUE: usize = 1024;
+const DEFAULT_MAX_BYTES: u64 = 100 * 1024 * 1024;
+
+// Credentials and connection details that do not belong in a recording
+const SKIPPED_HEADERS: [&str; 6] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+    "host",
+    "content-length",
+];
+
+pub struct Recorder {
+    path: PathBuf,
+    sample_percent: f64,
+    max_bytes: u64,
+    tx: mpsc::Sender<String>,
+    // Taken by the writer task
+    rx: Mutex<Option<mpsc::Receiver<String>>>,
+}
+
+impl Recorder {
+    pub fn from_config(cfg: &Option<RecorderConfig>) -> anyhow::Result<Option<Self>> {
+        let Some(cfg) = cfg else {
+            return Ok(None);
+        };
+        let sample_percent = cfg.sample_percent.unwrap_or(100.0);
+        anyhow::ensure!(
+            (0.0..=100.0).contains(&sample_percent),
+            "recorder.sample_percent must be between 0 and 100"
+        );
+        let (tx, rx) = mpsc::channel(QUEUE);
+        Ok(Some(Recorder {
+            path: PathBuf::from(&cfg.path),
+            sample_percent,
+            max_bytes: cfg.max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            tx,
+            rx: Mutex::new(Some(rx)),
+        }))
+    }
+
+    fn record(&self, endpoint: &str, headers: &HeaderMap, client: Option<&ClientIp>, body: &Bytes) {
+        if self.sample_percent < 100.0 && fastrand::f64() * 100.0 >= self.sample_percent {
+            return;
+        }
+        let Ok(query) = serde_json::from_slice::<Value>(body) else {
+            return;
+        };
+        let line = entry(endpoint, headers, client, query).to_string();
+        if self.tx.try_send(line).is_err() {
+            debug!("Recorder queue full, query not recorded");
+        }
+    }
+}
+
+/// Records the query `req` carries, if a recorder is configured and samples it.
+pub fn record(state: &AppState, req: &Request<Body>, body: &Bytes) {
+    if let Some(recorder) = &state.recorder {
+        recorder.record(
+            req.uri().path(),
+            req.headers(),
+            req.extensions().get::<ClientIp>(),
+            body,
+        );
+    }
+}
+
+fn entry(endpoint: &str, headers: &HeaderMap, client: Option<&ClientIp>, query: Value) -> Value {
+    let mut recorded = Map::new();
+    for (name, value) in headers {
+        if SKIPPED_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        if let Ok(value) = value.to_str() {
+            recorded.insert(name.to_string(), Value::String(value.to_string()));
+        }
+    }
+    json!({
+        "at_ms": now_ms(),
+        "endpoint": endpoint,
+        "request_id": headers.get("x-request-id").and_then(|v| v.to_str().ok()),
+        "client_ip": client.map(|c| c.0.to_string()),
+        "headers": recorded,
+        "query": query,
+    })
+}
+
+/// Writes recorded queries to the recording file until the proxy exits.
+pub fn spawn(state: Arc<AppState>) {
+    let Some(recorder) = &state.recorder else {
+        return;
+    };
+    let Some(mut rx) = recorder.rx.lock().unwrap().take() else {
+        return;
+    };
+    info!(
+        "Recording {}% of queries to {}",
+        recorder.sample_percent,
+        recorder.path.display()
+    );
+    tokio::spawn(async move {
+        let Some(recorder) = &state.recorder else {
+            return;
+        };
+        let mut rotated = recorder.path.clone().into_os_string();
+        rotated.push(".1");
+        while let Some(line) = rx.recv().await {
+            let size = tokio::fs::metadata(&recorder.path)
+                .await
+                .map_or(0, |m| m.len());
+            if size >= recorder.max_bytes {
+                if let Err(e) = tokio::fs::rename(&recorder.path, &rotated).await {
+                    error!("Cannot rotate {}: {}", recorder.path.display(), e);
+                }
+            }
+            let written = async {
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&recorder.path)
+                    .await?;
+                file.write_all(format!("{}\n", line).as_bytes()).await?;
+                // Everything already queued goes in the same write
+                let mut batch = String::new();
+                while let Ok(line) = rx.try_recv() {
+                    batch.push_str(&line);
+                    batch.push('\n');
+                }
+                file.write_all(batch.as_bytes()).await?;
+                file.flush().await
+            };
+            if let Err(e) = written.await {
+                error!("Cannot record to {}: {}", recorder.path.display(), e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_queries_without_credentials() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer s3cret".parse().unwrap());
+        headers.insert("x-request-id", "abc".parse().unwrap());
+        headers.insert("x-query-priority", "batch".parse().unwrap());
+        let client = ClientIp("192.0.2.7".parse().unwrap());
+        let query = json!({ "metrics": [{ "name": "cpu.load" }] });
+
+        let recorded = entry(
+            "/api/v1/datapoints/query",
+            &headers,
+            Some(&client),
+            query.clone(),
+        );
+        assert_eq!(recorded["endpoint"], "/api/v1/datapoints/query");
+        assert_eq!(recorded["request_id"], "abc");
+        assert_eq!(recorded["client_ip"], "192.0.2.7");
+        assert_eq!(
+            recorded["headers"],
+            json!({ "x-request-id": "abc", "x-query-priority": "batch" })
+        );
+        assert_eq!(recorded["query"], query);
+
+        let cfg = RecorderConfig {
+            path: "queries.jsonl".to_string(),
+            sample_percent: Some(101.0),
+            max_bytes: None,
+        };
+        assert!(Recorder::from_config(&Some(cfg)).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! A Redis connection for stores that must never hold up a request: the response cache and
+//! API key usage counters.
+//!
+//! The connection is opened on first use and re-established by redis' connection manager
+//! afterwards. Connecting and every command are bounded by a second. Once Redis fails to
+//! connect or answer in time, it is skipped for a backoff of 1 second, doubling up to 30
+//! while it keeps failing, so an outage does not add a timeout to every request; then a
+//! single request tries it again. Callers treat a skipped or failed command as no answer.
+
+use redis::aio::ConnectionManager;
+use std::future::Future;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+use tracing::warn;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+// Longest a command may take before Redis counts as unavailable
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+// How long an unavailable Redis is skipped; doubles while it keeps failing
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct RedisConnection {
+    // What the connection is for, in logs
+    role: &'static str,
+    client: redis::Client,
+    conn: OnceCell<ConnectionManager>,
+    backoff: Mutex<Backoff>,
+}
+
+#[derive(Default)]
+struct Backoff {
+    // Redis is skipped until then
+    until: Option<Instant>,
+    // Backoff after the next failure
+    next: Duration,
+}
+
+impl RedisConnection {
+    pub fn open(role: &'static str, url: &str) -> redis::RedisResult<Self> {
+        Ok(RedisConnection {
+            role,
+            client: redis::Client::open(url)?,
+            conn: OnceCell::new(),
+            backoff: Mutex::new(Backoff::default()),
+        })
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager, String> {
+        self.conn
+            .get_or_try_init(|| async {
+                tokio::time::timeout(CONNECT_TIMEOUT, ConnectionManager::new(self.client.clone()))
+                    .await
+                    .map_err(|_| "connection timed out".to_string())?
+                    .map_err(|e| e.to_string())
+            })
+            .await
+            .cloned()
+    }
+
+    fn backoff(&self) -> MutexGuard<'_, Backoff> {
+        self.backoff.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether Redis may be tried now. The first caller after a backoff has the next
+    /// attempt to itself until it reports back or could have timed out.
+    fn available(&self) -> bool {
+        let mut backoff = self.backoff();
+        let now = Instant::now();
+        match backoff.until {
+            Some(until) if now < until => false,
+            Some(_) => {
+                backoff.until = Some(now + CONNECT_TIMEOUT + COMMAND_TIMEOUT);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn failed(&self, what: &str, e: impl std::fmt::Display) {
+        let mut backoff = self.backoff();
+        let wait = backoff.next.max(MIN_BACKOFF);
+        backoff.until = Some(Instant::now() + wait);
+        backoff.next = (wait * 2).min(MAX_BACKOFF);
+        warn!(
+            "Redis {} for the {} failed, skipping redis for {:?}: {}",
+            what, self.role, wait, e
+        );
+    }
+
+    /// Runs `command` (a `GET`, a pipeline, ...) on the connection unless Redis is being
+    /// skipped. `None` when skipped or failed.
+    pub async fn run<T, F, Fut>(&self, what: &str, command: F) -> Option<T>
+    where
+        F: FnOnce(ConnectionManager) -> Fut,
+        Fut: Future<Output = redis::RedisResult<T>>,
+    {
+        if !self.available() {
+            return None;
+        }
+        let conn = match self.connection().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                self.failed("connection", e);
+                return None;
+            }
+        };
+        match tokio::time::timeout(COMMAND_TIMEOUT, command(conn)).await {
+            Err(_) => self.failed(what, "timed out"),
+            Ok(Err(e))
+                if e.is_io_error()
+                    || e.is_timeout()
+                    || e.is_connection_refusal()
+                    || e.is_connection_dropped() =>
+            {
+                self.failed(what, e)
+            }
+            Ok(result) => {
+                *self.backoff() = Backoff::default();
+                match result {
+                    Ok(value) => return Some(value),
+                    Err(e) => warn!("Redis {} for the {} failed: {}", what, self.role, e),
+                }
+            }
+        }
+        None
+    }
+
+    /// Runs `cmd`; see [`run`](Self::run).
+    pub async fn query<T: redis::FromRedisValue>(&self, what: &str, cmd: &redis::Cmd) -> Option<T> {
+        self.run(
+            what,
+            |mut conn| async move { cmd.query_async(&mut conn).await },
+        )
+        .await
+    }
+}
+
+/// A Redis server that accepts connections and never answers.
+#[cfg(test)]
+pub(crate) async fn unresponsive_redis() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = listener.accept().await {
+            held.push(socket);
+        }
+    });
+    format!("redis://{}", addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn skips_an_unresponsive_redis_for_a_growing_backoff() {
+        let conn = RedisConnection::open("cache", &unresponsive_redis().await).unwrap();
+        let mut cmd = redis::cmd("GET");
+        cmd.arg("q");
+        let get = || conn.query::<Option<String>>("GET", &cmd);
+
+        let started = Instant::now();
+        assert_eq!(get().await, None);
+        assert!(started.elapsed() >= COMMAND_TIMEOUT.min(CONNECT_TIMEOUT));
+        // Skipped without waiting while backing off
+        let started = Instant::now();
+        assert_eq!(get().await, None);
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(conn.backoff().next, Duration::from_secs(2));
+
+        // Tried again once the backoff is over, and skipped for longer after failing again
+        conn.backoff().until = Some(Instant::now());
+        assert_eq!(get().await, None);
+        assert_eq!(conn.backoff().next, Duration::from_secs(4));
+        let until = conn.backoff().until.expect("backing off");
+        assert!(until > Instant::now() + Duration::from_secs(1));
+    }
+}
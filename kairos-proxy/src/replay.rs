@@ -0,0 +1,202 @@
+//! `kairos-proxy replay <file>...`: sends recorded queries again.
+//!
+//! The queries in one or more recordings (see `recorder`) are sent through the proxy's own
+//! routing, fan-out and merging to the backends in the configuration, without listening on a
+//! port. They keep their recorded headers and the spacing they arrived with, scaled by
+//! `--speed` (2 replays twice as fast, 0 sends everything at once), so a new cluster topology
+//! can be load tested with real traffic. API keys are not checked, since recordings hold none,
+//! and nothing is recorded. A summary of statuses and latencies is printed at the end.
+
+use axum::Router;
+use hyper::{Body, Request};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tower::ServiceExt;
+use tracing::{info, warn};
+
+pub struct Args {
+    files: Vec<String>,
+    speed: f64,
+}
+
+impl Args {
+    /// The replay arguments, if the command line asks for a replay.
+    pub fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Option<Self>> {
+        if args.next().as_deref() != Some("replay") {
+            return Ok(None);
+        }
+        let mut parsed = Args {
+            files: Vec::new(),
+            speed: 1.0,
+        };
+        while let Some(arg) = args.next() {
+            if arg == "--speed" {
+                parsed.speed = args
+                    .next()
+                    .and_then(|s| s.parse::<f64>().ok())
+                    .filter(|s| *s >= 0.0)
+                    .ok_or_else(|| anyhow::anyhow!("--speed needs a factor of 0 or more"))?;
+            } else {
+                parsed.files.push(arg);
+            }
+        }
+        anyhow::ensure!(
+            !parsed.files.is_empty(),
+            "usage: kairos-proxy replay <recording>... [--speed <factor>]"
+        );
+        Ok(Some(parsed))
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Entry {
+    at_ms: i64,
+    endpoint: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    query: Value,
+}
+
+/// The entries of a recording, oldest first, each with the delay it is sent after.
+fn schedule(recording: &str, speed: f64) -> Vec<(Duration, Entry)> {
+    let mut entries: Vec<Entry> = recording
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .filter_map(|l| match serde_json::from_str(l) {
+            Ok(e) => Some(e),
+            Err(e) => {
+                warn!("Skipping unreadable recording line: {}", e);
+                None
+            }
+        })
+        .collect();
+    entries.sort_by_key(|e| e.at_ms);
+    let first = entries.first().map_or(0, |e| e.at_ms);
+    entries
+        .into_iter()
+        .map(|e| {
+            let delay = if speed == 0.0 {
+                Duration::ZERO
+            } else {
+                Duration::from_millis((e.at_ms - first).max(0) as u64).div_f64(speed)
+            };
+            (delay, e)
+        })
+        .collect()
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let i = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[i]
+}
+
+pub async fn run(app: Router, args: Args) -> anyhow::Result<()> {
+    let mut recording = String::new();
+    for file in &args.files {
+        recording.push_str(
+            &std::fs::read_to_string(file)
+                .map_err(|e| anyhow::anyhow!("Cannot read recording {}: {}", file, e))?,
+        );
+        recording.push('\n');
+    }
+    let entries = schedule(&recording, args.speed);
+    info!(
+        "Replaying {} recorded queries at {}x speed",
+        entries.len(),
+        args.speed
+    );
+
+    let started = Instant::now();
+    let mut sent = JoinSet::new();
+    for (delay, entry) in entries {
+        tokio::time::sleep_until((started + delay).into()).await;
+        let mut req = Request::post(&entry.endpoint);
+        for (name, value) in &entry.headers {
+            req = req.header(name, value);
+        }
+        let req = req
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(entry.query.to_string()))?;
+        let app = app.clone();
+        sent.spawn(async move {
+            let sent_at = Instant::now();
+            let response = app.oneshot(req).await.expect("infallible");
+            let status = response.status().as_u16();
+            // Answers are streamed in Simple mode; the latency includes the whole body
+            let _ = hyper::body::to_bytes(response.into_body()).await;
+            (status, sent_at.elapsed())
+        });
+    }
+
+    let mut statuses = BTreeMap::<u16, usize>::new();
+    let mut latencies = Vec::new();
+    while let Some(done) = sent.join_next().await {
+        let (status, latency) = done?;
+        *statuses.entry(status).or_default() += 1;
+        latencies.push(latency);
+    }
+    latencies.sort();
+    println!(
+        "Replayed {} queries in {:.1}s",
+        latencies.len(),
+        started.elapsed().as_secs_f64()
+    );
+    for (status, count) in &statuses {
+        println!("  {}: {}", status, count);
+    }
+    println!(
+        "  latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.9),
+        percentile(&latencies, 0.99),
+        latencies.last().copied().unwrap_or_default()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_recorded_spacing_scaled_by_speed() {
+        let recording = r#"
+{"at_ms":5000,"endpoint":"/api/v1/datapoints/query","headers":{},"query":{"metrics":[]}}
+not json
+{"at_ms":1000,"endpoint":"/api/v1/datapoints/query/tags","query":{"metrics":[]}}
+"#;
+        let delays = |speed| {
+            schedule(recording, speed)
+                .into_iter()
+                .map(|(d, e)| (d, e.endpoint))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            delays(2.0),
+            vec![
+                (Duration::ZERO, "/api/v1/datapoints/query/tags".to_string()),
+                (
+                    Duration::from_secs(2),
+                    "/api/v1/datapoints/query".to_string()
+                ),
+            ]
+        );
+        assert!(delays(0.0).iter().all(|(d, _)| d.is_zero()));
+
+        let args = |a: &[&str]| Args::parse(a.iter().map(|s| s.to_string()));
+        assert!(args(&[]).unwrap().is_none());
+        let parsed = args(&["replay", "a.jsonl", "--speed", "4"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed.files, vec!["a.jsonl"]);
+        assert_eq!(parsed.speed, 4.0);
+        assert!(args(&["replay"]).is_err());
+        assert!(args(&["replay", "a.jsonl", "--speed", "-1"]).is_err());
+    }
+}
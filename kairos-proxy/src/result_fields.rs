@@ -0,0 +1,90 @@
+//! Leaving sections out of query results, for clients that only plot values or only check
+//! which series exist. Sections are omitted by `omit_result_fields` in the config and/or an
+//! `omit=tags,group_by` query parameter on the request.
+
+use serde_json::Value;
+
+/// Result sections to leave out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Omit {
+    pub tags: bool,
+    pub group_by: bool,
+    // Also drops `sample_size`; with tags and group_by omitted too only names remain
+    pub values: bool,
+}
+
+impl Omit {
+    /// Adds the named sections (`tags`, `group_by`, `values`) to this set.
+    pub fn with<'a>(mut self, fields: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        for field in fields.into_iter().map(str::trim).filter(|f| !f.is_empty()) {
+            match field {
+                "tags" => self.tags = true,
+                "group_by" => self.group_by = true,
+                "values" => self.values = true,
+                _ => return Err(format!("unknown result field '{}'", field)),
+            }
+        }
+        Ok(self)
+    }
+
+    /// This set plus the sections named by the `omit` parameter of a request query string.
+    pub fn with_request(self, query: Option<&str>) -> Result<Self, String> {
+        let requested: Vec<String> = form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .filter(|(k, _)| k == "omit")
+            .map(|(_, v)| v.into_owned())
+            .collect();
+        self.with(requested.iter().flat_map(|v| v.split(',')))
+    }
+
+    /// Removes the omitted sections from every result of a KairosDB response.
+    pub fn apply(&self, response: &mut Value) {
+        if *self == Omit::default() {
+            return;
+        }
+        let Some(queries) = response.get_mut("queries").and_then(Value::as_array_mut) else {
+            return;
+        };
+        let results = queries
+            .iter_mut()
+            .filter_map(|q| q.get_mut("results").and_then(Value::as_array_mut))
+            .flatten()
+            .filter_map(Value::as_object_mut);
+        for result in results {
+            if self.tags {
+                result.remove("tags");
+            }
+            if self.group_by {
+                result.remove("group_by");
+            }
+            if self.values {
+                result.remove("values");
+                result.remove("sample_size");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn omits_configured_and_requested_sections() {
+        let configured = Omit::default().with(["group_by"]).unwrap();
+        let omit = configured.with_request(Some("omit=tags,values")).unwrap();
+        let mut response = json!({ "queries": [{ "sample_size": 1, "results": [{
+            "name": "cpu",
+            "tags": { "host": ["a"] },
+            "group_by": [{ "name": "type", "type": "number" }],
+            "values": [[1, 2]]
+        }]}]});
+        omit.apply(&mut response);
+        assert_eq!(
+            response,
+            json!({ "queries": [{ "sample_size": 1, "results": [{ "name": "cpu" }] }] })
+        );
+        assert_eq!(configured.with_request(None), Ok(configured));
+        assert!(Omit::default().with_request(Some("omit=name")).is_err());
+    }
+}
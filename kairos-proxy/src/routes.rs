@@ -0,0 +1,1096 @@
+//! Routing rules.
+//!
+//! A rule sends a metric to a backend when all of its predicates hold: metric name pattern,
+//! tag matchers, request header matchers and (via its time tier) the queried time range.
+//! Rules come from `[[routes]]` when present, and backend patterns beside them are warned
+//! about; otherwise every backend's own `pattern` is a rule. Rules are tried by descending `priority`, then in file order, or, with
+//! `route_selection = "most_specific"`, most specific first. A `[hash_ring]` adds one more
+//! rule, matching every metric, whose backend is picked by consistent hashing of the name.
+
+use crate::config::{Backend, Config, HashRingConfig, MergeStrategy, RouteConfig, RouteSelection};
+use crate::hash_ring::HashRing;
+use crate::state::BackendTarget;
+use crate::timerange::{parse_duration_ms, TimeTier};
+use hyper::header::HeaderName;
+use hyper::HeaderMap;
+use regex::{Regex, RegexBuilder};
+use std::collections::BTreeMap;
+use tracing::{info, warn};
+
+/// What a routing decision is made about: one metric of a query or one ingested datapoint.
+#[derive(Clone, Copy)]
+pub struct Subject<'a> {
+    pub metric: &'a str,
+    /// Tag object of a query metric (`{"dc": ["eu-1"]}`) or a datapoint (`{"dc": "eu-1"}`).
+    pub tags: Option<&'a serde_json::Value>,
+    pub headers: Option<&'a HeaderMap>,
+}
+
+impl<'a> Subject<'a> {
+    #[cfg(test)]
+    pub fn metric(metric: &'a str) -> Self {
+        Subject {
+            metric,
+            tags: None,
+            headers: None,
+        }
+    }
+}
+
+pub struct Route {
+    /// 1-based position in the config, for messages.
+    pub position: usize,
+    pub priority: i32,
+    pub backend: usize,
+    pub metric: Option<Regex>,
+    tags: Vec<(String, Regex)>,
+    headers: Vec<(HeaderName, Regex)>,
+    /// Ages of data the rule applies to; `None` means all of them.
+    pub tier: Option<TimeTier>,
+    /// Backend ingested datapoints are written to instead of `backend`.
+    pub write_backend: Option<usize>,
+    /// Additional backend ingested datapoints are written to.
+    pub secondary: Option<Secondary>,
+    /// Ring the backend is picked from per metric, overriding `backend` (then its first node).
+    pub ring: Option<HashRing>,
+    /// How results for the rule's metrics from several backends are merged.
+    pub merge: MergeStrategy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Secondary {
+    pub backend: usize,
+    /// Whether the write fails when the secondary rejects it.
+    pub required: bool,
+}
+
+impl Route {
+    /// Backend `metric` is sent to when this rule applies.
+    pub fn backend_for(&self, metric: &str) -> usize {
+        self.ring.as_ref().map_or(self.backend, |r| r.pick(metric))
+    }
+
+    /// Backend datapoints of `metric` are written to when this rule applies.
+    pub fn write_backend_for(&self, metric: &str) -> usize {
+        self.write_backend
+            .unwrap_or_else(|| self.backend_for(metric))
+    }
+
+    /// Whether the rule can be decided from the metric name and headers alone.
+    pub fn by_name_only(&self) -> bool {
+        self.tags.is_empty() && self.tier.is_none()
+    }
+
+    pub fn matches_metric(&self, metric: &str) -> bool {
+        self.metric.as_ref().is_none_or(|re| re.is_match(metric))
+    }
+
+    /// Whether every predicate except the time tier holds for `subject`. A tag matcher holds
+    /// when the subject filters on that tag and every value matches; a header matcher when
+    /// the header is present and matches.
+    pub fn matches(&self, subject: &Subject) -> bool {
+        if !self.matches_metric(subject.metric) {
+            return false;
+        }
+        let tags_match =
+            self.tags
+                .iter()
+                .all(|(name, re)| match subject.tags.and_then(|t| t.get(name)) {
+                    Some(serde_json::Value::String(v)) => re.is_match(v),
+                    Some(serde_json::Value::Array(values)) => {
+                        !values.is_empty()
+                            && values
+                                .iter()
+                                .all(|v| v.as_str().is_some_and(|v| re.is_match(v)))
+                    }
+                    _ => false,
+                });
+        tags_match
+            && self.headers.iter().all(|(name, re)| {
+                subject
+                    .headers
+                    .and_then(|h| h.get(name))
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| re.is_match(v))
+            })
+    }
+
+    pub fn header_names(&self) -> impl Iterator<Item = &HeaderName> {
+        self.headers.iter().map(|(name, _)| name)
+    }
+
+    /// How narrowly the rule matches: literal characters of the metric pattern, then the
+    /// number of other predicates.
+    fn specificity(&self) -> (usize, usize) {
+        let literal = self.metric.as_ref().map_or(0, |m| literal_len(m.as_str()));
+        let predicates = self.tags.len() + self.headers.len() + self.tier.is_some() as usize;
+        (literal, predicates)
+    }
+
+    /// Whether this rule, tried first, takes every request `later` could match. Catch-all and
+    /// identical metric patterns are recognised, as are literal prefixes such as `^cpu\.`
+    /// covering `^cpu\.load`; anything subtler goes unreported.
+    fn shadows(&self, later: &Route) -> bool {
+        if !self.tags.is_empty() || !self.headers.is_empty() || self.tier.is_some() {
+            return false;
+        }
+        match (&self.metric, &later.metric) {
+            (None, _) => true,
+            (Some(m), _)
+                if matches!(m.as_str(), "" | ".*" | "^.*" | "^.*$" | "^(?:.*)$" | ".+") =>
+            {
+                true
+            }
+            (Some(m), Some(l)) => m.as_str() == l.as_str() || covers(m.as_str(), l.as_str()),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// The literal text at the start of a metric pattern, which every name it matches contains.
+struct Literal {
+    text: String,
+    // Whether the text starts the name rather than appearing anywhere in it
+    anchored: bool,
+    // Whether the pattern is the text followed by anything at all
+    open: bool,
+    case_insensitive: bool,
+}
+
+impl Literal {
+    /// Reads the literal start of `pattern`; `None` for top-level alternations, whose branches
+    /// need not share one.
+    fn of(pattern: &str) -> Option<Self> {
+        let (case_insensitive, pattern) = match pattern.strip_prefix("(?i)") {
+            Some(p) => (true, p),
+            None => (false, pattern),
+        };
+        // `anchored = true` wraps patterns in `^(?:...)$`
+        let unwrapped = pattern
+            .strip_prefix("^(?:")
+            .and_then(|p| p.strip_suffix(")$"))
+            .map(|p| format!("^{}$", p.strip_prefix('^').unwrap_or(p)));
+        let pattern = unwrapped.as_deref().unwrap_or(pattern);
+        let (mut escaped, mut in_class, mut depth) = (false, false, 0);
+        for c in pattern.chars() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                ']' if in_class => in_class = false,
+                _ if in_class => {}
+                '[' => in_class = true,
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                '|' if depth == 0 => return None,
+                _ => {}
+            }
+        }
+        let (anchored, pattern) = match pattern.strip_prefix('^') {
+            Some(p) => (true, p),
+            None => (false, pattern),
+        };
+        let mut text = String::new();
+        let mut chars = pattern.char_indices().peekable();
+        let mut rest = "";
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.peek() {
+                    Some(&(_, e)) if !e.is_ascii_alphanumeric() => {
+                        text.push(e);
+                        chars.next();
+                    }
+                    _ => {
+                        rest = &pattern[i..];
+                        break;
+                    }
+                },
+                '*' | '+' | '?' | '{' => {
+                    // The quantifier makes the character before it optional or repeated
+                    text.pop();
+                    rest = &pattern[i..];
+                    break;
+                }
+                '.' | '(' | ')' | '[' | ']' | '$' => {
+                    rest = &pattern[i..];
+                    break;
+                }
+                _ => text.push(c),
+            }
+        }
+        Some(Literal {
+            text,
+            anchored,
+            open: matches!(rest, "" | ".*" | ".*$"),
+            case_insensitive,
+        })
+    }
+}
+
+/// Whether `earlier` matches every name `later` does, judging by their literal starts.
+fn covers(earlier: &str, later: &str) -> bool {
+    let (Some(e), Some(l)) = (Literal::of(earlier), Literal::of(later)) else {
+        return false;
+    };
+    if !e.open || (l.case_insensitive && !e.case_insensitive) {
+        return false;
+    }
+    let (et, lt) = if e.case_insensitive {
+        (e.text.to_lowercase(), l.text.to_lowercase())
+    } else {
+        (e.text, l.text)
+    };
+    if e.anchored {
+        l.anchored && lt.starts_with(&et)
+    } else {
+        lt.contains(&et)
+    }
+}
+
+/// Counts the characters of a regex that match literally (escaped punctuation included).
+fn literal_len(pattern: &str) -> usize {
+    let mut n = 0;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // Group flags such as `(?i)` or `(?:`
+            '(' if chars.next_if_eq(&'?').is_some() => {
+                while chars.next_if(|&c| c != ':' && c != ')').is_some() {}
+                chars.next_if_eq(&':');
+            }
+            '\\' => {
+                // `\.` is a literal dot, `\d` a class
+                if chars.next().is_some_and(|e| !e.is_ascii_alphanumeric()) {
+                    n += 1;
+                }
+            }
+            '.' | '*' | '+' | '?' | '^' | '$' | '(' | ')' | '[' | ']' | '{' | '}' | '|' => {}
+            _ => n += 1,
+        }
+    }
+    n
+}
+
+/// Orders rules for matching.
+fn order(mut routes: Vec<Route>, selection: &RouteSelection) -> Vec<Route> {
+    match selection {
+        RouteSelection::First => routes.sort_by_key(|r| std::cmp::Reverse(r.priority)),
+        RouteSelection::MostSpecific => {
+            routes.sort_by_key(|r| std::cmp::Reverse((r.priority, r.specificity())))
+        }
+    }
+    routes
+}
+
+fn shadowed(routes: &[Route], backends: &[BackendTarget]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for (i, later) in routes.iter().enumerate() {
+        if let Some(earlier) = routes[..i].iter().find(|e| e.shadows(later)) {
+            warnings.push(format!(
+                "Routing rule #{} (backend '{}') is never used: rule #{} (backend '{}') matches first",
+                later.position,
+                backends[later.backend].name,
+                earlier.position,
+                backends[earlier.backend].name
+            ));
+        }
+    }
+    warnings
+}
+
+/// Backend routing settings that `[[routes]]` take the place of, and so go unused.
+fn ignored_by_rules(cfg: &Config, backends: &[BackendTarget]) -> Vec<String> {
+    if cfg.routes.is_empty() {
+        return Vec::new();
+    }
+    cfg.backends
+        .iter()
+        .zip(backends)
+        .filter_map(|(b, target)| {
+            let set: Vec<_> = [
+                ("pattern", !b.pattern.is_empty()),
+                ("glob", b.glob.is_some()),
+                ("priority", b.priority.is_some()),
+                ("write_backend", b.write_backend.is_some()),
+            ]
+            .into_iter()
+            .filter_map(|(name, set)| set.then_some(name))
+            .collect();
+            (!set.is_empty()).then(|| {
+                format!(
+                    "Backend '{}' sets {}, which [[routes]] replace; set them on its rules instead",
+                    target.name,
+                    set.join(", ")
+                )
+            })
+        })
+        .collect()
+}
+
+/// Translates a shell-style glob into an anchored regex: `*` matches any run of characters,
+/// `?` one character, `[abc]` / `[!abc]` a set and `{a,b}` alternatives. Everything else,
+/// including `.`, is literal.
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+    let mut braces = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                re.push('[');
+                if chars.next_if_eq(&'!').is_some() {
+                    re.push('^');
+                }
+                for c in chars.by_ref() {
+                    match c {
+                        ']' => break,
+                        '\\' | '[' | '&' | '~' => {
+                            re.push('\\');
+                            re.push(c);
+                        }
+                        _ => re.push(c),
+                    }
+                }
+                re.push(']');
+            }
+            '{' => {
+                braces += 1;
+                re.push_str("(?:");
+            }
+            '}' if braces > 0 => {
+                braces -= 1;
+                re.push(')');
+            }
+            ',' if braces > 0 => re.push('|'),
+            _ => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push('$');
+    re
+}
+
+// Default bounds on the compiled size and match-time DFA cache of each configured regex
+const DEFAULT_MAX_REGEX_SIZE_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_REGEX_DFA_BYTES: usize = 2 * 1024 * 1024;
+
+/// Bounds on the regexes compiled from the config, so one pathological pattern cannot take
+/// the proxy's memory or CPU.
+#[derive(Clone, Copy, Debug)]
+pub struct RegexLimits {
+    size: usize,
+    dfa: usize,
+}
+
+impl Default for RegexLimits {
+    fn default() -> Self {
+        RegexLimits {
+            size: DEFAULT_MAX_REGEX_SIZE_BYTES,
+            dfa: DEFAULT_MAX_REGEX_DFA_BYTES,
+        }
+    }
+}
+
+impl RegexLimits {
+    pub fn of(cfg: &Config) -> Self {
+        RegexLimits {
+            size: cfg
+                .max_regex_size_bytes
+                .unwrap_or(DEFAULT_MAX_REGEX_SIZE_BYTES),
+            dfa: cfg
+                .max_regex_dfa_bytes
+                .unwrap_or(DEFAULT_MAX_REGEX_DFA_BYTES),
+        }
+    }
+
+    /// Compiles `pattern` within the limits, or says what is wrong with it.
+    pub fn build(&self, pattern: &str) -> Result<Regex, String> {
+        RegexBuilder::new(pattern)
+            .size_limit(self.size)
+            .dfa_size_limit(self.dfa)
+            .build()
+            .map_err(|e| match e {
+                regex::Error::CompiledTooBig(limit) => format!(
+                    "pattern is too complex: it compiles to more than {} bytes \
+                     (simplify it, or raise max_regex_size_bytes if it is intended)",
+                    limit
+                ),
+                e => e.to_string(),
+            })
+    }
+}
+
+/// Compiles the metric predicate of a rule or backend from its regex or glob form, applying
+/// the `case_insensitive` and `anchored` options of the backend it routes to.
+fn metric_pattern(
+    regex: Option<&str>,
+    glob: Option<&str>,
+    backend: &Backend,
+    owner: &str,
+    limits: &RegexLimits,
+) -> anyhow::Result<Option<Regex>> {
+    let mut pattern = match (regex, glob) {
+        (Some(_), Some(_)) => {
+            anyhow::bail!("'{}' sets both a regex pattern and a glob; use one", owner)
+        }
+        (Some(regex), None) if backend.anchored.unwrap_or(false) => format!("^(?:{})$", regex),
+        (Some(regex), None) => regex.to_string(),
+        (None, Some(glob)) => glob_to_regex(glob),
+        (None, None) => return Ok(None),
+    };
+    if backend.case_insensitive.unwrap_or(false) {
+        pattern.insert_str(0, "(?i)");
+    }
+    limits
+        .build(&pattern)
+        .map(Some)
+        .map_err(|e| anyhow::anyhow!("Invalid metric pattern for '{}': {}", owner, e))
+}
+
+/// Parses the optional `newer_than` / `older_than` ages of a backend or rule.
+pub(crate) fn parse_tier(
+    newer_than: &Option<String>,
+    older_than: &Option<String>,
+    owner: &str,
+) -> anyhow::Result<Option<TimeTier>> {
+    let age = |v: &Option<String>| {
+        v.as_deref()
+            .map(parse_duration_ms)
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("Invalid time tier for '{}': {}", owner, e))
+    };
+    let tier = TimeTier {
+        newer_than_ms: age(newer_than)?,
+        older_than_ms: age(older_than)?,
+    };
+    Ok((tier.newer_than_ms.is_some() || tier.older_than_ms.is_some()).then_some(tier))
+}
+
+fn compile_matchers(
+    matchers: &Option<BTreeMap<String, String>>,
+    kind: &str,
+    limits: &RegexLimits,
+) -> anyhow::Result<Vec<(String, Regex)>> {
+    matchers
+        .iter()
+        .flatten()
+        .map(|(name, pattern)| {
+            let re = limits
+                .build(pattern)
+                .map_err(|e| anyhow::anyhow!("Invalid {} matcher for '{}': {}", kind, name, e))?;
+            Ok((name.clone(), re))
+        })
+        .collect()
+}
+
+fn backend_named(backends: &[BackendTarget], name: &str) -> anyhow::Result<usize> {
+    let mut named = backends
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| b.name == name)
+        .map(|(i, _)| i);
+    let backend = named
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Route references unknown backend '{}'", name))?;
+    if named.next().is_some() {
+        anyhow::bail!("Route references ambiguous backend name '{}'", name);
+    }
+    Ok(backend)
+}
+
+fn compile_rule(
+    position: usize,
+    r: &RouteConfig,
+    configs: &[Backend],
+    backends: &[BackendTarget],
+    tiers: &[Option<TimeTier>],
+    limits: &RegexLimits,
+) -> anyhow::Result<Route> {
+    let backend = backend_named(backends, &r.backend)?;
+    let write_backend = r
+        .write_backend
+        .as_ref()
+        .map(|name| backend_named(backends, name))
+        .transpose()?;
+    let secondary = match &r.secondary_backend {
+        Some(name) => {
+            let secondary = backend_named(backends, name)?;
+            if secondary == write_backend.unwrap_or(backend) {
+                anyhow::bail!("Route to '{}' names it as its own secondary backend", name);
+            }
+            Some(Secondary {
+                backend: secondary,
+                required: r.secondary_required.unwrap_or(false),
+            })
+        }
+        None => None,
+    };
+    let metric = metric_pattern(
+        r.metric.as_deref(),
+        r.glob.as_deref(),
+        &configs[backend],
+        &r.backend,
+        limits,
+    )?;
+    let headers = compile_matchers(&r.headers, "header", limits)?
+        .into_iter()
+        .map(|(name, re)| {
+            let name = HeaderName::try_from(name.as_str())
+                .map_err(|e| anyhow::anyhow!("Invalid header name '{}': {}", name, e))?;
+            Ok((name, re))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    // A rule without its own tier inherits its backend's
+    let tier = parse_tier(&r.newer_than, &r.older_than, &r.backend)?.or(tiers[backend]);
+    Ok(Route {
+        position,
+        priority: r.priority.unwrap_or_default(),
+        backend,
+        metric,
+        tags: compile_matchers(&r.tags, "tag", limits)?,
+        headers,
+        tier,
+        write_backend,
+        secondary,
+        ring: None,
+        merge: r
+            .merge
+            .as_ref()
+            .and_then(|m| m.strategy)
+            .unwrap_or_default(),
+    })
+}
+
+/// Compiles the `[hash_ring]` into a rule matching every metric.
+fn compile_ring(
+    position: usize,
+    ring: &HashRingConfig,
+    backends: &[BackendTarget],
+) -> anyhow::Result<Route> {
+    let nodes = ring
+        .backends
+        .iter()
+        .map(|name| backend_named(backends, name))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let Some(&first) = nodes.first() else {
+        anyhow::bail!("[hash_ring] needs at least one backend");
+    };
+    let virtual_nodes = ring.virtual_nodes.unwrap_or(160);
+    info!(
+        "Sharding metrics across {} backend(s) by consistent hash ({} virtual nodes each)",
+        nodes.len(),
+        virtual_nodes
+    );
+    Ok(Route {
+        position,
+        priority: ring.priority.unwrap_or_default(),
+        backend: first,
+        metric: None,
+        tags: Vec::new(),
+        headers: Vec::new(),
+        tier: None,
+        write_backend: None,
+        secondary: None,
+        ring: Some(HashRing::new(
+            nodes.iter().map(|&i| (i, backends[i].name.as_str())),
+            virtual_nodes,
+        )),
+        merge: MergeStrategy::Union,
+    })
+}
+
+/// Compiles `block_patterns`, the metric names refused before any routing.
+pub fn compile_blocked(cfg: &Config) -> anyhow::Result<Vec<Regex>> {
+    cfg.block_patterns
+        .iter()
+        .flatten()
+        .map(|p| {
+            RegexLimits::of(cfg)
+                .build(p)
+                .map_err(|e| anyhow::anyhow!("Invalid block pattern '{}': {}", p, e))
+        })
+        .collect()
+}
+
+/// Compiles the routing table. `tiers` holds each backend's own time tier, in backend order.
+pub fn compile(
+    cfg: &Config,
+    backends: &[BackendTarget],
+    tiers: &[Option<TimeTier>],
+) -> anyhow::Result<Vec<Route>> {
+    let selection = cfg.route_selection.clone().unwrap_or_default();
+    let limits = RegexLimits::of(cfg);
+    let mut routes = Vec::new();
+    if cfg.routes.is_empty() {
+        for (i, b) in cfg.backends.iter().enumerate() {
+            let pattern = (!b.pattern.is_empty()).then_some(b.pattern.as_str());
+            let name = &backends[i].name;
+            let metric = metric_pattern(pattern, b.glob.as_deref(), b, name, &limits)?;
+            if metric.is_none() {
+                // Ring members and write backends need no pattern of their own
+                let in_ring = cfg
+                    .hash_ring
+                    .as_ref()
+                    .is_some_and(|r| r.backends.contains(&backends[i].name));
+                let writes_for = cfg
+                    .backends
+                    .iter()
+                    .any(|o| o.write_backend.as_ref() == Some(&backends[i].name));
+                if in_ring || writes_for {
+                    continue;
+                }
+                anyhow::bail!(
+                    "Backend '{}' needs a pattern or glob when no [[routes]] are configured",
+                    backends[i].name
+                );
+            }
+            routes.push(Route {
+                position: i + 1,
+                priority: b.priority.unwrap_or_default(),
+                backend: i,
+                metric,
+                tags: Vec::new(),
+                headers: Vec::new(),
+                tier: tiers[i],
+                write_backend: b
+                    .write_backend
+                    .as_ref()
+                    .map(|name| backend_named(backends, name))
+                    .transpose()?,
+                secondary: None,
+                ring: None,
+                merge: MergeStrategy::Union,
+            });
+        }
+    } else {
+        for (i, r) in cfg.routes.iter().enumerate() {
+            routes.push(compile_rule(
+                i + 1,
+                r,
+                &cfg.backends,
+                backends,
+                tiers,
+                &limits,
+            )?);
+        }
+        info!("Loaded {} routing rule(s)", routes.len());
+    }
+    if let Some(ring) = &cfg.hash_ring {
+        routes.push(compile_ring(routes.len() + 1, ring, backends)?);
+    }
+    let routes = order(routes, &selection);
+    let mut warnings = ignored_by_rules(cfg, backends);
+    warnings.extend(shadowed(&routes, backends));
+    if cfg.strict_routing.unwrap_or(false) && !warnings.is_empty() {
+        anyhow::bail!("{} (strict_routing is set)", warnings.join("; "));
+    }
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+    Ok(routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rule(cfg: &str) -> Route {
+        let r: RouteConfig = toml::from_str(cfg).expect("rule toml");
+        Route {
+            position: 1,
+            priority: 0,
+            backend: 0,
+            metric: r.metric.as_deref().map(|m| Regex::new(m).unwrap()),
+            tags: compile_matchers(&r.tags, "tag", &RegexLimits::default()).unwrap(),
+            headers: compile_matchers(&r.headers, "header", &RegexLimits::default())
+                .unwrap()
+                .into_iter()
+                .map(|(n, re)| (HeaderName::try_from(n.as_str()).unwrap(), re))
+                .collect(),
+            tier: None,
+            write_backend: None,
+            secondary: None,
+            ring: None,
+            merge: MergeStrategy::Union,
+        }
+    }
+
+    #[test]
+    fn combines_metric_tag_and_header_predicates() {
+        let r = rule(
+            r#"
+            backend = "eu"
+            metric = "^cpu\\."
+            tags = { dc = "^eu-" }
+            headers = { "X-Tenant" = "^acme$" }
+            "#,
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", "acme".parse().unwrap());
+        let query_tags = json!({ "dc": ["eu-1", "eu-2"] });
+        let subject = Subject {
+            metric: "cpu.load",
+            tags: Some(&query_tags),
+            headers: Some(&headers),
+        };
+        assert!(r.matches(&subject));
+
+        let mixed = json!({ "dc": ["eu-1", "us-1"] });
+        assert!(!r.matches(&Subject {
+            tags: Some(&mixed),
+            ..subject
+        }));
+        let datapoint_tags = json!({ "dc": "eu-3" });
+        assert!(r.matches(&Subject {
+            tags: Some(&datapoint_tags),
+            ..subject
+        }));
+        assert!(!r.matches(&Subject {
+            headers: None,
+            ..subject
+        }));
+        assert!(!r.matches(&Subject::metric("cpu.load")));
+    }
+
+    #[test]
+    fn routes_reference_named_backends_in_order() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [[backends]]
+            name = "acme"
+            url = "http://acme:8080"
+
+            [[backends]]
+            name = "shared"
+            url = "http://shared:8080"
+
+            [[routes]]
+            backend = "acme"
+            headers = { "X-Tenant" = "^acme$" }
+
+            [[routes]]
+            backend = "shared"
+            metric = ".*"
+            "#,
+        )
+        .expect("config");
+        let state = crate::state::AppState::from_config(&cfg).expect("state");
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", "acme".parse().unwrap());
+        let subject = Subject {
+            metric: "cpu.load",
+            tags: None,
+            headers: Some(&headers),
+        };
+        let now = crate::timerange::now_ms();
+        assert_eq!(state.select(&subject, now).unwrap().1.name, "acme");
+        assert_eq!(state.backend_for("cpu.load").unwrap().1.name, "shared");
+        assert_eq!(state.routing_key(&headers), "x-tenant=acme ");
+
+        let bad: Config = toml::from_str(
+            r#"
+            [[backends]]
+            name = "a"
+            url = "http://a:8080"
+
+            [[routes]]
+            backend = "missing"
+            "#,
+        )
+        .expect("config");
+        let err = crate::state::AppState::from_config(&bad)
+            .err()
+            .expect("error");
+        assert!(err.to_string().contains("unknown backend 'missing'"));
+    }
+
+    #[test]
+    fn backend_patterns_beside_rules_are_flagged() {
+        let cfg = |strict: bool| -> Config {
+            toml::from_str(&format!(
+                r#"
+                strict_routing = {}
+
+                [[backends]]
+                name = "cpu"
+                pattern = "^cpu\\."
+                priority = 5
+                url = "http://cpu:8080"
+
+                [[backends]]
+                name = "rest"
+                url = "http://rest:8080"
+
+                [[routes]]
+                backend = "rest"
+                metric = ".*"
+                "#,
+                strict
+            ))
+            .expect("config")
+        };
+        let lenient = crate::state::AppState::from_config(&cfg(false)).expect("state");
+        // Routed by the rules alone
+        assert_eq!(lenient.backend_for("cpu.load").unwrap().1.name, "rest");
+        let warnings = ignored_by_rules(&cfg(false), &lenient.backends);
+        assert_eq!(
+            warnings,
+            ["Backend 'cpu' sets pattern, priority, which [[routes]] replace; set them on its rules instead"]
+        );
+
+        let err = crate::state::AppState::from_config(&cfg(true))
+            .err()
+            .expect("strict");
+        assert!(
+            err.to_string().contains("Backend 'cpu' sets pattern"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn writes_go_to_the_write_backend_and_queries_to_the_replica() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [[backends]]
+            name = "cpu-replica"
+            pattern = "^cpu\\."
+            url = "http://cpu-replica:8080"
+            write_backend = "cpu-master"
+
+            [[backends]]
+            name = "cpu-master"
+            url = "http://cpu-master:8080"
+
+            [[backends]]
+            name = "rest"
+            pattern = ".*"
+            url = "http://rest:8080"
+            "#,
+        )
+        .expect("config");
+        let state = crate::state::AppState::from_config(&cfg).expect("state");
+        let now = crate::timerange::now_ms();
+        let subject = Subject::metric("cpu.load");
+        assert_eq!(state.select(&subject, now).unwrap().1.name, "cpu-replica");
+        let (write, _) = state.select_write(&subject, now).unwrap();
+        assert_eq!(state.backends[write].name, "cpu-master");
+        // The write backend has no pattern and never serves queries
+        let (write, _) = state
+            .select_write(&Subject::metric("mem.free"), now)
+            .unwrap();
+        assert_eq!(state.backends[write].name, "rest");
+    }
+
+    #[test]
+    fn hash_ring_shards_what_backend_patterns_leave() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [hash_ring]
+            backends = ["shard-a", "shard-b"]
+
+            [[backends]]
+            name = "special"
+            pattern = "^special\\."
+            url = "http://special:8080"
+
+            [[backends]]
+            name = "shard-a"
+            url = "http://a:8080"
+
+            [[backends]]
+            name = "shard-b"
+            url = "http://b:8080"
+            "#,
+        )
+        .expect("config");
+        let state = crate::state::AppState::from_config(&cfg).expect("state");
+        assert_eq!(state.backend_for("special.x").unwrap().1.name, "special");
+        let mut shards = std::collections::BTreeSet::new();
+        for n in 0..50 {
+            let metric = format!("app.{}", n);
+            let (i, _) = state.backend_for(&metric).unwrap();
+            assert_eq!(state.backend_for(&metric).unwrap().0, i);
+            shards.insert(state.backends[i].name.clone());
+        }
+        assert_eq!(shards, ["shard-a", "shard-b"].map(String::from).into());
+    }
+
+    #[test]
+    fn orders_by_priority_then_specificity_and_reports_shadowed_rules() {
+        let cfg = |selection: &str| -> Config {
+            toml::from_str(&format!(
+                r#"
+                route_selection = "{}"
+
+                [[backends]]
+                name = "all"
+                url = "http://all:8080"
+
+                [[backends]]
+                name = "cpu"
+                url = "http://cpu:8080"
+
+                [[backends]]
+                name = "cpu-load"
+                url = "http://load:8080"
+
+                [[routes]]
+                backend = "all"
+                metric = ".*"
+
+                [[routes]]
+                backend = "cpu"
+                metric = "^cpu\\."
+
+                [[routes]]
+                backend = "cpu-load"
+                metric = "^cpu\\.load"
+                priority = 10
+                "#,
+                selection
+            ))
+            .expect("config")
+        };
+        let first = crate::state::AppState::from_config(&cfg("first")).expect("state");
+        assert_eq!(first.backend_for("cpu.load").unwrap().1.name, "cpu-load");
+        assert_eq!(first.backend_for("cpu.idle").unwrap().1.name, "all");
+        let specific = crate::state::AppState::from_config(&cfg("most_specific")).expect("state");
+        assert_eq!(specific.backend_for("cpu.idle").unwrap().1.name, "cpu");
+        assert_eq!(specific.backend_for("mem.free").unwrap().1.name, "all");
+
+        assert_eq!(literal_len("^cpu\\.load$"), 8);
+        let warnings = shadowed(&first.routing().routes, &first.backends);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("#2"));
+    }
+
+    #[test]
+    fn broader_literal_prefixes_shadow_narrower_rules() {
+        assert!(covers("^cpu\\.", "^cpu\\.load"));
+        assert!(covers("^cpu\\..*", "^cpu\\.load\\.(avg|max)$"));
+        assert!(covers("cpu", "^host\\.cpu\\.load"));
+        assert!(covers("(?i)^CPU", "^cpu\\.load"));
+        assert!(covers("^cpu\\..*$", "^(?:cpu\\.load)$"));
+        // The `s` is optional, so `^cpu` is all `^cpus?` guarantees
+        assert!(!covers("^cpus", "^cpus?\\.load"));
+        assert!(!covers("^cpu\\.$", "^cpu\\.load"));
+        assert!(!covers("^cpu\\.", "cpu\\.load"));
+        assert!(!covers("^cpu\\.", "(?i)^cpu\\.load"));
+        assert!(!covers("^cpu|^mem", "^cpu\\.load"));
+        assert!(!covers("^(?:cpu|mem)$", "^cpu$"));
+        assert!(!covers("^cpu\\d", "^cpu1"));
+
+        let cfg = |strict: bool| -> Config {
+            toml::from_str(&format!(
+                r#"
+                strict_routing = {}
+
+                [[backends]]
+                name = "cpu"
+                url = "http://cpu:8080"
+                pattern = "^cpu\\."
+
+                [[backends]]
+                name = "cpu-load"
+                url = "http://load:8080"
+                pattern = "^cpu\\.load"
+                "#,
+                strict
+            ))
+            .expect("config")
+        };
+        let lenient = crate::state::AppState::from_config(&cfg(false)).expect("state");
+        let warnings = shadowed(&lenient.routing().routes, &lenient.backends);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("'cpu-load'"));
+        let strict = crate::state::AppState::from_config(&cfg(true));
+        assert!(strict.is_err_and(|e| e.to_string().contains("strict_routing")));
+    }
+
+    #[test]
+    fn refuses_patterns_beyond_the_regex_limits() {
+        let cfg = |pattern: &str| -> Config {
+            toml::from_str(&format!(
+                r#"
+                max_regex_size_bytes = 100000
+
+                [[backends]]
+                url = "http://cpu:8080"
+                pattern = '{}'
+                "#,
+                pattern
+            ))
+            .expect("config")
+        };
+        assert!(crate::state::AppState::from_config(&cfg("^cpu\\.\\w+")).is_ok());
+        let err = crate::state::AppState::from_config(&cfg("^cpu\\.\\w{100}"))
+            .err()
+            .expect("too complex");
+        assert!(err.to_string().contains("too complex"), "{}", err);
+        assert!(err.to_string().contains("max_regex_size_bytes"), "{}", err);
+    }
+
+    #[test]
+    fn globs_match_whole_names_literally() {
+        let re = |glob: &str| Regex::new(&glob_to_regex(glob)).unwrap();
+        let cpu = re("cpu.*");
+        assert!(cpu.is_match("cpu.load"));
+        assert!(!cpu.is_match("cpu_total"));
+        assert!(!cpu.is_match("host.cpu.load"));
+        let alt = re("{cpu,mem}.?sage");
+        assert!(alt.is_match("mem.usage"));
+        assert!(!alt.is_match("disk.usage"));
+        let set = re("disk[!0-9].*");
+        assert!(set.is_match("diska.free"));
+        assert!(!set.is_match("disk0.free"));
+
+        let backend = Backend::default();
+        assert!(metric_pattern(
+            Some("^cpu"),
+            Some("cpu.*"),
+            &backend,
+            "b",
+            &RegexLimits::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn backend_flags_control_case_and_anchoring() {
+        let backend = Backend {
+            case_insensitive: Some(true),
+            anchored: Some(true),
+            ..Default::default()
+        };
+        let exact = metric_pattern(
+            Some("cpu\\.load"),
+            None,
+            &backend,
+            "b",
+            &RegexLimits::default(),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(exact.is_match("CPU.Load"));
+        assert!(!exact.is_match("host.cpu.load"));
+        assert_eq!(literal_len(exact.as_str()), 8);
+        let glob = metric_pattern(None, Some("cpu.*"), &backend, "b", &RegexLimits::default())
+            .unwrap()
+            .unwrap();
+        assert!(glob.is_match("Cpu.idle"));
+
+        let contains = metric_pattern(
+            Some("cpu"),
+            None,
+            &Backend::default(),
+            "b",
+            &RegexLimits::default(),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(contains.is_match("host.cpu.load"));
+        assert!(!contains.is_match("CPU"));
+    }
+}
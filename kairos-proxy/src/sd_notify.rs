@@ -0,0 +1,96 @@
+//! systemd service notifications (`sd_notify`).
+//!
+//! Under systemd with `Type=notify`, `$NOTIFY_SOCKET` names a datagram socket the service
+//! reports to: `READY=1` once the listener is bound and `STOPPING=1` on shutdown. With
+//! `WatchdogSec=` set, systemd also passes `$WATCHDOG_USEC` and restarts the service when no
+//! `WATCHDOG=1` arrives in time; heartbeats are sent from the async runtime at half that
+//! interval, so a wedged runtime stops them. Outside systemd all of this does nothing.
+
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Sends `message` to `$NOTIFY_SOCKET`. Returns false if there is no socket or sending failed.
+pub fn notify(message: &str) -> bool {
+    #[cfg(unix)]
+    if let Ok(socket) = std::env::var("NOTIFY_SOCKET") {
+        return match send(&socket, message) {
+            Ok(()) => {
+                debug!("Notified systemd: {}", message);
+                true
+            }
+            Err(e) => {
+                warn!("Cannot notify systemd at {}: {}", socket, e);
+                false
+            }
+        };
+    }
+    let _ = message;
+    false
+}
+
+#[cfg(unix)]
+fn send(socket: &str, message: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+    let sender = UnixDatagram::unbound()?;
+    // A leading '@' names a socket in the abstract namespace
+    #[cfg(target_os = "linux")]
+    if let Some(name) = socket.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        sender.send_to_addr(message.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    sender.send_to(message.as_bytes(), socket)?;
+    Ok(())
+}
+
+/// The watchdog timeout systemd expects heartbeats within, if it set one for this process.
+fn watchdog_timeout() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // Set for a parent whose environment this process inherited, not for us
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Sends `READY=1` and starts the watchdog heartbeat, if systemd asked for one.
+pub fn ready() {
+    if !notify("READY=1") {
+        return;
+    }
+    let Some(timeout) = watchdog_timeout() else {
+        return;
+    };
+    info!(
+        "Sending systemd watchdog heartbeats every {:?}",
+        timeout / 2
+    );
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(timeout / 2);
+        loop {
+            ticker.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn sends_datagrams_to_the_notify_socket() {
+        let path = std::env::temp_dir().join(format!("kairos-proxy-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).expect("bind");
+        send(path.to_str().unwrap(), "READY=1").expect("send");
+        let mut buf = [0u8; 64];
+        let n = receiver.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..n], b"READY=1");
+        let _ = std::fs::remove_file(&path);
+    }
+}
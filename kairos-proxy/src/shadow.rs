@@ -0,0 +1,163 @@
+//! Shadow comparison of a backend's query answers with its mirror's, for validating a
+//! migration.
+//!
+//! With `mirror_compare = true`, Multi-mode queries mirrored to `mirror_url` are compared
+//! with the backend's answer once both arrived. The client always gets the backend's answer
+//! and the comparison runs in the background. Each series (metric name, group and tags) is
+//! summarised by its number of values and a checksum of them. Outcomes are counted in
+//! `kairos_proxy_shadow_comparisons_total{backend,outcome}` (`match`, `mismatch`, or `error`
+//! when the mirror gave no answer), and differing series in
+//! `kairos_proxy_shadow_mismatched_series_total{backend}`. Each mismatch is logged with the
+//! first series that differ. Each cluster resolves relative time ranges itself, so a series
+//! ending at "now" can differ by a point.
+
+use crate::metrics::Metrics;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+// Differing series named in a mismatch log line
+const LOGGED_SERIES: usize = 5;
+
+/// Series key -> number of values and their checksum.
+fn summarize(answer: &Value) -> BTreeMap<String, (usize, u64)> {
+    let results = answer
+        .get("queries")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|q| q.get("results").and_then(Value::as_array))
+        .flatten();
+    let mut summary = BTreeMap::new();
+    for result in results {
+        let key = format!(
+            "{} {} {}",
+            result
+                .get("name")
+                .and_then(Value::as_str)
+                .unwrap_or_default(),
+            result.get("group_by").unwrap_or(&Value::Null),
+            result.get("tags").unwrap_or(&Value::Null)
+        );
+        let values = result.get("values").and_then(Value::as_array);
+        let mut hasher = DefaultHasher::new();
+        for point in values.into_iter().flatten() {
+            for v in point.as_array().into_iter().flatten() {
+                // 1 and 1.0 are the same value whichever way a cluster prints it
+                match v.as_f64() {
+                    Some(n) => n.to_bits().hash(&mut hasher),
+                    None => v.to_string().hash(&mut hasher),
+                }
+            }
+        }
+        summary.insert(key, (values.map_or(0, Vec::len), hasher.finish()));
+    }
+    summary
+}
+
+/// Descriptions of the series that differ between the two answers.
+fn diff(primary: &Value, shadow: &Value) -> Vec<String> {
+    let primary = summarize(primary);
+    let mut shadow = summarize(shadow);
+    let mut diffs = Vec::new();
+    for (key, (count, checksum)) in primary {
+        match shadow.remove(&key) {
+            None => diffs.push(format!("{}: missing from the mirror", key)),
+            Some((c, _)) if c != count => {
+                diffs.push(format!("{}: {} values, mirror has {}", key, count, c))
+            }
+            Some((_, sum)) if sum != checksum => {
+                diffs.push(format!("{}: {} values differ", key, count))
+            }
+            Some(_) => {}
+        }
+    }
+    diffs.extend(
+        shadow
+            .into_keys()
+            .map(|key| format!("{}: only in the mirror", key)),
+    );
+    diffs
+}
+
+/// Compares `primary`, the backend's answer, with the mirror's once it arrives.
+pub(crate) fn spawn(
+    metrics: Arc<Metrics>,
+    backend: String,
+    primary: Value,
+    shadow: JoinHandle<Option<Value>>,
+) {
+    tokio::spawn(async move {
+        let outcome = match shadow.await {
+            Ok(Some(answer)) => {
+                let diffs = diff(&primary, &answer);
+                if diffs.is_empty() {
+                    debug!("Mirror of backend '{}' answered the same", backend);
+                    "match"
+                } else {
+                    warn!(
+                        "Mirror of backend '{}' answered differently in {} series: {}",
+                        backend,
+                        diffs.len(),
+                        diffs[..diffs.len().min(LOGGED_SERIES)].join("; ")
+                    );
+                    metrics.inc_counter_by(
+                        "kairos_proxy_shadow_mismatched_series_total",
+                        &[("backend", &backend)],
+                        diffs.len() as u64,
+                    );
+                    "mismatch"
+                }
+            }
+            _ => "error",
+        };
+        metrics.inc_counter(
+            "kairos_proxy_shadow_comparisons_total",
+            &[("backend", &backend), ("outcome", outcome)],
+        );
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn counts_series_whose_values_differ() {
+        let answer = |values: Value| {
+            json!({ "queries": [{ "results": [
+                { "name": "cpu", "tags": { "host": ["a"] }, "values": [[1, 1.0], [2, 2]] },
+                { "name": "mem", "tags": { "host": ["a"] }, "values": values },
+            ] }] })
+        };
+        let old = answer(json!([[1, 5]]));
+        assert!(diff(&old, &answer(json!([[1, 5.0]]))).is_empty());
+        assert_eq!(
+            diff(&old, &answer(json!([[1, 6]]))),
+            vec![r#"mem null {"host":["a"]}: 1 values differ"#]
+        );
+        assert_eq!(diff(&old, &answer(json!([[1, 5], [2, 5]]))).len(), 1);
+        assert_eq!(diff(&old, &json!({ "queries": [] })).len(), 2);
+
+        let metrics = Arc::new(Metrics::default());
+        let differing = answer(json!([]));
+        let mirror = tokio::spawn(async move { Some(differing) });
+        spawn(metrics.clone(), "old".to_string(), old, mirror);
+        let labels = [("backend", "old"), ("outcome", "mismatch")];
+        for _ in 0..100 {
+            if metrics.counter("kairos_proxy_shadow_comparisons_total", &labels) == 1 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        assert_eq!(
+            metrics.counter("kairos_proxy_shadow_comparisons_total", &labels),
+            1
+        );
+    }
+}
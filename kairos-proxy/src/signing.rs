@@ -0,0 +1,173 @@
+//! HMAC signing of outbound requests, for gateways that authenticate the proxy by a shared
+//! secret.
+//!
+//! A backend with a `[backends.signing]` table gets three headers on every query and write sent
+//! to it: the Unix time in seconds, a random nonce, and the hex HMAC of
+//! `<timestamp>\n<nonce>\n<body>`. The gateway recomputes the HMAC, refuses timestamps outside
+//! its replay window and nonces it has already seen within it. Health checks, metric name
+//! listings and mirrored copies are not signed. Headers of those names sent by the client are
+//! dropped, so only the proxy's own signature reaches the gateway.
+
+use crate::config::{SigningAlgorithm, SigningConfig};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct Signer {
+    key: hmac::Key,
+    header: HeaderName,
+    timestamp_header: HeaderName,
+    nonce_header: HeaderName,
+    rng: SystemRandom,
+}
+
+fn header_name(name: &Option<String>, default: &str) -> anyhow::Result<HeaderName> {
+    let name = name.as_deref().unwrap_or(default);
+    HeaderName::try_from(name)
+        .map_err(|e| anyhow::anyhow!("Invalid signing header name '{}': {}", name, e))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Signer {
+    pub fn new(cfg: &SigningConfig, backend: &str) -> anyhow::Result<Self> {
+        let secret = match (&cfg.secret, &cfg.secret_env) {
+            (Some(secret), None) => secret.clone(),
+            (None, Some(var)) => std::env::var(var).map_err(|_| {
+                anyhow::anyhow!(
+                    "Backend '{}' reads its signing secret from {}, which is not set",
+                    backend,
+                    var
+                )
+            })?,
+            _ => anyhow::bail!(
+                "Backend '{}' signing needs exactly one of secret and secret_env",
+                backend
+            ),
+        };
+        if secret.is_empty() {
+            anyhow::bail!("Backend '{}' has an empty signing secret", backend);
+        }
+        let algorithm = match cfg.algorithm.unwrap_or_default() {
+            SigningAlgorithm::Sha256 => hmac::HMAC_SHA256,
+            SigningAlgorithm::Sha384 => hmac::HMAC_SHA384,
+            SigningAlgorithm::Sha512 => hmac::HMAC_SHA512,
+        };
+        Ok(Signer {
+            key: hmac::Key::new(algorithm, secret.as_bytes()),
+            header: header_name(&cfg.header, "x-signature")?,
+            timestamp_header: header_name(&cfg.timestamp_header, "x-signature-timestamp")?,
+            nonce_header: header_name(&cfg.nonce_header, "x-signature-nonce")?,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    fn signature(&self, timestamp: u64, nonce: &str, body: &[u8]) -> String {
+        let mut ctx = hmac::Context::with_key(&self.key);
+        ctx.update(format!("{}\n{}\n", timestamp, nonce).as_bytes());
+        ctx.update(body);
+        hex(ctx.sign().as_ref())
+    }
+
+    /// Whether `name` is one of the signature headers, which clients must not supply.
+    pub fn owns(&self, name: &HeaderName) -> bool {
+        *name == self.header || *name == self.timestamp_header || *name == self.nonce_header
+    }
+
+    /// Sets the signature headers for `body`, sent now with a fresh nonce, replacing any the
+    /// request already has.
+    pub fn sign(&self, builder: reqwest::RequestBuilder, body: &[u8]) -> reqwest::RequestBuilder {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut nonce = [0u8; 16];
+        let nonce = match self.rng.fill(&mut nonce) {
+            Ok(()) => hex(&nonce),
+            // The system generator does not fail in practice; a weaker nonce still differs
+            Err(_) => format!("{:032x}", fastrand::u128(..)),
+        };
+        let signature = self.signature(timestamp, &nonce, body);
+        let mut headers = HeaderMap::new();
+        headers.insert(self.timestamp_header.clone(), HeaderValue::from(timestamp));
+        // Hex digits are always valid header values
+        for (name, value) in [(&self.nonce_header, nonce), (&self.header, signature)] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name.clone(), value);
+            }
+        }
+        // Unlike `header`, `headers` replaces existing values
+        builder.headers(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_timestamp_nonce_and_body() {
+        let cfg = SigningConfig {
+            secret: Some("key".to_string()),
+            ..Default::default()
+        };
+        let signer = Signer::new(&cfg, "gw").expect("signer");
+        let expected = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, b"key"),
+            b"1700000000\nabc\n{\"metrics\":[]}",
+        );
+        assert_eq!(
+            signer.signature(1_700_000_000, "abc", b"{\"metrics\":[]}"),
+            hex(expected.as_ref())
+        );
+        assert_eq!(signer.signature(1_700_000_000, "abd", b"").len(), 64);
+
+        let request = signer
+            .sign(
+                reqwest::Client::new().post("http://gw:8080/api/v1/datapoints"),
+                b"[]",
+            )
+            .build()
+            .unwrap();
+        let headers = request.headers();
+        assert_eq!(headers["x-signature"].len(), 64);
+        assert_eq!(headers["x-signature-nonce"].len(), 32);
+        assert!(headers.contains_key("x-signature-timestamp"));
+
+        let both = SigningConfig {
+            secret_env: Some("KAIROS_PROXY_TEST_SIGNING".to_string()),
+            ..cfg
+        };
+        assert!(Signer::new(&both, "gw").is_err());
+    }
+
+    #[test]
+    fn replaces_signature_headers_the_request_already_has() {
+        let cfg = SigningConfig {
+            secret: Some("key".to_string()),
+            ..Default::default()
+        };
+        let signer = Signer::new(&cfg, "gw").expect("signer");
+        assert!(signer.owns(&HeaderName::from_static("x-signature-nonce")));
+        assert!(!signer.owns(&HeaderName::from_static("authorization")));
+
+        let forged = reqwest::Client::new()
+            .post("http://gw:8080/api/v1/datapoints")
+            .header("x-signature", "forged")
+            .header("x-signature-timestamp", "1");
+        let request = signer.sign(forged, b"[]").build().unwrap();
+        let signatures: Vec<_> = request.headers().get_all("x-signature").iter().collect();
+        assert_eq!(signatures.len(), 1);
+        assert_ne!(signatures[0], "forged");
+        assert_eq!(
+            request
+                .headers()
+                .get_all("x-signature-timestamp")
+                .iter()
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! Coalescing of identical in-flight work.
+//!
+//! The first caller for a key runs the work; callers arriving with the same key while it is
+//! still running wait for that result instead of repeating it. Nothing is kept once the work
+//! finishes, so this is deduplication, not caching.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+pub struct Group<T> {
+    calls: Mutex<HashMap<String, broadcast::Sender<T>>>,
+}
+
+impl<T> Default for Group<T> {
+    fn default() -> Self {
+        Group {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Unregisters the leader's key even if its future is dropped mid-flight, so waiters are
+/// released (and retry on their own) rather than hanging.
+struct Leader<'a, T> {
+    group: &'a Group<T>,
+    key: &'a str,
+}
+
+impl<T> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        self.group.lock().remove(self.key);
+    }
+}
+
+impl<T> Group<T> {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, broadcast::Sender<T>>> {
+        self.calls.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<T: Clone> Group<T> {
+    /// Runs `work` for `key`, or waits for the identical call already in flight. The bool
+    /// is `true` when the result was shared from another caller.
+    pub async fn run<F, Fut>(&self, key: String, work: F) -> (T, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let waiting = {
+            let mut calls = self.lock();
+            match calls.get(&key) {
+                Some(tx) => Some(tx.subscribe()),
+                None => {
+                    calls.insert(key.clone(), broadcast::channel(1).0);
+                    None
+                }
+            }
+        };
+        if let Some(mut rx) = waiting {
+            return match rx.recv().await {
+                Ok(value) => (value, true),
+                // The leader was cancelled before finishing
+                Err(_) => (work().await, false),
+            };
+        }
+
+        let leader = Leader {
+            group: self,
+            key: &key,
+        };
+        let value = work().await;
+        // Unregister and publish under the lock so no waiter can subscribe in between
+        let mut calls = self.lock();
+        if let Some(tx) = calls.remove(&key) {
+            let _ = tx.send(value.clone());
+        }
+        drop(calls);
+        std::mem::forget(leader);
+        (value, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn concurrent_calls_share_one_execution() {
+        let group = Arc::new(Group::<u32>::default());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..5)
+            .map(|_| {
+                let group = group.clone();
+                let runs = runs.clone();
+                tokio::spawn(async move {
+                    group
+                        .run("q".to_string(), || async move {
+                            runs.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            42
+                        })
+                        .await
+                })
+            })
+            .collect();
+
+        let mut shared = 0;
+        for h in handles {
+            let (value, was_shared) = h.await.unwrap();
+            assert_eq!(value, 42);
+            shared += was_shared as usize;
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(shared, 4);
+
+        // Finished calls are not remembered
+        let (value, was_shared) = group.run("q".to_string(), || async { 7 }).await;
+        assert_eq!((value, was_shared), (7, false));
+    }
+}
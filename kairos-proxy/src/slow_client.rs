@@ -0,0 +1,118 @@
+//! Timeouts on clients that send slowly or not at all.
+//!
+//! A connection must deliver a request's headers within `header_read_timeout_secs` (default
+//! 30) or hyper closes it, and once the headers are in, the body may not pause for longer than
+//! `body_read_timeout_secs` (default 30) between chunks; a stalled body fails with
+//! `408 Request Timeout`. With `request_timeout_secs`, a request that has not started its
+//! response within that time gets `408` too, whatever it was waiting on. Each is counted in
+//! `kairos_proxy_inbound_timeouts_total{stage}`, `stage` being `body` or `request`.
+
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::stream;
+use hyper::Body;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+pub const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_BODY_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The error a request body fails with once it has stalled.
+#[derive(Debug)]
+pub struct BodyStalled;
+
+impl std::fmt::Display for BodyStalled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("request body stalled")
+    }
+}
+
+impl std::error::Error for BodyStalled {}
+
+/// Whether reading a request body failed because the client stopped sending it.
+pub fn stalled(e: &hyper::Error) -> bool {
+    std::error::Error::source(e).is_some_and(|s| s.is::<BodyStalled>())
+}
+
+/// `body`, failing with `BodyStalled` when no chunk arrives for `idle`.
+fn with_idle_timeout(body: Body, idle: Duration, state: Arc<AppState>) -> Body {
+    Body::wrap_stream(stream::unfold(Some(body), move |body| {
+        let state = state.clone();
+        async move {
+            let mut body = body?;
+            match tokio::time::timeout(idle, hyper::body::HttpBody::data(&mut body)).await {
+                Ok(Some(chunk)) => Some((chunk.map_err(Into::into), Some(body))),
+                Ok(None) => None,
+                Err(_) => {
+                    warn!("Request body stalled for {:?}", idle);
+                    state
+                        .metrics
+                        .inc_counter("kairos_proxy_inbound_timeouts_total", &[("stage", "body")]);
+                    let stalled: Box<dyn std::error::Error + Send + Sync> = Box::new(BodyStalled);
+                    Some((Err(stalled), None))
+                }
+            }
+        }
+    }))
+}
+
+/// Applies the body and request timeouts.
+pub async fn layer(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let req = match state.body_read_timeout {
+        Some(idle) => req.map(|body| with_idle_timeout(body, idle, state.clone())),
+        None => req,
+    };
+    let Some(limit) = state.request_timeout else {
+        return next.run(req).await;
+    };
+    match tokio::time::timeout(limit, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("Request took longer than {:?}", limit);
+            state.metrics.inc_counter(
+                "kairos_proxy_inbound_timeouts_total",
+                &[("stage", "request")],
+            );
+            StatusCode::REQUEST_TIMEOUT.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn stalled_bodies_fail_with_request_timeout() {
+        let state = Arc::new(AppState::from_config(&Config::default()).expect("state"));
+        let (mut sender, body) = Body::channel();
+        sender.send_data("{\"metrics\":".into()).await.unwrap();
+        let mut body = with_idle_timeout(body, Duration::from_millis(50), state.clone());
+        let read = crate::proxy::to_bytes(&mut body, 1024).await;
+        assert_eq!(read, Err(StatusCode::REQUEST_TIMEOUT));
+        assert_eq!(
+            state
+                .metrics
+                .counter("kairos_proxy_inbound_timeouts_total", &[("stage", "body")]),
+            1
+        );
+        drop(sender);
+
+        let mut complete = with_idle_timeout(Body::from("{}"), Duration::from_millis(50), state);
+        assert_eq!(
+            crate::proxy::to_bytes(&mut complete, 1024).await,
+            Ok(bytes::Bytes::from("{}"))
+        );
+    }
+}
@@ -0,0 +1,448 @@
+//! Disk-backed write-ahead queue for ingest.
+//!
+//! With an `[ingest_spool]` table, a batch of datapoints a backend fails to accept (a `5xx`,
+//! timeout or connection error) is appended to `<dir>/<backend>-<hash>.jsonl` instead of being
+//! lost, and the client's write succeeds. Every `replay_interval_secs` the batches left over
+//! in `<backend>-<hash>.replay.jsonl` and those queued since are posted again oldest first, at
+//! background priority and without mirroring; what remains is written to a new replay file that
+//! replaces the old one. A round stops when two batches in a row fail, as the backend is likely
+//! still down; a batch that fails while the next is accepted counts an attempt, and after
+//! `replay_max_attempts` it is moved to `<backend>-<hash>.dead.jsonl`, as is any batch the
+//! backend refuses with a `4xx`. The queue and replay files together never exceed `max_bytes`;
+//! once full, failed writes fail again.
+
+use crate::config::IngestSpool;
+use crate::ingest::{Origin, Posted};
+use crate::state::AppState;
+use crate::timerange::now_ms;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tracing::{debug, error, info, warn};
+
+pub struct Spool {
+    queue: PathBuf,
+    replay: PathBuf,
+    // The queue while a replay round takes it over
+    incoming: PathBuf,
+    dead: PathBuf,
+    max_bytes: u64,
+    max_attempts: u32,
+    // Serializes appends against moving the queue aside
+    lock: tokio::sync::Mutex<()>,
+}
+
+/// Spooled bytes and the time the oldest spooled batch was written, in epoch milliseconds.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Depth {
+    pub bytes: u64,
+    pub oldest_ms: Option<i64>,
+}
+
+async fn size(path: &Path) -> u64 {
+    tokio::fs::metadata(path).await.map_or(0, |m| m.len())
+}
+
+/// The lines of `path`, read as they are needed; empty if it cannot be opened.
+async fn lines(path: &Path) -> Option<tokio::io::Lines<BufReader<tokio::fs::File>>> {
+    let file = tokio::fs::File::open(path).await.ok()?;
+    Some(BufReader::new(file).lines())
+}
+
+async fn remove(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+async fn append(path: &Path, line: &str) -> std::io::Result<()> {
+    let mut f = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    f.write_all(line.as_bytes()).await?;
+    f.write_all(b"\n").await?;
+    f.flush().await
+}
+
+/// A spooled batch: its datapoints and the attempts to replay it so far.
+struct Entry {
+    value: serde_json::Map<String, Value>,
+}
+
+impl Entry {
+    fn parse(line: &str) -> Option<Self> {
+        match serde_json::from_str(line) {
+            Ok(Value::Object(value)) => Some(Entry { value }),
+            _ => None,
+        }
+    }
+
+    fn points(&self) -> Vec<Value> {
+        match self.value.get("datapoints") {
+            Some(Value::Array(points)) => points.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn attempts(&self) -> u32 {
+        self.value
+            .get("attempts")
+            .and_then(Value::as_u64)
+            .unwrap_or(0) as u32
+    }
+
+    fn line(&self) -> String {
+        Value::Object(self.value.clone()).to_string()
+    }
+}
+
+impl Spool {
+    /// The spool of `backend` under `cfg.dir`, which is created if missing.
+    pub fn new(cfg: &IngestSpool, backend: &str) -> anyhow::Result<Self> {
+        let dir = Path::new(&cfg.dir);
+        std::fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("Cannot create spool directory {:?}: {}", dir, e))?;
+        let sanitized: String = backend
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        // Names differing only in replaced characters still get files of their own
+        let file = format!("{}-{:016x}", sanitized, crate::hash_ring::hash(backend));
+        Ok(Spool {
+            queue: dir.join(format!("{}.jsonl", file)),
+            replay: dir.join(format!("{}.replay.jsonl", file)),
+            incoming: dir.join(format!("{}.incoming.jsonl", file)),
+            dead: dir.join(format!("{}.dead.jsonl", file)),
+            max_bytes: cfg.max_bytes.unwrap_or(1 << 30),
+            max_attempts: cfg.replay_max_attempts.unwrap_or(5).max(1),
+            lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Appends a batch to the queue. Returns false if the spool is full or cannot be written.
+    pub async fn push(&self, points: &[Value]) -> bool {
+        let mut line = json!({ "spooled_at": now_ms(), "datapoints": points }).to_string();
+        line.push('\n');
+        let _guard = self.lock.lock().await;
+        let used = self.bytes().await;
+        if used + line.len() as u64 > self.max_bytes {
+            warn!("Ingest spool {:?} is full, dropping batch", self.queue);
+            return false;
+        }
+        if let Err(e) = append(&self.queue, line.trim_end()).await {
+            error!("Failed to write ingest spool {:?}: {}", self.queue, e);
+            return false;
+        }
+        true
+    }
+
+    async fn bytes(&self) -> u64 {
+        size(&self.queue).await + size(&self.incoming).await + size(&self.replay).await
+    }
+
+    pub async fn depth(&self) -> Depth {
+        let bytes = self.bytes().await;
+        let mut oldest_ms = None;
+        for path in [&self.replay, &self.incoming, &self.queue] {
+            let first = match lines(path).await {
+                Some(mut lines) => lines.next_line().await.ok().flatten(),
+                None => None,
+            };
+            if let Some(first) = first {
+                oldest_ms = serde_json::from_str::<Value>(&first)
+                    .ok()
+                    .and_then(|v| v.get("spooled_at")?.as_i64());
+                break;
+            }
+        }
+        Depth { bytes, oldest_ms }
+    }
+
+    async fn bury(&self, entry: &Entry) -> usize {
+        if let Err(e) = append(&self.dead, &entry.line()).await {
+            error!("Failed to write ingest dead letters {:?}: {}", self.dead, e);
+        }
+        entry.points().len()
+    }
+
+    /// Posts spooled batches oldest first through `send`, as described in the module docs.
+    /// Returns the numbers of datapoints delivered and moved to the dead-letter file.
+    pub async fn replay<F, Fut>(&self, mut send: F) -> (usize, usize)
+    where
+        F: FnMut(Vec<Value>) -> Fut,
+        Fut: std::future::Future<Output = Posted>,
+    {
+        {
+            let _guard = self.lock.lock().await;
+            // An incoming file still present was taken over by an interrupted round
+            if size(&self.incoming).await == 0 && size(&self.queue).await > 0 {
+                if let Err(e) = tokio::fs::rename(&self.queue, &self.incoming).await {
+                    error!("Failed to move ingest spool {:?} aside: {}", self.queue, e);
+                    return (0, 0);
+                }
+            }
+        }
+        let sources = [lines(&self.replay).await, lines(&self.incoming).await];
+        if sources.iter().all(Option::is_none) {
+            return (0, 0);
+        }
+        let mut sources = sources.into_iter().flatten();
+        let mut pending = sources.next();
+        // What is left is written next to the replay file and moved over it once complete
+        let kept_path = self.replay.with_extension("jsonl.tmp");
+        // Left over if a previous round was interrupted; the replay file is still whole
+        let _ = tokio::fs::remove_file(&kept_path).await;
+        let mut kept = Vec::new();
+        let (mut delivered, mut buried) = (0, 0);
+        // The last batch that failed, until the next one shows whether the backend is up
+        let mut failed: Option<Entry> = None;
+        let mut stopped = false;
+        while let Some(current) = pending.as_mut() {
+            let line = match current.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => {
+                    pending = sources.next();
+                    continue;
+                }
+                Err(e) => {
+                    error!("Failed to read ingest spool {:?}: {}", self.replay, e);
+                    return (delivered, buried);
+                }
+            };
+            if stopped {
+                kept.push(line);
+            } else if let Some(entry) = Entry::parse(&line) {
+                let count = entry.points().len();
+                match send(entry.points()).await {
+                    Posted::Accepted => {
+                        delivered += count;
+                        // The backend is up, so the batch before failed on its own account
+                        if let Some(mut prev) = failed.take() {
+                            let attempts = prev.attempts() + 1;
+                            if attempts >= self.max_attempts {
+                                buried += self.bury(&prev).await;
+                            } else {
+                                prev.value.insert("attempts".into(), attempts.into());
+                                kept.push(prev.line());
+                            }
+                        }
+                    }
+                    Posted::Refused(_) => buried += self.bury(&entry).await,
+                    Posted::Failed => match failed.take() {
+                        Some(prev) => {
+                            kept.push(prev.line());
+                            kept.push(entry.line());
+                            stopped = true;
+                        }
+                        None => failed = Some(entry),
+                    },
+                }
+            } else {
+                warn!("Skipping corrupt entry in ingest spool {:?}", self.replay);
+            }
+            if kept.len() >= 1024 {
+                if let Err(e) = self.keep(&kept_path, &mut kept).await {
+                    error!("Failed to rewrite ingest spool {:?}: {}", self.replay, e);
+                    return (delivered, buried);
+                }
+            }
+        }
+        if let Some(prev) = failed {
+            kept.push(prev.line());
+        }
+        if let Err(e) = self.keep(&kept_path, &mut kept).await {
+            error!("Failed to rewrite ingest spool {:?}: {}", self.replay, e);
+            return (delivered, buried);
+        }
+        let renamed = if size(&kept_path).await > 0 {
+            tokio::fs::rename(&kept_path, &self.replay).await
+        } else {
+            let _ = tokio::fs::remove_file(&kept_path).await;
+            remove(&self.replay).await
+        };
+        // Until the replay file is replaced, the incoming batches are still needed
+        let replaced = match renamed {
+            Ok(()) => remove(&self.incoming).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = replaced {
+            error!("Failed to rewrite ingest spool {:?}: {}", self.replay, e);
+        }
+        (delivered, buried)
+    }
+
+    /// Appends `lines` to the file replacing the replay file.
+    async fn keep(&self, path: &Path, lines: &mut Vec<String>) -> std::io::Result<()> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let mut f = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        for line in lines.drain(..) {
+            f.write_all(line.as_bytes()).await?;
+            f.write_all(b"\n").await?;
+        }
+        f.flush().await
+    }
+}
+
+/// Replays every backend's spool each `every` and publishes its depth and age.
+pub fn spawn(state: Arc<AppState>, every: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(every);
+        loop {
+            ticker.tick().await;
+            for (i, target) in state.backends.iter().enumerate() {
+                let Some(spool) = &target.spool else {
+                    continue;
+                };
+                let state = &state;
+                let (delivered, buried) = spool
+                    .replay(|points| crate::ingest::post(state, i, points, Origin::Spool))
+                    .await;
+                if buried > 0 {
+                    warn!(
+                        "Moved {} spooled datapoint(s) for '{}' to dead letters",
+                        buried, target.name
+                    );
+                    state.metrics.inc_counter_by(
+                        "kairos_proxy_ingest_dead_lettered_datapoints_total",
+                        &[("backend", &target.name)],
+                        buried as u64,
+                    );
+                }
+                if delivered > 0 {
+                    info!(
+                        "Replayed {} spooled datapoint(s) to '{}'",
+                        delivered, target.name
+                    );
+                    state.metrics.inc_counter_by(
+                        "kairos_proxy_ingest_replayed_datapoints_total",
+                        &[("backend", &target.name)],
+                        delivered as u64,
+                    );
+                }
+                let depth = spool.depth().await;
+                let age = depth
+                    .oldest_ms
+                    .map_or(0.0, |at| (now_ms() - at).max(0) as f64 / 1000.0);
+                debug!("Ingest spool of '{}': {:?}", target.name, depth);
+                let labels = [("backend", target.name.as_str())];
+                state.metrics.set_gauge(
+                    "kairos_proxy_ingest_spool_bytes",
+                    &labels,
+                    depth.bytes as f64,
+                );
+                state
+                    .metrics
+                    .set_gauge("kairos_proxy_ingest_spool_age_seconds", &labels, age);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replays_in_order_and_keeps_what_fails() {
+        let dir = std::env::temp_dir().join(format!("kairos-spool-{}", fastrand::u64(..)));
+        let cfg = IngestSpool {
+            dir: dir.to_string_lossy().into_owned(),
+            max_bytes: Some(200),
+            ..Default::default()
+        };
+        let spool = Spool::new(&cfg, "prod/eu").unwrap();
+        assert!(spool.push(&[json!({ "name": "a" })]).await);
+        assert!(spool.push(&[json!({ "name": "b" })]).await);
+        assert!(!spool.push(&[json!({ "name": "x".repeat(200) })]).await);
+        assert!(spool.depth().await.oldest_ms.is_some());
+
+        // The backend accepts the first batch only
+        let mut sent = Vec::new();
+        let (delivered, _) = spool
+            .replay(|points| {
+                sent.push(points[0]["name"].clone());
+                std::future::ready(if sent.len() == 1 {
+                    Posted::Accepted
+                } else {
+                    Posted::Failed
+                })
+            })
+            .await;
+        assert_eq!(delivered, 1);
+        assert_eq!(sent, [json!("a"), json!("b")]);
+
+        assert!(spool.push(&[json!({ "name": "c" })]).await);
+        let mut sent = Vec::new();
+        spool
+            .replay(|points| {
+                sent.push(points[0]["name"].clone());
+                std::future::ready(Posted::Accepted)
+            })
+            .await;
+        // The retried batch first, then the newer one
+        assert_eq!(sent, [json!("b"), json!("c")]);
+        spool.replay(|_| std::future::ready(Posted::Accepted)).await;
+        assert_eq!(spool.depth().await, Depth::default());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn moves_batches_that_keep_failing_to_dead_letters() {
+        let dir = std::env::temp_dir().join(format!("kairos-spool-{}", fastrand::u64(..)));
+        let cfg = IngestSpool {
+            dir: dir.to_string_lossy().into_owned(),
+            replay_max_attempts: Some(2),
+            ..Default::default()
+        };
+        let spool = Spool::new(&cfg, "prod/eu").unwrap();
+        assert_ne!(spool.queue, Spool::new(&cfg, "prod_eu").unwrap().queue);
+        for name in ["poison", "refused", "ok"] {
+            assert!(spool.push(&[json!({ "name": name })]).await);
+        }
+        let answer = |points: Vec<Value>| {
+            std::future::ready(match points[0]["name"].as_str() {
+                Some("poison") => Posted::Failed,
+                Some("refused") => Posted::Refused(axum::http::StatusCode::BAD_REQUEST),
+                _ => Posted::Accepted,
+            })
+        };
+        // The refused batch goes at once; the failing one is kept for one more attempt
+        assert_eq!(spool.replay(answer).await, (1, 1));
+        assert!(spool.push(&[json!({ "name": "later" })]).await);
+        assert_eq!(spool.replay(answer).await, (1, 1));
+        let dead = std::fs::read_to_string(&spool.dead).unwrap();
+        assert_eq!(dead.lines().count(), 2);
+        assert_eq!(spool.depth().await.bytes, 0);
+
+        // Two failures in a row stop the round without counting attempts
+        for name in ["a", "b", "c"] {
+            assert!(spool.push(&[json!({ "name": name })]).await);
+        }
+        let mut sent = 0;
+        let down = spool
+            .replay(|_| {
+                sent += 1;
+                std::future::ready(Posted::Failed)
+            })
+            .await;
+        assert_eq!((down, sent), ((0, 0), 2));
+        assert!(!std::fs::read_to_string(&spool.replay)
+            .unwrap()
+            .contains("attempts"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
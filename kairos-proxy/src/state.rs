@@ -1,40 +1,400 @@
-use crate::config::{Config, Mode};
-use regex::Regex;
-use reqwest::{Client, Url};
+use crate::access::IpFilter;
+use crate::admission::Admission;
+use crate::auth::ApiKeys;
+use crate::balance::InstanceLoad;
+use crate::cache::ResponseCache;
+use crate::chaos::Chaos;
+use crate::client::{BackendClient, ClientOptions};
+use crate::config::{Config, MergeStrategy, Mode};
+use crate::credentials::{Source, Token};
+use crate::debug_trace;
+use crate::decompress;
+use crate::discovery::Endpoints;
+use crate::estimate::Estimates;
+use crate::events::Events;
+use crate::forwarded;
+use crate::generations::{Generation, Generations};
+use crate::ingest::Batcher;
+use crate::logging::LogControl;
+use crate::memory::MemoryBudget;
+use crate::metrics::Metrics;
+use crate::name_prefix::NamePrefix;
+use crate::quota::Quotas;
+use crate::ratelimit::TokenBucket;
+use crate::recorder::Recorder;
+use crate::result_fields::Omit;
+use crate::routes::{self, parse_tier, Route, Secondary, Subject};
+use crate::signing::Signer;
+use crate::singleflight;
+use crate::spool::Spool;
+use crate::tag_keys::TagKeyMap;
+use crate::telemetry::SelfTelemetry;
+use crate::timerange::{now_ms, TimeRange};
+use crate::unroutable::UnroutableCache;
+use crate::upstream::{BackendError, Failure, QueryError};
+use crate::validate::{
+    JsonLimits, MissingStart, QueryChecks, DEFAULT_MAX_JSON_DEPTH, DEFAULT_MAX_JSON_TOKENS,
+};
+use axum::http::StatusCode;
+use reqwest::Url;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::Semaphore;
-use tracing::{debug, info};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Asynchronous shadow copy of a backend's traffic.
+pub struct Mirror {
+    pub url: Url,
+    pub token: Option<String>,
+    pub percent: f64,
+    // Whether query answers are compared with the backend's
+    pub compare: bool,
+}
+
+/// Alternate cluster that takes a share of a backend's traffic in place of the primary.
+pub struct Canary {
+    pub url: Url,
+    pub token: Option<String>,
+    pub percent: f64,
+}
+
+/// A backend compiled from config: where and how to forward.
+pub struct BackendTarget {
+    pub name: String,
+    /// The configured URL, whose host is the one connected to and re-resolved.
+    pub url: Url,
+    /// `url` with `sni_hostname` as its host, if set: what requests to it address.
+    pub sni_url: Option<Url>,
+    /// Path prepended to every KairosDB endpoint, normalized to `/prefix/`.
+    pub path_prefix: Option<String>,
+    pub token: Token,
+    pub timeout: Duration,
+    pub mirror: Option<Mirror>,
+    pub canary: Option<Canary>,
+    /// Drained backends receive no new requests; routing falls through to the next match.
+    pub draining: AtomicBool,
+    /// Outbound client with this backend's proxy settings.
+    pub client: BackendClient,
+    /// Instances discovered from Kubernetes, used in place of `url` once any is ready.
+    pub endpoints: Option<Arc<Endpoints>>,
+    /// Tag keys renamed on the way to and from this backend.
+    pub tag_keys: Option<TagKeyMap>,
+    /// Metric name prefixes translated between clients and the backend.
+    pub name_prefix: Option<NamePrefix>,
+    /// Budget of requests per second, if limited.
+    pub rate_limit: Option<TokenBucket>,
+    /// Buffer of ingested datapoints awaiting a batched write, if batching is enabled.
+    pub batcher: Option<Batcher>,
+    /// On-disk queue of ingest batches awaiting replay, if spooling is enabled.
+    pub spool: Option<Spool>,
+    /// HMAC signer of the queries and writes sent to the backend, if required.
+    pub signer: Option<Signer>,
+    /// Recent latency and error rate of the requests sent to the backend.
+    pub estimates: Estimates,
+}
+
+/// Destination of a single request after the canary and instance decisions.
+pub struct Selected {
+    pub url: Url,
+    pub token: Option<String>,
+    pub canary: bool,
+    /// Discovered instance the request goes to, tracked as outstanding while this lives.
+    pub instance: Option<InstanceLoad>,
+}
+
+impl BackendTarget {
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    /// The URL requests to the configured address go to.
+    pub fn request_url(&self) -> &Url {
+        self.sni_url.as_ref().unwrap_or(&self.url)
+    }
+
+    /// The URL of a KairosDB `endpoint` (e.g. `api/v1/datapoints`) on `base`, the backend URL
+    /// or one chosen by `select`.
+    pub fn endpoint_url(&self, base: &Url, endpoint: &str) -> Result<Url, url::ParseError> {
+        match &self.path_prefix {
+            Some(prefix) => base.join(&format!("{}{}", prefix, endpoint.trim_start_matches('/'))),
+            None => base.join(endpoint),
+        }
+    }
+
+    /// Picks the primary or canary URL for one request.
+    pub fn select(&self) -> Selected {
+        match &self.canary {
+            Some(c) if fastrand::f64() * 100.0 < c.percent => Selected {
+                url: c.url.clone(),
+                token: c.token.clone(),
+                canary: true,
+                instance: None,
+            },
+            _ => {
+                let instance = self.endpoints.as_ref().and_then(|e| e.pick());
+                Selected {
+                    url: instance
+                        .as_ref()
+                        .map_or(self.request_url(), |i| i.url())
+                        .clone(),
+                    token: self.token.get(),
+                    canary: false,
+                    instance,
+                }
+            }
+        }
+    }
+}
+
+/// Parses an optional alternate URL and its traffic share (0-100, `default` if unset).
+fn parse_share(
+    kind: &str,
+    url: &Option<String>,
+    percent: Option<f64>,
+    default: f64,
+    pattern: &str,
+) -> anyhow::Result<Option<(Url, f64)>> {
+    let Some(u) = url else {
+        return Ok(None);
+    };
+    let parsed =
+        Url::parse(u).map_err(|e| anyhow::anyhow!("Invalid {} URL '{}': {}", kind, u, e))?;
+    let percent = percent.unwrap_or(default);
+    if !(0.0..=100.0).contains(&percent) {
+        anyhow::bail!(
+            "{}_percent for '{}' must be between 0 and 100",
+            kind,
+            pattern
+        );
+    }
+    Ok(Some((parsed, percent)))
+}
 
 pub struct AppState {
-    pub client: Client,
-    pub backends: Vec<(Regex, Url, Option<String>)>,
-    pub semaphore: Arc<Semaphore>,
+    pub metrics: Arc<Metrics>,
+    pub backends: Vec<BackendTarget>,
+    // Routing tables of the loaded configs; see `generations`.
+    pub generations: Generations,
+    // Metric names recently found to match no rule, if remembered.
+    pub unroutable_cache: Option<UnroutableCache>,
+    // File the config was loaded from, re-read on reload.
+    pub config_path: Option<String>,
+    // Runtime control of the log filter; set once logging is installed.
+    pub log_control: Option<Arc<LogControl>>,
+    // Destination of the proxy's own metrics, if enabled.
+    pub self_telemetry: Option<SelfTelemetry>,
+    pub admission: Arc<Admission>,
+    // Configured outbound timeout; client deadlines can only shorten it.
+    pub timeout: Duration,
     pub mode: Mode,
     pub max_request_body_bytes: usize,
+    // What gzip request bodies may expand to.
+    pub decompression: decompress::Limits,
+    // Request path -> body size and decompression limits replacing the two above.
+    pub endpoint_body_limits: HashMap<String, (usize, decompress::Limits)>,
+    // Shared by the bodies buffered by in-flight requests, if limited.
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    pub query_checks: QueryChecks,
+    pub admin_token: Option<String>,
+    // Peers whose `X-Forwarded-For` names the client.
+    pub trusted_proxies: Vec<ipnet::IpNet>,
+    // Client addresses allowed to use the data endpoints and the admin API.
+    pub ip_filter: IpFilter,
+    pub admin_ip_filter: IpFilter,
+    // API keys and their budgets, if any are configured.
+    pub api_keys: Option<ApiKeys>,
+    pub quotas: Option<Quotas>,
+    // Faults injected into backend requests (none unless enabled).
+    pub chaos: Chaos,
+    // Records sampled queries, if configured.
+    pub recorder: Option<Recorder>,
+    // Multi-mode queries currently in flight, keyed by endpoint and normalized body.
+    pub inflight: singleflight::Group<Result<(serde_json::Value, bool), QueryError>>,
+    pub cache: Option<ResponseCache>,
+    // Whether Simple-mode queries are routed from the start of their bodies and streamed.
+    pub stream_simple_queries: bool,
+    // Longest pause allowed between the chunks of a request body.
+    pub body_read_timeout: Option<Duration>,
+    // Time a request has to start its response.
+    pub request_timeout: Option<Duration>,
+    // Result sections left out of every Multi-mode query response.
+    pub omit_result_fields: Omit,
+    // Tag naming the source backends of Multi-mode results, if enabled.
+    pub annotate_backend: Option<String>,
+    // Whether Multi-mode answers are checked for conflicting series before merging.
+    pub detect_merge_conflicts: bool,
+    // Whether `X-Proxy-Debug: 1` requests get routing details in their response headers.
+    pub debug_header: bool,
+    // Whether responses carry a `Server-Timing` header with backend and merge durations.
+    pub server_timing: bool,
+    // Interval at which backend hostnames are re-resolved, if enabled.
+    pub dns_refresh: Option<Duration>,
+    // Interval at which spooled ingest batches are replayed, if spooling is enabled.
+    pub spool_replay: Option<Duration>,
+    // Set once a shutdown signal arrives; the proxy then reports itself not ready.
+    pub shutting_down: AtomicBool,
+    // Backend health and config changes for `/admin/events` subscribers.
+    pub events: Events,
 }
 
 impl AppState {
     pub fn from_config(cfg: &Config) -> anyhow::Result<Self> {
         let timeout = std::time::Duration::from_secs(cfg.timeout_secs.unwrap_or(5));
-        let client = Client::builder().timeout(timeout).build()?;
-        debug!("HTTP client created with timeout: {:?}", timeout);
+        let dns_refresh = cfg.dns_refresh_secs.map(Duration::from_secs);
+        let api_keys = crate::auth::key_configs(cfg)?;
 
         let mut backends = Vec::new();
+        let mut tiers = Vec::new();
         for b in &cfg.backends {
-            let re = Regex::new(&b.pattern).map_err(|e| anyhow::anyhow!(e))?;
             // Parse and validate backend URL at startup
             let url = Url::parse(&b.url)
                 .map_err(|e| anyhow::anyhow!("Invalid backend URL '{}': {}", b.url, e))?;
-            backends.push((re, url, b.token.clone()));
+            let mirror = parse_share("mirror", &b.mirror_url, b.mirror_percent, 100.0, &b.pattern)?
+                .map(|(url, percent)| {
+                    info!(
+                        "Mirroring {}% of '{}' traffic to {}",
+                        percent, b.pattern, url
+                    );
+                    Mirror {
+                        url,
+                        token: b.mirror_token.clone(),
+                        percent,
+                        compare: b.mirror_compare.unwrap_or(false),
+                    }
+                });
+            let canary = parse_share("canary", &b.canary_url, b.canary_percent, 0.0, &b.pattern)?
+                .map(|(url, percent)| {
+                    info!(
+                        "Sending {}% of '{}' traffic to canary {}",
+                        percent, b.pattern, url
+                    );
+                    Canary {
+                        url,
+                        token: b.canary_token.clone(),
+                        percent,
+                    }
+                });
+            let name = b.name.clone().unwrap_or_else(|| b.url.clone());
+            let tier = parse_tier(&b.newer_than, &b.older_than, &name)?;
+            if let Some(t) = &tier {
+                info!("Backend '{}' holds time tier {:?}", name, t);
+            }
+            tiers.push(tier);
             info!(
-                "Registered backend: pattern='{}' -> url='{}'",
-                b.pattern, b.url
+                "Registered backend '{}': pattern='{}' -> url='{}'",
+                name, b.pattern, b.url
             );
+            let endpoints = b.kubernetes.as_ref().map(|k| {
+                let strategy = b.load_balancing.unwrap_or_default();
+                Arc::new(Endpoints::new(k.clone(), url.clone(), strategy))
+            });
+            let options = ClientOptions::for_backend(b, &url, timeout, dns_refresh);
+            let client = BackendClient::new(options)?;
+            // Requests name the SNI host; the client connects to the configured address
+            let sni_url = match &b.sni_hostname {
+                Some(sni) => {
+                    let mut sni_url = url.clone();
+                    sni_url
+                        .set_host(Some(sni))
+                        .map_err(|e| anyhow::anyhow!("Invalid sni_hostname '{}': {}", sni, e))?;
+                    Some(sni_url)
+                }
+                None => None,
+            };
+            if b.insecure_skip_verify.unwrap_or(false) {
+                warn!(
+                    "TLS certificate verification is disabled for backend '{}'",
+                    name
+                );
+            }
+            let token = match (&b.token, &b.oauth2, &b.vault) {
+                (token, None, None) => Token::fixed(token.clone()),
+                (None, Some(oauth2), None) => Token::managed(Source::OAuth2(oauth2.clone())),
+                (None, None, Some(secret)) => Token::managed(Source::Vault(secret.clone())),
+                _ => anyhow::bail!(
+                    "Backend '{}' may set only one of token, oauth2 and vault",
+                    name
+                ),
+            };
+            let tag_keys = b
+                .tag_key_map
+                .as_ref()
+                .map(|m| TagKeyMap::new(m, &name))
+                .transpose()?;
+            let rate_limit = match b.max_rps {
+                Some(rps) if rps.is_finite() && rps > 0.0 => {
+                    let burst = b.burst.map_or(rps.ceil().max(1.0), f64::from);
+                    info!(
+                        "Limiting backend '{}' to {} requests/s (burst {})",
+                        name, rps, burst
+                    );
+                    Some(TokenBucket::new(rps, burst))
+                }
+                Some(rps) => anyhow::bail!("Backend '{}' has invalid max_rps {}", name, rps),
+                None => None,
+            };
+            let spool = cfg
+                .ingest_spool
+                .as_ref()
+                .map(|s| Spool::new(s, &name))
+                .transpose()?;
+            let signer = b
+                .signing
+                .as_ref()
+                .map(|s| Signer::new(s, &name))
+                .transpose()?;
+            backends.push(BackendTarget {
+                client,
+                name,
+                url,
+                sni_url,
+                path_prefix: b
+                    .path_prefix
+                    .as_deref()
+                    .map(|p| p.trim_matches('/'))
+                    .filter(|p| !p.is_empty())
+                    .map(|p| format!("/{}/", p)),
+                token,
+                timeout: b.timeout_secs.map_or(timeout, Duration::from_secs),
+                mirror,
+                canary,
+                draining: AtomicBool::new(b.draining.unwrap_or(false)),
+                endpoints,
+                tag_keys,
+                name_prefix: NamePrefix::new(&b.strip_prefix, &b.add_prefix),
+                rate_limit,
+                batcher: cfg.ingest_batching.as_ref().map(Batcher::new),
+                spool,
+                signer,
+                estimates: Estimates::default(),
+            });
         }
+        let routes = routes::compile(cfg, &backends, &tiers)?;
+        let blocked = routes::compile_blocked(cfg)?;
+        let self_telemetry = cfg
+            .self_telemetry
+            .as_ref()
+            .map(|t| SelfTelemetry::new(t, &backends))
+            .transpose()?;
 
         let max_outbound = cfg.max_outbound_concurrency.unwrap_or(32);
-        let semaphore = Arc::new(Semaphore::new(max_outbound));
-        debug!("Created semaphore with {} permits", max_outbound);
+        let batch_concurrency = cfg.batch_concurrency.unwrap_or(max_outbound);
+        let admission = Admission::new(
+            max_outbound,
+            batch_concurrency,
+            cfg.adaptive_concurrency.as_ref(),
+        );
+        debug!(
+            "Admitting {} concurrent outbound requests, {} of them batch",
+            max_outbound,
+            batch_concurrency.min(max_outbound)
+        );
 
         let mode = cfg.mode.clone().unwrap_or_default();
 
@@ -48,14 +408,495 @@ impl AppState {
             max_request_body_bytes / BYTES_PER_MB
         );
 
+        let decompression = |max_body_bytes: usize| decompress::Limits {
+            max_bytes: cfg
+                .max_decompressed_body_bytes
+                .unwrap_or(max_body_bytes.saturating_mul(4)),
+            max_ratio: cfg.max_compression_ratio,
+        };
+        let mut endpoint_body_limits = HashMap::new();
+        for (path, &max) in cfg.endpoint_body_limits.iter().flatten() {
+            if !path.starts_with('/') {
+                anyhow::bail!("endpoint_body_limits path '{}' must start with '/'", path);
+            }
+            debug!("Maximum request body size for {}: {} bytes", path, max);
+            endpoint_body_limits.insert(path.clone(), (max, decompression(max)));
+        }
+
         Ok(AppState {
-            client,
+            metrics: Arc::new(Metrics::default()),
             backends,
-            semaphore,
+            generations: Generations::new(
+                cfg.redacted(),
+                routes,
+                blocked,
+                cfg.config_history.unwrap_or(5),
+            ),
+            unroutable_cache: UnroutableCache::new(Duration::from_secs(
+                cfg.unroutable_cache_ttl_secs.unwrap_or(30),
+            )),
+            config_path: None,
+            log_control: None,
+            self_telemetry,
+            admission,
+            timeout,
             mode,
             max_request_body_bytes,
+            decompression: decompression(max_request_body_bytes),
+            endpoint_body_limits,
+            memory_budget: cfg
+                .max_buffered_bytes
+                .map(|limit| Arc::new(MemoryBudget::new(limit))),
+            query_checks: QueryChecks {
+                json_limits: JsonLimits {
+                    max_depth: cfg.max_json_depth.unwrap_or(DEFAULT_MAX_JSON_DEPTH),
+                    max_tokens: cfg.max_json_tokens.unwrap_or(DEFAULT_MAX_JSON_TOKENS),
+                },
+                validation: cfg.query_validation.clone().unwrap_or_default(),
+                max_metrics: cfg.max_metrics_per_query,
+                max_aggregators: cfg.max_aggregators_per_metric,
+                missing_start: cfg
+                    .missing_start
+                    .as_deref()
+                    .map(MissingStart::parse)
+                    .transpose()
+                    .map_err(|e| anyhow::anyhow!("Invalid missing_start: {}", e))?,
+            },
+            admin_token: cfg.admin_token.clone(),
+            trusted_proxies: forwarded::parse_networks(
+                "trusted_proxies",
+                cfg.trusted_proxies.as_deref().unwrap_or_default(),
+            )?,
+            ip_filter: IpFilter::from_config("ip_access", &cfg.ip_access)?,
+            admin_ip_filter: IpFilter::from_config("admin_ip_access", &cfg.admin_ip_access)?,
+            api_keys: ApiKeys::from_config(&api_keys)?,
+            quotas: Quotas::from_config(&api_keys, &cfg.quota_store)?,
+            chaos: Chaos::from_config(&cfg.chaos)?,
+            recorder: Recorder::from_config(&cfg.recorder)?,
+            inflight: singleflight::Group::default(),
+            cache: cfg
+                .cache
+                .as_ref()
+                .map(ResponseCache::from_config)
+                .transpose()?,
+            stream_simple_queries: cfg.stream_simple_queries.unwrap_or(false),
+            body_read_timeout: match cfg.body_read_timeout_secs {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => Some(crate::slow_client::DEFAULT_BODY_READ_TIMEOUT),
+            },
+            request_timeout: cfg
+                .request_timeout_secs
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            omit_result_fields: Omit::default()
+                .with(cfg.omit_result_fields.iter().flatten().map(String::as_str))
+                .map_err(|e| anyhow::anyhow!("Invalid omit_result_fields: {}", e))?,
+            annotate_backend: cfg.annotate_backend.clone(),
+            detect_merge_conflicts: cfg.detect_merge_conflicts.unwrap_or(false),
+            debug_header: cfg.debug_header.unwrap_or(true),
+            server_timing: cfg.server_timing.unwrap_or(true),
+            dns_refresh,
+            shutting_down: AtomicBool::new(false),
+            events: Events::default(),
+            spool_replay: cfg
+                .ingest_spool
+                .as_ref()
+                .map(|s| Duration::from_secs(s.replay_interval_secs.unwrap_or(10).max(1))),
+        })
+    }
+
+    /// Pins the SNI names of backends with `sni_hostname` to their configured hosts.
+    pub async fn pin_backend_addresses(&self) -> anyhow::Result<()> {
+        for b in &self.backends {
+            b.client.pin().await?;
+        }
+        Ok(())
+    }
+
+    /// Swaps in backend clients with empty connection pools.
+    pub async fn rebuild_clients(&self) -> anyhow::Result<()> {
+        for b in &self.backends {
+            b.client.rebuild().await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the backend of the first rule matching `metric_name` alone whose backend is
+    /// not drained and whose time tier holds current data.
+    #[cfg(test)]
+    pub fn backend_for(&self, metric_name: &str) -> Option<(usize, &BackendTarget)> {
+        self.select(&Subject::metric(metric_name), now_ms())
+    }
+
+    /// Recompiles the routing table from `cfg` and makes it the current generation. Backends
+    /// are fixed at startup, so `cfg` must declare the same backends in the same order; only
+    /// routing (rules, backend patterns and tiers, `route_selection`, `[hash_ring]`) changes.
+    pub fn reload(&self, cfg: &Config) -> anyhow::Result<u64> {
+        let names: Vec<String> = cfg
+            .backends
+            .iter()
+            .map(|b| b.name.clone().unwrap_or_else(|| b.url.clone()))
+            .collect();
+        if names.len() != self.backends.len()
+            || names.iter().zip(&self.backends).any(|(n, b)| *n != b.name)
+        {
+            anyhow::bail!("The config declares different backends; restart to apply them");
+        }
+        let tiers = cfg
+            .backends
+            .iter()
+            .zip(&names)
+            .map(|(b, name)| parse_tier(&b.newer_than, &b.older_than, name))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let routes = routes::compile(cfg, &self.backends, &tiers)?;
+        let blocked = routes::compile_blocked(cfg)?;
+        let number = self.generations.push(cfg.redacted(), routes, blocked);
+        info!("Loaded routing config generation {}", number);
+        self.events
+            .publish("config_reloaded", json!({ "generation": number }));
+        Ok(number)
+    }
+
+    /// Re-reads the config file the proxy was started with and reloads its routing.
+    pub fn reload_from_file(&self) -> anyhow::Result<u64> {
+        let Some(path) = &self.config_path else {
+            anyhow::bail!("The config was not loaded from a file");
+        };
+        self.reload(&Config::from_file(path)?)
+    }
+
+    /// Routing rules of the current config generation.
+    pub fn routing(&self) -> Arc<Generation> {
+        self.generations.current()
+    }
+
+    /// Returns the backend of the first rule matching `subject` whose backend is not drained
+    /// and whose time tier holds data at `at_ms`.
+    pub fn select(&self, subject: &Subject, at_ms: i64) -> Option<(usize, &BackendTarget)> {
+        let (i, _) = self.pick(subject, at_ms, false)?;
+        Some((i, &self.backends[i]))
+    }
+
+    /// The backend datapoints of `subject` are written to: that of the rule `select` picks,
+    /// or its write backend, with the dual-write secondary of the rule.
+    pub fn select_write(
+        &self,
+        subject: &Subject,
+        at_ms: i64,
+    ) -> Option<(usize, Option<Secondary>)> {
+        self.pick(subject, at_ms, true)
+    }
+
+    fn pick(
+        &self,
+        subject: &Subject,
+        at_ms: i64,
+        write: bool,
+    ) -> Option<(usize, Option<Secondary>)> {
+        let now = now_ms();
+        let routing = self.routing();
+        if is_blocked(&routing, subject.metric) || self.known_unroutable(&routing, subject.metric) {
+            return None;
+        }
+        let Some(rule) = self
+            .live_routes(&routing.routes, subject, write)
+            .find(|r| r.tier.is_none_or(|t| t.contains(at_ms, now)))
+        else {
+            self.remember_unroutable(&routing, subject.metric);
+            return None;
+        };
+        let backend = if write {
+            rule.write_backend_for(subject.metric)
+        } else {
+            rule.backend_for(subject.metric)
+        };
+        self.trace_route(rule, subject.metric, backend);
+        Some((backend, rule.secondary))
+    }
+
+    /// Whether `metric` was recently found to match no rule of `routing`.
+    fn known_unroutable(&self, routing: &Generation, metric: &str) -> bool {
+        let Some(cache) = &self.unroutable_cache else {
+            return false;
+        };
+        if !cache.contains(routing.number, metric) {
+            return false;
+        }
+        self.metrics
+            .inc_counter("kairos_proxy_unroutable_cache_hits_total", &[]);
+        true
+    }
+
+    /// Remembers `metric` as unroutable if no rule of `routing` can match its name, whatever
+    /// the tags, headers and time of a request.
+    fn remember_unroutable(&self, routing: &Generation, metric: &str) {
+        if let Some(cache) = &self.unroutable_cache {
+            if !routing.routes.iter().any(|r| r.matches_metric(metric)) {
+                cache.insert(routing.number, metric);
+            }
+        }
+    }
+
+    /// Records a routing decision on the request's debug trace, if there is one.
+    fn trace_route(&self, rule: &Route, metric: &str, backend: usize) {
+        debug_trace::route(
+            metric,
+            &self.backends[backend].name,
+            rule.position,
+            rule.metric.as_ref().map(|m| m.as_str()),
+        );
+    }
+
+    /// Rules matching `subject` whose backend (the write backend for `write`) is not drained.
+    fn live_routes<'a>(
+        &'a self,
+        routes: &'a [Route],
+        subject: &'a Subject,
+        write: bool,
+    ) -> impl Iterator<Item = &'a Route> + 'a {
+        routes.iter().filter(move |r| {
+            let backend = if write {
+                r.write_backend_for(subject.metric)
+            } else {
+                r.backend_for(subject.metric)
+            };
+            !self.backends[backend].is_draining() && r.matches(subject)
         })
     }
+
+    /// Routes a metric queried over `range` to the backends holding that data. Without time
+    /// tiers this is just the first matching rule. Otherwise the range is carved up in rule
+    /// order, and each piece carries the sub-range its backend should be asked for (`None`
+    /// when it is the whole query). Parts no backend holds are dropped; an empty result means
+    /// the metric is unroutable.
+    pub fn route(
+        &self,
+        subject: &Subject,
+        range: Option<TimeRange>,
+    ) -> Vec<(usize, Option<TimeRange>)> {
+        let Some(range) = range else {
+            return self
+                .select(subject, now_ms())
+                .map(|(i, _)| (i, None))
+                .into_iter()
+                .collect();
+        };
+        let now = now_ms();
+        let piece = |r: TimeRange| (r != range).then_some(r);
+        let mut uncovered = vec![range];
+        let mut routes = Vec::new();
+        let routing = self.routing();
+        if is_blocked(&routing, subject.metric) || self.known_unroutable(&routing, subject.metric) {
+            return routes;
+        }
+        for rule in self.live_routes(&routing.routes, subject, false) {
+            if uncovered.is_empty() {
+                break;
+            }
+            let Some(tier) = rule.tier else {
+                let backend = rule.backend_for(subject.metric);
+                self.trace_route(rule, subject.metric, backend);
+                routes.extend(uncovered.drain(..).map(|r| (backend, piece(r))));
+                break;
+            };
+            let mut rest = Vec::new();
+            for r in uncovered {
+                let (held, missing) = tier.split(r, now);
+                if let Some(h) = held {
+                    let backend = rule.backend_for(subject.metric);
+                    self.trace_route(rule, subject.metric, backend);
+                    routes.push((backend, piece(h)));
+                }
+                rest.extend(missing);
+            }
+            uncovered = rest;
+        }
+        if routes.is_empty() {
+            self.remember_unroutable(&routing, subject.metric);
+        } else if !uncovered.is_empty() {
+            warn!(
+                "No backend holds part of the queried range for '{}': {:?}",
+                subject.metric, uncovered
+            );
+        }
+        routes
+    }
+
+    /// Body size limit of requests to `path`, and what gzip bodies there may expand to.
+    pub fn body_limits(&self, path: &str) -> (usize, decompress::Limits) {
+        self.endpoint_body_limits
+            .get(path)
+            .copied()
+            .unwrap_or((self.max_request_body_bytes, self.decompression))
+    }
+
+    /// How results for `subject` from several backends are merged: the strategy of the first
+    /// rule it matches.
+    pub fn merge_strategy(&self, subject: &Subject) -> MergeStrategy {
+        let routing = self.routing();
+        if routing
+            .routes
+            .iter()
+            .all(|r| r.merge == MergeStrategy::Union)
+        {
+            return MergeStrategy::Union;
+        }
+        let strategy = self
+            .live_routes(&routing.routes, subject, false)
+            .next()
+            .map_or(MergeStrategy::Union, |r| r.merge);
+        strategy
+    }
+
+    /// Values of the request headers that routing rules look at, for keying shared results
+    /// (cache, in-flight queries) so that requests routed differently never share one.
+    pub fn routing_key(&self, headers: &hyper::HeaderMap) -> String {
+        let mut key = String::new();
+        let routing = self.routing();
+        for name in routing.routes.iter().flat_map(|r| r.header_names()) {
+            if let Some(v) = headers.get(name).and_then(|v| v.to_str().ok()) {
+                key.push_str(name.as_str());
+                key.push('=');
+                key.push_str(v);
+                key.push(' ');
+            }
+        }
+        key
+    }
+
+    /// Whether the proxy should receive traffic: it is not shutting down and at least one
+    /// backend is not drained.
+    pub fn is_ready(&self) -> bool {
+        !self.shutting_down.load(Ordering::Relaxed)
+            && self.backends.iter().any(|b| !b.is_draining())
+    }
+
+    /// Status for a metric that could not be routed: `FORBIDDEN` if it is blocked,
+    /// `SERVICE_UNAVAILABLE` when a rule for its name points at a drained backend,
+    /// `BAD_GATEWAY` otherwise.
+    pub fn unroutable_status(&self, metric_name: &str) -> StatusCode {
+        self.unroutable(metric_name).status()
+    }
+
+    /// Why a metric could not be routed: `FORBIDDEN` if it is blocked, the drained backend a
+    /// rule for its name points at, or `BAD_GATEWAY` if no rule matches.
+    pub fn unroutable(&self, metric_name: &str) -> QueryError {
+        let routing = self.routing();
+        if is_blocked(&routing, metric_name) {
+            warn!("Refused blocked metric '{}'", metric_name);
+            self.metrics
+                .inc_counter("kairos_proxy_blocked_metrics_total", &[]);
+            return StatusCode::FORBIDDEN.into();
+        }
+        match routing
+            .routes
+            .iter()
+            .map(|r| &self.backends[r.backend_for(metric_name)])
+            .zip(routing.routes.iter())
+            .find(|(b, r)| b.is_draining() && r.matches_metric(metric_name))
+        {
+            Some((b, _)) => {
+                warn!(
+                    "Metric '{}' only matches drained backend '{}'",
+                    metric_name, b.name
+                );
+                BackendError::new(self, &b.name, Failure::Drained).into()
+            }
+            None => StatusCode::BAD_GATEWAY.into(),
+        }
+    }
+
+    /// Waits until `target`'s rate limit admits one more request. If that would take longer
+    /// than `max_wait`, returns the wait instead, for clients to retry after.
+    pub async fn throttle(
+        &self,
+        target: &BackendTarget,
+        max_wait: Duration,
+    ) -> Result<(), Duration> {
+        let Some(bucket) = &target.rate_limit else {
+            return Ok(());
+        };
+        match bucket.reserve(max_wait) {
+            Ok(wait) => {
+                if !wait.is_zero() {
+                    debug!("Delaying request to '{}' by {:?}", target.name, wait);
+                    tokio::time::sleep(wait).await;
+                }
+                Ok(())
+            }
+            Err(wait) => {
+                warn!("Rate limit of backend '{}' exhausted", target.name);
+                self.metrics.inc_counter(
+                    "kairos_proxy_rate_limited_total",
+                    &[("backend", &target.name)],
+                );
+                Err(wait)
+            }
+        }
+    }
+
+    /// Whether a backend request counts as a success in [`record_outcome`](Self::record_outcome):
+    /// the backend answered, with anything but a server error. Every mode uses this, so
+    /// primary and canary error rates mean the same thing.
+    pub fn succeeded(resp: &reqwest::Result<reqwest::Response>) -> bool {
+        matches!(resp, Ok(r) if !r.status().is_server_error())
+    }
+
+    /// Records the outcome of a request: its latency feeds the chosen instance's stats and the
+    /// adaptive concurrency limit and,
+    /// for backends with a canary, primary and canary error rates and latencies are exported
+    /// side by side.
+    pub fn record_outcome(
+        &self,
+        target: &BackendTarget,
+        selected: &Selected,
+        success: bool,
+        elapsed: Duration,
+    ) {
+        if let Some(instance) = &selected.instance {
+            instance.observe(success, elapsed);
+        }
+        target.estimates.observe(success, elapsed);
+        if let Some(latency) = target.estimates.latency() {
+            self.metrics.set_gauge(
+                "kairos_proxy_backend_latency_ewma_seconds",
+                &[("backend", &target.name)],
+                latency.as_secs_f64(),
+            );
+        }
+        self.metrics.set_gauge(
+            "kairos_proxy_backend_error_rate_ewma",
+            &[("backend", &target.name)],
+            target.estimates.error_rate(),
+        );
+        if success {
+            self.events.backend_answered(&target.name);
+        }
+        self.admission.observe(elapsed);
+        if target.canary.is_none() {
+            return;
+        }
+        let selected = if selected.canary { "canary" } else { "primary" };
+        let outcome = if success { "success" } else { "error" };
+        self.metrics.inc_counter(
+            "kairos_proxy_canary_requests_total",
+            &[
+                ("backend", &target.name),
+                ("target", selected),
+                ("outcome", outcome),
+            ],
+        );
+        self.metrics.observe_duration(
+            "kairos_proxy_canary_request_duration_seconds",
+            &[("backend", &target.name), ("target", selected)],
+            elapsed,
+        );
+    }
+}
+
+fn is_blocked(routing: &Generation, metric: &str) -> bool {
+    routing.blocked.iter().any(|re| re.is_match(metric))
 }
 
 #[cfg(test)]
@@ -71,11 +912,13 @@ mod tests {
                 pattern: "^a".to_string(),
                 url: "http://127.0.0.1:9000".to_string(),
                 token: None,
+                ..Default::default()
             }],
             timeout_secs: Some(1),
             max_outbound_concurrency: Some(4),
             mode: Some(Mode::Simple),
             max_request_body_bytes: None,
+            ..Default::default()
         };
         let st = AppState::from_config(&cfg).expect("build state");
         // mode should be set to Simple
@@ -86,6 +929,59 @@ mod tests {
         assert_eq!(st.backends.len(), 1, "should have one backend compiled");
     }
 
+    #[test]
+    fn canaries_get_no_traffic_until_given_a_share() {
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: "^a".to_string(),
+                url: "http://127.0.0.1:9000".to_string(),
+                canary_url: Some("http://127.0.0.1:9001".to_string()),
+                mirror_url: Some("http://127.0.0.1:9002".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let st = AppState::from_config(&cfg).expect("build state");
+        assert_eq!(st.backends[0].canary.as_ref().expect("canary").percent, 0.0);
+        assert_eq!(
+            st.backends[0].mirror.as_ref().expect("mirror").percent,
+            100.0
+        );
+
+        let answer = |status: u16| {
+            Ok(reqwest::Response::from(
+                axum::http::Response::builder()
+                    .status(status)
+                    .body("")
+                    .unwrap(),
+            ))
+        };
+        assert!(AppState::succeeded(&answer(200)));
+        assert!(AppState::succeeded(&answer(404)));
+        assert!(!AppState::succeeded(&answer(503)));
+    }
+
+    #[test]
+    fn sni_hostname_keeps_the_configured_url() {
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: "^a".to_string(),
+                url: "https://10.0.0.7:8443/".to_string(),
+                sni_hostname: Some("kairosdb.internal".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let st = AppState::from_config(&cfg).expect("build state");
+        let target = &st.backends[0];
+        // Re-resolved by the DNS refresh, so it must stay the connected-to host
+        assert_eq!(target.url.as_str(), "https://10.0.0.7:8443/");
+        assert_eq!(
+            target.select().url.as_str(),
+            "https://kairosdb.internal:8443/"
+        );
+    }
+
     #[test]
     fn appstate_rejects_invalid_backend_url() {
         let cfg = Config {
@@ -94,11 +990,13 @@ mod tests {
                 pattern: "^test".to_string(),
                 url: "not-a-valid-url".to_string(),
                 token: None,
+                ..Default::default()
             }],
             timeout_secs: Some(1),
             max_outbound_concurrency: Some(4),
             mode: Some(Mode::Multi),
             max_request_body_bytes: None,
+            ..Default::default()
         };
         let result = AppState::from_config(&cfg);
         assert!(result.is_err(), "should fail with invalid URL");
@@ -111,4 +1009,65 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn path_prefix_is_put_before_every_endpoint() {
+        let backend = |url: &str, path_prefix: Option<&str>| Backend {
+            pattern: ".*".to_string(),
+            url: url.to_string(),
+            path_prefix: path_prefix.map(str::to_string),
+            ..Default::default()
+        };
+        let cfg = Config {
+            backends: vec![
+                backend("https://gateway.corp", Some("kairos/")),
+                backend("https://gateway.corp/ignored/", Some("/a/b")),
+                backend("http://kairos:8080", None),
+            ],
+            ..Default::default()
+        };
+        let st = AppState::from_config(&cfg).expect("build state");
+        let url = |i: usize, endpoint: &str| {
+            let b = &st.backends[i];
+            b.endpoint_url(&b.url, endpoint).unwrap().to_string()
+        };
+        assert_eq!(
+            url(0, "/api/v1/datapoints/query"),
+            "https://gateway.corp/kairos/api/v1/datapoints/query"
+        );
+        assert_eq!(
+            url(1, "api/v1/datapoints"),
+            "https://gateway.corp/a/b/api/v1/datapoints"
+        );
+        assert_eq!(
+            url(2, "/api/v1/datapoints"),
+            "http://kairos:8080/api/v1/datapoints"
+        );
+    }
+
+    #[test]
+    fn blocked_metrics_are_refused_before_routing() {
+        let cfg = Config {
+            backends: vec![Backend {
+                pattern: ".*".to_string(),
+                url: "http://127.0.0.1:9000".to_string(),
+                ..Default::default()
+            }],
+            block_patterns: Some(vec!["^legacy\\.".to_string()]),
+            ..Default::default()
+        };
+        let st = AppState::from_config(&cfg).expect("build state");
+        assert!(st.backend_for("legacy.cpu").is_none());
+        assert!(st.route(&Subject::metric("legacy.cpu"), None).is_empty());
+        assert_eq!(st.unroutable_status("legacy.cpu"), StatusCode::FORBIDDEN);
+        assert!(st.backend_for("cpu.legacy").is_some());
+
+        // Reloading without the pattern unblocks the namespace
+        let reloaded = Config {
+            block_patterns: None,
+            ..cfg
+        };
+        st.reload(&reloaded).expect("reload");
+        assert!(st.backend_for("legacy.cpu").is_some());
+    }
 }
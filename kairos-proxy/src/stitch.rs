@@ -0,0 +1,216 @@
+//! Stitching of time-split query pieces.
+//!
+//! When time tiers split a metric's range, each backend answers for its own sub-range, but
+//! not always only for it: aligned aggregation buckets can carry a timestamp before the
+//! sub-range start, and tiers that overlap in what they hold return the same points twice.
+//! Stitching joins the pieces of each series (the metric's place in the client's query plus
+//! the result's tags and `group_by` groups) into one result in timestamp order and keeps a
+//! single value per timestamp, preferring the piece whose sub-range holds that timestamp. The
+//! pieces' `sample_size`s are added up.
+
+use crate::timerange::TimeRange;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use tracing::debug;
+
+/// A backend's response, with the sub-range it answers for when time tiers split the query
+/// and the index in the client's query of the metric each of its queries answers.
+pub(crate) type Answer = (Option<TimeRange>, Vec<usize>, Value);
+
+/// One piece's result for a series and the sub-range it was asked for.
+type Piece = (TimeRange, Map<String, Value>);
+
+/// Responses of time-split pieces (those with a sub-range) are replaced by a single response
+/// with their series stitched; the others pass through.
+pub(crate) fn stitch(responses: Vec<Answer>) -> Vec<Value> {
+    let mut whole = Vec::new();
+    let mut pieces = Vec::new();
+    for (range, metrics, response) in responses {
+        match range {
+            Some(r) => pieces.push((r, metrics, response)),
+            None => whole.push(response),
+        }
+    }
+    if pieces.is_empty() {
+        return whole;
+    }
+    pieces.sort_by_key(|(r, _, _)| r.start_ms);
+
+    // Series key -> (sub-range, result) of every piece holding it, oldest piece first
+    let mut series: BTreeMap<(usize, String), Vec<Piece>> = BTreeMap::new();
+    let mut sample_size: Option<u64> = None;
+    for (range, metrics, response) in pieces {
+        let queries = response.get("queries").and_then(Value::as_array);
+        for (i, query) in queries.into_iter().flatten().enumerate() {
+            if let Some(n) = query.get("sample_size").and_then(Value::as_u64) {
+                sample_size = Some(sample_size.unwrap_or(0) + n);
+            }
+            let results = query
+                .get("results")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_object);
+            for result in results {
+                // Queries answer the piece's metrics in order
+                let key = (
+                    metrics.get(i).copied().unwrap_or(usize::MAX),
+                    format!(
+                        "{} {} {}",
+                        result.get("name").unwrap_or(&Value::Null),
+                        result.get("tags").unwrap_or(&Value::Null),
+                        result.get("group_by").unwrap_or(&Value::Null)
+                    ),
+                );
+                series.entry(key).or_default().push((range, result.clone()));
+            }
+        }
+    }
+
+    let results: Vec<Value> = series.into_values().map(join).collect();
+    let mut query = Map::new();
+    if let Some(n) = sample_size {
+        query.insert("sample_size".into(), n.into());
+    }
+    query.insert("results".into(), results.into());
+    whole.push(json!({ "queries": [query] }));
+    whole
+}
+
+/// Joins the pieces of one series: tags are unioned and values stitched.
+fn join(pieces: Vec<Piece>) -> Value {
+    let mut points = Vec::new();
+    let mut tags: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    let mut joined = Map::new();
+    for (order, (range, mut result)) in pieces.into_iter().enumerate() {
+        if let Some(Value::Array(values)) = result.remove("values") {
+            for v in values {
+                let ts = v.get(0).and_then(Value::as_i64).unwrap_or(i64::MIN);
+                let owned = (range.start_ms..=range.end_ms).contains(&ts);
+                points.push((ts, !owned, order, v));
+            }
+        }
+        if let Some(Value::Object(t)) = result.remove("tags") {
+            for (k, values) in t {
+                let entry = tags.entry(k).or_default();
+                for v in values.as_array().into_iter().flatten() {
+                    if !entry.contains(v) {
+                        entry.push(v.clone());
+                    }
+                }
+            }
+        }
+        for (k, v) in result {
+            joined.entry(k).or_insert(v);
+        }
+    }
+    points.sort_by_key(|p| (p.0, p.1, p.2));
+    let before = points.len();
+    points.dedup_by_key(|p| p.0);
+    if points.len() < before {
+        debug!(
+            "Reconciled {} overlapping value(s) of {}",
+            before - points.len(),
+            joined.get("name").unwrap_or(&serde_json::Value::Null)
+        );
+    }
+    joined.insert(
+        "tags".into(),
+        Value::Object(tags.into_iter().map(|(k, v)| (k, v.into())).collect()),
+    );
+    joined.insert(
+        "values".into(),
+        points.into_iter().map(|p| p.3).collect::<Vec<_>>().into(),
+    );
+    Value::Object(joined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stitches_pieces_in_order_and_keeps_the_owner_of_each_timestamp() {
+        let cold = TimeRange {
+            start_ms: 0,
+            end_ms: 99,
+        };
+        let hot = TimeRange {
+            start_ms: 100,
+            end_ms: 200,
+        };
+        let result = |tags: Value, values: Value| json!({ "queries": [{ "results": [{ "name": "cpu", "tags": tags, "values": values }] }] });
+        let other = json!({ "queries": [{ "results": [{ "name": "mem", "values": [[5, 1]] }] }] });
+        let stitched = stitch(vec![
+            // The hot bucket aligned to 90 spills before its sub-range; cold owns 90
+            (
+                Some(hot),
+                vec![0],
+                result(
+                    json!({ "dc": ["eu"] }),
+                    json!([[90, 9], [100, 10], [150, 15]]),
+                ),
+            ),
+            (
+                Some(cold),
+                vec![0],
+                result(json!({ "dc": ["eu"] }), json!([[50, 5], [90, 1]])),
+            ),
+            (None, vec![1], other.clone()),
+        ]);
+        assert_eq!(stitched[0], other);
+        assert_eq!(
+            stitched[1],
+            json!({ "queries": [{ "results": [{
+                "name": "cpu",
+                "tags": { "dc": ["eu"] },
+                "values": [[50, 5], [90, 1], [100, 10], [150, 15]]
+            }] }] })
+        );
+    }
+
+    #[test]
+    fn keeps_series_of_different_queries_and_tags_apart() {
+        let cold = TimeRange {
+            start_ms: 0,
+            end_ms: 99,
+        };
+        let hot = TimeRange {
+            start_ms: 100,
+            end_ms: 200,
+        };
+        let series = |dc: &str, ts: i64, v: i64| json!({ "name": "cpu", "tags": { "dc": [dc] }, "values": [[ts, v]] });
+        // Metric 0 sums, metric 1 averages the same name; cold holds only metric 0
+        let stitched = stitch(vec![
+            (
+                Some(hot),
+                vec![0, 1],
+                json!({ "queries": [
+                    { "sample_size": 3, "results": [series("eu", 150, 1), series("us", 150, 2)] },
+                    { "sample_size": 2, "results": [series("eu", 150, 3)] }
+                ] }),
+            ),
+            (
+                Some(cold),
+                vec![0],
+                json!({ "queries": [{ "sample_size": 4, "results": [series("eu", 50, 4)] }] }),
+            ),
+        ]);
+        assert_eq!(stitched.len(), 1);
+        assert_eq!(stitched[0]["queries"][0]["sample_size"], 9);
+        let values: Vec<&Value> = stitched[0]["queries"][0]["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| &r["values"])
+            .collect();
+        assert_eq!(
+            values,
+            [
+                &json!([[50, 4], [150, 1]]),
+                &json!([[150, 2]]),
+                &json!([[150, 3]])
+            ]
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! KairosDB's read-only system endpoints, answered for all backends at once.
+//!
+//! `/api/v1/features` lists only what every backend supports (features, and the aggregators,
+//! group-bys and properties within them present on all), so clients feature-detecting through
+//! the proxy never pick something one cluster lacks. `/api/v1/health/status` is the union of
+//! the backends' checks, each prefixed with the backend name, and `/api/v1/health/check`
+//! answers `204` only if every backend does.
+
+use crate::admission::Priority;
+use crate::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::{stream::FuturesUnordered, StreamExt};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{debug, error};
+
+const FEATURES_ENDPOINT: &str = "api/v1/features";
+const HEALTH_STATUS_ENDPOINT: &str = "api/v1/health/status";
+const HEALTH_CHECK_ENDPOINT: &str = "api/v1/health/check";
+
+/// Sends `GET endpoint` to every backend that is not drained, once per distinct URL, and
+/// returns the backend names with the response status and JSON body, or `None` if the
+/// request failed.
+async fn fetch_all(state: &AppState, endpoint: &str) -> Vec<(String, Option<(u16, Value)>)> {
+    // Several routing rules may share a backend; ask each distinct URL once
+    let mut urls = BTreeMap::new();
+    for b in state.backends.iter().filter(|b| !b.is_draining()) {
+        let Ok(url) = b.endpoint_url(&b.url, endpoint) else {
+            continue;
+        };
+        urls.entry((url, b.token.get()))
+            .or_insert_with(|| (b.name.clone(), b.client.get()));
+    }
+    let mut futs = FuturesUnordered::new();
+    for ((url, token), (name, client)) in urls {
+        let admission = state.admission.clone();
+        let timeout = state.timeout;
+        futs.push(async move {
+            let _permit = admission.acquire(Priority::Interactive).await;
+            let mut builder = client.get(url.clone()).timeout(timeout);
+            if let Some(t) = token {
+                builder = builder.header("Authorization", format!("Bearer {}", t));
+            }
+            let answer = match builder.send().await {
+                Ok(r) => {
+                    let status = r.status().as_u16();
+                    // Health checks answer 204 with no body
+                    Some((status, r.json::<Value>().await.unwrap_or(Value::Null)))
+                }
+                Err(e) => {
+                    error!("Request to {} failed: {}", url, e);
+                    None
+                }
+            };
+            (name, answer)
+        });
+    }
+    let mut answers = Vec::new();
+    while let Some(answer) = futs.next().await {
+        answers.push(answer);
+    }
+    answers.sort_by(|a, b| a.0.cmp(&b.0));
+    answers
+}
+
+/// Items of `a` (objects identified by `name`) that `b` also has, with their `properties`
+/// intersected the same way.
+fn intersect(a: &[Value], b: &[Value]) -> Vec<Value> {
+    a.iter()
+        .filter_map(|item| {
+            let name = item.get("name")?;
+            let other = b.iter().find(|o| o.get("name") == Some(name))?;
+            let mut item = item.clone();
+            if let (Some(mine), Some(theirs)) = (
+                item.get("properties").and_then(|p| p.as_array()),
+                other.get("properties").and_then(|p| p.as_array()),
+            ) {
+                item["properties"] = Value::Array(intersect(mine, theirs));
+            }
+            Some(item)
+        })
+        .collect()
+}
+
+/// The features every answering backend supports; `BAD_GATEWAY` if none answered.
+async fn common_features(state: &AppState, endpoint: &str) -> Result<Vec<Value>, StatusCode> {
+    let lists: Vec<Vec<Value>> = fetch_all(state, endpoint)
+        .await
+        .into_iter()
+        .filter_map(|(_, answer)| match answer? {
+            (200, Value::Array(list)) => Some(list),
+            // A single category
+            (200, feature @ Value::Object(_)) => Some(vec![feature]),
+            _ => None,
+        })
+        .collect();
+    debug!("Intersecting features of {} backend(s)", lists.len());
+    let mut lists = lists.into_iter();
+    let first = lists.next().ok_or(StatusCode::BAD_GATEWAY)?;
+    Ok(lists.fold(first, |common, list| intersect(&common, &list)))
+}
+
+/// `GET /api/v1/features`
+pub async fn features_handler(State(state): State<Arc<AppState>>) -> Result<Response, StatusCode> {
+    let features = common_features(&state, FEATURES_ENDPOINT).await?;
+    Ok(Json(features).into_response())
+}
+
+/// `GET /api/v1/features/:category`
+pub async fn feature_category_handler(
+    State(state): State<Arc<AppState>>,
+    Path(category): Path<String>,
+) -> Result<Response, StatusCode> {
+    // The category becomes part of the backend URL path
+    if !category
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let endpoint = format!("{}/{}", FEATURES_ENDPOINT, category);
+    let features = common_features(&state, &endpoint).await?;
+    let feature = features.into_iter().next().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(feature).into_response())
+}
+
+/// `GET /api/v1/health/status`
+pub async fn health_status_handler(State(state): State<Arc<AppState>>) -> Json<Vec<String>> {
+    let mut checks = Vec::new();
+    for (name, answer) in fetch_all(&state, HEALTH_STATUS_ENDPOINT).await {
+        match answer {
+            Some((_, Value::Array(list))) => checks.extend(
+                list.iter()
+                    .filter_map(|c| c.as_str())
+                    .map(|c| format!("{}: {}", name, c)),
+            ),
+            Some((status, _)) => checks.push(format!("{}: answered {}", name, status)),
+            None => checks.push(format!("{}: unreachable", name)),
+        }
+    }
+    Json(checks)
+}
+
+/// `GET /api/v1/health/check`
+pub async fn health_check_handler(State(state): State<Arc<AppState>>) -> StatusCode {
+    let answers = fetch_all(&state, HEALTH_CHECK_ENDPOINT).await;
+    if !answers.is_empty()
+        && answers
+            .iter()
+            .all(|(_, a)| a.as_ref().is_some_and(|(s, _)| (200..300).contains(s)))
+    {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn keeps_features_every_backend_has() {
+        let a = json!([
+            { "name": "aggregators", "label": "Aggregator", "properties": [
+                { "name": "avg" }, { "name": "sum" }, { "name": "percentile" }
+            ] },
+            { "name": "group_by", "properties": [{ "name": "tag" }] }
+        ]);
+        let b = json!([
+            { "name": "aggregators", "label": "Aggregator", "properties": [
+                { "name": "sum" }, { "name": "avg" }
+            ] }
+        ]);
+        let common = intersect(a.as_array().unwrap(), b.as_array().unwrap());
+        assert_eq!(
+            Value::Array(common),
+            json!([{ "name": "aggregators", "label": "Aggregator", "properties": [
+                { "name": "avg" }, { "name": "sum" }
+            ] }])
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Per-backend renaming of tag keys, for clusters whose tag naming differs from what clients
+//! use. Keys are renamed in the tag filters and `tag` group-bys of outbound queries and renamed
+//! back in the results.
+
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+
+pub struct TagKeyMap {
+    // Client key to backend key
+    outbound: HashMap<String, String>,
+    // Backend key to client key
+    inbound: HashMap<String, String>,
+}
+
+impl TagKeyMap {
+    /// Builds the map from a backend's `tag_key_map`. Two client keys may not share a backend
+    /// key, or results could not be renamed back.
+    pub fn new(map: &BTreeMap<String, String>, backend: &str) -> anyhow::Result<Self> {
+        let mut inbound = HashMap::new();
+        for (client, renamed) in map {
+            if let Some(other) = inbound.insert(renamed.clone(), client.clone()) {
+                anyhow::bail!(
+                    "Backend '{}' maps both tag keys '{}' and '{}' to '{}'",
+                    backend,
+                    other,
+                    client,
+                    renamed
+                );
+            }
+        }
+        Ok(TagKeyMap {
+            outbound: map.clone().into_iter().collect(),
+            inbound,
+        })
+    }
+
+    /// Renames tag keys in the metrics of a query sent to the backend.
+    pub fn outbound(&self, query: &mut Value) {
+        let Some(metrics) = query.get_mut("metrics").and_then(Value::as_array_mut) else {
+            return;
+        };
+        for metric in metrics {
+            if let Some(tags) = metric.get_mut("tags").and_then(Value::as_object_mut) {
+                rename_keys(tags, &self.outbound);
+            }
+            rename_group_by(metric, &self.outbound);
+        }
+    }
+
+    /// Renames tag keys in the results of a backend response back to the client's names.
+    pub fn inbound(&self, response: &mut Value) {
+        let Some(queries) = response.get_mut("queries").and_then(Value::as_array_mut) else {
+            return;
+        };
+        let results = queries
+            .iter_mut()
+            .filter_map(|q| q.get_mut("results").and_then(Value::as_array_mut))
+            .flatten();
+        for result in results {
+            if let Some(tags) = result.get_mut("tags").and_then(Value::as_object_mut) {
+                rename_keys(tags, &self.inbound);
+            }
+            rename_group_by(result, &self.inbound);
+        }
+    }
+}
+
+fn rename(key: &str, map: &HashMap<String, String>) -> String {
+    map.get(key).cloned().unwrap_or_else(|| key.to_string())
+}
+
+fn rename_keys(obj: &mut Map<String, Value>, map: &HashMap<String, String>) {
+    *obj = std::mem::take(obj)
+        .into_iter()
+        .map(|(k, v)| (rename(&k, map), v))
+        .collect();
+}
+
+/// Renames the tags of `tag` group-bys: the `tags` list of a query, plus the `group` of a result.
+fn rename_group_by(parent: &mut Value, map: &HashMap<String, String>) {
+    let Some(group_by) = parent.get_mut("group_by").and_then(Value::as_array_mut) else {
+        return;
+    };
+    for g in group_by {
+        if g.get("name").and_then(Value::as_str) != Some("tag") {
+            continue;
+        }
+        if let Some(tags) = g.get_mut("tags").and_then(Value::as_array_mut) {
+            for tag in tags {
+                if let Some(k) = tag.as_str() {
+                    *tag = Value::String(rename(k, map));
+                }
+            }
+        }
+        if let Some(group) = g.get_mut("group").and_then(Value::as_object_mut) {
+            rename_keys(group, map);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn renames_tags_out_and_back() {
+        let cfg = BTreeMap::from([("host".to_string(), "hostname".to_string())]);
+        let map = TagKeyMap::new(&cfg, "old").unwrap();
+
+        let mut query = json!({ "metrics": [{
+            "name": "cpu",
+            "tags": { "host": ["a"], "dc": ["eu"] },
+            "group_by": [{ "name": "tag", "tags": ["host"] }, { "name": "time" }]
+        }]});
+        map.outbound(&mut query);
+        assert_eq!(
+            query["metrics"][0]["tags"],
+            json!({ "hostname": ["a"], "dc": ["eu"] })
+        );
+        assert_eq!(
+            query["metrics"][0]["group_by"][0]["tags"],
+            json!(["hostname"])
+        );
+
+        let mut response = json!({ "queries": [{ "results": [{
+            "name": "cpu",
+            "tags": { "hostname": ["a"] },
+            "group_by": [{ "name": "tag", "tags": ["hostname"], "group": { "hostname": "a" } }],
+            "values": []
+        }]}]});
+        map.inbound(&mut response);
+        let result = &response["queries"][0]["results"][0];
+        assert_eq!(result["tags"], json!({ "host": ["a"] }));
+        assert_eq!(result["group_by"][0]["tags"], json!(["host"]));
+        assert_eq!(result["group_by"][0]["group"], json!({ "host": "a" }));
+
+        let clash = BTreeMap::from([
+            ("host".to_string(), "h".to_string()),
+            ("node".to_string(), "h".to_string()),
+        ]);
+        assert!(TagKeyMap::new(&clash, "old").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! The proxy's own metrics written to KairosDB.
+//!
+//! With a `[self_telemetry]` table, every series of the `/metrics` registry is posted as a
+//! datapoint to the named backend each `interval_secs`, so installations without Prometheus
+//! can chart the proxy next to the data it serves. Counters and histogram `_count` / `_sum`
+//! series are cumulative; apply KairosDB's `rate` aggregator for per-second rates. Labels
+//! become tags, plus an `instance` tag telling replicas apart.
+
+use crate::config::SelfTelemetryConfig;
+use crate::ingest::Origin;
+use crate::metrics::Sample;
+use crate::state::{AppState, BackendTarget};
+use crate::timerange::now_ms;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+pub struct SelfTelemetry {
+    backend: usize,
+    every: Duration,
+    prefix: String,
+    instance: String,
+}
+
+impl SelfTelemetry {
+    pub fn new(cfg: &SelfTelemetryConfig, backends: &[BackendTarget]) -> anyhow::Result<Self> {
+        let backend = backends
+            .iter()
+            .position(|b| b.name == cfg.backend)
+            .ok_or_else(|| {
+                anyhow::anyhow!("[self_telemetry] names unknown backend '{}'", cfg.backend)
+            })?;
+        let instance = cfg
+            .instance
+            .clone()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| "kairos-proxy".to_string());
+        Ok(SelfTelemetry {
+            backend,
+            every: Duration::from_secs(cfg.interval_secs.unwrap_or(60).max(1)),
+            prefix: cfg.metric_prefix.clone().unwrap_or_default(),
+            instance,
+        })
+    }
+
+    /// KairosDB datapoints for `samples` taken at `at_ms`.
+    fn datapoints(&self, samples: Vec<Sample>, at_ms: i64) -> Vec<Value> {
+        samples
+            .into_iter()
+            .filter(|(_, _, v)| v.is_finite())
+            .map(|(name, labels, value)| {
+                let mut tags: Map<String, Value> = labels
+                    .into_iter()
+                    // KairosDB refuses empty tag values
+                    .filter(|(_, v)| !v.is_empty())
+                    .map(|(k, v)| (k, v.into()))
+                    .collect();
+                tags.insert("instance".into(), self.instance.clone().into());
+                json!({
+                    "name": format!("{}{}", self.prefix, name),
+                    "timestamp": at_ms,
+                    "value": value,
+                    "tags": tags,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Writes the metrics registry to the telemetry backend every interval.
+pub fn spawn(state: Arc<AppState>) {
+    let Some(telemetry) = &state.self_telemetry else {
+        return;
+    };
+    let every = telemetry.every;
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(every);
+        // The first tick completes at once; there is nothing worth writing yet
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(telemetry) = &state.self_telemetry else {
+                return;
+            };
+            let points = telemetry.datapoints(state.metrics.snapshot(), now_ms());
+            let count = points.len();
+            let posted = crate::ingest::post(&state, telemetry.backend, points, Origin::Client);
+            if posted.await.accepted() {
+                debug!("Wrote {} self-telemetry datapoint(s)", count);
+            } else {
+                warn!(
+                    "Failed to write self-telemetry to '{}'",
+                    state.backends[telemetry.backend].name
+                );
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_become_tagged_datapoints() {
+        let telemetry = SelfTelemetry {
+            backend: 0,
+            every: Duration::from_secs(60),
+            prefix: "proxy.".to_string(),
+            instance: "proxy-1".to_string(),
+        };
+        let samples = vec![
+            (
+                "requests_total".to_string(),
+                vec![
+                    ("backend".to_string(), "eu".to_string()),
+                    ("route".to_string(), String::new()),
+                ],
+                3.0,
+            ),
+            ("latency_seconds_sum".to_string(), Vec::new(), f64::NAN),
+        ];
+        assert_eq!(
+            telemetry.datapoints(samples, 1000),
+            vec![json!({
+                "name": "proxy.requests_total",
+                "timestamp": 1000,
+                "value": 3.0,
+                "tags": { "backend": "eu", "instance": "proxy-1" }
+            })]
+        );
+    }
+}
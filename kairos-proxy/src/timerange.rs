@@ -0,0 +1,261 @@
+//! Query time ranges and the time windows used for time-tiered routing.
+
+use serde::Deserialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Maps a time unit abbreviation (`ms`, `s`, `min`, `h`, `d`, `w`, `mon`, `y` and their long
+/// forms) to KairosDB's unit name.
+pub(crate) fn kairos_unit(unit: &str) -> Option<&'static str> {
+    Some(match unit {
+        "ms" | "millisecond" | "milliseconds" => "milliseconds",
+        "s" | "sec" | "second" | "seconds" => "seconds",
+        "min" | "minute" | "minutes" => "minutes",
+        "h" | "hour" | "hours" => "hours",
+        "d" | "day" | "days" => "days",
+        "w" | "week" | "weeks" => "weeks",
+        "mon" | "month" | "months" => "months",
+        "y" | "year" | "years" => "years",
+        _ => return None,
+    })
+}
+
+/// Length of one KairosDB time unit in milliseconds. Months and years use 30 and 365 days.
+pub(crate) fn unit_millis(unit: &str) -> Option<i64> {
+    Some(match unit {
+        "milliseconds" => 1,
+        "seconds" => 1_000,
+        "minutes" => 60_000,
+        "hours" => 3_600_000,
+        "days" => 86_400_000,
+        "weeks" => 7 * 86_400_000,
+        "months" => 30 * 86_400_000,
+        "years" => 365 * 86_400_000,
+        _ => return None,
+    })
+}
+
+/// Splits a duration such as `30d` or `12h` into its value and KairosDB unit name.
+pub(crate) fn parse_duration(s: &str) -> Result<(i64, &'static str), String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("duration is missing a unit: {}", s))?;
+    let value: i64 = s[..split]
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    let unit = kairos_unit(&s[split..]).ok_or_else(|| format!("unknown time unit in: {}", s))?;
+    Ok((value, unit))
+}
+
+/// Parses a duration such as `30d` or `12h` into milliseconds.
+pub(crate) fn parse_duration_ms(s: &str) -> Result<i64, String> {
+    let (value, unit) = parse_duration(s)?;
+    value
+        .checked_mul(unit_millis(unit).unwrap_or_default())
+        .ok_or_else(|| format!("duration too long: {}", s))
+}
+
+/// KairosDB accepts numbers or numeric strings for times and relative values.
+fn as_millis(v: &serde_json::Value) -> Option<i64> {
+    match v {
+        serde_json::Value::String(s) => s.parse().ok(),
+        v => v.as_i64().or_else(|| v.as_f64().map(|f| f as i64)),
+    }
+}
+
+/// Length of a `{ "value": 2, "unit": "days" }` relative time; `None` if it overflows.
+fn relative_millis(v: &serde_json::Value) -> Option<i64> {
+    let unit = v.get("unit")?.as_str()?.to_ascii_lowercase();
+    as_millis(v.get("value")?)?.checked_mul(unit_millis(&unit)?)
+}
+
+/// The time fields of a KairosDB query. Kept as raw values so that a malformed time never
+/// fails parsing of the request it is embedded in; it just yields no range.
+#[derive(Deserialize, Default)]
+pub(crate) struct TimeFields {
+    start_absolute: Option<serde_json::Value>,
+    start_relative: Option<serde_json::Value>,
+    end_absolute: Option<serde_json::Value>,
+    end_relative: Option<serde_json::Value>,
+}
+
+/// Inclusive range of epoch milliseconds a query covers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct TimeRange {
+    pub start_ms: i64,
+    pub end_ms: i64,
+}
+
+impl TimeFields {
+    /// Resolves the range against `now_ms`. `None` if the query has no usable start or a
+    /// relative time reaches beyond the representable range.
+    pub fn range(&self, now_ms: i64) -> Option<TimeRange> {
+        let start_ms = match (&self.start_absolute, &self.start_relative) {
+            (Some(abs), _) => as_millis(abs)?,
+            (None, Some(rel)) => now_ms.checked_sub(relative_millis(rel)?)?,
+            (None, None) => return None,
+        };
+        let end_ms = match (&self.end_absolute, &self.end_relative) {
+            (Some(abs), _) => as_millis(abs)?,
+            (None, Some(rel)) => now_ms.checked_sub(relative_millis(rel)?)?,
+            (None, None) => now_ms,
+        };
+        Some(TimeRange { start_ms, end_ms })
+    }
+}
+
+impl TimeRange {
+    pub fn of_query(query: &serde_json::Value, now_ms: i64) -> Option<TimeRange> {
+        TimeFields::deserialize(query).ok()?.range(now_ms)
+    }
+
+    /// Replaces the time fields of a query object with this range as absolute times.
+    pub fn apply(&self, query: &mut serde_json::Map<String, serde_json::Value>) {
+        for k in ["start_relative", "end_relative"] {
+            query.remove(k);
+        }
+        query.insert("start_absolute".into(), self.start_ms.into());
+        query.insert("end_absolute".into(), self.end_ms.into());
+    }
+}
+
+/// Ages of data a time-tiered backend holds: newer than `newer_than_ms` and/or older than
+/// `older_than_ms`, both measured back from now.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeTier {
+    pub newer_than_ms: Option<i64>,
+    pub older_than_ms: Option<i64>,
+}
+
+impl TimeTier {
+    /// Inclusive bounds of the tier at `now_ms`.
+    fn bounds(&self, now_ms: i64) -> (i64, i64) {
+        // An age reaching past the representable range leaves the tier unbounded on that side
+        (
+            self.newer_than_ms
+                .map_or(i64::MIN, |age| now_ms.saturating_sub(age)),
+            self.older_than_ms
+                .map_or(i64::MAX, |age| now_ms.saturating_sub(age).saturating_sub(1)),
+        )
+    }
+
+    pub fn contains(&self, at_ms: i64, now_ms: i64) -> bool {
+        let (lo, hi) = self.bounds(now_ms);
+        (lo..=hi).contains(&at_ms)
+    }
+
+    /// Splits `range` into the part this tier holds, if any, and the parts it does not.
+    pub fn split(&self, range: TimeRange, now_ms: i64) -> (Option<TimeRange>, Vec<TimeRange>) {
+        let (lo, hi) = self.bounds(now_ms);
+        let start = range.start_ms.max(lo);
+        let end = range.end_ms.min(hi);
+        if start > end {
+            return (None, vec![range]);
+        }
+        let mut rest = Vec::new();
+        if range.start_ms < start {
+            rest.push(TimeRange {
+                start_ms: range.start_ms,
+                end_ms: start - 1,
+            });
+        }
+        if end < range.end_ms {
+            rest.push(TimeRange {
+                start_ms: end + 1,
+                end_ms: range.end_ms,
+            });
+        }
+        (
+            Some(TimeRange {
+                start_ms: start,
+                end_ms: end,
+            }),
+            rest,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const DAY: i64 = 86_400_000;
+
+    #[test]
+    fn resolves_relative_and_absolute_ranges() {
+        let now = 100 * DAY;
+        let q = json!({ "start_relative": { "value": "2", "unit": "days" } });
+        assert_eq!(
+            TimeRange::of_query(&q, now),
+            Some(TimeRange {
+                start_ms: 98 * DAY,
+                end_ms: now
+            })
+        );
+        let q = json!({ "start_absolute": 5, "end_relative": { "value": 1, "unit": "DAYS" } });
+        assert_eq!(
+            TimeRange::of_query(&q, now),
+            Some(TimeRange {
+                start_ms: 5,
+                end_ms: 99 * DAY
+            })
+        );
+        assert_eq!(TimeRange::of_query(&json!({ "metrics": [] }), now), None);
+        assert_eq!(parse_duration_ms("30d"), Ok(30 * DAY));
+        assert!(parse_duration_ms("30").is_err());
+    }
+
+    #[test]
+    fn splits_range_at_tier_boundary() {
+        let now = 100 * DAY;
+        let cold = TimeTier {
+            older_than_ms: Some(30 * DAY),
+            ..Default::default()
+        };
+        let range = TimeRange {
+            start_ms: 60 * DAY,
+            end_ms: now,
+        };
+        let (held, rest) = cold.split(range, now);
+        assert_eq!(
+            held,
+            Some(TimeRange {
+                start_ms: 60 * DAY,
+                end_ms: 70 * DAY - 1
+            })
+        );
+        assert_eq!(
+            rest,
+            vec![TimeRange {
+                start_ms: 70 * DAY,
+                end_ms: now
+            }]
+        );
+        assert!(!cold.contains(now, now));
+        assert!(cold.contains(60 * DAY, now));
+    }
+
+    #[test]
+    fn huge_relative_times_yield_no_range() {
+        let now = 100 * DAY;
+        let q = json!({ "start_relative": { "value": i64::MAX, "unit": "years" } });
+        assert_eq!(TimeRange::of_query(&q, now), None);
+        let q = json!({ "start_absolute": 5, "end_relative": { "value": i64::MAX, "unit": "ms" } });
+        assert_eq!(TimeRange::of_query(&q, -now), None);
+        assert!(parse_duration_ms("9223372036854775807y").is_err());
+
+        let ancient = TimeTier {
+            newer_than_ms: Some(i64::MAX),
+            older_than_ms: Some(i64::MAX),
+        };
+        assert!(!ancient.contains(now, -now));
+    }
+}
@@ -0,0 +1,73 @@
+//! Short-lived memory of metric names no routing rule matches.
+//!
+//! Routing an unknown name tries every rule's regex before giving up, and a writer sending
+//! high-cardinality names no rule covers makes the proxy do that for every datapoint. A name
+//! that matches no rule's metric pattern (ignoring tags, headers, time tiers and draining,
+//! which can change per request) is remembered for `unroutable_cache_ttl_secs` and refused
+//! straight away, counted in `kairos_proxy_unroutable_cache_hits_total`. Entries belong to
+//! the routing generation they were found in, so a reload or rollback starts afresh.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Beyond this many names the cache starts over rather than growing with the writer's cardinality
+const MAX_ENTRIES: usize = 10_000;
+
+pub struct UnroutableCache {
+    ttl: Duration,
+    // Name -> generation it was unroutable in, and when that expires
+    entries: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+impl UnroutableCache {
+    /// A cache keeping names for `ttl`; `None` when the TTL is zero.
+    pub fn new(ttl: Duration) -> Option<Self> {
+        (!ttl.is_zero()).then(|| UnroutableCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether `metric` was found unroutable in `generation` recently.
+    pub fn contains(&self, generation: u64, metric: &str) -> bool {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(metric)
+            .is_some_and(|(g, expires)| *g == generation && *expires > Instant::now())
+    }
+
+    pub fn insert(&self, generation: u64, metric: &str) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (g, expires)| *g == generation && *expires > now);
+            if entries.len() >= MAX_ENTRIES {
+                entries.clear();
+            }
+        }
+        entries.insert(metric.to_string(), (generation, now + self.ttl));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_names_on_expiry_and_reload() {
+        assert!(UnroutableCache::new(Duration::ZERO).is_none());
+        let cache = UnroutableCache::new(Duration::from_millis(50)).expect("cache");
+        cache.insert(1, "typo.cpu");
+        assert!(cache.contains(1, "typo.cpu"));
+        assert!(!cache.contains(2, "typo.cpu"));
+        assert!(!cache.contains(1, "cpu"));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!cache.contains(1, "typo.cpu"));
+
+        for i in 0..MAX_ENTRIES + 1 {
+            cache.insert(1, &format!("m{}", i));
+        }
+        assert!(cache.entries.lock().unwrap().len() <= MAX_ENTRIES);
+    }
+}
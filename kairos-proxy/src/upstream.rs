@@ -0,0 +1,352 @@
+//! Backend failures and the statuses clients see for them.
+//!
+//! A backend that is slow, gone or refusing traffic gets a distinct status, so alerting on
+//! the proxy can tell them apart: timeouts are `504`, unreachable backends and broken answers
+//! `502`, drained and rate-limited backends `503` with `Retry-After` (for a rate limit, the
+//! time until its budget admits another request). `429` is left to the client's own quota,
+//! so a backend answering `429` is `503` too. A backend refusing the proxy's credentials
+//! (`401`, `403`) is `502`: the client cannot fix that. An answer too large for the memory
+//! budget is `503` with `Retry-After` as well.
+//! The body names the backend: `{"error": "backend timed out", "backend": "eu"}`.
+
+use crate::merge_policy::Overlap;
+use crate::state::AppState;
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::time::Duration;
+
+/// Seconds clients are asked to wait before retrying a drained backend.
+const DRAINED_RETRY_AFTER_SECS: u64 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// No answer within the backend timeout or the client's deadline.
+    Timeout,
+    /// Connection refused or reset, or the name did not resolve.
+    Unreachable,
+    /// Taken out of rotation via the admin API.
+    Drained,
+    /// Its rate limit would have held the request for too long.
+    Throttled,
+    /// Answered with this error status.
+    Status(u16),
+    /// Answered with a body that is not a KairosDB response.
+    Invalid,
+    /// The request could not be built.
+    Internal,
+    /// Its answer did not fit in `max_buffered_bytes`.
+    MemoryBudget,
+}
+
+impl Failure {
+    /// Classifies a failed `reqwest` call.
+    pub fn of(e: &reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Failure::Timeout
+        } else if e.is_decode() {
+            Failure::Invalid
+        } else if e.is_builder() {
+            Failure::Internal
+        } else {
+            Failure::Unreachable
+        }
+    }
+
+    /// Label for the `kairos_proxy_backend_failures_total` counter.
+    fn reason(&self) -> &'static str {
+        match self {
+            Failure::Timeout => "timeout",
+            Failure::Unreachable => "unreachable",
+            Failure::Drained => "drained",
+            Failure::Throttled => "throttled",
+            Failure::Status(_) => "status",
+            Failure::Invalid => "invalid",
+            Failure::Internal => "internal",
+            Failure::MemoryBudget => "memory_budget",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackendError {
+    pub backend: String,
+    pub failure: Failure,
+    // When clients may try again, for failures that pass
+    retry_after: Option<Duration>,
+}
+
+impl BackendError {
+    /// A failure of `backend`, counted in `kairos_proxy_backend_failures_total`.
+    pub fn new(state: &AppState, backend: &str, failure: Failure) -> Self {
+        state.metrics.inc_counter(
+            "kairos_proxy_backend_failures_total",
+            &[("backend", backend), ("reason", failure.reason())],
+        );
+        state.events.backend_failed(backend, failure);
+        let retry_after = match failure {
+            Failure::Drained => Some(Duration::from_secs(DRAINED_RETRY_AFTER_SECS)),
+            Failure::Throttled | Failure::MemoryBudget | Failure::Status(429) => {
+                Some(Duration::from_secs(1))
+            }
+            _ => None,
+        };
+        BackendError {
+            backend: backend.to_string(),
+            failure,
+            retry_after,
+        }
+    }
+
+    /// This error with clients asked to retry after `wait` (at least a second).
+    pub fn with_retry_after(mut self, wait: Duration) -> Self {
+        self.retry_after = Some(wait);
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self.failure {
+            Failure::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Failure::Unreachable | Failure::Invalid => StatusCode::BAD_GATEWAY,
+            Failure::Drained | Failure::MemoryBudget | Failure::Throttled => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Failure::Status(429) => StatusCode::SERVICE_UNAVAILABLE,
+            // The backend refusing our credentials is ours to fix
+            Failure::Status(401 | 403) => StatusCode::BAD_GATEWAY,
+            // Other client errors (a bad query) are the client's to fix; server errors are ours
+            Failure::Status(s) => match StatusCode::from_u16(s) {
+                Ok(s) if s.is_client_error() => s,
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            Failure::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self.failure {
+            Failure::Timeout => "backend timed out".to_string(),
+            Failure::Unreachable => "backend unreachable".to_string(),
+            Failure::Drained => "backend is drained".to_string(),
+            Failure::Throttled => "backend rate limit exceeded".to_string(),
+            Failure::Status(s) => format!("backend answered {}", s),
+            Failure::Invalid => "backend sent an invalid response".to_string(),
+            Failure::Internal => "cannot build the backend request".to_string(),
+            Failure::MemoryBudget => "memory budget exhausted, retry later".to_string(),
+        }
+    }
+}
+
+impl IntoResponse for BackendError {
+    fn into_response(self) -> Response {
+        let body = Json(json!({ "error": self.message(), "backend": self.backend }));
+        // Retry-After is in whole seconds; round up so clients never come back too early
+        let retry_after = self
+            .retry_after
+            .map(|wait| (wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).max(1));
+        match retry_after {
+            Some(secs) => (
+                self.status(),
+                [(header::RETRY_AFTER, secs.to_string())],
+                body,
+            )
+                .into_response(),
+            None => (self.status(), body).into_response(),
+        }
+    }
+}
+
+/// Why a query could not be answered: a plain status for problems with the request itself,
+/// or the backend that failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueryError {
+    Status(StatusCode),
+    Backend(BackendError),
+    Overlap(Overlap),
+}
+
+impl QueryError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            QueryError::Status(s) => *s,
+            QueryError::Backend(e) => e.status(),
+            QueryError::Overlap(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl From<StatusCode> for QueryError {
+    fn from(status: StatusCode) -> Self {
+        QueryError::Status(status)
+    }
+}
+
+impl From<Overlap> for QueryError {
+    fn from(e: Overlap) -> Self {
+        QueryError::Overlap(e)
+    }
+}
+
+impl From<BackendError> for QueryError {
+    fn from(e: BackendError) -> Self {
+        QueryError::Backend(e)
+    }
+}
+
+impl IntoResponse for QueryError {
+    fn into_response(self) -> Response {
+        match self {
+            QueryError::Status(s) => s.into_response(),
+            QueryError::Backend(e) => e.into_response(),
+            QueryError::Overlap(e) => e.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, Config, Mode};
+    use crate::proxy::query_metric_handler;
+    use axum::{body::Body, extract::State, http::Request, routing::post, Router};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    async fn spawn_slow_backend() -> String {
+        let app = Router::new().route(
+            "/api/v1/datapoints/query",
+            post(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                "{}"
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+        format!("http://127.0.0.1:{}", addr.port())
+    }
+
+    #[tokio::test]
+    async fn tells_slow_backends_from_gone_ones() {
+        // Nothing listens on a port released right after binding it
+        let gone = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let gone_url = format!("http://{}", gone.local_addr().expect("addr"));
+        drop(gone);
+        let cfg = Config {
+            backends: vec![
+                Backend {
+                    name: Some("slow".to_string()),
+                    pattern: "^slow\\.".to_string(),
+                    url: spawn_slow_backend().await,
+                    ..Default::default()
+                },
+                Backend {
+                    name: Some("gone".to_string()),
+                    pattern: "^gone\\.".to_string(),
+                    url: gone_url,
+                    ..Default::default()
+                },
+            ],
+            mode: Some(Mode::Multi),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::from_config(&cfg).expect("state"));
+
+        for (metric, status, backend) in [
+            ("slow.cpu", StatusCode::GATEWAY_TIMEOUT, "slow"),
+            ("gone.cpu", StatusCode::BAD_GATEWAY, "gone"),
+        ] {
+            let req = Request::builder()
+                .method("POST")
+                .uri("/api/v1/datapoints/query")
+                .header("x-request-timeout-ms", "200")
+                .body(Body::from(format!(
+                    r#"{{"metrics":[{{"name":"{}"}}]}}"#,
+                    metric
+                )))
+                .unwrap();
+            let deadline = crate::deadline::Deadline::from_headers(req.headers());
+            let resp =
+                crate::deadline::scope(deadline, query_metric_handler(State(state.clone()), req))
+                    .await
+                    .expect("resp");
+            assert_eq!(resp.status(), status);
+            let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(body["backend"], backend);
+        }
+
+        state.backends[1].set_draining(true);
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/v1/datapoints/query")
+            .body(Body::from(r#"{"metrics":[{"name":"gone.cpu"}]}"#))
+            .unwrap();
+        let resp = query_metric_handler(State(state), req).await.expect("resp");
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "30");
+    }
+
+    #[test]
+    fn rate_limits_and_refused_credentials_are_not_the_clients_fault() {
+        let state = AppState::from_config(&Config::default()).expect("state");
+        let status = |failure| BackendError::new(&state, "eu", failure).status();
+        assert_eq!(status(Failure::Throttled), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            status(Failure::Status(429)),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(status(Failure::Status(401)), StatusCode::BAD_GATEWAY);
+        assert_eq!(status(Failure::Status(403)), StatusCode::BAD_GATEWAY);
+        assert_eq!(status(Failure::Status(400)), StatusCode::BAD_REQUEST);
+
+        let limited = BackendError::new(&state, "eu", Failure::Status(429)).into_response();
+        assert_eq!(limited.headers()[header::RETRY_AFTER], "1");
+    }
+
+    #[tokio::test]
+    async fn throttled_clients_retry_when_the_rate_limit_admits_them() {
+        let cfg = Config {
+            backends: vec![Backend {
+                name: Some("eu".to_string()),
+                pattern: ".*".to_string(),
+                url: "http://127.0.0.1:1".to_string(),
+                max_rps: Some(0.25),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let state = AppState::from_config(&cfg).expect("state");
+        let target = &state.backends[0];
+        assert_eq!(state.throttle(target, Duration::ZERO).await, Ok(()));
+        let wait = state
+            .throttle(target, Duration::ZERO)
+            .await
+            .expect_err("throttled");
+        assert!(wait > Duration::from_millis(3900) && wait <= Duration::from_secs(4));
+
+        let retry_after = |wait: Option<Duration>| {
+            let error = BackendError::new(&state, "eu", Failure::Throttled);
+            let resp = match wait {
+                Some(wait) => error.with_retry_after(wait),
+                None => error,
+            }
+            .into_response();
+            resp.headers()[header::RETRY_AFTER]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        // Whole seconds, rounded up so clients never come back too early
+        assert_eq!(retry_after(Some(wait)), "4");
+        assert_eq!(retry_after(Some(Duration::from_millis(2300))), "3");
+        assert_eq!(retry_after(Some(Duration::from_millis(200))), "1");
+        assert_eq!(retry_after(None), "1");
+    }
+}
@@ -0,0 +1,582 @@
+//! Schema check of KairosDB query bodies before they reach a backend.
+//!
+//! Bodies are checked against the documented query format: time fields, `metrics` with their
+//! `tags`, `group_by` and `aggregators`. Values of the wrong type are always rejected; unknown
+//! fields are stripped or rejected depending on `query_validation`. Queries over
+//! `max_metrics_per_query` or `max_aggregators_per_metric` are refused with 422, and queries
+//! without a start time are refused or given one per `missing_start`.
+//!
+//! Before any of that, the raw body is scanned for nesting deeper than `max_json_depth` and
+//! more than `max_json_tokens` values, so crafted bodies are refused before a parser spends
+//! stack or memory on them.
+
+use crate::config::QueryValidation;
+use crate::quota;
+use crate::timerange::{parse_duration, unit_millis};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use serde_json::{json, Map, Value};
+use tracing::{debug, warn};
+
+#[derive(Clone, Copy)]
+enum Type {
+    // A finite number or numeric string
+    Number,
+    String,
+    // One of KairosDB's time unit names, in any case
+    Unit,
+    Bool,
+    // `{ "value": .., "unit": .. }`
+    Relative,
+    // Tag name to a value or list of values
+    Tags,
+    Metrics,
+    // Objects with a `name`, whose other fields are plugin specific
+    Named,
+    Array,
+}
+
+const QUERY_FIELDS: &[(&str, Type)] = &[
+    ("start_absolute", Type::Number),
+    ("end_absolute", Type::Number),
+    ("start_relative", Type::Relative),
+    ("end_relative", Type::Relative),
+    ("time_zone", Type::String),
+    ("cache_time", Type::Number),
+    ("metrics", Type::Metrics),
+    ("plugins", Type::Array),
+];
+
+const METRIC_FIELDS: &[(&str, Type)] = &[
+    ("name", Type::String),
+    ("tags", Type::Tags),
+    ("group_by", Type::Named),
+    ("aggregators", Type::Named),
+    ("exclude_tags", Type::Bool),
+    ("limit", Type::Number),
+    ("order", Type::String),
+    ("plugins", Type::Array),
+];
+
+struct Checker {
+    strip: bool,
+    stripped: usize,
+    problems: Vec<String>,
+}
+
+impl Checker {
+    fn object(&mut self, path: &str, obj: &mut Map<String, Value>, fields: &[(&str, Type)]) {
+        let keys: Vec<String> = obj.keys().cloned().collect();
+        for key in keys {
+            let field_path = format!("{}.{}", path, key);
+            match fields.iter().find(|(name, _)| *name == key) {
+                Some((_, ty)) => {
+                    if let Some(v) = obj.get_mut(&key) {
+                        self.value(&field_path, v, *ty);
+                    }
+                }
+                None if self.strip => {
+                    debug!("Stripping unknown query field {}", field_path);
+                    obj.remove(&key);
+                    self.stripped += 1;
+                }
+                None => self.problems.push(format!("{}: unknown field", field_path)),
+            }
+        }
+    }
+
+    fn require(&mut self, path: &str, obj: &Map<String, Value>, field: &str) {
+        if !obj.contains_key(field) {
+            self.problems.push(format!("{}.{}: required", path, field));
+        }
+    }
+
+    fn value(&mut self, path: &str, v: &mut Value, ty: Type) {
+        let ok = match (ty, &mut *v) {
+            (Type::Number, Value::Number(_)) => true,
+            // JSON has no NaN or infinity, but `"NaN"` and `"inf"` parse as floats
+            (Type::Number, Value::String(s)) => s.parse::<f64>().is_ok_and(f64::is_finite),
+            (Type::Unit, Value::String(s)) => unit_millis(&s.to_ascii_lowercase()).is_some(),
+            (Type::String, Value::String(_)) | (Type::Bool, Value::Bool(_)) => true,
+            (Type::Array, Value::Array(_)) => true,
+            (Type::Relative, Value::Object(rel)) => {
+                self.require(path, rel, "value");
+                self.require(path, rel, "unit");
+                if let Some(value) = rel.get_mut("value") {
+                    self.value(&format!("{}.value", path), value, Type::Number);
+                }
+                if let Some(unit) = rel.get_mut("unit") {
+                    self.value(&format!("{}.unit", path), unit, Type::Unit);
+                }
+                true
+            }
+            (Type::Tags, Value::Object(tags)) => {
+                for (name, values) in tags.iter() {
+                    let valid = match values {
+                        Value::String(_) => true,
+                        Value::Array(a) => a.iter().all(Value::is_string),
+                        _ => false,
+                    };
+                    if !valid {
+                        self.problems.push(format!(
+                            "{}.{}: expected a string or list of strings",
+                            path, name
+                        ));
+                    }
+                }
+                true
+            }
+            (Type::Metrics, Value::Array(metrics)) => {
+                for (i, metric) in metrics.iter_mut().enumerate() {
+                    let path = format!("{}[{}]", path, i);
+                    match metric {
+                        Value::Object(m) => {
+                            self.require(&path, m, "name");
+                            self.object(&path, m, METRIC_FIELDS);
+                        }
+                        _ => self.problems.push(format!("{}: expected an object", path)),
+                    }
+                }
+                true
+            }
+            (Type::Named, Value::Array(items)) => {
+                for (i, item) in items.iter().enumerate() {
+                    if !item.get("name").is_some_and(Value::is_string) {
+                        self.problems
+                            .push(format!("{}[{}]: expected an object with a name", path, i));
+                    }
+                }
+                true
+            }
+            _ => false,
+        };
+        if !ok {
+            self.problems
+                .push(format!("{}: expected {}", path, ty.describe()));
+        }
+    }
+}
+
+impl Type {
+    fn describe(self) -> &'static str {
+        match self {
+            Type::Number => "a number",
+            Type::String => "a string",
+            Type::Unit => {
+                "one of milliseconds, seconds, minutes, hours, days, weeks, months, years"
+            }
+            Type::Bool => "a boolean",
+            Type::Relative => "an object with value and unit",
+            Type::Tags => "an object",
+            Type::Metrics | Type::Named | Type::Array => "an array",
+        }
+    }
+}
+
+/// Checks a parsed query, stripping unknown fields when `strip` is set. Returns the number of
+/// fields stripped, or every problem found.
+fn check(query: &mut Value, strip: bool) -> Result<usize, Vec<String>> {
+    let mut checker = Checker {
+        strip,
+        stripped: 0,
+        problems: Vec::new(),
+    };
+    match query {
+        Value::Object(q) => {
+            checker.require("query", q, "metrics");
+            checker.object("query", q, QUERY_FIELDS);
+        }
+        _ => checker
+            .problems
+            .push("query: expected an object".to_string()),
+    }
+    if checker.problems.is_empty() {
+        Ok(checker.stripped)
+    } else {
+        Err(checker.problems)
+    }
+}
+
+/// Problems that keep a query from being forwarded.
+#[derive(Debug)]
+pub struct Rejection {
+    status: StatusCode,
+    details: Vec<String>,
+}
+
+impl Rejection {
+    fn invalid(details: Vec<String>) -> Self {
+        Rejection {
+            status: StatusCode::BAD_REQUEST,
+            details,
+        }
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        warn!("Rejected query: {}", self.details.join("; "));
+        let error = match self.status {
+            StatusCode::UNPROCESSABLE_ENTITY => "query too large",
+            StatusCode::FORBIDDEN => "query not permitted",
+            _ => "invalid query",
+        };
+        (
+            self.status,
+            axum::Json(json!({ "error": error, "details": self.details })),
+        )
+            .into_response()
+    }
+}
+
+/// What to do with queries that have neither `start_absolute` nor `start_relative`.
+#[derive(Debug, PartialEq)]
+pub enum MissingStart {
+    Reject,
+    // Insert this `start_relative`
+    Default { value: i64, unit: &'static str },
+}
+
+impl MissingStart {
+    /// Parses `missing_start`: `reject` or a duration such as `1h`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if s == "reject" {
+            return Ok(MissingStart::Reject);
+        }
+        let (value, unit) = parse_duration(s)?;
+        Ok(MissingStart::Default { value, unit })
+    }
+}
+
+pub const DEFAULT_MAX_JSON_DEPTH: usize = 64;
+pub const DEFAULT_MAX_JSON_TOKENS: usize = 1_000_000;
+
+/// Limits on the shape of a JSON body, checked without parsing it.
+#[derive(Clone, Copy, Debug)]
+pub struct JsonLimits {
+    pub max_depth: usize,
+    // Values (objects, arrays, strings, keys and scalars) in the whole body
+    pub max_tokens: usize,
+}
+
+impl Default for JsonLimits {
+    fn default() -> Self {
+        JsonLimits {
+            max_depth: DEFAULT_MAX_JSON_DEPTH,
+            max_tokens: DEFAULT_MAX_JSON_TOKENS,
+        }
+    }
+}
+
+impl JsonLimits {
+    /// Scans `body` byte by byte, stopping at the first limit exceeded. Malformed JSON passes;
+    /// the parser reports it.
+    pub fn check(&self, body: &[u8]) -> Result<(), Rejection> {
+        let too_large = |detail: String| Rejection {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            details: vec![detail],
+        };
+        let (mut depth, mut tokens) = (0usize, 0usize);
+        let (mut in_string, mut escaped, mut in_scalar) = (false, false, false);
+        for &b in body {
+            if in_string {
+                match b {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match b {
+                b'{' | b'[' => {
+                    depth += 1;
+                    tokens += 1;
+                    if depth > self.max_depth {
+                        return Err(too_large(format!(
+                            "query: nesting exceeds the limit of {}",
+                            self.max_depth
+                        )));
+                    }
+                }
+                b'}' | b']' => depth = depth.saturating_sub(1),
+                b'"' => {
+                    in_string = true;
+                    tokens += 1;
+                }
+                b',' | b':' | b' ' | b'\t' | b'\n' | b'\r' => {}
+                // Numbers, true, false and null
+                _ if !in_scalar => tokens += 1,
+                _ => {}
+            }
+            in_scalar = !matches!(
+                b,
+                b'{' | b'[' | b'}' | b']' | b'"' | b',' | b':' | b' ' | b'\t' | b'\n' | b'\r'
+            );
+            if tokens > self.max_tokens {
+                return Err(too_large(format!(
+                    "query: more than {} JSON values",
+                    self.max_tokens
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Restricts the query to the tag scope of the request's API key, if it has one.
+fn scope(body: Bytes) -> Result<Bytes, Rejection> {
+    if !quota::tag_scoped() {
+        return Ok(body);
+    }
+    let query: Value = serde_json::from_slice(&body)
+        .map_err(|e| Rejection::invalid(vec![format!("query: {}", e)]))?;
+    match quota::scope_query(&query) {
+        Ok(Some(scoped)) => serde_json::to_vec(&scoped)
+            .map(Bytes::from)
+            .map_err(|e| Rejection::invalid(vec![e.to_string()])),
+        Ok(None) => Ok(body),
+        Err(detail) => Err(Rejection {
+            status: StatusCode::FORBIDDEN,
+            details: vec![detail],
+        }),
+    }
+}
+
+/// Checks run on query bodies before they are forwarded.
+#[derive(Default)]
+pub struct QueryChecks {
+    pub json_limits: JsonLimits,
+    pub validation: QueryValidation,
+    pub max_metrics: Option<usize>,
+    pub max_aggregators: Option<usize>,
+    pub missing_start: Option<MissingStart>,
+}
+
+impl QueryChecks {
+    pub fn enabled(&self) -> bool {
+        !matches!(self.validation, QueryValidation::Off)
+            || self.max_metrics.is_some()
+            || self.max_aggregators.is_some()
+            || self.missing_start.is_some()
+    }
+
+    /// Returns the body to forward, re-serialized if it was changed, or why it cannot be.
+    pub fn apply(&self, body: Bytes) -> Result<Bytes, Rejection> {
+        self.json_limits.check(&body)?;
+        let body = scope(body)?;
+        if !self.enabled() {
+            return Ok(body);
+        }
+        let mut query: Value = serde_json::from_slice(&body)
+            .map_err(|e| Rejection::invalid(vec![format!("query: {}", e)]))?;
+        let mut changed = match self.validation {
+            QueryValidation::Off => false,
+            QueryValidation::Strip => check(&mut query, true).map_err(Rejection::invalid)? > 0,
+            QueryValidation::Reject => check(&mut query, false).map_err(Rejection::invalid)? > 0,
+        };
+        self.limits(&query)?;
+        changed |= self.start(&mut query)?;
+        if !changed {
+            return Ok(body);
+        }
+        serde_json::to_vec(&query)
+            .map(Bytes::from)
+            .map_err(|e| Rejection::invalid(vec![e.to_string()]))
+    }
+
+    /// Applies `missing_start` to a query without a start time. Returns whether one was added.
+    fn start(&self, query: &mut Value) -> Result<bool, Rejection> {
+        let Some(policy) = &self.missing_start else {
+            return Ok(false);
+        };
+        let Value::Object(q) = query else {
+            return Ok(false);
+        };
+        if q.contains_key("start_absolute") || q.contains_key("start_relative") {
+            return Ok(false);
+        }
+        match policy {
+            MissingStart::Reject => Err(Rejection::invalid(vec![
+                "query: start_absolute or start_relative is required".to_string(),
+            ])),
+            MissingStart::Default { value, unit } => {
+                debug!("Query has no start time; using the last {} {}", value, unit);
+                q.insert(
+                    "start_relative".to_string(),
+                    json!({ "value": value, "unit": unit }),
+                );
+                Ok(true)
+            }
+        }
+    }
+
+    /// Enforces `max_metrics_per_query` and `max_aggregators_per_metric`.
+    fn limits(&self, query: &Value) -> Result<(), Rejection> {
+        let metrics = query["metrics"].as_array().map_or(&[][..], Vec::as_slice);
+        let mut details = Vec::new();
+        if let Some(max) = self.max_metrics.filter(|max| metrics.len() > *max) {
+            details.push(format!(
+                "query.metrics: {} metrics exceed the limit of {}",
+                metrics.len(),
+                max
+            ));
+        }
+        if let Some(max) = self.max_aggregators {
+            for (i, metric) in metrics.iter().enumerate() {
+                let n = metric["aggregators"].as_array().map_or(0, Vec::len);
+                if n > max {
+                    details.push(format!(
+                        "query.metrics[{}].aggregators: {} aggregators exceed the limit of {}",
+                        i, n, max
+                    ));
+                }
+            }
+        }
+        if details.is_empty() {
+            Ok(())
+        } else {
+            Err(Rejection {
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+                details,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_unknown_fields_and_rejects_wrong_types() {
+        let mut query = json!({
+            "start_relative": { "value": "1", "unit": "hours" },
+            "debug": true,
+            "metrics": [{
+                "name": "cpu.load",
+                "tags": { "host": ["a", "b"] },
+                "aggregators": [{ "name": "avg", "sampling": { "value": 1, "unit": "minutes" } }],
+                "script": "rm -rf /"
+            }]
+        });
+        assert_eq!(check(&mut query.clone(), false).unwrap_err().len(), 2);
+        assert_eq!(check(&mut query, true), Ok(2));
+        assert!(query.get("debug").is_none());
+        assert!(query["metrics"][0].get("script").is_none());
+        assert_eq!(
+            query["metrics"][0]["aggregators"][0]["sampling"]["value"],
+            1
+        );
+
+        let mut bad = json!({
+            "start_absolute": "yesterday",
+            "metrics": [{ "tags": { "host": 5 }, "aggregators": [{}] }, "cpu"]
+        });
+        assert_eq!(
+            check(&mut bad, true).unwrap_err(),
+            vec![
+                "query.metrics[0].name: required",
+                "query.metrics[0].aggregators[0]: expected an object with a name",
+                "query.metrics[0].tags.host: expected a string or list of strings",
+                "query.metrics[1]: expected an object",
+                "query.start_absolute: expected a number",
+            ]
+        );
+        assert!(check(&mut json!({}), false).is_err());
+    }
+
+    #[test]
+    fn rejects_non_finite_numbers_and_unknown_units() {
+        let mut query = json!({
+            "start_relative": { "value": "NaN", "unit": "fortnights" },
+            "end_absolute": "inf",
+            "metrics": [{ "name": "m", "limit": "1e400" }]
+        });
+        assert_eq!(
+            check(&mut query, true).unwrap_err(),
+            vec![
+                "query.end_absolute: expected a number",
+                "query.metrics[0].limit: expected a number",
+                "query.start_relative.value: expected a number",
+                "query.start_relative.unit: expected one of milliseconds, seconds, minutes, \
+                 hours, days, weeks, months, years",
+            ]
+        );
+        let mut query = json!({
+            "start_relative": { "value": 2, "unit": "HOURS" },
+            "metrics": [{ "name": "m" }]
+        });
+        assert_eq!(check(&mut query, false), Ok(0));
+    }
+
+    #[test]
+    fn refuses_deep_or_huge_bodies_before_parsing() {
+        let limits = JsonLimits {
+            max_depth: 5,
+            max_tokens: 14,
+        };
+        let query = br#"{"metrics": [{"name": "a{[", "tags": {"h": ["x", "y"]}}], "limit": 10}"#;
+        // Brackets inside strings do not count; 14 values, 5 levels
+        assert!(limits.check(query).is_ok());
+        let deep = format!("{}{}", "[".repeat(6), "]".repeat(6));
+        let err = limits.check(deep.as_bytes()).unwrap_err();
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.details, vec!["query: nesting exceeds the limit of 5"]);
+        assert!(limits
+            .check(b"[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14]")
+            .is_err());
+        // A stack-busting body never reaches the parser
+        let bomb = "[".repeat(100_000);
+        assert!(QueryChecks::default().apply(Bytes::from(bomb)).is_err());
+    }
+
+    #[test]
+    fn caps_metrics_and_aggregators() {
+        let checks = QueryChecks {
+            max_metrics: Some(2),
+            max_aggregators: Some(1),
+            ..Default::default()
+        };
+        let query = |metrics: usize, aggregators: usize| {
+            let metric =
+                json!({ "name": "m", "aggregators": vec![json!({ "name": "avg" }); aggregators] });
+            Bytes::from(json!({ "metrics": vec![metric; metrics] }).to_string())
+        };
+        assert!(checks.apply(query(2, 1)).is_ok());
+        let err = checks.apply(query(3, 2)).unwrap_err();
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.details.len(), 4);
+        assert_eq!(
+            err.details[0],
+            "query.metrics: 3 metrics exceed the limit of 2"
+        );
+    }
+
+    #[test]
+    fn missing_start_is_rejected_or_defaulted() {
+        let body = Bytes::from(r#"{"metrics":[{"name":"m"}]}"#);
+        let reject = QueryChecks {
+            missing_start: Some(MissingStart::parse("reject").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            reject.apply(body.clone()).unwrap_err().status,
+            StatusCode::BAD_REQUEST
+        );
+
+        let default = QueryChecks {
+            missing_start: Some(MissingStart::parse("1h").unwrap()),
+            ..Default::default()
+        };
+        let query: Value = serde_json::from_slice(&default.apply(body).unwrap()).unwrap();
+        assert_eq!(
+            query["start_relative"],
+            json!({ "value": 1, "unit": "hours" })
+        );
+        let explicit = Bytes::from(r#"{"start_absolute":1,"metrics":[]}"#);
+        assert_eq!(default.apply(explicit.clone()).unwrap(), explicit);
+        assert!(MissingStart::parse("soon").is_err());
+    }
+}
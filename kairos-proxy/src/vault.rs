@@ -0,0 +1,324 @@
+//! HashiCorp Vault as a source of backend tokens.
+//!
+//! Secrets are read with the configured Vault token or, for secrets with a `role`, a token
+//! obtained by logging in with the pod's Kubernetes service account. Renewable leases are
+//! renewed before they run out; anything else is re-read periodically, so rotated secrets
+//! are picked up without a restart. A renewal that comes back shorter than the lease it
+//! renewed (the lease reached its max TTL) has the secret re-read at once.
+
+use crate::config::{VaultConfig, VaultSecret};
+use crate::credentials::Token;
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// How often secrets without a lease (e.g. KV v2) are re-read
+const REREAD: Duration = Duration::from_secs(300);
+const MIN_RETRY: Duration = Duration::from_secs(5);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+const SERVICE_ACCOUNT_TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+/// Renew (or re-read) once two thirds of a lease have passed.
+fn refresh_after(lease: Duration) -> Duration {
+    if lease.is_zero() {
+        REREAD
+    } else {
+        (lease * 2 / 3).max(MIN_RETRY)
+    }
+}
+
+fn lease_duration(v: &Value) -> Duration {
+    Duration::from_secs(v["lease_duration"].as_u64().unwrap_or(0))
+}
+
+pub struct Vault {
+    client: Client,
+    address: Url,
+    token: Option<String>,
+    auth_mount: String,
+    jwt_path: String,
+}
+
+impl Vault {
+    pub fn new(cfg: &VaultConfig) -> anyhow::Result<Self> {
+        let address = cfg
+            .address
+            .clone()
+            .or_else(|| std::env::var("VAULT_ADDR").ok())
+            .ok_or_else(|| anyhow::anyhow!("Vault secrets need [vault] address or VAULT_ADDR"))?;
+        Ok(Vault {
+            client: Client::builder().timeout(REQUEST_TIMEOUT).build()?,
+            address: Url::parse(&address)
+                .map_err(|e| anyhow::anyhow!("Invalid Vault address '{}': {}", address, e))?,
+            token: cfg
+                .token
+                .clone()
+                .or_else(|| std::env::var("VAULT_TOKEN").ok()),
+            auth_mount: cfg
+                .auth_mount
+                .clone()
+                .unwrap_or_else(|| "kubernetes".to_string()),
+            jwt_path: cfg
+                .jwt_path
+                .clone()
+                .unwrap_or_else(|| SERVICE_ACCOUNT_TOKEN.to_string()),
+        })
+    }
+
+    fn url(&self, path: &str) -> anyhow::Result<Url> {
+        Ok(self
+            .address
+            .join(&format!("/v1/{}", path.trim_start_matches('/')))?)
+    }
+
+    /// A Vault token for reading `secret`, and how long it lasts if it came from a login.
+    async fn authenticate(
+        &self,
+        secret: &VaultSecret,
+    ) -> anyhow::Result<(String, Option<Duration>)> {
+        let Some(role) = &secret.role else {
+            let token = self.token.clone().ok_or_else(|| {
+                anyhow::anyhow!("no Vault token configured and no role to log in with")
+            })?;
+            return Ok((token, None));
+        };
+        let jwt = std::fs::read_to_string(&self.jwt_path)
+            .map_err(|e| anyhow::anyhow!("cannot read {}: {}", self.jwt_path, e))?;
+        let resp: Value = self
+            .client
+            .post(self.url(&format!("auth/{}/login", self.auth_mount))?)
+            .json(&json!({ "role": role, "jwt": jwt.trim() }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let token = resp["auth"]["client_token"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("login response has no client_token"))?;
+        Ok((token.to_string(), Some(lease_duration(&resp["auth"]))))
+    }
+
+    /// Reads the secret's token field. Returns the value and the raw response for its lease.
+    async fn read(
+        &self,
+        vault_token: &str,
+        secret: &VaultSecret,
+    ) -> anyhow::Result<(String, Value)> {
+        let resp: Value = self
+            .client
+            .get(self.url(&secret.path)?)
+            .header("X-Vault-Token", vault_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let field = secret.field.as_deref().unwrap_or("token");
+        // KV v2 nests the secret one level deeper
+        let data = match &resp["data"]["data"] {
+            Value::Object(_) => &resp["data"]["data"],
+            _ => &resp["data"],
+        };
+        let value = data[field]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("secret has no string field '{}'", field))?;
+        Ok((value.to_string(), resp))
+    }
+
+    /// Renews a lease, returning its new duration.
+    async fn renew(&self, vault_token: &str, lease_id: &str) -> anyhow::Result<Duration> {
+        let resp: Value = self
+            .client
+            .put(self.url("sys/leases/renew")?)
+            .header("X-Vault-Token", vault_token)
+            .json(&json!({ "lease_id": lease_id }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(lease_duration(&resp))
+    }
+
+    /// Reads the secret into `token`, then renews its lease for as long as that works, the
+    /// lease does not shrink and the login token lasts. Returns when the secret should be
+    /// read again.
+    async fn cycle(&self, token: &Token, secret: &VaultSecret) -> anyhow::Result<()> {
+        let (vault_token, login_ttl) = self.authenticate(secret).await?;
+        let relogin_at = login_ttl
+            .filter(|ttl| !ttl.is_zero())
+            .map(|ttl| Instant::now() + refresh_after(ttl));
+        let (value, resp) = self.read(&vault_token, secret).await?;
+        token.set(value);
+
+        let lease_id = resp["lease_id"].as_str().unwrap_or_default();
+        let renewable = resp["renewable"].as_bool().unwrap_or(false) && !lease_id.is_empty();
+        let mut lease = lease_duration(&resp);
+        loop {
+            let wait = refresh_after(lease);
+            if let Some(at) = relogin_at.filter(|at| Instant::now() + wait > *at) {
+                tokio::time::sleep_until(at.into()).await;
+                return Ok(());
+            }
+            tokio::time::sleep(wait).await;
+            if !renewable {
+                return Ok(());
+            }
+            let renewed = self.renew(&vault_token, lease_id).await?;
+            if renewed < lease {
+                // Capped by the lease's max TTL: it ends soon whatever we do
+                debug!(
+                    "Vault lease {} renewed for only {:?}; re-reading",
+                    lease_id, renewed
+                );
+                return Ok(());
+            }
+            lease = renewed;
+            debug!("Renewed Vault lease {} for {:?}", lease_id, lease);
+        }
+    }
+
+    /// Keeps `token` current for the lifetime of the process. Failures retry with backoff
+    /// while the previous value stays in use.
+    pub async fn maintain(&self, name: String, token: &Token, secret: &VaultSecret) {
+        let mut retry = MIN_RETRY;
+        loop {
+            match self.cycle(token, secret).await {
+                Ok(()) => retry = MIN_RETRY,
+                Err(e) => {
+                    warn!(
+                        "Failed to read Vault secret {} for backend '{}': {}",
+                        secret.path, name, e
+                    );
+                    tokio::time::sleep(retry).await;
+                    retry = (retry * 2).min(MAX_RETRY);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::Source;
+    use axum::http::HeaderMap;
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+
+    #[tokio::test]
+    async fn logs_in_with_kubernetes_role_and_reads_kv2_secret() {
+        let app = Router::new()
+            .route(
+                "/v1/auth/kubernetes/login",
+                post(|Json(body): Json<Value>| async move {
+                    assert_eq!(body, json!({ "role": "kairos-proxy", "jwt": "sa-jwt" }));
+                    Json(json!({ "auth": { "client_token": "s.login", "lease_duration": 3600 } }))
+                }),
+            )
+            .route(
+                "/v1/secret/data/kairos/eu",
+                get(|headers: HeaderMap| async move {
+                    assert_eq!(headers["x-vault-token"], "s.login");
+                    Json(json!({
+                        "lease_id": "",
+                        "lease_duration": 0,
+                        "renewable": false,
+                        "data": { "data": { "api_key": "backend-token" }, "metadata": {} }
+                    }))
+                }),
+            );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        let jwt_path = std::env::temp_dir().join(format!("kairos-proxy-jwt-{}", addr.port()));
+        std::fs::write(&jwt_path, "sa-jwt\n").expect("write jwt");
+        let vault = Vault::new(&VaultConfig {
+            address: Some(format!("http://{}", addr)),
+            jwt_path: Some(jwt_path.to_string_lossy().into_owned()),
+            ..Default::default()
+        })
+        .expect("vault");
+        let secret = VaultSecret {
+            path: "secret/data/kairos/eu".to_string(),
+            field: Some("api_key".to_string()),
+            role: Some("kairos-proxy".to_string()),
+        };
+        let token = std::sync::Arc::new(Token::managed(Source::Vault(secret.clone())));
+        let managed = token.clone();
+        let task =
+            tokio::spawn(async move { vault.maintain("eu".to_string(), &managed, &secret).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(token.get().as_deref(), Some("backend-token"));
+        task.abort();
+        let _ = std::fs::remove_file(jwt_path);
+
+        assert_eq!(refresh_after(Duration::ZERO), REREAD);
+        assert_eq!(
+            refresh_after(Duration::from_secs(3600)),
+            Duration::from_secs(2400)
+        );
+    }
+
+    #[tokio::test]
+    async fn rereads_at_once_when_a_renewal_comes_back_short() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let reads = Arc::new(AtomicUsize::new(0));
+        let counted = reads.clone();
+        let app = Router::new()
+            .route(
+                "/v1/database/creds/kairos",
+                get(move || {
+                    let n = counted.fetch_add(1, Ordering::SeqCst) + 1;
+                    async move {
+                        Json(json!({
+                            "lease_id": "database/creds/kairos/1",
+                            "lease_duration": 6,
+                            "renewable": true,
+                            "data": { "token": format!("secret-{}", n) }
+                        }))
+                    }
+                }),
+            )
+            .route(
+                "/v1/sys/leases/renew",
+                axum::routing::put(|| async { Json(json!({ "lease_duration": 0 })) }),
+            );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        let vault = Vault::new(&VaultConfig {
+            address: Some(format!("http://{}", addr)),
+            token: Some("s.root".to_string()),
+            ..Default::default()
+        })
+        .expect("vault");
+        let secret = VaultSecret {
+            path: "database/creds/kairos".to_string(),
+            field: None,
+            role: None,
+        };
+        let token = Token::managed(Source::Vault(secret.clone()));
+        // The first renewal is due after MIN_RETRY; without the re-read the cycle would
+        // sleep out a REREAD after it
+        tokio::time::timeout(MIN_RETRY * 2, vault.cycle(&token, &secret))
+            .await
+            .expect("cycle returns after the short renewal")
+            .expect("cycle");
+        assert_eq!(token.get().as_deref(), Some("secret-1"));
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+    }
+}
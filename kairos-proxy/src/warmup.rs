@@ -0,0 +1,108 @@
+//! Opening backend connections before the proxy reports ready.
+//!
+//! With `warmup_connections = N`, startup sends N concurrent `GET /api/v1/health/check`
+//! requests to every backend (for each routing rule, as each has a pool of its own) and waits
+//! for their answers before listening, so the first queries after a deploy find connections
+//! already set up. Warm-up gives up after `warmup_timeout_secs` (default 10); a backend that
+//! is down only delays startup by that much. Drained backends are skipped, and for Kubernetes
+//! backends only the configured URL is warmed, not the discovered pods.
+
+use crate::config::Mode;
+use crate::state::{AppState, BackendTarget};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const HEALTH_CHECK_ENDPOINT: &str = "api/v1/health/check";
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Opens up to `connections` connections to `target`; returns how many pings were answered.
+async fn warm(state: &AppState, target: &BackendTarget, connections: usize) -> usize {
+    let Ok(url) = target.endpoint_url(target.request_url(), HEALTH_CHECK_ENDPOINT) else {
+        return 0;
+    };
+    // Simple mode sends queries through the pass-through client
+    let client = match state.mode {
+        Mode::Simple => target.client.passthrough(),
+        _ => target.client.get(),
+    };
+    let token = target.token.get();
+    let mut pings: FuturesUnordered<_> = (0..connections)
+        .map(|_| {
+            let mut builder = client.get(url.clone()).timeout(target.timeout);
+            if let Some(t) = &token {
+                builder = builder.header("Authorization", format!("Bearer {}", t));
+            }
+            target.client.send(&state.metrics, &target.name, builder)
+        })
+        .collect();
+    let mut answered = 0;
+    while let Some(ping) = pings.next().await {
+        match ping {
+            Ok(_) => answered += 1,
+            Err(e) => warn!("Warm-up request to backend '{}' failed: {}", target.name, e),
+        }
+    }
+    answered
+}
+
+/// Warms every backend's pool with `connections` connections, for at most `timeout`.
+pub async fn run(state: &AppState, connections: usize, timeout: Duration) {
+    let warming = futures::future::join_all(
+        state
+            .backends
+            .iter()
+            .filter(|b| !b.is_draining())
+            .map(|b| async move { (b.name.as_str(), warm(state, b, connections).await) }),
+    );
+    match tokio::time::timeout(timeout, warming).await {
+        Ok(warmed) => {
+            for (backend, answered) in warmed {
+                info!(
+                    "Warmed up backend '{}': {} of {} connections",
+                    backend, answered, connections
+                );
+            }
+        }
+        Err(_) => warn!("Backend warm-up did not finish within {:?}", timeout),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Backend, Config};
+    use axum::{routing::get, Router};
+
+    #[tokio::test]
+    async fn opens_the_requested_connections_before_serving() {
+        let app = Router::new().route(
+            "/api/v1/health/check",
+            get(|| async {
+                // Keep each answer pending long enough that the pings cannot share a connection
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                axum::http::StatusCode::NO_CONTENT
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let server = axum::Server::from_tcp(listener)
+            .expect("server")
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        let cfg = Config {
+            mode: Some(Mode::Multi),
+            backends: vec![Backend {
+                name: Some("local".to_string()),
+                pattern: ".*".to_string(),
+                url: format!("http://{}", addr),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let state = AppState::from_config(&cfg).expect("state");
+        run(&state, 3, DEFAULT_TIMEOUT).await;
+        assert_eq!(state.backends[0].client.connections(), (0, 3));
+    }
+}